@@ -0,0 +1,86 @@
+//! Time one candidate slug of every strategy takes, storage round trips included for the strategies
+//! asking the store for numbers. Run with `cargo bench --bench slugs`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use url_shortener::{
+    CounterSlugs, DeterministicSlugs, HashSlugs, MemoryStore, RandomSlugs, SequentialSlugs,
+    SlugAlphabet, SlugStrategy, SnowflakeSlugs,
+};
+
+const URL: &str =
+    "https://example.com/blog/2024/launching-the-new-pricing-page?utm_source=newsletter";
+const LENGTH: usize = 7;
+
+fn strategies() -> Vec<(&'static str, Arc<dyn SlugStrategy>)> {
+    let alphabet = SlugAlphabet::Base62;
+    vec![
+        (
+            "random",
+            Arc::new(RandomSlugs {
+                length: LENGTH,
+                alphabet,
+            }),
+        ),
+        (
+            "hash",
+            Arc::new(HashSlugs {
+                length: LENGTH,
+                alphabet,
+            }),
+        ),
+        (
+            "deterministic",
+            Arc::new(DeterministicSlugs::new("bench-key", LENGTH, alphabet)),
+        ),
+        (
+            "snowflake",
+            Arc::new(SnowflakeSlugs::new(alphabet, LENGTH, 1)),
+        ),
+        (
+            "counter",
+            Arc::new(CounterSlugs {
+                store: Arc::new(MemoryStore::new()),
+                alphabet,
+            }),
+        ),
+        (
+            "sequential",
+            Arc::new(SequentialSlugs::new(
+                Arc::new(MemoryStore::new()),
+                alphabet,
+                LENGTH,
+                1000,
+            )),
+        ),
+    ]
+}
+
+fn slug_strategies(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("Failed to start the runtime");
+    let mut group = c.benchmark_group("next_slug");
+    for (name, strategy) in strategies() {
+        group.bench_function(name, |b| {
+            b.to_async(&runtime)
+                .iter(|| async { strategy.next_slug(URL, 1).await.unwrap() })
+        });
+    }
+    group.finish();
+
+    // Later attempts of the hashing strategies hash more, or take longer slugs
+    let mut group = c.benchmark_group("next_slug_after_collisions");
+    for (name, strategy) in strategies()
+        .into_iter()
+        .filter(|(name, _)| matches!(*name, "hash" | "deterministic"))
+    {
+        group.bench_function(name, |b| {
+            b.to_async(&runtime)
+                .iter(|| async { strategy.next_slug(URL, 4).await.unwrap() })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, slug_strategies);
+criterion_main!(benches);
@@ -0,0 +1,4 @@
+fn main() {
+    // Askama only tracks the templates it used, a new override in templates/custom needs a rebuild too
+    println!("cargo:rerun-if-changed=templates");
+}
@@ -0,0 +1,112 @@
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::error::ApiError;
+
+/// Most networks accepted in either list of a link
+const MAX_NETWORKS: usize = 100;
+
+/// Networks a link can be opened from, checked against the client IP when resolving
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccessRules {
+    /// Only clients in one of these networks get through, every client when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_ips: Vec<IpNet>,
+    /// Clients in one of these networks never get through, even when they are allowed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_ips: Vec<IpNet>,
+}
+
+impl AccessRules {
+    /// Parses the `allowed_ips` and `denied_ips` of a shorten request, single addresses are taken as `/32` or `/128`
+    pub fn parse(allowed_ips: &[String], denied_ips: &[String]) -> Result<Self, String> {
+        Ok(AccessRules {
+            allowed_ips: parse_networks("allowed_ips", allowed_ips)?,
+            denied_ips: parse_networks("denied_ips", denied_ips)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allowed_ips.is_empty() && self.denied_ips.is_empty()
+    }
+
+    /// Whether a client at `ip` may open the link, a client of unknown address only passes links without rules
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Some(ip) = ip.map(|ip| ip.to_canonical()) else {
+            return false;
+        };
+        !self.denied_ips.iter().any(|net| net.contains(&ip))
+            && (self.allowed_ips.is_empty() || self.allowed_ips.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+fn parse_networks(field: &str, networks: &[String]) -> Result<Vec<IpNet>, String> {
+    if networks.len() > MAX_NETWORKS {
+        return Err(format!(
+            "{} can hold at most {} networks",
+            field, MAX_NETWORKS
+        ));
+    }
+    networks
+        .iter()
+        .map(|network| {
+            let network = network.trim();
+            network
+                .parse::<IpNet>()
+                .map(|net| net.trunc())
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    format!(
+                        "{}: '{}' is neither an IP address nor a CIDR network",
+                        field, network
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Rejects clients outside the networks the link is restricted to with `403 Forbidden`
+pub fn check(rules: &AccessRules, ip: Option<IpAddr>) -> Result<(), ApiError> {
+    if rules.allows(ip) {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden {
+            message: "This link can't be opened from your network.".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(allowed: &[&str], denied: &[&str]) -> AccessRules {
+        let strings =
+            |networks: &[&str]| networks.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        AccessRules::parse(&strings(allowed), &strings(denied)).unwrap()
+    }
+
+    #[test]
+    fn test_allow_and_deny_lists() {
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        let internal = rules(&["10.0.0.0/8", "2001:db8::/32"], &["10.0.13.0/24"]);
+        assert!(internal.allows(ip("10.1.2.3")));
+        assert!(internal.allows(ip("2001:db8::1")));
+        // IPv4 clients connecting over IPv6 sockets
+        assert!(internal.allows(ip("::ffff:10.1.2.3")));
+        assert!(!internal.allows(ip("10.0.13.7")));
+        assert!(!internal.allows(ip("192.0.2.1")));
+        assert!(!internal.allows(None));
+
+        let blocked = rules(&[], &["192.0.2.1"]);
+        assert!(!blocked.allows(ip("192.0.2.1")));
+        assert!(blocked.allows(ip("192.0.2.2")));
+        assert!(AccessRules::default().allows(None));
+
+        assert!(AccessRules::parse(&["intranet".to_string()], &[]).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::clicks::ClickEvent;
+use crate::metrics::{Counter, Metrics};
+use crate::storage::{CountBatch, UrlStore};
+
+/// How clicks are buffered between the redirects and the analytics writer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnalyticsConfig {
+    /// Clicks held before the oldest ones are dropped, rounded up to a power of two
+    pub buffer_size: usize,
+    /// How often the buffered clicks are written
+    pub flush_interval: Duration,
+}
+
+/// Hands clicks to a background writer, which adds them up and writes each flush with a single pipeline
+pub struct Analytics {
+    sender: broadcast::Sender<ClickEvent>,
+    writer: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl Analytics {
+    /// Starts the writer, it runs until `shutdown` is called or the `Analytics` is dropped
+    pub fn start(config: AnalyticsConfig, store: Arc<dyn UrlStore>, metrics: Arc<Metrics>) -> Self {
+        // The only tokio channel that makes room by overwriting the oldest message instead of waiting
+        // or refusing the new one. It has a single receiver here.
+        let (sender, receiver) = broadcast::channel(config.buffer_size);
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(write_clicks(receiver, stopped, config, store, metrics));
+        Analytics {
+            sender,
+            writer: Mutex::new(Some((stop, task))),
+        }
+    }
+
+    /// Queues a click without waiting, a full buffer drops its oldest click to make room
+    pub fn send(&self, event: ClickEvent) {
+        // Only fails once the writer is gone during shutdown
+        let _ = self.sender.send(event);
+    }
+
+    /// Writes the clicks still buffered and stops the writer
+    pub async fn shutdown(&self) {
+        let writer = self.writer.lock().unwrap().take();
+        if let Some((stop, task)) = writer {
+            let _ = stop.send(());
+            if let Err(err) = task.await {
+                log::warn!("Analytics writer stopped abnormally: {}", err);
+            }
+        }
+    }
+}
+
+async fn write_clicks(
+    mut receiver: broadcast::Receiver<ClickEvent>,
+    mut stopped: oneshot::Receiver<()>,
+    config: AnalyticsConfig,
+    store: Arc<dyn UrlStore>,
+    metrics: Arc<Metrics>,
+) {
+    let mut ticker = interval(config.flush_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        // Stops on `shutdown` as well as when the sender half was dropped
+        let stopping = tokio::select! {
+            _ = ticker.tick() => false,
+            _ = &mut stopped => true,
+        };
+        let counts = drain(&mut receiver, config.buffer_size, &metrics);
+        if !counts.is_empty() {
+            if let Err(err) = store.write_counts(&counts).await {
+                metrics.incr(Counter::StorageErrors);
+                log::warn!("Failed to write click counts: {}", err);
+            }
+        }
+        if stopping {
+            return;
+        }
+    }
+}
+
+/// Adds up to `limit` buffered clicks, so a steady stream of new ones can't hold up the write
+fn drain(
+    receiver: &mut broadcast::Receiver<ClickEvent>,
+    limit: usize,
+    metrics: &Metrics,
+) -> CountBatch {
+    let mut counts = CountBatch::default();
+    let mut received = 0;
+    while received < limit {
+        match receiver.try_recv() {
+            Ok(event) => {
+                event.count(&mut counts);
+                received += 1;
+            }
+            Err(TryRecvError::Lagged(dropped)) => {
+                metrics.add(Counter::ClicksDropped, dropped);
+                log::warn!(
+                    "Dropped {} clicks, the analytics writer can't keep up",
+                    dropped
+                );
+            }
+            Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clicks;
+    use crate::link::Link;
+    use crate::memory::MemoryStore;
+    use actix_web::test::TestRequest;
+
+    #[tokio::test]
+    async fn test_full_buffer_drops_oldest_clicks() {
+        let store = Arc::new(MemoryStore::new());
+        let metrics = Arc::new(Metrics::default());
+        for slug in ["first", "second", "third"] {
+            let (key, value, ttl) = clicks::total_entry(slug, Some(60));
+            store.set(&key, &value, ttl).await.unwrap();
+        }
+        let analytics = Analytics::start(
+            AnalyticsConfig {
+                buffer_size: 2,
+                flush_interval: Duration::from_secs(3600),
+            },
+            store.clone(),
+            metrics.clone(),
+        );
+        // Lets the writer take its first, immediate tick before anything is queued
+        tokio::task::yield_now().await;
+
+        let req = TestRequest::default().to_http_request();
+        let link = Link::new("https://example.com".to_string());
+        for slug in ["first", "second", "third", "third"] {
+            analytics.send(ClickEvent::from_request(&req, slug, &link));
+        }
+        analytics.shutdown().await;
+
+        let slugs = ["first", "second", "third"].map(String::from);
+        assert_eq!(
+            clicks::total_clicks(store.as_ref(), &slugs).await.unwrap(),
+            vec![Some(0), Some(0), Some(2)]
+        );
+        assert_eq!(metrics.get(Counter::ClicksDropped), 2);
+    }
+}
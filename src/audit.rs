@@ -0,0 +1,277 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::web::{self, Data};
+use actix_web::{get, Error, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthenticatedKey;
+use crate::error::ApiError;
+use crate::AppState;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longest `X-Request-Id` taken over from the caller, longer ones are replaced by a generated id
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+/// Every entry lives in one list, the newest first
+const AUDIT_LOG_KEY: &str = "audit:log";
+/// Entries read from storage at once while filtering
+const PAGE_SIZE: usize = 500;
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// Id of the request, attached to the request extensions by `request_id` or on first use
+#[derive(Clone, Debug)]
+struct RequestId(String);
+
+fn is_usable_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// The id of the request, the caller's `X-Request-Id` if it is usable, otherwise a generated one
+pub fn request_id_of(req: &HttpRequest) -> String {
+    if let Some(RequestId(id)) = req.extensions().get::<RequestId>() {
+        return id.clone();
+    }
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_usable_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    req.extensions_mut().insert(RequestId(id.clone()));
+    id
+}
+
+/// Gives every request an id and echoes it in `X-Request-Id`, so callers can find their requests in the audit log
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = request_id_of(req.request());
+    let mut res = next.call(req).await?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut()
+            .insert(HeaderName::from_static("x-request-id"), value);
+    }
+    Ok(res)
+}
+
+/// What was done, recorded as `action`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    LinkCreated,
+    LinkUpdated,
+    LinkDeleted,
+    LinkDisabled,
+    LinkEnabled,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    TenantQuotaChanged,
+    ReportsDismissed,
+    SlugReserved,
+    PreviewTokenCreated,
+    BackupRestored,
+}
+
+/// Who made a request, a logged in user, an API key, both or neither for anonymous requests
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Actor {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Id of the API key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_name: Option<String>,
+}
+
+/// The caller and id of a request, recorded with everything it changes
+#[derive(Clone, Debug, Default)]
+pub struct AuditContext {
+    pub actor: Actor,
+    pub request_id: String,
+}
+
+impl AuditContext {
+    /// The caller of `req`, the API key is the one `require_api_key`, `require_admin` or `admin_key` verified
+    pub fn from_request(req: &HttpRequest, user_id: Option<&str>) -> Self {
+        let key = req.extensions().get::<AuthenticatedKey>().cloned();
+        AuditContext {
+            actor: Actor {
+                user: user_id.map(str::to_string),
+                api_key: key.as_ref().map(|key| key.id.clone()),
+                api_key_name: key.map(|key| key.key.name),
+            },
+            request_id: request_id_of(req),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    /// Slug, API key id or tenant the action was taken on
+    pub target: String,
+    pub actor: Actor,
+    /// Tenant of the link or API key, `None` outside of tenants
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub request_id: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Adds an entry to the audit log. The action already happened, so a failed write is logged with the entry
+/// instead of failing the request.
+pub async fn record(
+    state: &AppState,
+    context: &AuditContext,
+    action: AuditAction,
+    target: &str,
+    tenant: Option<&str>,
+) {
+    let entry = AuditEntry {
+        action,
+        target: target.to_string(),
+        actor: context.actor.clone(),
+        tenant: tenant.map(str::to_string),
+        request_id: context.request_id.clone(),
+        at: Utc::now(),
+    };
+    let json = serde_json::to_string(&entry).expect("audit entries are always serializable");
+    if let Err(err) = state
+        .store
+        .push_capped(AUDIT_LOG_KEY, &json, state.audit_log_max_entries)
+        .await
+    {
+        log::error!("Failed to write audit entry {}: {}", json, err);
+    }
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    action: Option<AuditAction>,
+    /// User id or API key id
+    actor: Option<String>,
+    target: Option<String>,
+    tenant: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.action.is_none_or(|action| action == entry.action)
+            && self.actor.as_ref().is_none_or(|actor| {
+                entry.actor.user.as_ref() == Some(actor)
+                    || entry.actor.api_key.as_ref() == Some(actor)
+            })
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|target| *target == entry.target)
+            && self
+                .tenant
+                .as_ref()
+                .is_none_or(|tenant| entry.tenant.as_ref() == Some(tenant))
+            && self.until.is_none_or(|until| entry.at <= until)
+    }
+}
+
+/// Audit entries matching the filters, the newest first. Admin keys of a tenant only see the entries of
+/// their tenant.
+#[get(
+    "/api/admin/audit",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn audit_log(
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut query = query.into_inner();
+    let own = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .and_then(|key| key.key.tenant.clone());
+    if let Some(own) = own {
+        if query.tenant.as_ref().is_some_and(|tenant| *tenant != own) {
+            return Err(ApiError::Forbidden {
+                message: "This API key can only see the audit log of its own tenant.".to_string(),
+            });
+        }
+        query.tenant = Some(own);
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::validation(
+            "invalid_limit",
+            format!("limit must be between 1 and {}.", MAX_LIMIT),
+        ));
+    }
+    Ok(HttpResponse::Ok().json(find_entries(&state, &query, limit).await?))
+}
+
+async fn find_entries(
+    state: &AppState,
+    query: &AuditQuery,
+    limit: usize,
+) -> Result<Vec<AuditEntry>, ApiError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let page = state
+            .store
+            .list_range(AUDIT_LOG_KEY, offset, PAGE_SIZE)
+            .await?;
+        offset += page.len();
+        for entry in page
+            .iter()
+            .filter_map(|raw| serde_json::from_str::<AuditEntry>(raw).ok())
+        {
+            // Newest first, everything after this one is older still
+            if query.since.is_some_and(|since| entry.at < since) {
+                return Ok(entries);
+            }
+            if query.matches(&entry) {
+                entries.push(entry);
+                if entries.len() == limit {
+                    return Ok(entries);
+                }
+            }
+        }
+        if page.len() < PAGE_SIZE {
+            return Ok(entries);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_request_id_is_taken_over_when_usable() {
+        let req = TestRequest::default()
+            .insert_header((REQUEST_ID_HEADER, "req-42"))
+            .to_http_request();
+        assert_eq!(request_id_of(&req), "req-42");
+
+        let req = TestRequest::default()
+            .insert_header((REQUEST_ID_HEADER, "<script>"))
+            .to_http_request();
+        let generated = request_id_of(&req);
+        assert_eq!(generated.len(), 16);
+        // The same request keeps its id
+        assert_eq!(request_id_of(&req), generated);
+    }
+}
@@ -0,0 +1,452 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::{self, Data};
+use actix_web::{delete, post, Error, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::audit::{self, AuditAction, AuditContext};
+use crate::body::JsonBody;
+use crate::error::ApiError;
+use crate::short_domains::host_of;
+use crate::storage::{StorageError, UrlStore};
+use crate::tenants;
+use crate::AppState;
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+const API_KEY_PREFIX: &str = "usk_";
+
+/// Metadata stored for every provisioned key, the key itself is only kept as a SHA-256 hash
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// Maximum number of links the key may create, `None` for unlimited. Keys of a tenant share the quota of
+    /// the tenant instead.
+    pub quota: Option<u64>,
+    /// Links the key may create per calendar month, `None` falls back to `API_KEY_MONTHLY_QUOTA`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_quota: Option<u64>,
+    #[serde(default)]
+    pub admin: bool,
+    /// May create links that never expire, admin keys always can
+    #[serde(default)]
+    pub permanent_links: bool,
+    /// Host of the domain every link created with the key is minted under
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Tenant the key belongs to, its links are tagged with it and count against the tenant's quota and usage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+impl ApiKey {
+    pub fn may_create_permanent_links(&self) -> bool {
+        self.admin || self.permanent_links
+    }
+}
+
+/// Identity attached to the request extensions once a key has been verified
+#[derive(Clone, Debug)]
+pub struct AuthenticatedKey {
+    /// SHA-256 hash of the key, doubles as its public identifier
+    pub id: String,
+    pub key: ApiKey,
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn storage_key(id: &str) -> String {
+    format!("apikey:{}", id)
+}
+
+fn generate_api_key() -> String {
+    let mut rng = SmallRng::from_os_rng();
+    let secret: String = (0..32)
+        .map(|_| rng.sample(rand::distr::Alphanumeric) as char)
+        .collect();
+    format!("{}{}", API_KEY_PREFIX, secret)
+}
+
+/// Provisions a new key with `metadata`, the plaintext key is returned once and never stored
+pub async fn create_api_key(
+    store: &dyn UrlStore,
+    metadata: ApiKey,
+) -> Result<(String, AuthenticatedKey), StorageError> {
+    let key = generate_api_key();
+    let id = hash_api_key(&key);
+    let record = serde_json::to_string(&metadata).expect("API key metadata is always serializable");
+    store.set(&storage_key(&id), &record, None).await?;
+    Ok((key, AuthenticatedKey { id, key: metadata }))
+}
+
+/// The stored metadata of the key `id`, `None` if there is no such key
+pub async fn load_api_key(store: &dyn UrlStore, id: &str) -> Result<Option<ApiKey>, StorageError> {
+    let record = store.get(&storage_key(id)).await?;
+    Ok(record.and_then(|record| serde_json::from_str(&record).ok()))
+}
+
+pub async fn revoke_api_key(store: &dyn UrlStore, id: &str) -> Result<bool, StorageError> {
+    store.delete(&storage_key(id)).await
+}
+
+pub async fn lookup_api_key(
+    store: &dyn UrlStore,
+    key: &str,
+) -> Result<Option<AuthenticatedKey>, StorageError> {
+    let id = hash_api_key(key);
+    Ok(load_api_key(store, &id)
+        .await?
+        .map(|key| AuthenticatedKey { id, key }))
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AuthConfig {
+    /// Reject requests to protected routes that carry no key
+    pub require_api_key: bool,
+    /// Bootstrap admin key from the environment, used to provision the first stored keys
+    pub admin_api_key: Option<String>,
+    /// Monthly link quota of non-admin keys created without their own
+    pub default_monthly_quota: Option<u64>,
+}
+
+enum AuthOutcome {
+    Anonymous,
+    Authenticated(AuthenticatedKey),
+    Rejected(ApiError),
+}
+
+async fn authenticate(req: &HttpRequest) -> AuthOutcome {
+    let Some(state) = req.app_data::<Data<AppState>>() else {
+        return AuthOutcome::Anonymous;
+    };
+    let Some(key) = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return AuthOutcome::Anonymous;
+    };
+
+    // Digests are compared, so the time taken doesn't tell how much of the bootstrap key a guess got right
+    let is_bootstrap = state
+        .auth
+        .admin_api_key
+        .as_deref()
+        .is_some_and(|admin_key| hash_api_key(admin_key) == hash_api_key(key));
+    if is_bootstrap {
+        return AuthOutcome::Authenticated(AuthenticatedKey {
+            id: "bootstrap".to_string(),
+            key: ApiKey {
+                name: "bootstrap admin".to_string(),
+                created_at: DateTime::UNIX_EPOCH,
+                quota: None,
+                monthly_quota: None,
+                admin: true,
+                permanent_links: true,
+                domain: None,
+                tenant: None,
+            },
+        });
+    }
+
+    match lookup_api_key(state.store.as_ref(), key).await {
+        Ok(Some(authenticated)) => AuthOutcome::Authenticated(authenticated),
+        Ok(None) => AuthOutcome::Rejected(ApiError::Unauthorized {
+            code: "invalid_api_key",
+            message: format!("The key passed in {} is not valid.", API_KEY_HEADER),
+        }),
+        Err(err) => AuthOutcome::Rejected(err.into()),
+    }
+}
+
+pub fn missing_key() -> ApiError {
+    ApiError::Unauthorized {
+        code: "missing_api_key",
+        message: format!("Pass an API key in the {} header.", API_KEY_HEADER),
+    }
+}
+
+/// Verifies `X-Api-Key` when present, requests without a key only pass when keys are not required
+pub async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let require_api_key = req
+        .app_data::<Data<AppState>>()
+        .is_some_and(|state| state.auth.require_api_key);
+
+    match authenticate(req.request()).await {
+        AuthOutcome::Authenticated(key) => {
+            req.extensions_mut().insert(key);
+        }
+        AuthOutcome::Anonymous if !require_api_key => {}
+        AuthOutcome::Anonymous => {
+            return Ok(req
+                .into_response(missing_key().error_response())
+                .map_into_right_body())
+        }
+        AuthOutcome::Rejected(err) => {
+            return Ok(req
+                .into_response(err.error_response())
+                .map_into_right_body())
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Only lets through requests authenticated with an admin key
+pub async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let err = match authenticate(req.request()).await {
+        AuthOutcome::Authenticated(key) if key.key.admin => {
+            req.extensions_mut().insert(key);
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body);
+        }
+        AuthOutcome::Authenticated(_) => ApiError::Forbidden {
+            message: "This endpoint requires an admin API key.".to_string(),
+        },
+        AuthOutcome::Anonymous => missing_key(),
+        AuthOutcome::Rejected(err) => err,
+    };
+    Ok(req
+        .into_response(err.error_response())
+        .map_into_right_body())
+}
+
+/// Rejects admin keys of a tenant for endpoints reaching past their tenant, `action` completes "Only admin
+/// keys without a tenant can ..."
+pub(crate) fn ensure_global_admin(req: &HttpRequest, action: &str) -> Result<(), ApiError> {
+    let tenant = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .and_then(|key| key.key.tenant.clone());
+    if tenant.is_some() {
+        return Err(ApiError::Forbidden {
+            message: format!("Only admin keys without a tenant can {}.", action),
+        });
+    }
+    Ok(())
+}
+
+/// Admin key passed with a request to a route without `require_admin`, `None` for anonymous and non-admin keys.
+/// Like `require_admin`, it attaches the key to the request extensions.
+pub async fn admin_key(req: &HttpRequest) -> Result<Option<AuthenticatedKey>, ApiError> {
+    match authenticate(req).await {
+        AuthOutcome::Authenticated(key) if key.key.admin => {
+            req.extensions_mut().insert(key.clone());
+            Ok(Some(key))
+        }
+        AuthOutcome::Authenticated(_) | AuthOutcome::Anonymous => Ok(None),
+        AuthOutcome::Rejected(err) => Err(err),
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateApiKeyRequest {
+    name: String,
+    quota: Option<u64>,
+    monthly_quota: Option<u64>,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    permanent_links: bool,
+    /// Binds the key to one of the domains, as host or base URL
+    domain: Option<String>,
+    tenant: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CreateApiKeyResponse {
+    id: String,
+    /// Only ever returned here, it can't be recovered later
+    api_key: String,
+    #[serde(flatten)]
+    metadata: ApiKey,
+}
+
+#[post(
+    "/api/admin/api-keys",
+    wrap = "actix_web::middleware::from_fn(require_admin)"
+)]
+async fn create_key(
+    req: HttpRequest,
+    body: JsonBody<CreateApiKeyRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let CreateApiKeyRequest {
+        name,
+        quota,
+        monthly_quota,
+        admin,
+        permanent_links,
+        domain,
+        tenant,
+    } = body.into_inner();
+    // Stored as the host, or as the host of `SHORTENER_DOMAIN` for keys bound to the primary domain
+    let domain = match domain {
+        Some(domain) => Some(
+            state
+                .short_domains
+                .lookup(&domain)?
+                .unwrap_or_else(|| host_of(state.short_domains.primary()).unwrap_or_default()),
+        ),
+        None => None,
+    };
+    // Admin keys of a tenant can only provision keys of their own tenant
+    let own = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .and_then(|key| key.key.tenant.clone());
+    if own.is_some() && tenant != own {
+        return Err(ApiError::Forbidden {
+            message: "This API key can only create keys of its own tenant.".to_string(),
+        });
+    }
+    if tenant.is_some() && quota.is_some() {
+        return Err(ApiError::validation(
+            "tenant_key_quota",
+            "Keys of a tenant share the quota of the tenant, set it with PUT /api/admin/tenants/{tenant}/quota.",
+        ));
+    }
+    if let Some(tenant) = &tenant {
+        tenants::validate_tenant(tenant)?;
+        tenants::start_counters(state.store.as_ref(), tenant).await?;
+    }
+    let metadata = ApiKey {
+        name,
+        created_at: Utc::now(),
+        quota,
+        monthly_quota,
+        admin,
+        permanent_links,
+        domain,
+        tenant,
+    };
+    let (api_key, created) = create_api_key(state.store.as_ref(), metadata).await?;
+    audit::record(
+        &state,
+        &AuditContext::from_request(&req, None),
+        AuditAction::ApiKeyCreated,
+        &created.id,
+        created.key.tenant.as_deref(),
+    )
+    .await;
+    if let Some(creator) = req.extensions().get::<AuthenticatedKey>() {
+        log::info!(
+            "API key '{}' ({}) created by '{}' ({})",
+            created.key.name,
+            created.id,
+            creator.key.name,
+            creator.id
+        );
+    }
+    Ok(HttpResponse::Created().json(CreateApiKeyResponse {
+        id: created.id,
+        api_key,
+        metadata: created.key,
+    }))
+}
+
+#[delete(
+    "/api/admin/api-keys/{id}",
+    wrap = "actix_web::middleware::from_fn(require_admin)"
+)]
+async fn revoke_key(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let not_found = || ApiError::NotFound {
+        message: format!("There is no API key '{}'.", id),
+    };
+    let Some(record) = load_api_key(state.store.as_ref(), &id).await? else {
+        return Err(not_found());
+    };
+    // Admin keys of a tenant can only revoke keys of their own tenant
+    let own = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .and_then(|key| key.key.tenant.clone());
+    if own.is_some() && record.tenant != own {
+        return Err(ApiError::Forbidden {
+            message: "This API key can only revoke keys of its own tenant.".to_string(),
+        });
+    }
+    if !revoke_api_key(state.store.as_ref(), &id).await? {
+        return Err(not_found());
+    }
+    audit::record(
+        &state,
+        &AuditContext::from_request(&req, None),
+        AuditAction::ApiKeyRevoked,
+        &id,
+        record.tenant.as_deref(),
+    )
+    .await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    #[test]
+    fn test_hash_api_key_is_stable_hex_sha256() {
+        let hash = hash_api_key("usk_test");
+
+        assert_eq!(hash.len(), 64);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hash, hash_api_key("usk_test"));
+        assert_ne!(hash, hash_api_key("usk_other"));
+    }
+
+    #[tokio::test]
+    async fn test_create_lookup_and_revoke_api_key() {
+        let store = MemoryStore::new();
+
+        let metadata = ApiKey {
+            name: "ci".to_string(),
+            created_at: Utc::now(),
+            quota: Some(100),
+            monthly_quota: None,
+            admin: false,
+            permanent_links: true,
+            domain: None,
+            tenant: Some("acme".to_string()),
+        };
+        let (key, created) = create_api_key(&store, metadata).await.unwrap();
+        assert!(key.starts_with(API_KEY_PREFIX));
+        // Only the hash is persisted
+        assert_eq!(store.get(&storage_key(&key)).await.unwrap(), None);
+
+        let found = lookup_api_key(&store, &key).await.unwrap().unwrap();
+        assert_eq!(found.id, created.id);
+        assert_eq!(found.key.name, "ci");
+        assert_eq!(found.key.quota, Some(100));
+        assert!(!found.key.admin);
+        assert!(found.key.may_create_permanent_links());
+        assert_eq!(found.key.tenant.as_deref(), Some("acme"));
+
+        assert!(revoke_api_key(&store, &created.id).await.unwrap());
+        assert!(lookup_api_key(&store, &key).await.unwrap().is_none());
+    }
+}
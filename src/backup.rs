@@ -0,0 +1,394 @@
+use actix_web::http::header;
+use actix_web::web::{self, Bytes, BytesMut, Data};
+use actix_web::{get, post, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::audit::{self, AuditAction, AuditContext};
+use crate::auth::ensure_global_admin;
+use crate::error::ApiError;
+use crate::expiry_events;
+use crate::link::Link;
+use crate::ownership::{
+    counter_entries, counter_keys, created_at, index_owned_links, links_by_created_key,
+};
+use crate::tags;
+use crate::AppState;
+
+/// Keys looked at per `SCAN` page, each page becomes one chunk of the backup
+const BACKUP_PAGE_SIZE: usize = 500;
+/// Records stored per round trip when restoring
+const RESTORE_BATCH_SIZE: usize = 500;
+
+/// One line of a backup, the link record under `key` as it is stored
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct BackupRecord {
+    key: String,
+    record: String,
+    /// Absolute, so a backup restored a week later doesn't give its links another full lifetime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    /// When the owner created the link, left out for links without an owner or from before it was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+    /// Records stored next to the link by their keys: click and split test counters and metadata. Counters
+    /// missing from older backups start over when restoring.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    counters: BTreeMap<String, String>,
+}
+
+impl BackupRecord {
+    /// Rejects keys that aren't link keys and counters that don't belong to the link, a backup can't write
+    /// any other record
+    fn validate(&self) -> Result<(), String> {
+        if self.key.is_empty() || self.key.contains(':') {
+            return Err(format!("'{}' is not the key of a link", self.key));
+        }
+        let allowed = counter_keys(&self.key, &Link::decode(&self.record));
+        match self.counters.keys().find(|key| !allowed.contains(key)) {
+            Some(key) => Err(format!(
+                "'{}' is not a counter of the link '{}'",
+                key, self.key
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The records to store next to the restored link, the link's fresh counters with the values of the
+    /// backup
+    fn counter_entries(
+        &self,
+        link: &Link,
+        ttl: Option<usize>,
+    ) -> Vec<(String, String, Option<usize>)> {
+        let mut entries = counter_entries(&self.key, link.max_clicks, link.variants.len(), ttl);
+        for (key, value) in &self.counters {
+            match entries.iter_mut().find(|(fresh, _, _)| fresh == key) {
+                Some(entry) => entry.1 = value.clone(),
+                None => entries.push((key.clone(), value.clone(), ttl)),
+            }
+        }
+        entries
+    }
+}
+
+/// Outcome of a restore
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    /// Keys taken already, the stored link is kept
+    pub existing: usize,
+    /// Links that expired since the backup was taken
+    pub expired: usize,
+}
+
+/// The link records of one `SCAN` page as JSON lines, and the cursor of the next page
+async fn backup_page(state: &AppState, cursor: u64) -> Result<(String, u64, usize), ApiError> {
+    let (next, keys) = state.store.scan_keys(cursor, BACKUP_PAGE_SIZE).await?;
+    // Internal records share the keyspace, link keys never contain ':'
+    let keys: Vec<String> = keys.into_iter().filter(|key| !key.contains(':')).collect();
+    let records = state.store.get_many(&keys).await?;
+    let ttls = state.store.ttl_many(&keys).await?;
+    let now = Utc::now();
+    let links: Vec<(String, String, Link, Option<usize>)> = keys
+        .into_iter()
+        .zip(records)
+        .zip(ttls)
+        .filter_map(|((key, record), ttl)| {
+            let record = record?;
+            let link = Link::decode(&record);
+            Some((key, record, link, ttl))
+        })
+        .collect();
+
+    // The records next to the links of the page are read in one round trip
+    let counter_keys: Vec<Vec<String>> = links
+        .iter()
+        .map(|(key, _, link, _)| counter_keys(key, link))
+        .collect();
+    let mut values = state
+        .store
+        .get_many(&counter_keys.concat())
+        .await?
+        .into_iter();
+    let created = created_times(state, &links).await?;
+
+    let mut lines = String::new();
+    let mut count = 0;
+    for (((key, record, _, ttl), keys), created_at) in
+        links.into_iter().zip(counter_keys).zip(created)
+    {
+        let counters = keys
+            .into_iter()
+            .zip(values.by_ref())
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        let line = BackupRecord {
+            key,
+            record,
+            expires_at: ttl.map(|ttl| now + Duration::seconds(ttl as i64)),
+            created_at,
+            counters,
+        };
+        lines.push_str(&serde_json::to_string(&line).expect("serializable"));
+        lines.push('\n');
+        count += 1;
+    }
+    Ok((lines, next, count))
+}
+
+/// When the owners of `links` created them, going by the owners' indexes
+async fn created_times(
+    state: &AppState,
+    links: &[(String, String, Link, Option<usize>)],
+) -> Result<Vec<Option<DateTime<Utc>>>, ApiError> {
+    let mut by_owner: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (index, (_, _, link, _)) in links.iter().enumerate() {
+        if let Some(owner) = &link.owner {
+            by_owner.entry(owner).or_default().push(index);
+        }
+    }
+    let mut created = vec![None; links.len()];
+    for (owner, indexes) in by_owner {
+        let slugs: Vec<String> = indexes
+            .iter()
+            .map(|index| links[*index].0.clone())
+            .collect();
+        let scores = state
+            .store
+            .scores(&links_by_created_key(owner), &slugs)
+            .await?;
+        for (index, score) in indexes.into_iter().zip(scores) {
+            created[index] = score.and_then(created_at);
+        }
+    }
+    Ok(created)
+}
+
+/// Writes every link record to `out` as gzip compressed JSON lines, one `SCAN` page at a time.
+/// Returns the number of links written.
+pub async fn write_backup(state: &AppState, out: impl Write) -> Result<usize, ApiError> {
+    let mut encoder = GzEncoder::new(out, Compression::default());
+    let (mut cursor, mut total) = (0, 0);
+    loop {
+        let (lines, next, count) = backup_page(state, cursor).await?;
+        encoder.write_all(lines.as_bytes()).map_err(write_failed)?;
+        total += count;
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    encoder.finish().map_err(write_failed)?;
+    Ok(total)
+}
+
+fn write_failed(err: std::io::Error) -> ApiError {
+    ApiError::Internal(format!("Failed to write the backup: {}", err))
+}
+
+/// Stores the links of a backup written by `write_backup`. Links whose key is taken are left alone, so a
+/// restore can be repeated, and links that expired in the meantime are skipped.
+pub async fn restore(state: &AppState, backup: impl Read) -> Result<RestoreSummary, ApiError> {
+    let mut summary = RestoreSummary::default();
+    let mut batch = Vec::with_capacity(RESTORE_BATCH_SIZE);
+    let lines = BufReader::new(GzDecoder::new(backup)).lines();
+    for (index, line) in lines.enumerate() {
+        let invalid = |message: String| {
+            ApiError::validation(
+                "invalid_backup",
+                format!(
+                    "Line {} of the backup: {}. The links before it were restored.",
+                    index + 1,
+                    message
+                ),
+            )
+        };
+        let line = line.map_err(|err| invalid(err.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: BackupRecord =
+            serde_json::from_str(&line).map_err(|err| invalid(err.to_string()))?;
+        record.validate().map_err(invalid)?;
+        batch.push(record);
+        if batch.len() == RESTORE_BATCH_SIZE {
+            restore_batch(state, std::mem::take(&mut batch), &mut summary).await?;
+        }
+    }
+    restore_batch(state, batch, &mut summary).await?;
+    Ok(summary)
+}
+
+async fn restore_batch(
+    state: &AppState,
+    batch: Vec<BackupRecord>,
+    summary: &mut RestoreSummary,
+) -> Result<(), ApiError> {
+    let now = Utc::now();
+    let mut lines = Vec::with_capacity(batch.len());
+    let mut entries = Vec::with_capacity(batch.len());
+    for line in batch {
+        let ttl = match line.expires_at {
+            Some(expires_at) if expires_at <= now => {
+                summary.expired += 1;
+                continue;
+            }
+            Some(expires_at) => Some((expires_at - now).num_seconds().max(1) as usize),
+            None => None,
+        };
+        entries.push((line.key.clone(), line.record.clone(), ttl));
+        lines.push(line);
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let stored = state.store.set_many(&entries).await?;
+
+    // Counters go with the links that were restored, a missing `max_clicks` counter would use the link up
+    let mut restored = Vec::new();
+    let mut counters = Vec::new();
+    for ((line, (_, _, ttl)), stored) in lines.into_iter().zip(entries).zip(stored) {
+        if stored {
+            let link = Link::decode(&line.record);
+            counters.extend(line.counter_entries(&link, ttl));
+            counters.extend(expiry_events::indexes_entry(
+                &line.key,
+                link.owner.as_deref(),
+                &link.tags,
+                ttl,
+            ));
+            restored.push((line.key, link, line.created_at));
+        } else {
+            summary.existing += 1;
+        }
+    }
+    state.store.set_many(&counters).await?;
+    summary.restored += restored.len();
+    // Restored links show up in the listings of their owners and tags again, links whose creation time
+    // is unknown sort as the oldest
+    let mut by_owner: BTreeMap<&str, Vec<(String, DateTime<Utc>)>> = BTreeMap::new();
+    for (key, link, created_at) in &restored {
+        if let Some(owner) = &link.owner {
+            by_owner
+                .entry(owner)
+                .or_default()
+                .push((key.clone(), created_at.unwrap_or(DateTime::UNIX_EPOCH)));
+        }
+    }
+    for (owner, links) in by_owner {
+        index_owned_links(state, owner, &links).await;
+    }
+    tags::index_links(
+        state,
+        restored
+            .iter()
+            .map(|(key, link, _)| (key.as_str(), link.tags.as_slice())),
+    )
+    .await;
+    Ok(())
+}
+
+/// Streams every link record as gzip compressed JSON lines, the format `POST /api/admin/restore` takes.
+/// The keyspace is read one page at a time, so the backup is never held in memory as a whole.
+#[get(
+    "/api/admin/backup",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn backup_links(req: HttpRequest, state: Data<AppState>) -> Result<HttpResponse, ApiError> {
+    // Backups hold the links of every tenant, so admin keys of a tenant can neither take nor restore them
+    ensure_global_admin(&req, "back up and restore links")?;
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let pages = stream::try_unfold(Some((0, encoder)), move |page| {
+        let state = state.clone();
+        async move {
+            let Some((cursor, mut encoder)) = page else {
+                return Ok(None);
+            };
+            let (lines, next, _) = backup_page(&state, cursor).await?;
+            encoder.write_all(lines.as_bytes()).map_err(write_failed)?;
+            if next == 0 {
+                let chunk = encoder.finish().map_err(write_failed)?;
+                return Ok(Some((Bytes::from(chunk), None)));
+            }
+            // Hands out what was compressed so far, the encoder keeps its state for the next page
+            encoder.flush().map_err(write_failed)?;
+            let chunk = std::mem::take(encoder.get_mut());
+            Ok::<_, ApiError>(Some((Bytes::from(chunk), Some((next, encoder)))))
+        }
+    })
+    // Headers are already sent, all that is left is cutting the response short
+    .map_err(|err| {
+        log::error!("Backup failed: {}", err);
+        std::io::Error::other(err.to_string())
+    })
+    .try_filter(|chunk| std::future::ready(!chunk.is_empty()));
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/gzip")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"links-backup.jsonl.gz\"",
+        ))
+        .streaming(pages))
+}
+
+/// Restores the links of a backup taken with `GET /api/admin/backup`, sent as the request body
+#[post(
+    "/api/admin/restore",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn restore_links(
+    req: HttpRequest,
+    mut payload: web::Payload,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    ensure_global_admin(&req, "back up and restore links")?;
+    // Backups are far larger than `MAX_BODY_BYTES`, the body is only limited by admins sending it
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| ApiError::InvalidBody {
+            message: err.to_string(),
+            field: None,
+        })?;
+        body.extend_from_slice(&chunk);
+    }
+    let summary = restore(&state, body.as_ref()).await?;
+    audit::record(
+        &state,
+        &AuditContext::from_request(&req, None),
+        AuditAction::BackupRestored,
+        &format!("{} links", summary.restored),
+        None,
+    )
+    .await;
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_without_expiry_leave_it_out() {
+        let record = BackupRecord {
+            key: "launch".to_string(),
+            record: "{\"url\":\"https://example.com\"}".to_string(),
+            expires_at: None,
+            created_at: None,
+            counters: BTreeMap::new(),
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            line,
+            r#"{"key":"launch","record":"{\"url\":\"https://example.com\"}"}"#
+        );
+        assert_eq!(serde_json::from_str::<BackupRecord>(&line).unwrap(), record);
+    }
+}
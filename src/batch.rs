@@ -0,0 +1,546 @@
+use actix_web::web::Data;
+use actix_web::{post, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::Serialize;
+use url::Url;
+
+use crate::body::JsonBody;
+use crate::dedup;
+use crate::error::{ApiError, ErrorBody};
+use crate::link::{Link, Schedule};
+use crate::metadata::{metadata_key, LinkMetadata};
+use crate::metrics::Counter;
+use crate::ownership::record_owned_links;
+use crate::short_domains::{link_key, split_key};
+use crate::storage::StorageError;
+use crate::tags;
+use crate::url_shortener::validate_alias;
+use crate::usage;
+use crate::users::MaybeUser;
+use crate::{
+    audit_created, prepare_link, shared_link, AppState, Creator, PreparedLink, UrlShortenData,
+    UrlShortenOptions,
+};
+
+/// Outcome for one URL of a batch, results are returned in request order
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchItemResult {
+    Shortened(UrlShortenData),
+    Failed(ErrorBody),
+}
+
+#[post(
+    "/api/shorten-batch",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_api_key)"
+)]
+async fn shorten_batch(
+    req: HttpRequest,
+    req_body: JsonBody<Vec<UrlShortenOptions>>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let items = req_body.into_inner();
+    if items.is_empty() || items.len() > state.max_batch_size {
+        return Err(ApiError::validation(
+            "invalid_batch_size",
+            format!(
+                "A batch must contain between 1 and {} URLs, got {}.",
+                state.max_batch_size,
+                items.len()
+            ),
+        ));
+    }
+
+    let creator = Creator::from_request(&req, user.0.map(|user| user.id));
+    let results = shorten_all(items, &creator, &state)
+        .await
+        .inspect_err(|_| state.metrics.incr(Counter::StorageErrors))?;
+    Ok(HttpResponse::Ok().json(results))
+}
+
+/// Shortens every item of the batch. Each collision resolution round stores all slugs that are
+/// still pending with a single pipelined `set_many`, so a batch costs one round trip in the common case.
+async fn shorten_all(
+    items: Vec<UrlShortenOptions>,
+    creator: &Creator,
+    state: &AppState,
+) -> Result<Vec<BatchItemResult>, StorageError> {
+    let now = Utc::now();
+    let mut results: Vec<Option<BatchItemResult>> = Vec::with_capacity(items.len());
+    // Items waiting to be stored, with the storage key of their candidate slug
+    let mut pending: Vec<(usize, String, PreparedLink)> = Vec::new();
+
+    for (index, options) in items.into_iter().enumerate() {
+        state.metrics.incr(Counter::ShortenRequests);
+        let prepared = match prepare_link(options, creator, state, now).await {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                results.push(Some(BatchItemResult::Failed(err.body())));
+                continue;
+            }
+        };
+        let slug = match &prepared.alias {
+            Some(alias) => match validate_alias(alias, &state.settings.get().reserved_slugs) {
+                Ok(()) => alias.clone(),
+                Err(err) => {
+                    let err = ApiError::InvalidAlias {
+                        alias: alias.clone(),
+                        message: err.to_string(),
+                    };
+                    results.push(Some(BatchItemResult::Failed(err.body())));
+                    continue;
+                }
+            },
+            None => state.slugs.next_slug(&prepared.url, 1).await?,
+        };
+        results.push(None);
+        pending.push((index, prepared.key(&slug), prepared));
+    }
+
+    // Links that were shortened before are answered from the reverse index instead of minting a new slug
+    let candidates: Vec<usize> = (0..pending.len())
+        .filter(|&i| pending[i].2.deduplicate && pending[i].2.alias.is_none())
+        .collect();
+    if !candidates.is_empty() {
+        let links: Vec<&str> = candidates
+            .iter()
+            .map(|&i| pending[i].2.link.as_str())
+            .collect();
+        let found = dedup::find_existing(state.store.as_ref(), &links).await?;
+        let mut reused = vec![false; pending.len()];
+        for (&i, existing) in candidates.iter().zip(found) {
+            if let Some(existing) = existing {
+                results[pending[i].0] = Some(BatchItemResult::Shortened(UrlShortenData {
+                    short_url: state.short_url(&existing.slug),
+                    expires_at: Some(existing.expires_at),
+                    collisions: None,
+                    warning: None,
+                }));
+                reused[i] = true;
+            }
+        }
+        let mut reused = reused.into_iter();
+        pending.retain(|_| !reused.next().unwrap_or(false));
+    }
+
+    // Counted against the tenant and the key's quota once it is clear the item needs a new link
+    let mut claimed = Vec::with_capacity(pending.len());
+    for (index, key, prepared) in pending {
+        match usage::claim_link(state, creator).await {
+            Ok(()) => claimed.push((index, key, prepared)),
+            Err(err) => results[index] = Some(BatchItemResult::Failed(err.body())),
+        }
+    }
+    pending = claimed;
+
+    let mut index_entries = Vec::new();
+    let mut counters = Vec::new();
+    let mut created = Vec::new();
+    let mut events = Vec::new();
+    let mut tagged = Vec::new();
+    let mut attempts = 0;
+    while !pending.is_empty() {
+        attempts += 1;
+        // Generated slugs that hit a reserved word or the slug filter are never written and retried like
+        // collisions
+        let settings = state.settings.get();
+        let writable: Vec<bool> = pending
+            .iter()
+            .map(|(_, key, prepared)| {
+                let slug = split_key(key).0;
+                !settings.reserved_slugs.is_reserved(slug)
+                    && (prepared.alias.is_some() || !state.filters_slug(slug))
+            })
+            .collect();
+        let entries: Vec<(String, String, Option<usize>)> = pending
+            .iter()
+            .zip(&writable)
+            .filter(|(_, writable)| **writable)
+            .map(|((_, slug, prepared), _)| (slug.clone(), prepared.link.clone(), prepared.ttl))
+            .collect();
+        let mut stored = state.store.set_many(&entries).await?.into_iter();
+
+        let mut retry = Vec::new();
+        for ((index, slug, prepared), writable) in pending.into_iter().zip(writable) {
+            let stored = writable && stored.next().unwrap_or(false);
+            // Generated slugs of strategies sharing slugs may already hold this very link
+            let shared =
+                if writable && !stored && prepared.alias.is_none() && state.slugs.shares_slugs() {
+                    shared_link(state, std::slice::from_ref(&slug), &prepared.link).await?
+                } else {
+                    None
+                };
+            let result = if stored {
+                created.push(slug.clone());
+                events.push(prepared.created_event(&slug));
+                state
+                    .metadata
+                    .fetch_later(&slug, &prepared.url, prepared.ttl);
+                if !prepared.tags.is_empty() {
+                    tagged.push((slug.clone(), prepared.tags.clone()));
+                }
+                counters.extend(prepared.counter_entries(&slug));
+                if prepared.alias.is_none() {
+                    index_entries.extend(prepared.dedup_entry(&slug));
+                }
+                BatchItemResult::Shortened(UrlShortenData {
+                    short_url: state.short_url(&slug),
+                    expires_at: prepared.expires_at,
+                    collisions: state.collision_report(attempts),
+                    warning: None,
+                })
+            } else if let Some(alias) = prepared.alias {
+                usage::release_link(state, creator).await;
+                BatchItemResult::Failed(ApiError::AliasTaken { alias }.body())
+            } else if let Some(shared) = shared {
+                usage::release_link(state, creator).await;
+                BatchItemResult::Shortened(shared)
+            } else if attempts < state.collision_attempts() {
+                state.metrics.incr(Counter::ShortenCollisions);
+                let slug = state.slugs.next_slug(&prepared.url, attempts + 1).await?;
+                let slug =
+                    state
+                        .collision_policy
+                        .candidate(slug, attempts + 1, state.slug_alphabet);
+                retry.push((index, prepared.key(&slug), prepared));
+                continue;
+            } else {
+                usage::release_link(state, creator).await;
+                state.metrics.incr(Counter::ShortenCollisions);
+                state.metrics.incr(Counter::ShortenFailures);
+                BatchItemResult::Failed(
+                    ApiError::Collision {
+                        attempts: state.collision_attempts(),
+                        url: prepared.url,
+                    }
+                    .body(),
+                )
+            };
+            results[index] = Some(result);
+        }
+        pending = retry;
+    }
+
+    // Without their counters limited links would resolve as used up, so this one has to succeed
+    if !counters.is_empty() {
+        state.store.set_many(&counters).await?;
+    }
+    for event in events {
+        state.publish(event);
+    }
+    for slug in &created {
+        audit_created(state, creator, slug).await;
+    }
+    if let Some(owner) = &creator.owner {
+        record_owned_links(state, owner, &created).await;
+    }
+    tags::index_links(
+        state,
+        tagged
+            .iter()
+            .map(|(slug, tags)| (slug.as_str(), tags.as_slice())),
+    )
+    .await;
+    if !index_entries.is_empty() {
+        // The links are stored, a missing index entry only means the next request mints a new slug
+        if let Err(err) = state.store.set_many(&index_entries).await {
+            state.metrics.incr(Counter::StorageErrors);
+            log::warn!("Failed to index short URLs for deduplication: {}", err);
+        }
+    }
+
+    Ok(results
+        .into_iter()
+        .map(|result| result.expect("every batch item is resolved"))
+        .collect())
+}
+
+/// What a visitor of a link in a batch resolve would get
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LinkStatus {
+    Active,
+    /// The destination is only revealed to visitors who know the password
+    PasswordProtected,
+    Disabled,
+    Suspended,
+    /// Scheduled to go live at `active_from`
+    Scheduled,
+    /// Unknown, expired, past its `active_until` or reserved without a destination yet
+    NotFound,
+}
+
+#[derive(Serialize)]
+struct ResolvedLink {
+    /// The item as requested
+    slug: String,
+    status: LinkStatus,
+    /// Where an active link redirects visitors without a device target or split test variant
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    /// Title, description and icon of the destination of an active link, once they were fetched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<LinkMetadata>,
+}
+
+/// Expands up to `MAX_BATCH_SIZE` slugs or short URLs with a single `MGET`, for services that render many
+/// links at once. Nothing is counted as a click.
+#[post(
+    "/api/resolve-batch",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_api_key)"
+)]
+async fn resolve_batch(
+    req_body: JsonBody<Vec<String>>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let items = req_body.into_inner();
+    if items.is_empty() || items.len() > state.max_batch_size {
+        return Err(ApiError::validation(
+            "invalid_batch_size",
+            format!(
+                "A batch must contain between 1 and {} slugs, got {}.",
+                state.max_batch_size,
+                items.len()
+            ),
+        ));
+    }
+
+    let keys: Vec<Option<String>> = items.iter().map(|item| resolve_key(&state, item)).collect();
+    // The links and their metadata are read together
+    let links: Vec<String> = keys.iter().flatten().cloned().collect();
+    let lookups: Vec<String> = links
+        .iter()
+        .cloned()
+        .chain(links.iter().map(|key| metadata_key(key)))
+        .collect();
+    let mut records = state
+        .store
+        .get_many(&lookups)
+        .await
+        .inspect_err(|_| state.metrics.incr(Counter::StorageErrors))?;
+    let mut metadata = records.split_off(links.len()).into_iter();
+    let now = Utc::now();
+    let mut records = records.into_iter();
+    let results: Vec<ResolvedLink> = items
+        .into_iter()
+        .zip(keys)
+        .map(|(slug, key)| {
+            let (record, metadata) = match key {
+                Some(_) => (records.next().flatten(), metadata.next().flatten()),
+                None => (None, None),
+            };
+            let link = record
+                .map(|record| Link::decode(&record))
+                .filter(|link| !link.reserved && link.schedule(now) != Schedule::Ended);
+            let (status, url) = match link {
+                None => (LinkStatus::NotFound, None),
+                Some(link) if link.suspension.is_some() => (LinkStatus::Suspended, None),
+                Some(link) if link.disabled => (LinkStatus::Disabled, None),
+                Some(link) if link.schedule(now) == Schedule::Pending => {
+                    (LinkStatus::Scheduled, None)
+                }
+                Some(link) if link.password_hash.is_some() => (LinkStatus::PasswordProtected, None),
+                Some(link) => (LinkStatus::Active, Some(link.destination("", None, None))),
+            };
+            ResolvedLink {
+                slug,
+                metadata: metadata
+                    .filter(|_| status == LinkStatus::Active)
+                    .and_then(|raw| serde_json::from_str(&raw).ok()),
+                status,
+                url,
+            }
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(results))
+}
+
+/// Storage key of a batch resolve item, a slug of the primary domain or a short URL of any domain.
+/// `None` for items that can't name a link.
+fn resolve_key(state: &AppState, item: &str) -> Option<String> {
+    let (slug, host) = match Url::parse(item.trim()) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+            let host = state.short_domains.lookup(url.host_str()?).ok()?;
+            (url.path().trim_start_matches('/').to_string(), host)
+        }
+        _ => (item.trim().to_string(), None),
+    };
+    let slug = state.slug(slug);
+    // Internal records share the keyspace, slugs never contain ':'
+    if slug.is_empty() || slug.contains([':', '@', '/']) {
+        return None;
+    }
+    Some(link_key(&slug, host.as_deref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analytics::Analytics;
+    use crate::auth::AuthConfig;
+    use crate::config::AppConfig;
+    use crate::deadlinks::{DeadLinkChecker, DeadLinkConfig};
+    use crate::domains::DomainLists;
+    use crate::expiration::TtlBounds;
+    use crate::live_config::LiveSettings;
+    use crate::memory::MemoryStore;
+    use crate::metrics::Metrics;
+    use crate::ratelimit::RateLimitConfig;
+    use crate::reachability::ReachabilityCheck;
+    use crate::short_domains::ShortDomains;
+    use crate::storage::UrlStore;
+    use crate::url_shortener::{CollisionPolicy, HashSlugs, SlugAlphabet};
+    use crate::users::SessionTokens;
+    use crate::validation::DEFAULT_MAX_URL_LENGTH;
+    use actix_web::http::StatusCode;
+    use std::sync::Arc;
+
+    fn test_state(store: Arc<dyn UrlStore>) -> AppState {
+        let metrics = Arc::new(Metrics::default());
+        AppState {
+            analytics: Analytics::start(
+                AppConfig::default().analytics,
+                store.clone(),
+                metrics.clone(),
+            ),
+            events: None,
+            audit_log_max_entries: 100,
+            preview_token_ttl_seconds: 60,
+            report_rate_limit: 0,
+            request_timeout: None,
+            link_cache: Default::default(),
+            short_domains: ShortDomains::new("https://short.me".to_string(), &[]),
+            store,
+            default_ttl_seconds: 3600,
+            ttl_bounds: TtlBounds {
+                min_seconds: 60,
+                max_seconds: 86400,
+            },
+            max_collision_attempts: 3,
+            collision_policy: CollisionPolicy::Retry,
+            slug_alphabet: SlugAlphabet::Base62,
+            slugs: Arc::new(HashSlugs {
+                length: 7,
+                alphabet: SlugAlphabet::Base62,
+            }),
+            domains: Arc::new(DomainLists::default()),
+            threats: None,
+            max_batch_size: 10,
+            max_url_length: DEFAULT_MAX_URL_LENGTH,
+            max_body_bytes: 256 * 1024,
+            slug_filter: Default::default(),
+            metadata: Default::default(),
+            reachability: ReachabilityCheck::new(std::time::Duration::from_secs(1)),
+            dead_links: DeadLinkChecker::new(&DeadLinkConfig::default()),
+            expiry_events: false,
+            deduplicate: false,
+            case_insensitive_slugs: false,
+            allow_permanent_links: false,
+            sessions: SessionTokens::new(b"test secret", 3600),
+            sso: None,
+            redirect_status: StatusCode::TEMPORARY_REDIRECT,
+            redirect_cache_control: None,
+            trusted_proxies: Default::default(),
+            interstitial_delay_seconds: 5,
+            unfurl_crawlers: true,
+            auth: AuthConfig::default(),
+            settings: LiveSettings::new(&AppConfig {
+                rate_limit: RateLimitConfig {
+                    requests: 0,
+                    api_key_requests: 0,
+                    window_seconds: 60,
+                },
+                ..AppConfig::default()
+            }),
+            metrics,
+        }
+    }
+
+    fn items(json: &str) -> Vec<UrlShortenOptions> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_batch_reports_per_item_results_in_order() {
+        let state = test_state(Arc::new(MemoryStore::new()));
+
+        let results = shorten_all(
+            items(
+                r#"[
+                    {"url": "https://example.com/a"},
+                    {"url": "ftp://example.com/b"},
+                    {"url": "https://example.com/c", "alias": "launch"},
+                    {"url": "https://example.com/d", "alias": "launch"}
+                ]"#,
+            ),
+            &Creator::default(),
+            &state,
+        )
+        .await
+        .unwrap();
+
+        let json = serde_json::to_value(&results).unwrap();
+        assert!(json[0]["short_url"]
+            .as_str()
+            .unwrap()
+            .starts_with("https://short.me/"));
+        assert_eq!(json[1]["code"], "invalid_url");
+        assert_eq!(json[2]["short_url"], "https://short.me/launch");
+        assert_eq!(json[3]["code"], "alias_taken");
+        assert_eq!(state.metrics.get(Counter::ShortenRequests), 4);
+    }
+
+    #[tokio::test]
+    async fn test_batch_resolves_collisions() {
+        let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+        let state = test_state(store.clone());
+
+        // Hash slugs give the same URL the same first candidate, the second one has to move on to its next attempt
+        let results = shorten_all(
+            items(r#"[{"url": "https://example.com/x"}, {"url": "https://example.com/x"}]"#),
+            &Creator::default(),
+            &state,
+        )
+        .await
+        .unwrap();
+
+        let json = serde_json::to_value(&results).unwrap();
+        let first = json[0]["short_url"].as_str().unwrap();
+        let second = json[1]["short_url"].as_str().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(state.metrics.get(Counter::ShortenCollisions), 1);
+        for short_url in [first, second] {
+            let slug = short_url.trim_start_matches("https://short.me/");
+            assert!(store.get(slug).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_deduplicates_when_asked() {
+        let state = test_state(Arc::new(MemoryStore::new()));
+        let batch = r#"[
+            {"url": "https://example.com/dup", "deduplicate": true},
+            {"url": "https://example.com/dup"}
+        ]"#;
+
+        let first = serde_json::to_value(
+            shorten_all(items(batch), &Creator::default(), &state)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        let second = serde_json::to_value(
+            shorten_all(items(batch), &Creator::default(), &state)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(first[0]["short_url"], second[0]["short_url"]);
+        assert_eq!(first[0]["expires_at"], second[0]["expires_at"]);
+        assert_ne!(first[1]["short_url"], second[1]["short_url"]);
+    }
+}
@@ -0,0 +1,73 @@
+use actix_web::dev::Payload;
+use actix_web::web::Json;
+use actix_web::{FromRequest, HttpRequest};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::error::ApiError;
+
+/// JSON request body that reports which field didn't match the request. Reading, size limits and
+/// content type checks are left to `web::Json`, the parsed document is then mapped onto `T`.
+pub struct JsonBody<T>(pub T);
+
+impl<T> JsonBody<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// `invalid_body` error naming the path of the field that failed, e.g. `[1].variants[0].weight`
+fn field_error(err: serde_path_to_error::Error<serde_json::Error>) -> ApiError {
+    let field = err.path().to_string();
+    ApiError::InvalidBody {
+        message: err.to_string(),
+        // "." is the document itself, e.g. an object where an array was expected
+        field: (field != ".").then_some(field),
+    }
+}
+
+/// Maps a parsed JSON document onto `T`
+fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, ApiError> {
+    serde_path_to_error::deserialize(value).map_err(field_error)
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for JsonBody<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let document = Json::<Value>::from_request(req, payload);
+        Box::pin(async move {
+            let Json(value) = document.await?;
+            Ok(JsonBody(from_value(value)?))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    struct Variant {
+        #[allow(dead_code)]
+        weight: u32,
+    }
+
+    #[test]
+    fn test_errors_name_the_field() {
+        let err = from_value::<Vec<Variant>>(json!([{ "weight": 1 }, { "weight": "heavy" }]))
+            .unwrap_err()
+            .body();
+        assert_eq!(err.code, "invalid_body");
+        assert_eq!(err.details, Some(json!({ "field": "[1].weight" })));
+        assert!(err.message.starts_with("[1].weight: invalid type"));
+
+        let err = from_value::<Vec<Variant>>(json!({})).unwrap_err().body();
+        assert_eq!(err.details, None);
+    }
+}
@@ -0,0 +1,226 @@
+use actix_web::http::header;
+use actix_web::web::{self, Data};
+use actix_web::{get, HttpRequest, HttpResponse};
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::error::ApiError;
+use crate::ownership::{load_link, Manager};
+use crate::storage::CountBatch;
+use crate::users::MaybeUser;
+use crate::AppState;
+
+/// The breakdowns outlive a link by as long as the daily click timeseries
+const RETENTION_DAYS: i64 = 366;
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+/// Referrer of visitors who didn't come from a web page, e.g. typed the link or opened it from an app
+const DIRECT: &str = "direct";
+const OTHER: &str = "other";
+
+pub fn referrers_key(slug: &str) -> String {
+    format!("referrers:{}", slug)
+}
+
+fn devices_key(slug: &str) -> String {
+    format!("devices:{}", slug)
+}
+
+fn browsers_key(slug: &str) -> String {
+    format!("browsers:{}", slug)
+}
+
+/// Every breakdown kept for the link
+pub fn breakdown_keys(slug: &str) -> [String; 3] {
+    [referrers_key(slug), devices_key(slug), browsers_key(slug)]
+}
+
+/// Where a click came from, reduced to categories that can't identify the visitor
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Visit {
+    referrer: String,
+    device: &'static str,
+    browser: String,
+}
+
+impl Visit {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+        };
+        let (device, browser) = classify_agent(header(header::USER_AGENT));
+        Visit {
+            referrer: referrer_domain(header(header::REFERER)),
+            device,
+            browser,
+        }
+    }
+}
+
+/// Host of the referring page without `www.`, paths and query strings can carry personal data
+fn referrer_domain(referer: &str) -> String {
+    Url::parse(referer)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+        .map(|host| host.strip_prefix("www.").unwrap_or(&host).to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| DIRECT.to_string())
+}
+
+/// Device category and browser name of a `User-Agent`
+fn classify_agent(user_agent: &str) -> (&'static str, String) {
+    let Some(parsed) = woothee::parser::Parser::new().parse(user_agent) else {
+        return (OTHER, OTHER.to_string());
+    };
+    let device = match parsed.category {
+        "pc" => "desktop",
+        "smartphone" | "mobilephone" => "mobile",
+        "crawler" => "bot",
+        _ => OTHER,
+    };
+    let browser = match parsed.name {
+        woothee::woothee::VALUE_UNKNOWN => OTHER,
+        name => name,
+    };
+    (device, browser.to_string())
+}
+
+/// Counts the visit in the link's referrer, device and browser breakdowns
+pub fn count_visit(counts: &mut CountBatch, slug: &str, visit: &Visit) {
+    let ttl = Duration::days(RETENTION_DAYS).num_seconds() as usize;
+    counts.increment_score(&referrers_key(slug), &visit.referrer, ttl);
+    counts.increment_score(&devices_key(slug), visit.device, ttl);
+    counts.increment_score(&browsers_key(slug), &visit.browser, ttl);
+}
+
+#[derive(Deserialize)]
+struct BreakdownQuery {
+    limit: Option<usize>,
+}
+
+impl BreakdownQuery {
+    fn limit(&self) -> Result<usize, ApiError> {
+        match self.limit {
+            None => Ok(DEFAULT_LIMIT),
+            Some(limit @ 1..=MAX_LIMIT) => Ok(limit),
+            Some(_) => Err(ApiError::validation(
+                "invalid_limit",
+                format!("limit must be between 1 and {}", MAX_LIMIT),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Share {
+    name: String,
+    clicks: u64,
+}
+
+fn shares(scores: Vec<(String, u64)>) -> Vec<Share> {
+    scores
+        .into_iter()
+        .map(|(name, clicks)| Share { name, clicks })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Referrers {
+    slug: String,
+    referrers: Vec<Share>,
+}
+
+#[derive(Serialize)]
+struct Devices {
+    slug: String,
+    devices: Vec<Share>,
+    browsers: Vec<Share>,
+}
+
+/// Checks the caller may see the stats of the link
+async fn authorize(
+    req: &HttpRequest,
+    user: MaybeUser,
+    state: &AppState,
+    slug: &str,
+) -> Result<(), ApiError> {
+    let manager = Manager::identify(req, user).await?;
+    let (_, link) = load_link(state, slug).await?;
+    manager.ensure_can_view(&link)
+}
+
+/// Referring domains with the most clicks, for the owner of the link and admin API keys
+#[get("/api/links/{slug}/stats/referrers")]
+async fn top_referrers(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<BreakdownQuery>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let limit = query.limit()?;
+    authorize(&req, user, &state, &slug).await?;
+    let referrers = state.store.top_scores(&referrers_key(&slug), limit).await?;
+    Ok(HttpResponse::Ok().json(Referrers {
+        slug,
+        referrers: shares(referrers),
+    }))
+}
+
+/// Clicks per device category and the browsers with the most clicks, for the owner of the link and admin API keys
+#[get("/api/links/{slug}/stats/devices")]
+async fn device_split(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<BreakdownQuery>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let limit = query.limit()?;
+    authorize(&req, user, &state, &slug).await?;
+    // There are only a handful of device categories, all of them are returned
+    let devices = state
+        .store
+        .top_scores(&devices_key(&slug), MAX_LIMIT)
+        .await?;
+    let browsers = state.store.top_scores(&browsers_key(&slug), limit).await?;
+    Ok(HttpResponse::Ok().json(Devices {
+        slug,
+        devices: shares(devices),
+        browsers: shares(browsers),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referrers_are_reduced_to_domains() {
+        assert_eq!(
+            referrer_domain("https://www.Google.com/search?q=secret"),
+            "google.com"
+        );
+        assert_eq!(referrer_domain("android-app://com.slack/"), "com.slack");
+        assert_eq!(referrer_domain(""), DIRECT);
+        assert_eq!(referrer_domain("not a url"), DIRECT);
+    }
+
+    #[test]
+    fn test_user_agents_are_classified() {
+        let firefox =
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:128.0) Gecko/20100101 Firefox/128.0";
+        assert_eq!(classify_agent(firefox), ("desktop", "Firefox".to_string()));
+        let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.5 Mobile/15E148 Safari/604.1";
+        assert_eq!(classify_agent(iphone), ("mobile", "Safari".to_string()));
+        let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+        assert_eq!(classify_agent(googlebot).0, "bot");
+        assert_eq!(classify_agent(""), (OTHER, OTHER.to_string()));
+    }
+}
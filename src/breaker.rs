@@ -0,0 +1,613 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::storage::{CountBatch, LinkWrites, StorageError, UrlStore};
+
+/// Whether `err` means the backend can't be reached, as opposed to a command it rejected
+fn is_outage(err: &StorageError) -> bool {
+    match err {
+        StorageError::Redis(err) => {
+            err.is_io_error()
+                || err.is_timeout()
+                || err.is_connection_dropped()
+                || err.is_connection_refusal()
+        }
+        StorageError::Unavailable { .. } => true,
+        // The breaker only wraps Redis
+        StorageError::Sqlite(_) => false,
+    }
+}
+
+/// Link records are stored under their slug, which never contains ':'
+pub(crate) fn is_link_key(key: &str) -> bool {
+    !key.contains(':')
+}
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    /// Set while the breaker is open, calls before this instant fail right away
+    open_until: Option<Instant>,
+}
+
+/// Link records read while the backend was healthy, oldest evicted first
+struct LocalCache {
+    capacity: usize,
+    entries: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl LocalCache {
+    fn insert(&mut self, key: &str, value: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self
+            .entries
+            .insert(key.to_string(), value.to_string())
+            .is_none()
+        {
+            self.order.push_back(key.to_string());
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Wraps a store that can go away, like Redis. After `failure_threshold` outage errors in a row the breaker
+/// opens: link records are served from a local cache of recent reads, everything else fails right away with
+/// `StorageError::Unavailable` instead of waiting for timeouts. After `cooldown` one call is let through to
+/// probe the backend, it closes the breaker when it succeeds and keeps it open for another cooldown when not.
+///
+/// Cached links don't see changes made through other instances, they are only served while the backend is down.
+pub struct CircuitBreaker {
+    inner: Arc<dyn UrlStore>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+    cache: Mutex<LocalCache>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        inner: Arc<dyn UrlStore>,
+        failure_threshold: u32,
+        cooldown: Duration,
+        cache_size: usize,
+    ) -> Self {
+        CircuitBreaker {
+            inner,
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+            cache: Mutex::new(LocalCache {
+                capacity: cache_size,
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Fails fast while the breaker is open, lets a single probe through once the cooldown passed
+    fn admit(&self) -> Result<(), StorageError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(open_until) = state.open_until else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < open_until {
+            let left = open_until - now;
+            return Err(StorageError::Unavailable {
+                retry_after_seconds: left.as_secs() as usize + 1,
+            });
+        }
+        // Calls arriving while the probe runs keep failing fast
+        state.open_until = Some(now + self.cooldown);
+        Ok(())
+    }
+
+    fn record<T>(&self, result: &Result<T, StorageError>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Err(err) if is_outage(err) => {
+                state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+                if state.consecutive_failures >= self.failure_threshold {
+                    if state.open_until.is_none() {
+                        log::error!(
+                            "Storage failed {} times in a row, failing fast for {:?}: {}",
+                            state.consecutive_failures,
+                            self.cooldown,
+                            err
+                        );
+                    }
+                    state.open_until = Some(Instant::now() + self.cooldown);
+                }
+            }
+            _ => {
+                if state.open_until.take().is_some() {
+                    log::info!("Storage recovered, closing the circuit breaker");
+                }
+                state.consecutive_failures = 0;
+            }
+        }
+    }
+
+    async fn guard<T>(
+        &self,
+        call: impl Future<Output = Result<T, StorageError>>,
+    ) -> Result<T, StorageError> {
+        self.admit()?;
+        let result = call.await;
+        self.record(&result);
+        result
+    }
+
+    /// Guards a read of `key`, keeping link records to serve while the breaker is open
+    async fn read(
+        &self,
+        key: &str,
+        call: impl Future<Output = Result<Option<String>, StorageError>>,
+    ) -> Result<Option<String>, StorageError> {
+        match self.guard(call).await {
+            Ok(value) => {
+                if is_link_key(key) {
+                    match &value {
+                        Some(value) => self.cache.lock().unwrap().insert(key, value),
+                        None => self.invalidate([key]),
+                    }
+                }
+                Ok(value)
+            }
+            Err(err) if is_outage(&err) && is_link_key(key) => match self.cached(key) {
+                Some(value) => Ok(Some(value)),
+                None => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    fn cached(&self, key: &str) -> Option<String> {
+        self.cache.lock().unwrap().entries.get(key).cloned()
+    }
+
+    /// Drops the cached records of keys about to be written, the write may succeed even if we never hear back
+    fn invalidate<'a>(&self, keys: impl IntoIterator<Item = &'a str>) {
+        let mut cache = self.cache.lock().unwrap();
+        for key in keys {
+            cache.entries.remove(key);
+        }
+    }
+}
+
+#[async_trait]
+impl UrlStore for CircuitBreaker {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.read(key, self.inner.get(key)).await
+    }
+
+    async fn get_for_resolve(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.read(key, self.inner.get_for_resolve(key)).await
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<usize>) -> Result<bool, StorageError> {
+        self.invalidate([key]);
+        self.guard(self.inner.set(key, value, ttl)).await
+    }
+
+    async fn set_first(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<Option<usize>, StorageError> {
+        self.invalidate(keys.iter().map(String::as_str));
+        self.guard(self.inner.set_first(keys, value, ttl)).await
+    }
+
+    async fn set_first_with(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+        writes: &[LinkWrites],
+    ) -> Result<Option<usize>, StorageError> {
+        self.invalidate(keys.iter().map(String::as_str));
+        self.guard(self.inner.set_first_with(keys, value, ttl, writes))
+            .await
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<bool, StorageError> {
+        self.invalidate([key]);
+        self.guard(self.inner.compare_and_set(key, expected, value, ttl))
+            .await
+    }
+
+    async fn expire(&self, key: &str, ttl: usize) -> Result<bool, StorageError> {
+        self.guard(self.inner.expire(key, ttl)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, StorageError> {
+        self.invalidate([key]);
+        self.guard(self.inner.delete(key)).await
+    }
+
+    async fn add_to_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        self.guard(self.inner.add_to_set(key, members)).await
+    }
+
+    async fn remove_from_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        self.guard(self.inner.remove_from_set(key, members)).await
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError> {
+        self.guard(self.inner.set_members(key)).await
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+        self.guard(self.inner.get_many(keys)).await
+    }
+
+    async fn ttl_many(&self, keys: &[String]) -> Result<Vec<Option<usize>>, StorageError> {
+        self.guard(self.inner.ttl_many(keys)).await
+    }
+
+    async fn set_many(
+        &self,
+        entries: &[(String, String, Option<usize>)],
+    ) -> Result<Vec<bool>, StorageError> {
+        self.invalidate(entries.iter().map(|(key, _, _)| key.as_str()));
+        self.guard(self.inner.set_many(entries)).await
+    }
+
+    async fn incr_window(
+        &self,
+        key: &str,
+        window_seconds: usize,
+    ) -> Result<(u64, usize), StorageError> {
+        self.guard(self.inner.incr_window(key, window_seconds))
+            .await
+    }
+
+    async fn scan_keys(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<String>), StorageError> {
+        self.guard(self.inner.scan_keys(cursor, count)).await
+    }
+
+    async fn increment(&self, key: &str, amount: u64) -> Result<u64, StorageError> {
+        self.guard(self.inner.increment(key, amount)).await
+    }
+
+    async fn increment_existing(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        self.guard(self.inner.increment_existing(key)).await
+    }
+
+    async fn decrement(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        self.guard(self.inner.decrement(key)).await
+    }
+
+    async fn hash_fields(&self, key: &str) -> Result<HashMap<String, String>, StorageError> {
+        self.guard(self.inner.hash_fields(key)).await
+    }
+
+    async fn count_estimate(&self, keys: &[String]) -> Result<u64, StorageError> {
+        self.guard(self.inner.count_estimate(keys)).await
+    }
+
+    async fn write_counts(&self, counts: &CountBatch) -> Result<(), StorageError> {
+        self.guard(self.inner.write_counts(counts)).await
+    }
+
+    async fn top_scores(
+        &self,
+        key: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, u64)>, StorageError> {
+        self.guard(self.inner.top_scores(key, limit)).await
+    }
+
+    async fn add_scored(&self, key: &str, members: &[(String, i64)]) -> Result<(), StorageError> {
+        self.guard(self.inner.add_scored(key, members)).await
+    }
+
+    async fn remove_scored(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        self.guard(self.inner.remove_scored(key, members)).await
+    }
+
+    async fn scores(
+        &self,
+        key: &str,
+        members: &[String],
+    ) -> Result<Vec<Option<i64>>, StorageError> {
+        self.guard(self.inner.scores(key, members)).await
+    }
+
+    async fn scored_page(
+        &self,
+        key: &str,
+        after: Option<(i64, &str)>,
+        descending: bool,
+        limit: usize,
+    ) -> Result<Vec<(String, i64)>, StorageError> {
+        self.guard(self.inner.scored_page(key, after, descending, limit))
+            .await
+    }
+
+    async fn push_capped(
+        &self,
+        key: &str,
+        entry: &str,
+        max_len: usize,
+    ) -> Result<(), StorageError> {
+        self.guard(self.inner.push_capped(key, entry, max_len))
+            .await
+    }
+
+    async fn list_range(
+        &self,
+        key: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, StorageError> {
+        self.guard(self.inner.list_range(key, offset, count)).await
+    }
+
+    #[cfg(test)]
+    async fn cleanup(&self) -> Result<(), StorageError> {
+        self.cache.lock().unwrap().entries.clear();
+        self.inner.cleanup().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Memory store whose backend can be taken down
+    #[derive(Default)]
+    struct FlakyStore {
+        inner: MemoryStore,
+        down: AtomicBool,
+    }
+
+    impl FlakyStore {
+        fn check(&self) -> Result<(), StorageError> {
+            if self.down.load(Ordering::SeqCst) {
+                let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+                return Err(StorageError::Redis(refused.into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl UrlStore for FlakyStore {
+        async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+            self.check()?;
+            self.inner.get(key).await
+        }
+
+        async fn set(
+            &self,
+            key: &str,
+            value: &str,
+            ttl: Option<usize>,
+        ) -> Result<bool, StorageError> {
+            self.check()?;
+            self.inner.set(key, value, ttl).await
+        }
+
+        async fn compare_and_set(
+            &self,
+            key: &str,
+            expected: &str,
+            value: &str,
+            ttl: Option<usize>,
+        ) -> Result<bool, StorageError> {
+            self.check()?;
+            self.inner.compare_and_set(key, expected, value, ttl).await
+        }
+
+        async fn expire(&self, key: &str, ttl: usize) -> Result<bool, StorageError> {
+            self.check()?;
+            self.inner.expire(key, ttl).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<bool, StorageError> {
+            self.check()?;
+            self.inner.delete(key).await
+        }
+
+        async fn add_to_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+            self.check()?;
+            self.inner.add_to_set(key, members).await
+        }
+
+        async fn remove_from_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+            self.check()?;
+            self.inner.remove_from_set(key, members).await
+        }
+
+        async fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError> {
+            self.check()?;
+            self.inner.set_members(key).await
+        }
+
+        async fn ttl_many(&self, keys: &[String]) -> Result<Vec<Option<usize>>, StorageError> {
+            self.check()?;
+            self.inner.ttl_many(keys).await
+        }
+
+        async fn incr_window(
+            &self,
+            key: &str,
+            window_seconds: usize,
+        ) -> Result<(u64, usize), StorageError> {
+            self.check()?;
+            self.inner.incr_window(key, window_seconds).await
+        }
+
+        async fn scan_keys(
+            &self,
+            cursor: u64,
+            count: usize,
+        ) -> Result<(u64, Vec<String>), StorageError> {
+            self.check()?;
+            self.inner.scan_keys(cursor, count).await
+        }
+
+        async fn increment(&self, key: &str, amount: u64) -> Result<u64, StorageError> {
+            self.check()?;
+            self.inner.increment(key, amount).await
+        }
+
+        async fn increment_existing(&self, key: &str) -> Result<Option<i64>, StorageError> {
+            self.check()?;
+            self.inner.increment_existing(key).await
+        }
+
+        async fn decrement(&self, key: &str) -> Result<Option<i64>, StorageError> {
+            self.check()?;
+            self.inner.decrement(key).await
+        }
+
+        async fn hash_fields(&self, key: &str) -> Result<HashMap<String, String>, StorageError> {
+            self.check()?;
+            self.inner.hash_fields(key).await
+        }
+
+        async fn count_estimate(&self, keys: &[String]) -> Result<u64, StorageError> {
+            self.check()?;
+            self.inner.count_estimate(keys).await
+        }
+
+        async fn write_counts(&self, counts: &CountBatch) -> Result<(), StorageError> {
+            self.check()?;
+            self.inner.write_counts(counts).await
+        }
+
+        async fn top_scores(
+            &self,
+            key: &str,
+            limit: usize,
+        ) -> Result<Vec<(String, u64)>, StorageError> {
+            self.check()?;
+            self.inner.top_scores(key, limit).await
+        }
+
+        async fn add_scored(
+            &self,
+            key: &str,
+            members: &[(String, i64)],
+        ) -> Result<(), StorageError> {
+            self.check()?;
+            self.inner.add_scored(key, members).await
+        }
+
+        async fn remove_scored(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+            self.check()?;
+            self.inner.remove_scored(key, members).await
+        }
+
+        async fn scores(
+            &self,
+            key: &str,
+            members: &[String],
+        ) -> Result<Vec<Option<i64>>, StorageError> {
+            self.check()?;
+            self.inner.scores(key, members).await
+        }
+
+        async fn scored_page(
+            &self,
+            key: &str,
+            after: Option<(i64, &str)>,
+            descending: bool,
+            limit: usize,
+        ) -> Result<Vec<(String, i64)>, StorageError> {
+            self.check()?;
+            self.inner.scored_page(key, after, descending, limit).await
+        }
+
+        async fn push_capped(
+            &self,
+            key: &str,
+            entry: &str,
+            max_len: usize,
+        ) -> Result<(), StorageError> {
+            self.check()?;
+            self.inner.push_capped(key, entry, max_len).await
+        }
+
+        async fn list_range(
+            &self,
+            key: &str,
+            offset: usize,
+            count: usize,
+        ) -> Result<Vec<String>, StorageError> {
+            self.check()?;
+            self.inner.list_range(key, offset, count).await
+        }
+
+        async fn cleanup(&self) -> Result<(), StorageError> {
+            self.inner.cleanup().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_breaker_opens_and_serves_cached_links() {
+        let backend = Arc::new(FlakyStore::default());
+        let store = CircuitBreaker::new(backend.clone(), 2, Duration::from_millis(50), 100);
+        store.set("abc", "https://example.com", None).await.unwrap();
+        store.set("rate:1", "1", None).await.unwrap();
+        assert_eq!(
+            store.get("abc").await.unwrap().as_deref(),
+            Some("https://example.com")
+        );
+
+        backend.down.store(true, Ordering::SeqCst);
+        // Links read before the outage keep resolving, other keys fail with the backend's error
+        assert_eq!(
+            store.get("abc").await.unwrap().as_deref(),
+            Some("https://example.com")
+        );
+        assert!(matches!(
+            store.get("rate:1").await,
+            Err(StorageError::Redis(_))
+        ));
+
+        // Open after two failures in a row, calls fail without reaching the backend
+        assert!(matches!(
+            store.set("def", "https://example.org", None).await,
+            Err(StorageError::Unavailable { .. })
+        ));
+        assert!(store.get("abc").await.unwrap().is_some());
+        assert!(matches!(
+            store.get("def").await,
+            Err(StorageError::Unavailable { .. })
+        ));
+
+        // The probe after the cooldown closes the breaker once the backend is back
+        backend.down.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(store.set("def", "https://example.org", None).await.unwrap());
+        assert!(store.delete("abc").await.unwrap());
+        assert_eq!(store.get("abc").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,368 @@
+use actix_web::http::header::{self, EntityTag, Header, IfNoneMatch};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use qrcode::{Color, QrCode};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::error::ApiError;
+use crate::link::Link;
+use crate::ownership;
+use crate::short_domains::split_key;
+use crate::AppState;
+
+// 1200x630 is the size recommended for og:image by Facebook, Twitter and LinkedIn
+const CARD_WIDTH: u32 = 1200;
+const CARD_HEIGHT: u32 = 630;
+const MARGIN: u32 = 60;
+const QR_SIZE: u32 = 420;
+
+const BACKGROUND: [u8; 3] = [0x1f, 0x29, 0x37];
+const FOREGROUND: [u8; 3] = [0xff, 0xff, 0xff];
+const ACCENT: [u8; 3] = [0x60, 0xa5, 0xfa];
+const MUTED: [u8; 3] = [0x9c, 0xa3, 0xaf];
+/// The card only changes if the link does, crawlers can keep it for a while
+const CARD_CACHE_CONTROL: &str = "public, max-age=3600";
+
+#[derive(Debug)]
+pub enum CardError {
+    Qr(qrcode::types::QrError),
+    Encoding(png::EncodingError),
+}
+
+impl fmt::Display for CardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CardError::Qr(err) => write!(f, "failed to generate QR code: {}", err),
+            CardError::Encoding(err) => write!(f, "failed to encode PNG: {}", err),
+        }
+    }
+}
+
+/// RGB canvas, small enough that we don't need a full imaging library
+struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32, background: [u8; 3]) -> Self {
+        let pixels = background
+            .iter()
+            .copied()
+            .cycle()
+            .take((width * height * 3) as usize)
+            .collect();
+        Canvas {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    fn fill_rect(&mut self, x: u32, y: u32, w: u32, h: u32, color: [u8; 3]) {
+        for py in y..(y + h).min(self.height) {
+            for px in x..(x + w).min(self.width) {
+                let offset = ((py * self.width + px) * 3) as usize;
+                self.pixels[offset..offset + 3].copy_from_slice(&color);
+            }
+        }
+    }
+
+    /// Draws text with the built-in 5x7 font, each font pixel becomes a `scale`x`scale` square
+    fn draw_text(&mut self, x: u32, y: u32, text: &str, scale: u32, color: [u8; 3]) {
+        for (i, c) in text.chars().enumerate() {
+            let glyph_x = x + i as u32 * GLYPH_ADVANCE * scale;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                        self.fill_rect(
+                            glyph_x + col * scale,
+                            y + row as u32 * scale,
+                            scale,
+                            scale,
+                            color,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn encode_png(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.pixels)?;
+        Ok(out)
+    }
+}
+
+/// Largest scale (capped at `max_scale`) at which `text` still fits in `max_width` pixels
+fn fit_scale(text: &str, max_width: u32, max_scale: u32) -> u32 {
+    let chars = text.chars().count().max(1) as u32;
+    (max_width / (chars * GLYPH_ADVANCE)).clamp(1, max_scale)
+}
+
+/// Truncates text that wouldn't be legible even at the smallest scale
+fn ellipsize(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars - 3).collect();
+    truncated.push_str("...");
+    truncated
+}
+
+/// Renders the social card for a short link: brand, slug, destination domain and a QR code of the short URL
+pub fn render_card(brand: &str, slug: &str, destination_host: &str) -> Result<Vec<u8>, CardError> {
+    let mut canvas = Canvas::new(CARD_WIDTH, CARD_HEIGHT, BACKGROUND);
+    let text_width = CARD_WIDTH - QR_SIZE - 3 * MARGIN;
+
+    // Brand strip on top
+    canvas.fill_rect(0, 0, CARD_WIDTH, 12, ACCENT);
+    let brand_host = brand
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let brand_text = ellipsize(brand_host, 40);
+    canvas.draw_text(
+        MARGIN,
+        MARGIN,
+        &brand_text,
+        fit_scale(&brand_text, text_width, 5),
+        ACCENT,
+    );
+
+    let slug_text = format!("/{}", ellipsize(slug, 40));
+    let slug_scale = fit_scale(&slug_text, text_width, 14);
+    canvas.draw_text(MARGIN, 220, &slug_text, slug_scale, FOREGROUND);
+
+    let host_text = ellipsize(destination_host, 60);
+    let host_y = 220 + GLYPH_HEIGHT * slug_scale + 40;
+    canvas.draw_text(MARGIN, host_y, "to", 4, MUTED);
+    canvas.draw_text(
+        MARGIN,
+        host_y + GLYPH_HEIGHT * 4 + 16,
+        &host_text,
+        fit_scale(&host_text, text_width, 6),
+        FOREGROUND,
+    );
+
+    // QR code on a white quiet zone so phones can scan it from the dark background
+    let short_url = format!("{}/{}", brand.trim_end_matches('/'), slug);
+    let code = QrCode::new(short_url.as_bytes()).map_err(CardError::Qr)?;
+    let modules = code.width() as u32;
+    let qr_x = CARD_WIDTH - MARGIN - QR_SIZE;
+    let qr_y = (CARD_HEIGHT - QR_SIZE) / 2;
+    canvas.fill_rect(qr_x, qr_y, QR_SIZE, QR_SIZE, FOREGROUND);
+    // Four modules of quiet zone on each side, as required by the QR spec
+    let module_size = QR_SIZE / (modules + 8);
+    let offset = (QR_SIZE - module_size * modules) / 2;
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color == Color::Dark {
+            let mx = i as u32 % modules;
+            let my = i as u32 / modules;
+            canvas.fill_rect(
+                qr_x + offset + mx * module_size,
+                qr_y + offset + my * module_size,
+                module_size,
+                module_size,
+                BACKGROUND,
+            );
+        }
+    }
+
+    canvas.encode_png().map_err(CardError::Encoding)
+}
+
+/// Strong validator of a card, built from everything drawn on it. The version is part of it since
+/// a new release may draw the same link differently.
+fn card_etag(brand: &str, slug: &str, destination_host: &str) -> EntityTag {
+    let hash: String = Sha256::digest(
+        [env!("CARGO_PKG_VERSION"), brand, slug, destination_host]
+            .join("\n")
+            .as_bytes(),
+    )
+    .iter()
+    .take(16)
+    .map(|b| format!("{:02x}", b))
+    .collect();
+    EntityTag::new_strong(hash)
+}
+
+/// Whether the client already holds the card with this tag
+fn is_fresh(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+// Links carry no modification time, so there is no `Last-Modified`, the ETag covers revalidation
+#[get("/{slug}/card.png")]
+async fn social_card(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let key = state.visited_key(&req, &path.into_inner())?;
+    let record = if key.contains(':') {
+        None
+    } else {
+        state.store.get(&key).await?
+    };
+    let link = Link::decode(&record.ok_or_else(|| ApiError::not_found(&key))?);
+    ownership::ensure_enabled(&key, &link)?;
+    let (slug, host) = split_key(&key);
+    let brand = state.short_domains.base_url(host);
+    // The card is public, so it must not reveal where a protected link goes
+    let destination_host = if link.password_hash.is_some() {
+        "password-protected".to_string()
+    } else {
+        url::Url::parse(&link.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or(link.url)
+    };
+
+    let etag = card_etag(&brand, slug, &destination_host);
+    if is_fresh(&req, &etag) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .insert_header((header::CACHE_CONTROL, CARD_CACHE_CONTROL))
+            .finish());
+    }
+
+    let png = render_card(&brand, slug, &destination_host).map_err(|err| {
+        ApiError::Internal(format!(
+            "Failed to render social card for {}: {}",
+            slug, err
+        ))
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .insert_header(header::ETag(etag))
+        .insert_header((header::CACHE_CONTROL, CARD_CACHE_CONTROL))
+        .body(png))
+}
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+/// Glyph width plus one column of spacing
+const GLYPH_ADVANCE: u32 = GLYPH_WIDTH + 1;
+
+/// 5x7 bitmap font covering the characters that can appear in slugs and host names, one byte per row
+#[rustfmt::skip]
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        'A' => [0b01110, 0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001],
+        'B' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+        'C' => [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+        'D' => [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100],
+        'E' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111],
+        'F' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+        'G' => [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+        'H' => [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'I' => [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'J' => [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100],
+        'K' => [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+        'M' => [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001],
+        'N' => [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'P' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000],
+        'Q' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101],
+        'R' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+        'S' => [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'V' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'W' => [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010],
+        'X' => [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+        'Y' => [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+        'Z' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111],
+        'a' => [0b00000, 0b00000, 0b01110, 0b00001, 0b01111, 0b10001, 0b01111],
+        'b' => [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b11110],
+        'c' => [0b00000, 0b00000, 0b01110, 0b10000, 0b10000, 0b10001, 0b01110],
+        'd' => [0b00001, 0b00001, 0b01101, 0b10011, 0b10001, 0b10001, 0b01111],
+        'e' => [0b00000, 0b00000, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110],
+        'f' => [0b00110, 0b01001, 0b01000, 0b11100, 0b01000, 0b01000, 0b01000],
+        'g' => [0b00000, 0b01111, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110],
+        'h' => [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001],
+        'i' => [0b00100, 0b00000, 0b01100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'j' => [0b00010, 0b00000, 0b00110, 0b00010, 0b00010, 0b10010, 0b01100],
+        'k' => [0b10000, 0b10000, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010],
+        'l' => [0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'm' => [0b00000, 0b00000, 0b11010, 0b10101, 0b10101, 0b10001, 0b10001],
+        'n' => [0b00000, 0b00000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001],
+        'o' => [0b00000, 0b00000, 0b01110, 0b10001, 0b10001, 0b10001, 0b01110],
+        'p' => [0b00000, 0b00000, 0b11110, 0b10001, 0b11110, 0b10000, 0b10000],
+        'q' => [0b00000, 0b00000, 0b01101, 0b10011, 0b01111, 0b00001, 0b00001],
+        'r' => [0b00000, 0b00000, 0b10110, 0b11001, 0b10000, 0b10000, 0b10000],
+        's' => [0b00000, 0b00000, 0b01110, 0b10000, 0b01110, 0b00001, 0b11110],
+        't' => [0b01000, 0b01000, 0b11100, 0b01000, 0b01000, 0b01001, 0b00110],
+        'u' => [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b10011, 0b01101],
+        'v' => [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'w' => [0b00000, 0b00000, 0b10001, 0b10001, 0b10101, 0b10101, 0b01010],
+        'x' => [0b00000, 0b00000, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001],
+        'y' => [0b00000, 0b00000, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110],
+        'z' => [0b00000, 0b00000, 0b11111, 0b00010, 0b00100, 0b01000, 0b11111],
+        '.' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100],
+        '-' => [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000],
+        '_' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111],
+        '/' => [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000],
+        ':' => [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000],
+        ' ' => [0; 7],
+        _ => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_card_produces_png_of_expected_size() {
+        let png = render_card("https://short.me", "abc123", "example.com").unwrap();
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR is always the first chunk, width and height are big endian u32s
+        assert_eq!(&png[16..20], &CARD_WIDTH.to_be_bytes());
+        assert_eq!(&png[20..24], &CARD_HEIGHT.to_be_bytes());
+    }
+
+    #[test]
+    fn test_fit_scale_shrinks_long_text() {
+        assert_eq!(fit_scale("abc", 600, 14), 14);
+        assert_eq!(fit_scale(&"a".repeat(40), 600, 14), 2);
+        assert_eq!(fit_scale(&"a".repeat(500), 600, 14), 1);
+    }
+
+    #[test]
+    fn test_card_etag_follows_the_drawn_content() {
+        let etag = card_etag("https://short.me", "abc123", "example.com");
+
+        assert!(!etag.weak);
+        assert_eq!(etag.tag().len(), 32);
+        assert_eq!(etag, card_etag("https://short.me", "abc123", "example.com"));
+        assert_ne!(etag, card_etag("https://short.me", "abc123", "example.org"));
+    }
+
+    #[test]
+    fn test_ellipsize() {
+        assert_eq!(ellipsize("short", 10), "short");
+        assert_eq!(ellipsize("a-very-long-host-name", 10), "a-very-...");
+    }
+}
@@ -0,0 +1,180 @@
+use async_graphql::MaybeUndefined;
+use clap::{Parser, Subcommand};
+use std::fs::File;
+use std::path::PathBuf;
+
+use crate::error::ApiError;
+use crate::{UrlShortenOptions, UrlShortener};
+
+/// URL shortener service. The commands besides `serve` work on the configured storage directly,
+/// reading the same environment variables as the server.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Runs the HTTP server when left out
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML or YAML file of settings, environment variables override it
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server
+    Serve,
+    /// Shorten a URL and print the short URL
+    Shorten {
+        url: String,
+        /// Custom slug instead of a generated one
+        #[arg(long)]
+        alias: Option<String>,
+        /// Lifetime of the link, `DEFAULT_TTL_SECONDS` when left out
+        #[arg(long, value_name = "SECONDS")]
+        expires_in: Option<u64>,
+        /// Store the link without an expiry
+        #[arg(long, conflicts_with = "expires_in")]
+        permanent: bool,
+        /// Number of redirects after which the link is used up
+        #[arg(long)]
+        max_clicks: Option<u64>,
+        /// Mint a new slug even if the URL was shortened before
+        #[arg(long)]
+        no_deduplicate: bool,
+    },
+    /// Print the destination of a short link, without counting a click
+    Resolve {
+        /// Slug or full short URL
+        slug: String,
+    },
+    /// Drop the slugs of expired links from the link listings of users
+    PurgeExpired,
+    /// Write every link record to a gzip compressed JSON lines file
+    Backup {
+        /// File to write, replaced if it exists
+        output: PathBuf,
+    },
+    /// Store the links of a backup, links whose slug is taken already are kept
+    Restore {
+        /// File written by `backup`
+        input: PathBuf,
+    },
+}
+
+/// Runs a storage command, results go to stdout so scripts can pick them up
+pub async fn run(command: Command, shortener: &UrlShortener) -> Result<(), ApiError> {
+    match command {
+        Command::Serve => unreachable!("the server is started by main"),
+        Command::Shorten {
+            url,
+            alias,
+            expires_in,
+            permanent,
+            max_clicks,
+            no_deduplicate,
+        } => {
+            let expires_in_seconds = match expires_in {
+                _ if permanent => MaybeUndefined::Null,
+                Some(seconds) => MaybeUndefined::Value(seconds),
+                None => MaybeUndefined::Undefined,
+            };
+            let options = UrlShortenOptions {
+                url,
+                alias,
+                expires_in_seconds,
+                max_clicks,
+                deduplicate: no_deduplicate.then_some(false),
+                ..Default::default()
+            };
+            let created = shortener.shorten(options).await?;
+            println!("{}", created.short_url);
+        }
+        Command::Resolve { slug } => {
+            let link = shortener.resolve(&slug).await?;
+            if link.suspension.is_some() {
+                log::warn!("{} is suspended, visitors don't get redirected", slug);
+            } else if link.disabled {
+                log::warn!("{} is disabled, visitors get 410 Gone", slug);
+            }
+            println!("{}", link.destination("", None, None));
+        }
+        Command::PurgeExpired => {
+            let (users, purged) = shortener.purge_expired().await?;
+            println!(
+                "Removed {} expired links from the listings of {} users",
+                purged, users
+            );
+        }
+        Command::Backup { output } => {
+            let file = File::create(&output).map_err(|err| file_error(&output, err))?;
+            let written = shortener.backup(file).await?;
+            println!("Backed up {} links to {}", written, output.display());
+        }
+        Command::Restore { input } => {
+            let file = File::open(&input).map_err(|err| file_error(&input, err))?;
+            let summary = shortener.restore(file).await?;
+            println!(
+                "Restored {} links, kept {} existing ones, skipped {} expired ones",
+                summary.restored, summary.existing, summary.expired
+            );
+        }
+    }
+    Ok(())
+}
+
+fn file_error(path: &std::path::Path, err: std::io::Error) -> ApiError {
+    ApiError::Internal(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_parse_commands() {
+        Cli::command().debug_assert();
+
+        assert_eq!(Cli::parse_from(["url-shortener"]).command, None);
+        assert_eq!(
+            Cli::parse_from([
+                "url-shortener",
+                "shorten",
+                "https://example.com",
+                "--alias",
+                "launch",
+                "--expires-in",
+                "3600",
+            ])
+            .command,
+            Some(Command::Shorten {
+                url: "https://example.com".to_string(),
+                alias: Some("launch".to_string()),
+                expires_in: Some(3600),
+                permanent: false,
+                max_clicks: None,
+                no_deduplicate: false,
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["url-shortener", "purge-expired"]).command,
+            Some(Command::PurgeExpired)
+        );
+        assert_eq!(
+            Cli::parse_from(["url-shortener", "backup", "links.jsonl.gz"]).command,
+            Some(Command::Backup {
+                output: PathBuf::from("links.jsonl.gz"),
+            })
+        );
+        assert!(Cli::try_parse_from(["url-shortener", "resolve"]).is_err());
+        assert!(Cli::try_parse_from([
+            "url-shortener",
+            "shorten",
+            "https://example.com",
+            "--permanent",
+            "--expires-in",
+            "3600",
+        ])
+        .is_err());
+    }
+}
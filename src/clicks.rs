@@ -0,0 +1,210 @@
+use actix_web::HttpRequest;
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::breakdown::{self, Visit};
+use crate::error::ApiError;
+use crate::events::LinkEvent;
+use crate::link::Link;
+use crate::metrics::Counter;
+use crate::ownership;
+use crate::storage::{CountBatch, StorageError, UrlStore};
+use crate::tenants;
+use crate::timeseries;
+use crate::usage;
+use crate::visitors;
+use crate::AppState;
+
+/// Rankings cover a day, warming up right after midnight still needs the day before
+const RANKING_TTL_SECONDS: usize = 2 * 24 * 60 * 60;
+
+/// Counter of the redirects a `max_clicks` link has left
+pub fn counter_key(slug: &str) -> String {
+    format!("clicks:{}", slug)
+}
+
+/// Counter for a newly stored link as `(key, value, ttl)`, it expires together with the link
+pub fn counter_entry(
+    slug: &str,
+    max_clicks: u64,
+    ttl: Option<usize>,
+) -> (String, String, Option<usize>) {
+    (counter_key(slug), max_clicks.to_string(), ttl)
+}
+
+/// Counter of all redirects of a link, whether or not it has `max_clicks`
+pub fn total_key(slug: &str) -> String {
+    format!("clicks:total:{}", slug)
+}
+
+/// Total counter for a newly stored link as `(key, value, ttl)`, it expires together with the link
+pub fn total_entry(slug: &str, ttl: Option<usize>) -> (String, String, Option<usize>) {
+    (total_key(slug), "0".to_string(), ttl)
+}
+
+/// Sorted set ranking the links by their redirects on `day`, read to warm up the link cache
+pub fn ranking_key(day: NaiveDate) -> String {
+    format!("clicks:ranking:{}", day.format("%Y-%m-%d"))
+}
+
+/// A redirect waiting to be counted
+#[derive(Clone, Debug)]
+pub struct ClickEvent {
+    slug: String,
+    at: DateTime<Utc>,
+    visitor: String,
+    visit: Visit,
+    /// Tenant of the link, its redirects add up to the tenant's usage
+    tenant: Option<String>,
+    /// API key the link was created with, its redirects add up to the key's usage
+    api_key: Option<String>,
+    /// Owner of the link, its redirects move it up the owner's index by clicks
+    owner: Option<String>,
+}
+
+impl ClickEvent {
+    /// A click on the link `slug` happening now
+    pub fn from_request(req: &HttpRequest, slug: &str, link: &Link) -> Self {
+        ClickEvent {
+            slug: slug.to_string(),
+            at: Utc::now(),
+            visitor: visitors::visitor_id(req),
+            visit: Visit::from_request(req),
+            tenant: link.tenant.clone(),
+            api_key: link.api_key.clone(),
+            owner: link.owner.clone(),
+        }
+    }
+
+    /// Adds the click to every counter it shows up in
+    pub fn count(&self, counts: &mut CountBatch) {
+        // Links stored before the counter was introduced have none, INCR would create one that never expires
+        counts.increment_existing(&total_key(&self.slug));
+        timeseries::count_click(counts, &self.slug, self.at);
+        visitors::count_visit(counts, &self.slug, &self.visitor, self.at);
+        breakdown::count_visit(counts, &self.slug, &self.visit);
+        counts.increment_score(
+            &ranking_key(self.at.date_naive()),
+            &self.slug,
+            RANKING_TTL_SECONDS,
+        );
+        if let Some(tenant) = &self.tenant {
+            counts.increment_existing(&tenants::clicks_key(tenant));
+        }
+        if let Some(id) = &self.api_key {
+            usage::count_redirect(counts, id, self.at);
+        }
+        if let Some(owner) = &self.owner {
+            counts.increment_index_score(&ownership::links_by_clicks_key(owner), &self.slug);
+        }
+    }
+}
+
+/// Queues a redirect for the analytics writer, the visitor doesn't wait for it and failures only affect the stats
+pub fn record_click(state: &AppState, req: &HttpRequest, slug: &str, link: &Link) {
+    let click = ClickEvent::from_request(req, slug, link);
+    state.publish(LinkEvent::LinkResolved {
+        slug: click.slug.clone(),
+        visit: click.visit.clone(),
+        at: click.at,
+    });
+    state.analytics.send(click);
+}
+
+/// Redirects counted so far for each of `slugs`, `None` for links without a counter
+pub async fn total_clicks(
+    store: &dyn UrlStore,
+    slugs: &[String],
+) -> Result<Vec<Option<u64>>, StorageError> {
+    let keys: Vec<String> = slugs.iter().map(|slug| total_key(slug)).collect();
+    Ok(store
+        .get_many(&keys)
+        .await?
+        .into_iter()
+        .map(|count| count.and_then(|count| count.parse().ok()))
+        .collect())
+}
+
+/// Redirects a `max_clicks` link has left, `None` for unlimited links
+pub async fn clicks_left(
+    store: &dyn UrlStore,
+    slug: &str,
+    link: &Link,
+) -> Result<Option<u64>, StorageError> {
+    if link.max_clicks.is_none() {
+        return Ok(None);
+    }
+    let left = store
+        .get(&counter_key(slug))
+        .await?
+        .and_then(|left| left.parse::<i64>().ok())
+        .unwrap_or(0);
+    Ok(Some(left.max(0) as u64))
+}
+
+/// Takes one click off the counter, returns `false` once the link is used up.
+/// The counter stays below zero after the last click, so the link keeps answering `410 Gone` until it expires.
+async fn take_click(store: &dyn UrlStore, slug: &str) -> Result<bool, StorageError> {
+    // A missing counter means it was never written or the link is being deleted
+    Ok(store
+        .decrement(&counter_key(slug))
+        .await?
+        .is_some_and(|left| left >= 0))
+}
+
+/// Lets redirects of unlimited links through, limited ones use up a click or get `410 Gone`
+pub async fn consume_click(state: &AppState, slug: &str, link: &Link) -> Result<(), ApiError> {
+    if link.max_clicks.is_none() {
+        return Ok(());
+    }
+    let allowed = take_click(state.store.as_ref(), slug)
+        .await
+        .inspect_err(|_| state.metrics.incr(Counter::StorageErrors))?;
+    if !allowed {
+        return Err(ApiError::Gone {
+            message: format!("The link '{}' has reached its click limit.", slug),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    #[tokio::test]
+    async fn test_take_click_until_used_up() {
+        let store = MemoryStore::new();
+        let (key, value, ttl) = counter_entry("abc", 2, Some(60));
+        store.set(&key, &value, ttl).await.unwrap();
+
+        assert!(take_click(&store, "abc").await.unwrap());
+        assert!(take_click(&store, "abc").await.unwrap());
+        assert!(!take_click(&store, "abc").await.unwrap());
+        assert!(!take_click(&store, "abc").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_total_clicks_of_links_with_and_without_counter() {
+        let store = MemoryStore::new();
+        let (key, value, ttl) = total_entry("abc", Some(60));
+        store.set(&key, &value, ttl).await.unwrap();
+        store.increment_existing(&key).await.unwrap();
+        store.increment_existing(&total_key("old")).await.unwrap();
+
+        assert_eq!(
+            total_clicks(&store, &["abc".to_string(), "old".to_string()])
+                .await
+                .unwrap(),
+            vec![Some(1), None]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_take_click_without_counter() {
+        let store = MemoryStore::new();
+
+        assert!(!take_click(&store, "abc").await.unwrap());
+        assert_eq!(store.get(&counter_key("abc")).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,140 @@
+use actix_web::web::{self, Data};
+use actix_web::{post, HttpRequest, HttpResponse};
+use async_graphql::MaybeUndefined;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::body::JsonBody;
+use crate::error::ApiError;
+use crate::link::{Link, UtmParams};
+use crate::ownership::{load_link, Manager};
+use crate::users::MaybeUser;
+use crate::{prepare_link, store_link, AppState, Creator, UrlShortenOptions};
+
+/// What a clone changes, everything else is copied from the link
+#[derive(Deserialize)]
+struct CloneRequest {
+    /// Custom slug of the clone, a slug is generated without it
+    alias: Option<String>,
+    /// Domain to mint the clone under, defaults to the domain of the link
+    domain: Option<String>,
+    /// Lifetime of the clone like when shortening, defaults to what is left of the link's.
+    /// `null` stores the clone without an expiry.
+    #[serde(default)]
+    expires_in_seconds: MaybeUndefined<u64>,
+    expires_at: Option<DateTime<Utc>>,
+    /// Replaces the tags of the link
+    tags: Option<Vec<String>>,
+    /// Given values replace those of the link, e.g. another `utm_source` per channel
+    #[serde(flatten)]
+    utm: UtmParams,
+}
+
+/// Shorten options recreating `link`, with the overrides of `request`
+fn clone_options(link: &Link, ttl: Option<usize>, request: CloneRequest) -> UrlShortenOptions {
+    let CloneRequest {
+        alias,
+        domain,
+        expires_in_seconds,
+        expires_at,
+        tags,
+        utm,
+    } = request;
+    let expires_in_seconds = match (expires_in_seconds, expires_at) {
+        (MaybeUndefined::Undefined, None) => match ttl {
+            Some(ttl) => MaybeUndefined::Value(ttl as u64),
+            None => MaybeUndefined::Null,
+        },
+        (expires_in_seconds, _) => expires_in_seconds,
+    };
+    UrlShortenOptions {
+        // Split test links carry their destinations in the variants
+        url: if link.variants.is_empty() {
+            link.url.clone()
+        } else {
+            String::new()
+        },
+        query_passthrough: link.query_passthrough,
+        fragment: link.fragment.clone(),
+        preserve_fragment_hint: link.preserve_fragment_hint,
+        alias,
+        domain: domain.or_else(|| link.domain.clone()),
+        expires_in_seconds,
+        expires_at,
+        // The clone is meant to be a link of its own
+        deduplicate: Some(false),
+        password: None,
+        max_clicks: link.max_clicks,
+        utm: UtmParams {
+            utm_source: utm.utm_source.or_else(|| link.utm.utm_source.clone()),
+            utm_medium: utm.utm_medium.or_else(|| link.utm.utm_medium.clone()),
+            utm_campaign: utm.utm_campaign.or_else(|| link.utm.utm_campaign.clone()),
+            utm_term: utm.utm_term.or_else(|| link.utm.utm_term.clone()),
+            utm_content: utm.utm_content.or_else(|| link.utm.utm_content.clone()),
+        },
+        device_targets: link.device_targets.clone(),
+        variants: link.variants.clone(),
+        sticky_variants: link.sticky_variants,
+        interstitial: link.interstitial,
+        redirect_status: link.redirect_status,
+        verify: false,
+        active_from: link.active_from,
+        active_until: link.active_until,
+        tags: tags.unwrap_or_else(|| link.tags.clone()),
+        allowed_ips: link
+            .access
+            .allowed_ips
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        denied_ips: link
+            .access
+            .denied_ips
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+    }
+}
+
+/// Creates a new link to the same destination with the same options, e.g. one per campaign channel.
+/// For the owner of the link and admin API keys, the clone belongs to whoever created it.
+#[post(
+    "/api/links/{slug}/clone",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)"
+)]
+async fn clone_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: JsonBody<CloneRequest>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let manager = Manager::identify(&req, user).await?;
+    let (_, link) = load_link(&state, &slug).await?;
+    manager.ensure_can_view(&link)?;
+    if link.reserved || link.suspension.is_some() {
+        return Err(ApiError::validation(
+            "invalid_clone",
+            "Reserved slugs and suspended links can't be cloned.",
+        ));
+    }
+
+    let ttl = state
+        .store
+        .ttl_many(std::slice::from_ref(&slug))
+        .await?
+        .pop()
+        .flatten();
+    let options = clone_options(&link, ttl, body.into_inner());
+    let creator = Creator::from_request(&req, manager.user_id.clone());
+    let mut prepared = prepare_link(options, &creator, &state, Utc::now()).await?;
+    // Only the hash of the password is known, it carries over as it is
+    if link.password_hash.is_some() {
+        let mut record = Link::decode(&prepared.link);
+        record.password_hash = link.password_hash;
+        prepared.link = record.encode();
+    }
+    let created = store_link(&state, prepared, &creator).await?;
+    Ok(HttpResponse::Created().json(created))
+}
@@ -0,0 +1,285 @@
+use actix_web::body::{BodySize, BodyStream, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, AcceptEncoding, ContentEncoding, Encoding, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::web::{Bytes, Data};
+use actix_web::{Error, HttpMessage};
+use flate2::write::GzEncoder;
+use futures_util::{stream, Stream};
+use std::io::{self, Write};
+use std::pin::Pin;
+
+/// Chunks up to this size are compressed right away, larger ones on the blocking pool so high levels don't
+/// hold up the worker
+const MAX_INLINE_CHUNK: usize = 1024;
+
+/// Levels responses are compressed at, higher ones shrink responses further for more CPU per response.
+/// The defaults are the fast levels of actix-web's `Compress`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompressionLevels {
+    /// Quality 0 to 11
+    pub brotli: u32,
+    /// Level 0 to 9
+    pub gzip: u32,
+    /// Level 1 to 22
+    pub zstd: i32,
+}
+
+impl Default for CompressionLevels {
+    fn default() -> Self {
+        CompressionLevels {
+            brotli: 3,
+            gzip: 1,
+            zstd: 3,
+        }
+    }
+}
+
+/// Encodings offered, in the order picked when the client likes them equally
+fn supported_encodings() -> [Encoding; 4] {
+    [
+        Encoding::brotli(),
+        Encoding::zstd(),
+        Encoding::gzip(),
+        Encoding::identity(),
+    ]
+}
+
+/// Streaming encoder at one of the configured levels, compressed output piles up in its buffer
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: ContentEncoding, levels: CompressionLevels) -> Option<Self> {
+        match encoding {
+            ContentEncoding::Brotli => Some(Encoder::Brotli(Box::new(
+                brotli::CompressorWriter::new(Vec::new(), 4096, levels.brotli, 22),
+            ))),
+            ContentEncoding::Gzip => Some(Encoder::Gzip(GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(levels.gzip),
+            ))),
+            ContentEncoding::Zstd => zstd::stream::write::Encoder::new(Vec::new(), levels.zstd)
+                .ok()
+                .map(Encoder::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compresses `chunk` and returns the output so far, flushed so streamed bodies reach the client as
+    /// they are produced
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let buffer = match self {
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(buffer)))
+    }
+
+    /// Ends the stream, returns the rest of the output
+    fn finish(self) -> io::Result<Bytes> {
+        let buffer = match self {
+            Encoder::Brotli(mut encoder) => {
+                encoder.flush()?;
+                encoder.into_inner()
+            }
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+        Ok(Bytes::from(buffer))
+    }
+}
+
+/// Whether a response is worth compressing, images other than SVG and videos are compressed already
+fn compressible(res: &ServiceResponse<impl MessageBody>) -> bool {
+    let head = res.response().head();
+    if head.headers.contains_key(header::CONTENT_ENCODING)
+        || matches!(
+            head.status,
+            StatusCode::SWITCHING_PROTOCOLS | StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT
+        )
+        || matches!(
+            res.response().body().size(),
+            BodySize::None | BodySize::Sized(0)
+        )
+    {
+        return false;
+    }
+    let content_type = head
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    !(content_type.starts_with("video/")
+        || content_type.starts_with("image/") && !content_type.starts_with("image/svg+xml"))
+}
+
+/// Compresses responses with brotli, zstd or gzip, whichever the client prefers in `Accept-Encoding`, at the
+/// levels of the `CompressionLevels` in the app data. Clients accepting none of them get the response as is.
+pub async fn compress<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let levels = req
+        .app_data::<Data<CompressionLevels>>()
+        .map(|levels| *levels.get_ref())
+        .unwrap_or_default();
+    let encoding = req
+        .get_header::<AcceptEncoding>()
+        .and_then(|accepted| accepted.negotiate(supported_encodings().iter()))
+        .and_then(|encoding| match encoding {
+            Encoding::Known(encoding) => Some(encoding),
+            Encoding::Unknown(_) => None,
+        })
+        .unwrap_or(ContentEncoding::Identity);
+
+    let res = next.call(req).await?;
+    let encoder = match Encoder::new(encoding, levels) {
+        Some(encoder) if compressible(&res) => encoder,
+        _ => return Ok(res.map_into_boxed_body()),
+    };
+    Ok(res.map_body(|head, body| {
+        head.headers
+            .insert(header::CONTENT_ENCODING, encoding.to_header_value());
+        head.headers
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        head.headers.remove(header::CONTENT_LENGTH);
+        head.no_chunking(false);
+        BoxBody::new(BodyStream::new(encode(body, encoder)))
+    }))
+}
+
+/// The compressed chunks of `body`
+fn encode<B: MessageBody + 'static>(
+    body: B,
+    encoder: Encoder,
+) -> impl Stream<Item = Result<Bytes, Box<dyn std::error::Error>>> {
+    let body: Pin<Box<B>> = Box::pin(body);
+    stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+        loop {
+            let chunk = std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await;
+            let result = match chunk {
+                Some(Ok(chunk)) if chunk.len() <= MAX_INLINE_CHUNK => {
+                    encoder.write(&chunk).map(|output| (encoder, output))
+                }
+                Some(Ok(chunk)) => tokio::task::spawn_blocking(move || {
+                    encoder.write(&chunk).map(|output| (encoder, output))
+                })
+                .await
+                .unwrap_or_else(|err| Err(io::Error::other(err))),
+                Some(Err(err)) => return Some((Err(err.into()), None)),
+                None => {
+                    let output = tokio::task::spawn_blocking(move || encoder.finish())
+                        .await
+                        .unwrap_or_else(|err| Err(io::Error::other(err)));
+                    return Some((output.map_err(Into::into), None));
+                }
+            };
+            match result {
+                Ok((next, output)) if output.is_empty() => encoder = next,
+                Ok((next, output)) => return Some((Ok(output), Some((body, next)))),
+                Err(err) => return Some((Err(err.into()), None)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+    use std::io::Read;
+
+    const BODY: &str = "{\"links\":[\"launch\",\"docs\",\"launch\",\"docs\",\"launch\",\"docs\"]}";
+
+    async fn fetch(levels: CompressionLevels, accept: &str) -> (Option<String>, Vec<u8>) {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(levels))
+                .wrap(actix_web::middleware::from_fn(compress))
+                .route(
+                    "/",
+                    web::get().to(|| async { HttpResponse::Ok().body(BODY) }),
+                ),
+        )
+        .await;
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .insert_header((header::ACCEPT_ENCODING, accept))
+                .to_request(),
+        )
+        .await;
+        let encoding = res
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|value| value.to_str().unwrap().to_string());
+        (encoding, test::read_body(res).await.to_vec())
+    }
+
+    #[actix_web::test]
+    async fn test_responses_are_compressed_as_the_client_prefers() {
+        let levels = CompressionLevels {
+            brotli: 11,
+            gzip: 9,
+            zstd: 19,
+        };
+        let mut decoded = String::new();
+
+        let (encoding, body) = fetch(levels, "gzip").await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, BODY);
+
+        let (encoding, body) = fetch(levels, "gzip;q=0.5, br").await;
+        assert_eq!(encoding.as_deref(), Some("br"));
+        decoded.clear();
+        brotli::Decompressor::new(&body[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, BODY);
+
+        let (encoding, body) = fetch(levels, "zstd").await;
+        assert_eq!(encoding.as_deref(), Some("zstd"));
+        assert_eq!(zstd::decode_all(&body[..]).unwrap(), BODY.as_bytes());
+
+        let (encoding, body) = fetch(levels, "identity").await;
+        assert_eq!(encoding, None);
+        assert_eq!(body, BODY.as_bytes());
+    }
+
+    #[actix_web::test]
+    async fn test_higher_levels_compress_harder() {
+        let fast = CompressionLevels {
+            gzip: 0,
+            ..CompressionLevels::default()
+        };
+        let best = CompressionLevels {
+            gzip: 9,
+            ..CompressionLevels::default()
+        };
+        let (_, stored) = fetch(fast, "gzip").await;
+        let (_, compressed) = fetch(best, "gzip").await;
+        assert!(compressed.len() < stored.len());
+    }
+}
@@ -0,0 +1,197 @@
+use serde::Serialize;
+
+#[derive(Clone, Debug)]
+pub struct ConsulConfig {
+    /// Base URL of the local Consul agent, e.g. `http://127.0.0.1:8500`
+    pub agent_url: String,
+    pub token: Option<String>,
+    pub service_name: String,
+    pub service_id: String,
+    pub service_address: Option<String>,
+    pub service_port: u16,
+    pub tags: Vec<String>,
+    pub health_check_url: String,
+    pub health_check_interval: String,
+}
+
+impl ConsulConfig {
+    /// Reads the registration settings from the environment, registration is disabled unless `CONSUL_HTTP_ADDR` is set
+    pub fn from_env(service_port: u16) -> Option<Self> {
+        let agent_url = std::env::var("CONSUL_HTTP_ADDR").ok()?;
+        let agent_url = if agent_url.starts_with("http://") || agent_url.starts_with("https://") {
+            agent_url
+        } else {
+            format!("http://{}", agent_url)
+        };
+        let service_name =
+            std::env::var("CONSUL_SERVICE_NAME").unwrap_or_else(|_| "url-shortener".to_string());
+        // HOSTNAME is unique per container which keeps ids stable across restarts of the same instance
+        let service_id =
+            std::env::var("CONSUL_SERVICE_ID").unwrap_or_else(|_| {
+                match std::env::var("HOSTNAME") {
+                    Ok(hostname) => format!("{}-{}", service_name, hostname),
+                    Err(_) => format!("{}-{}", service_name, service_port),
+                }
+            });
+        let service_address = std::env::var("CONSUL_SERVICE_ADDRESS").ok();
+        let tags = std::env::var("CONSUL_SERVICE_TAGS")
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let health_check_url = std::env::var("CONSUL_HEALTH_CHECK_URL").unwrap_or_else(|_| {
+            format!(
+                "http://{}:{}/healthz",
+                service_address.as_deref().unwrap_or("127.0.0.1"),
+                service_port
+            )
+        });
+        let health_check_interval =
+            std::env::var("CONSUL_HEALTH_CHECK_INTERVAL").unwrap_or_else(|_| "10s".to_string());
+
+        Some(ConsulConfig {
+            agent_url: agent_url.trim_end_matches('/').to_string(),
+            token: std::env::var("CONSUL_HTTP_TOKEN").ok(),
+            service_name,
+            service_id,
+            service_address,
+            service_port,
+            tags,
+            health_check_url,
+            health_check_interval,
+        })
+    }
+}
+
+/// Body of the agent `PUT /v1/agent/service/register` call
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceRegistration<'a> {
+    #[serde(rename = "ID")]
+    id: &'a str,
+    name: &'a str,
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<&'a str>,
+    port: u16,
+    check: HealthCheck<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthCheck<'a> {
+    #[serde(rename = "HTTP")]
+    http: &'a str,
+    interval: &'a str,
+    timeout: &'a str,
+    /// Lets Consul garbage collect instances that died without deregistering
+    deregister_critical_service_after: &'a str,
+}
+
+pub struct ConsulRegistration {
+    http: reqwest::Client,
+    config: ConsulConfig,
+}
+
+impl ConsulRegistration {
+    pub fn new(config: ConsulConfig) -> Self {
+        ConsulRegistration {
+            http: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    fn payload(&self) -> ServiceRegistration<'_> {
+        ServiceRegistration {
+            id: &self.config.service_id,
+            name: &self.config.service_name,
+            tags: &self.config.tags,
+            address: self.config.service_address.as_deref(),
+            port: self.config.service_port,
+            check: HealthCheck {
+                http: &self.config.health_check_url,
+                interval: &self.config.health_check_interval,
+                timeout: "2s",
+                deregister_critical_service_after: "1m",
+            },
+        }
+    }
+
+    fn request(&self, url: String) -> reqwest::RequestBuilder {
+        let request = self.http.put(url);
+        match &self.config.token {
+            Some(token) => request.header("X-Consul-Token", token),
+            None => request,
+        }
+    }
+
+    pub async fn register(&self) -> Result<(), reqwest::Error> {
+        self.request(format!(
+            "{}/v1/agent/service/register",
+            self.config.agent_url
+        ))
+        .json(&self.payload())
+        .send()
+        .await?
+        .error_for_status()?;
+        log::info!(
+            "Registered service {} ({}) with Consul",
+            self.config.service_name,
+            self.config.service_id
+        );
+        Ok(())
+    }
+
+    pub async fn deregister(&self) -> Result<(), reqwest::Error> {
+        self.request(format!(
+            "{}/v1/agent/service/deregister/{}",
+            self.config.agent_url, self.config.service_id
+        ))
+        .send()
+        .await?
+        .error_for_status()?;
+        log::info!(
+            "Deregistered service {} from Consul",
+            self.config.service_id
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> ConsulConfig {
+        ConsulConfig {
+            agent_url: "http://127.0.0.1:8500".to_string(),
+            token: None,
+            service_name: "url-shortener".to_string(),
+            service_id: "url-shortener-1".to_string(),
+            service_address: None,
+            service_port: 8080,
+            tags: vec!["api".to_string()],
+            health_check_url: "http://127.0.0.1:8080/healthz".to_string(),
+            health_check_interval: "10s".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_registration_payload_uses_consul_field_names() {
+        let registration = ConsulRegistration::new(test_config());
+
+        let payload = serde_json::to_value(registration.payload()).unwrap();
+
+        assert_eq!(payload["ID"], "url-shortener-1");
+        assert_eq!(payload["Name"], "url-shortener");
+        assert_eq!(payload["Tags"][0], "api");
+        assert_eq!(payload["Port"], 8080);
+        assert_eq!(payload["Check"]["HTTP"], "http://127.0.0.1:8080/healthz");
+        assert_eq!(payload["Check"]["Interval"], "10s");
+        assert!(payload.get("Address").is_none());
+    }
+}
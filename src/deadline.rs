@@ -0,0 +1,103 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, ResponseError};
+
+use crate::audit::request_id_of;
+use crate::error::ApiError;
+use crate::metrics::Counter;
+use crate::AppState;
+
+/// Answers `504 Gateway Timeout` when a request takes longer than `REQUEST_TIMEOUT_MS` to get its response,
+/// so a hung storage call doesn't hold the client and the worker. The handler is dropped at the deadline,
+/// which cancels whatever it was waiting on. Bodies streamed after the response started, like exports,
+/// aren't limited.
+///
+/// The middlewares inside are dropped with the handler, so this one is wrapped last, around all of them.
+/// The request is still owned by the handler when the deadline passes, so the `504` is handed to actix as
+/// an error carrying the finished response.
+pub async fn request_deadline(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(state) = req.app_data::<Data<AppState>>().cloned() else {
+        return next.call(req).await;
+    };
+    let Some(timeout) = state.request_timeout else {
+        return next.call(req).await;
+    };
+    let (method, path) = (req.method().clone(), req.path().to_string());
+    let request_id = request_id_of(req.request());
+    match tokio::time::timeout(timeout, next.call(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            state.metrics.incr(Counter::RequestTimeouts);
+            log::warn!(
+                "{} {} ({}) timed out after {:?}",
+                method,
+                path,
+                request_id,
+                timeout
+            );
+            let mut response = ApiError::Timeout.error_response();
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static("x-request-id"), value);
+            }
+            Err(InternalError::from_response("request timed out", response).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::{AppConfig, UrlShortener};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_slow_requests_get_504() {
+        let mut config = AppConfig::default();
+        config.server.request_timeout = Some(Duration::from_millis(20));
+        let shortener = UrlShortener::with_store(&config, Arc::new(MemoryStore::new()))
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(shortener.state.clone())
+                .wrap(actix_web::middleware::from_fn(request_deadline))
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .route("/fast", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/slow")
+            .insert_header(("X-Request-Id", "req-slow"))
+            .to_request();
+        let res = match test::try_call_service(&app, req).await {
+            Ok(_) => panic!("the slow request got through"),
+            Err(err) => err.error_response(),
+        };
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(res.headers().get("x-request-id").unwrap(), "req-slow");
+
+        let req = test::TestRequest::get().uri("/fast").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(shortener.state.metrics.get(Counter::RequestTimeouts), 1);
+    }
+}
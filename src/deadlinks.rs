@@ -0,0 +1,255 @@
+use actix_web::web::{self, Data};
+use actix_web::{get, HttpMessage, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+use url::Url;
+
+use crate::auth::AuthenticatedKey;
+use crate::error::ApiError;
+use crate::link::Link;
+use crate::metadata::is_internal;
+use crate::notifications::Webhook;
+use crate::ownership::load_link;
+use crate::tenants;
+use crate::AppState;
+
+/// Keys of the links that failed `failure_threshold` checks in a row
+const DEAD_LINKS_KEY: &str = "links:dead";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLinkConfig {
+    /// How often a sample of links is checked, `None` disables the checker
+    pub interval: Option<Duration>,
+    /// Links checked per run, the next run continues where the last one stopped
+    pub sample_size: usize,
+    /// Failed checks in a row after which a link counts as dead
+    pub failure_threshold: u64,
+    /// Receives a JSON `POST` per link found dead
+    pub webhook_url: Option<String>,
+}
+
+impl Default for DeadLinkConfig {
+    fn default() -> Self {
+        DeadLinkConfig {
+            interval: None,
+            sample_size: 100,
+            failure_threshold: 3,
+            webhook_url: None,
+        }
+    }
+}
+
+/// Failed checks in a row of the link under `key`, removed once its destination answers again. It expires
+/// with the link.
+pub fn failures_key(key: &str) -> String {
+    format!("deadlink:{}", key)
+}
+
+/// Sent once when a link reaches the failure threshold
+#[derive(Debug, Serialize)]
+struct DeadLinkNotice<'a> {
+    slug: &'a str,
+    short_url: String,
+    url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    failures: u64,
+    error: &'a str,
+}
+
+/// Checks destinations of stored links in the background and remembers the ones that keep failing
+pub struct DeadLinkChecker {
+    sample_size: usize,
+    failure_threshold: u64,
+    webhook: Option<Webhook>,
+}
+
+impl DeadLinkChecker {
+    pub fn new(config: &DeadLinkConfig) -> Self {
+        DeadLinkChecker {
+            sample_size: config.sample_size,
+            failure_threshold: config.failure_threshold,
+            webhook: config.webhook_url.clone().map(Webhook::new),
+        }
+    }
+}
+
+/// Checks the links of one page of keys from `cursor` on, up to `sample_size` of them.
+/// Returns the cursor to continue from, 0 once every key was visited, and the number of links found dead.
+pub async fn check_sample(state: &AppState, cursor: u64) -> Result<(u64, usize), ApiError> {
+    let checker = &state.dead_links;
+    let (next, keys) = state.store.scan_keys(cursor, checker.sample_size).await?;
+    // Internal records share the keyspace, slugs never contain ':'
+    let keys: Vec<String> = keys.into_iter().filter(|key| !key.contains(':')).collect();
+    let records = state.store.get_many(&keys).await?;
+    let links: Vec<(String, Link)> = keys
+        .into_iter()
+        .zip(records)
+        .filter_map(|(key, record)| Some((key, Link::decode(&record?))))
+        .filter(|(_, link)| !link.reserved && link.suspension.is_none())
+        .collect();
+
+    let probes = links.iter().map(|(_, link)| async move {
+        // Internal destinations are never requested, see `metadata::is_internal`
+        let url = Url::parse(&link.url).ok().filter(|url| !is_internal(url))?;
+        Some(state.reachability.probe(&url).await)
+    });
+    let results = futures_util::future::join_all(probes).await;
+
+    let mut dead = 0;
+    for ((key, link), result) in links.iter().zip(results) {
+        let Some(result) = result else {
+            continue;
+        };
+        let failures_key = failures_key(key);
+        let Some(error) = result else {
+            if state.store.delete(&failures_key).await? {
+                state
+                    .store
+                    .remove_from_set(DEAD_LINKS_KEY, std::slice::from_ref(key))
+                    .await?;
+            }
+            continue;
+        };
+        let failures = state.store.increment(&failures_key, 1).await?;
+        if failures == 1 {
+            let ttl = state.store.ttl_many(std::slice::from_ref(key)).await?;
+            if let Some(ttl) = ttl.into_iter().next().flatten() {
+                state.store.expire(&failures_key, ttl).await?;
+            }
+        }
+        // Announced once, when the threshold is reached
+        if failures != checker.failure_threshold {
+            continue;
+        }
+        log::warn!(
+            "Link {} to {} failed {} checks in a row: {}",
+            key,
+            link.url,
+            failures,
+            error
+        );
+        state
+            .store
+            .add_to_set(DEAD_LINKS_KEY, std::slice::from_ref(key))
+            .await?;
+        dead += 1;
+        if let Some(webhook) = &checker.webhook {
+            let notice = DeadLinkNotice {
+                slug: key,
+                short_url: state.short_url(key),
+                url: &link.url,
+                tenant: link.tenant.as_deref(),
+                failures,
+                error: &error,
+            };
+            if let Err(err) = webhook.post(&notice).await {
+                log::warn!("Failed to announce the dead link {}: {}", key, err);
+            }
+        }
+    }
+    Ok((next, dead))
+}
+
+/// Checks a sample of links every `interval`, so every link is checked once in a while without a run
+/// requesting every destination at once
+pub fn spawn_dead_link_checker(
+    state: Data<AppState>,
+    config: &DeadLinkConfig,
+) -> Option<JoinHandle<()>> {
+    let every = config.interval?;
+    Some(tokio::spawn(async move {
+        let mut ticker = interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut cursor = 0;
+        loop {
+            ticker.tick().await;
+            match check_sample(&state, cursor).await {
+                Ok((next, dead)) => {
+                    cursor = next;
+                    log::info!("Dead link check done, {} links found dead", dead);
+                }
+                Err(err) => log::error!("Dead link check failed: {}", err),
+            }
+        }
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LinkStatusFilter {
+    Dead,
+}
+
+#[derive(Deserialize)]
+struct LinksQuery {
+    status: LinkStatusFilter,
+}
+
+#[derive(Serialize)]
+struct DeadLink {
+    slug: String,
+    short_url: String,
+    url: String,
+    tenant: Option<String>,
+    /// Failed checks in a row so far
+    failures: u64,
+}
+
+/// Links whose destinations failed the dead link check repeatedly. Admin keys of a tenant only see the
+/// links of their tenant.
+#[get(
+    "/api/admin/links",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn admin_links(
+    req: HttpRequest,
+    query: web::Query<LinksQuery>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let LinkStatusFilter::Dead = query.into_inner().status;
+    let tenant = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .and_then(|key| key.key.tenant.clone());
+    let mut keys = state.store.set_members(DEAD_LINKS_KEY).await?;
+    keys.sort();
+    let mut dead = Vec::new();
+    let mut stale = Vec::new();
+    for key in keys {
+        let link = match load_link(&state, &key).await {
+            Ok((_, link)) => link,
+            Err(ApiError::NotFound { .. }) => {
+                stale.push(key);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if tenants::ensure_same_tenant(tenant.as_deref(), &link).is_err() {
+            continue;
+        }
+        let failures = state
+            .store
+            .get(&failures_key(&key))
+            .await?
+            .and_then(|failures| failures.parse().ok())
+            .unwrap_or_default();
+        dead.push(DeadLink {
+            short_url: state.short_url(&key),
+            slug: key,
+            url: link.url,
+            tenant: link.tenant,
+            failures,
+        });
+    }
+    // Expired and deleted links leave the list on their own
+    if !stale.is_empty() {
+        for key in &stale {
+            state.store.delete(&failures_key(key)).await?;
+        }
+        state.store.remove_from_set(DEAD_LINKS_KEY, &stale).await?;
+    }
+    Ok(HttpResponse::Ok().json(dead))
+}
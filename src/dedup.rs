@@ -0,0 +1,125 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::storage::{StorageError, UrlStore};
+
+/// Reverse index entry stored under `url:<hash>`, pointing at the slug already serving a link
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DedupEntry {
+    pub slug: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Reverse index key for an encoded link record. The whole record is hashed,
+/// so links to the same URL with different redirect options don't share a slug.
+pub fn index_key(link: &str) -> String {
+    let hash: String = Sha256::digest(link.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("url:{}", hash)
+}
+
+/// Index entry for a newly stored link as `(key, value, ttl)`, it expires together with the link
+pub fn index_entry(
+    link: &str,
+    slug: &str,
+    expires_at: DateTime<Utc>,
+    ttl: usize,
+) -> (String, String, Option<usize>) {
+    let entry = DedupEntry {
+        slug: slug.to_string(),
+        expires_at,
+    };
+    (
+        index_key(link),
+        serde_json::to_string(&entry).expect("dedup entry is always serializable"),
+        Some(ttl),
+    )
+}
+
+/// Looks up the slugs already serving each link, in two round trips regardless of the number of links.
+/// Index entries whose slug no longer holds the same link are ignored.
+pub async fn find_existing(
+    store: &dyn UrlStore,
+    links: &[&str],
+) -> Result<Vec<Option<DedupEntry>>, StorageError> {
+    let keys: Vec<String> = links.iter().map(|link| index_key(link)).collect();
+    let entries: Vec<Option<DedupEntry>> = store
+        .get_many(&keys)
+        .await?
+        .into_iter()
+        .map(|raw| raw.and_then(|raw| serde_json::from_str(&raw).ok()))
+        .collect();
+
+    let slugs: Vec<String> = entries
+        .iter()
+        .flatten()
+        .map(|entry| entry.slug.clone())
+        .collect();
+    if slugs.is_empty() {
+        return Ok(entries);
+    }
+    let mut current = store.get_many(&slugs).await?.into_iter();
+    Ok(entries
+        .into_iter()
+        .zip(links)
+        .map(|(entry, link)| {
+            let entry = entry?;
+            let current = current.next().flatten();
+            (current.as_deref() == Some(*link)).then_some(entry)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    #[test]
+    fn test_index_key_depends_on_the_whole_record() {
+        let plain = r#"{"url":"https://example.com/"}"#;
+        let passthrough = r#"{"url":"https://example.com/","query_passthrough":"append"}"#;
+
+        assert!(index_key(plain).starts_with("url:"));
+        assert_eq!(index_key(plain), index_key(plain));
+        assert_ne!(index_key(plain), index_key(passthrough));
+    }
+
+    #[tokio::test]
+    async fn test_find_existing_skips_stale_entries() {
+        let store = MemoryStore::new();
+        let expires_at = Utc::now();
+        let live = "https://example.com/live";
+        let stale = "https://example.com/stale";
+
+        store.set("abc", live, Some(60)).await.unwrap();
+        let (key, value, ttl) = index_entry(live, "abc", expires_at, 60);
+        store.set(&key, &value, ttl).await.unwrap();
+        // The slug was reused for something else after the original link expired
+        store
+            .set("xyz", "https://example.com/other", Some(60))
+            .await
+            .unwrap();
+        let (key, value, ttl) = index_entry(stale, "xyz", expires_at, 60);
+        store.set(&key, &value, ttl).await.unwrap();
+
+        let found = find_existing(&store, &[live, "https://example.com/new", stale])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            found,
+            vec![
+                Some(DedupEntry {
+                    slug: "abc".to_string(),
+                    expires_at
+                }),
+                None,
+                None
+            ]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+
+/// Device class a link can send visitors to an alternate destination for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    Ios,
+    Android,
+    Desktop,
+}
+
+impl DeviceType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceType::Ios => "iOS",
+            DeviceType::Android => "Android",
+            DeviceType::Desktop => "Desktop",
+        }
+    }
+}
+
+/// Classifies a `User-Agent` header, `None` for other mobile platforms, bots and unknown clients.
+/// Only the platform tokens browsers have kept stable for years are looked at, no full UA parsing.
+pub fn classify(user_agent: &str) -> Option<DeviceType> {
+    // Windows Phone pretends to be Android and iOS
+    if user_agent.contains("Windows Phone") {
+        return None;
+    }
+    if ["iPhone", "iPad", "iPod"]
+        .iter()
+        .any(|token| user_agent.contains(token))
+    {
+        return Some(DeviceType::Ios);
+    }
+    if user_agent.contains("Android") {
+        return Some(DeviceType::Android);
+    }
+    if user_agent.contains("Mobi") {
+        return None;
+    }
+    if ["Windows NT", "Macintosh", "X11", "CrOS"]
+        .iter()
+        .any(|token| user_agent.contains(token))
+    {
+        return Some(DeviceType::Desktop);
+    }
+    None
+}
+
+/// Device of the visitor sending `req`
+pub fn from_request(req: &HttpRequest) -> Option<DeviceType> {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(classify)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_common_user_agents() {
+        let cases = [
+            ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1", Some(DeviceType::Ios)),
+            ("Mozilla/5.0 (iPad; CPU OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.6 Mobile/15E148 Safari/604.1", Some(DeviceType::Ios)),
+            ("Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36", Some(DeviceType::Android)),
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36", Some(DeviceType::Desktop)),
+            ("Mozilla/5.0 (Macintosh; Intel Mac OS X 14_4) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15", Some(DeviceType::Desktop)),
+            ("Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0", Some(DeviceType::Desktop)),
+            ("Mozilla/5.0 (Windows Phone 10.0; Android 6.0.1; Microsoft; Lumia 950) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/52.0.2743.116 Mobile Safari/537.36 Edge/15.14977", None),
+            ("Mozilla/5.0 (Mobile; rv:48.0) Gecko/48.0 Firefox/48.0 KAIOS/2.5", None),
+            ("curl/8.5.0", None),
+            ("", None),
+        ];
+        for (user_agent, expected) in cases {
+            assert_eq!(classify(user_agent), expected, "{}", user_agent);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::error::ApiError;
+use crate::storage::UrlStore;
+
+/// Where a domain list is read from, configured as `file:<path>` or `redis:<key>`
+#[derive(Clone, Debug, PartialEq)]
+pub enum DomainListSource {
+    /// One domain per line, `#` starts a comment
+    File(PathBuf),
+    /// Members of a set in the store, e.g. maintained with `SADD`
+    Set(String),
+}
+
+impl std::str::FromStr for DomainListSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(DomainListSource::File(path.into())),
+            Some(("redis", key)) if !key.is_empty() => Ok(DomainListSource::Set(key.to_string())),
+            _ => Err("expected 'file:<path>' or 'redis:<key>'".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for DomainListSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DomainListSource::File(path) => write!(f, "file:{}", path.display()),
+            DomainListSource::Set(key) => write!(f, "redis:{}", key),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DomainListsConfig {
+    pub blocklist: Option<DomainListSource>,
+    /// When set, only destinations on these domains can be shortened
+    pub allowlist: Option<DomainListSource>,
+    /// When set, visitors of destinations outside these domains see the interstitial page first
+    pub trusted: Option<DomainListSource>,
+    pub reload_interval: Duration,
+}
+
+impl Default for DomainListsConfig {
+    fn default() -> Self {
+        DomainListsConfig {
+            blocklist: None,
+            allowlist: None,
+            trusted: None,
+            reload_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Lowercased domains without a leading `*.` or trailing dot, blank lines and `#` comments are skipped
+fn parse_domains<'a>(entries: impl IntoIterator<Item = &'a str>) -> HashSet<String> {
+    entries
+        .into_iter()
+        .map(|entry| entry.split('#').next().unwrap_or_default().trim())
+        .map(|entry| entry.trim_start_matches("*.").trim_end_matches('.'))
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// The entry covering `host`, an entry covers the domain itself and all of its subdomains
+fn find_match<'a>(domains: &'a HashSet<String>, host: &str) -> Option<&'a str> {
+    let mut candidate = host;
+    loop {
+        if let Some(entry) = domains.get(candidate) {
+            return Some(entry);
+        }
+        candidate = candidate.split_once('.')?.1;
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct DomainRules {
+    blocked: HashSet<String>,
+    allowed: Option<HashSet<String>>,
+    trusted: Option<HashSet<String>>,
+}
+
+impl DomainRules {
+    fn check(&self, host: &str) -> Result<(), ApiError> {
+        let host = host.trim_end_matches('.').to_lowercase();
+        if let Some(entry) = find_match(&self.blocked, &host) {
+            return Err(ApiError::DomainRejected {
+                code: "domain_blocked",
+                domain: host.clone(),
+                message: format!(
+                    "Links to '{}' are blocked because '{}' is on the blocklist.",
+                    host, entry
+                ),
+            });
+        }
+        if let Some(allowed) = &self.allowed {
+            if find_match(allowed, &host).is_none() {
+                return Err(ApiError::DomainRejected {
+                    code: "domain_not_allowed",
+                    message: format!("Links to '{}' are not allowed on this service.", host),
+                    domain: host,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Every host is trusted unless a trusted list is configured
+    fn trusts(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        self.trusted
+            .as_ref()
+            .is_none_or(|trusted| find_match(trusted, &host).is_some())
+    }
+}
+
+/// Destination domain rules checked when links are created or changed
+#[derive(Default)]
+pub struct DomainLists {
+    /// Replaced when a config reload points the lists elsewhere, see `reconfigure`
+    config: RwLock<DomainListsConfig>,
+    rules: RwLock<DomainRules>,
+}
+
+async fn read_list(
+    source: &DomainListSource,
+    store: &dyn UrlStore,
+) -> Result<HashSet<String>, String> {
+    match source {
+        DomainListSource::File(path) => tokio::fs::read_to_string(path)
+            .await
+            .map(|text| parse_domains(text.lines()))
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err)),
+        DomainListSource::Set(key) => store
+            .set_members(key)
+            .await
+            .map(|members| parse_domains(members.iter().map(String::as_str)))
+            .map_err(|err| format!("failed to read set {}: {}", key, err)),
+    }
+}
+
+async fn read_rules(
+    config: &DomainListsConfig,
+    store: &dyn UrlStore,
+) -> Result<DomainRules, String> {
+    let blocked = match &config.blocklist {
+        Some(source) => read_list(source, store).await?,
+        None => HashSet::new(),
+    };
+    let allowed = match &config.allowlist {
+        Some(source) => Some(read_list(source, store).await?),
+        None => None,
+    };
+    let trusted = match &config.trusted {
+        Some(source) => Some(read_list(source, store).await?),
+        None => None,
+    };
+    Ok(DomainRules {
+        blocked,
+        allowed,
+        trusted,
+    })
+}
+
+impl DomainLists {
+    /// Loads the configured lists, failing here keeps the service from starting with lists it can't enforce
+    pub async fn load(config: DomainListsConfig, store: &dyn UrlStore) -> Result<Self, String> {
+        let lists = DomainLists {
+            config: RwLock::new(config),
+            rules: RwLock::default(),
+        };
+        lists.reload(store).await?;
+        Ok(lists)
+    }
+
+    pub fn config(&self) -> DomainListsConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    fn is_configured(&self) -> bool {
+        let config = self.config.read().unwrap();
+        config.blocklist.is_some() || config.allowlist.is_some() || config.trusted.is_some()
+    }
+
+    /// Switches to the lists of `config`, which are loaded first so failing keeps the previous lists and
+    /// their sources. Returns whether `config` differs from the one in use.
+    pub async fn reconfigure(
+        &self,
+        config: DomainListsConfig,
+        store: &dyn UrlStore,
+    ) -> Result<bool, String> {
+        if self.config() == config {
+            return Ok(false);
+        }
+        let rules = read_rules(&config, store).await?;
+        *self.config.write().unwrap() = config;
+        self.apply(rules);
+        Ok(true)
+    }
+
+    async fn reload(&self, store: &dyn UrlStore) -> Result<(), String> {
+        let rules = read_rules(&self.config(), store).await?;
+        self.apply(rules);
+        Ok(())
+    }
+
+    fn apply(&self, rules: DomainRules) {
+        let mut current = self.rules.write().unwrap();
+        if *current != rules {
+            let count = |list: &Option<HashSet<String>>| {
+                list.as_ref()
+                    .map_or("any".to_string(), |list| list.len().to_string())
+            };
+            log::info!(
+                "Loaded domain lists: {} blocked, {} allowed, {} trusted",
+                rules.blocked.len(),
+                count(&rules.allowed),
+                count(&rules.trusted)
+            );
+            *current = rules;
+        }
+    }
+
+    /// Rejects destinations on blocked domains, or outside the allowlist when there is one
+    pub fn check_url(&self, url: &str) -> Result<(), ApiError> {
+        let Some(host) = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+        else {
+            return Ok(());
+        };
+        self.rules.read().unwrap().check(&host)
+    }
+
+    /// Whether visitors can be sent to `url` without the interstitial page
+    pub fn is_trusted(&self, url: &str) -> bool {
+        match Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+        {
+            Some(host) => self.rules.read().unwrap().trusts(&host),
+            None => self.rules.read().unwrap().trusted.is_none(),
+        }
+    }
+}
+
+/// Reloads the lists every `reload_interval`, a failed reload keeps the previous lists. Runs without lists
+/// too, a config reload can configure some later.
+pub fn spawn_domain_list_reloader(
+    lists: Arc<DomainLists>,
+    store: Arc<dyn UrlStore>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            // Read every time, a config reload can change the interval
+            let reload_interval = lists.config().reload_interval;
+            tokio::time::sleep(reload_interval).await;
+            if !lists.is_configured() {
+                continue;
+            }
+            if let Err(err) = lists.reload(store.as_ref()).await {
+                log::error!(
+                    "Failed to reload domain lists, keeping the previous ones: {}",
+                    err
+                );
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    #[test]
+    fn test_parse_domains_and_subdomain_matching() {
+        let domains = parse_domains([
+            "Evil.example",
+            "*.phish.test.",
+            "# comment",
+            " ",
+            "bad.io # malware",
+        ]);
+
+        assert_eq!(domains.len(), 3);
+        assert_eq!(find_match(&domains, "evil.example"), Some("evil.example"));
+        assert_eq!(find_match(&domains, "login.phish.test"), Some("phish.test"));
+        assert_eq!(find_match(&domains, "bad.io"), Some("bad.io"));
+        assert_eq!(find_match(&domains, "notevil.example"), None);
+        assert_eq!(find_match(&domains, "example"), None);
+    }
+
+    #[test]
+    fn test_blocklist_wins_over_allowlist() {
+        let rules = DomainRules {
+            blocked: parse_domains(["bad.example.com"]),
+            allowed: Some(parse_domains(["example.com"])),
+            trusted: None,
+        };
+
+        assert!(rules.check("www.example.com").is_ok());
+        assert_eq!(
+            rules.check("bad.example.com").unwrap_err().code(),
+            "domain_blocked"
+        );
+        assert_eq!(
+            rules.check("example.org").unwrap_err().code(),
+            "domain_not_allowed"
+        );
+    }
+
+    #[test]
+    fn test_trusted_domains() {
+        let mut rules = DomainRules::default();
+        assert!(rules.trusts("anything.example"));
+
+        rules.trusted = Some(parse_domains(["example.com"]));
+        assert!(rules.trusts("docs.Example.com."));
+        assert!(!rules.trusts("example.org"));
+    }
+
+    #[tokio::test]
+    async fn test_lists_are_reloaded_from_the_store() {
+        let store = MemoryStore::new();
+        let config = DomainListsConfig {
+            blocklist: Some("redis:domains:blocked".parse().unwrap()),
+            ..Default::default()
+        };
+        let lists = DomainLists::load(config, &store).await.unwrap();
+        assert!(lists.check_url("https://phish.example/login").is_ok());
+
+        store
+            .add_to_set("domains:blocked", &["phish.example".to_string()])
+            .await
+            .unwrap();
+        lists.reload(&store).await.unwrap();
+        assert!(lists.check_url("https://phish.example/login").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use actix_web::web::Data;
+use actix_web::{post, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::body::JsonBody;
+use crate::dedup;
+use crate::error::ApiError;
+use crate::metrics::Counter;
+use crate::storage::StorageError;
+use crate::url_shortener::validate_alias;
+use crate::users::MaybeUser;
+use crate::{prepare_link, AppState, CollisionReport, Creator, PreparedLink, UrlShortenOptions};
+
+/// What `POST /shorten-url` would answer for the same request
+#[derive(Debug, Serialize)]
+struct ShortenPreview {
+    /// Destination as it would be stored, after normalization
+    url: String,
+    /// Short URL of the alias, the identical link found, or the slug generated. Random and time based slugs
+    /// differ once the link is created. `null` for `counter` and `sequential` slugs, whose numbers are only
+    /// taken when a link is stored.
+    short_url: Option<String>,
+    /// `null` for links that never expire
+    expires_at: Option<DateTime<Utc>>,
+    /// Whether an identical link exists and would be answered instead of a new one
+    existing: bool,
+    /// How the generated slug was found when the first candidates were taken, left out otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collisions: Option<CollisionReport>,
+    /// Why the destination looks broken, for requests with `verify`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+/// Runs a shorten request up to the point of storing the link: validation, normalization, the alias and
+/// dedup lookups and slug generation. Nothing is written, quotas aren't claimed and no event is published.
+#[post(
+    "/api/shorten-url/preview",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_api_key)"
+)]
+async fn preview_shorten(
+    req: HttpRequest,
+    req_body: JsonBody<UrlShortenOptions>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let creator = Creator::from_request(&req, user.0.map(|user| user.id));
+    let preview = preview_link(&state, req_body.into_inner(), &creator).await?;
+    Ok(HttpResponse::Ok().json(preview))
+}
+
+async fn preview_link(
+    state: &AppState,
+    options: UrlShortenOptions,
+    creator: &Creator,
+) -> Result<ShortenPreview, ApiError> {
+    let storage_error = |_: &StorageError| state.metrics.incr(Counter::StorageErrors);
+    let mut prepared = prepare_link(options, creator, state, Utc::now()).await?;
+    let warning = match prepared.verify {
+        true => state.reachability.check(&prepared.url).await,
+        false => None,
+    };
+    let mut preview = ShortenPreview {
+        url: prepared.url.clone(),
+        short_url: None,
+        expires_at: prepared.expires_at,
+        existing: false,
+        collisions: None,
+        warning,
+    };
+
+    if let Some(alias) = prepared.alias.take() {
+        if let Err(err) = validate_alias(&alias, &state.settings.get().reserved_slugs) {
+            return Err(ApiError::InvalidAlias {
+                message: err.to_string(),
+                alias,
+            });
+        }
+        let key = prepared.key(&alias);
+        if state
+            .store
+            .get(&key)
+            .await
+            .inspect_err(storage_error)?
+            .is_some()
+        {
+            return Err(ApiError::AliasTaken { alias });
+        }
+        preview.short_url = Some(state.short_url(&key));
+        return Ok(preview);
+    }
+
+    if prepared.deduplicate {
+        let found = dedup::find_existing(state.store.as_ref(), &[&prepared.link])
+            .await
+            .inspect_err(storage_error)?;
+        if let Some(existing) = found.into_iter().next().flatten() {
+            preview.short_url = Some(state.short_url(&existing.slug));
+            preview.expires_at = Some(existing.expires_at);
+            preview.existing = true;
+            return Ok(preview);
+        }
+    }
+
+    if !state.slugs.previews_slugs() {
+        return Ok(preview);
+    }
+    let Some((key, attempts, existing)) = candidate_key(state, &prepared)
+        .await
+        .inspect_err(storage_error)?
+    else {
+        return Err(ApiError::Collision {
+            attempts: state.collision_attempts(),
+            url: prepared.url,
+        });
+    };
+    preview.short_url = Some(state.short_url(&key));
+    if existing {
+        let ttl = state
+            .store
+            .ttl_many(std::slice::from_ref(&key))
+            .await
+            .inspect_err(storage_error)?;
+        preview.expires_at = ttl
+            .into_iter()
+            .next()
+            .flatten()
+            .map(|seconds| Utc::now() + Duration::seconds(seconds as i64));
+        preview.existing = true;
+    } else {
+        preview.collisions = state.collision_report(attempts);
+    }
+    Ok(preview)
+}
+
+/// The first candidate of the slug strategy that is neither reserved, filtered nor taken, with the attempt
+/// that generated it and whether it already holds this very link. `None` when every candidate is taken.
+async fn candidate_key(
+    state: &AppState,
+    prepared: &PreparedLink,
+) -> Result<Option<(String, u32, bool)>, StorageError> {
+    let settings = state.settings.get();
+    let max_attempts = state.collision_attempts();
+    let mut keys = Vec::new();
+    let mut attempts = Vec::new();
+    for attempt in 1..=max_attempts {
+        let slug = state.slugs.next_slug(&prepared.url, attempt).await?;
+        let slug = state
+            .collision_policy
+            .candidate(slug, attempt, state.slug_alphabet);
+        if settings.reserved_slugs.is_reserved(&slug) || state.slug_filter.rejects(&slug) {
+            continue;
+        }
+        keys.push(prepared.key(&slug));
+        attempts.push(attempt);
+    }
+    let records = state.store.get_many(&keys).await?;
+    Ok(keys.into_iter().zip(attempts).zip(records).find_map(
+        |((key, attempt), record)| match record {
+            None => Some((key, attempt, false)),
+            Some(record) if state.slugs.shares_slugs() && record == prepared.link => {
+                Some((key, attempt, true))
+            }
+            Some(_) => None,
+        },
+    ))
+}
@@ -0,0 +1,417 @@
+use actix_web::error::{JsonPayloadError, QueryPayloadError, UrlencodedError};
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt;
+
+use crate::storage::StorageError;
+
+/// Error returned by the API handlers, rendered as a JSON `ErrorBody` with a matching status code
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request failed validation, `code` tells clients which check failed (e.g. `invalid_url`)
+    Validation {
+        code: &'static str,
+        message: String,
+    },
+    /// The body isn't valid JSON or doesn't match the request, `field` is the path of the offending
+    /// field (e.g. `variants[1].weight`) when the problem is with a single field
+    InvalidBody {
+        message: String,
+        field: Option<String>,
+    },
+    /// The body is larger than `MAX_BODY_BYTES`
+    PayloadTooLarge {
+        limit: usize,
+    },
+    InvalidAlias {
+        alias: String,
+        message: String,
+    },
+    AliasTaken {
+        alias: String,
+    },
+    /// Every generated slug collided with an existing link
+    Collision {
+        attempts: u32,
+        url: String,
+    },
+    Conflict {
+        code: &'static str,
+        message: String,
+    },
+    /// The threat checker flagged the destination, e.g. as `MALWARE`
+    UnsafeDestination {
+        threat_type: String,
+    },
+    /// The destination is on a blocked domain or outside the allowlist
+    DomainRejected {
+        code: &'static str,
+        domain: String,
+        message: String,
+    },
+    NotFound {
+        message: String,
+    },
+    /// The link exists but can't be followed anymore
+    Gone {
+        message: String,
+    },
+    /// The link is scheduled and can't be followed before `active_from`
+    NotYetActive {
+        slug: String,
+        active_from: DateTime<Utc>,
+    },
+    Unauthorized {
+        code: &'static str,
+        message: String,
+    },
+    Forbidden {
+        message: String,
+    },
+    RateLimited {
+        retry_after_seconds: usize,
+    },
+    /// The API key, or the tenant it belongs to, created as many links as the key's quota allows
+    QuotaExceeded {
+        quota: u64,
+    },
+    /// The API key created as many links this month as its monthly quota allows
+    MonthlyQuotaExceeded {
+        quota: u64,
+        retry_after_seconds: usize,
+    },
+    Storage(StorageError),
+    /// The worker already handles as many requests of the kind as `MAX_INFLIGHT_*` allows
+    Overloaded {
+        retry_after_seconds: usize,
+    },
+    /// The request ran past `REQUEST_TIMEOUT_MS`
+    Timeout,
+    /// Unexpected failure, the message is logged but not sent to the client
+    Internal(String),
+}
+
+/// Body of every error response, `details` carries machine readable context and is `null` when there is none
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn validation(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::Validation {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(slug: &str) -> Self {
+        ApiError::NotFound {
+            message: format!("There is no link '{}'.", slug),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Validation { code, .. }
+            | ApiError::Conflict { code, .. }
+            | ApiError::DomainRejected { code, .. }
+            | ApiError::Unauthorized { code, .. } => code,
+            ApiError::InvalidBody { .. } => "invalid_body",
+            ApiError::PayloadTooLarge { .. } => "payload_too_large",
+            ApiError::InvalidAlias { .. } => "invalid_alias",
+            ApiError::AliasTaken { .. } => "alias_taken",
+            ApiError::UnsafeDestination { .. } => "unsafe_url",
+            ApiError::Collision { .. } => "collision",
+            ApiError::NotFound { .. } => "not_found",
+            ApiError::Gone { .. } => "gone",
+            ApiError::NotYetActive { .. } => "not_yet_active",
+            ApiError::Forbidden { .. } => "forbidden",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::QuotaExceeded { .. } => "quota_exceeded",
+            ApiError::MonthlyQuotaExceeded { .. } => "monthly_quota_exceeded",
+            ApiError::Storage(StorageError::Unavailable { .. }) => "storage_unavailable",
+            ApiError::Storage(_) => "storage_error",
+            ApiError::Overloaded { .. } => "overloaded",
+            ApiError::Timeout => "timeout",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<Value> {
+        match self {
+            ApiError::InvalidAlias { alias, .. } | ApiError::AliasTaken { alias } => {
+                Some(json!({ "alias": alias }))
+            }
+            ApiError::InvalidBody {
+                field: Some(field), ..
+            } => Some(json!({ "field": field })),
+            ApiError::PayloadTooLarge { limit } => Some(json!({ "limit": limit })),
+            ApiError::NotYetActive { active_from, .. } => {
+                Some(json!({ "active_from": active_from }))
+            }
+            ApiError::DomainRejected { domain, .. } => Some(json!({ "domain": domain })),
+            ApiError::UnsafeDestination { threat_type } => {
+                Some(json!({ "threat_type": threat_type }))
+            }
+            ApiError::Collision { attempts, url } => {
+                Some(json!({ "attempts": attempts, "url": url }))
+            }
+            ApiError::RateLimited {
+                retry_after_seconds,
+            } => Some(json!({ "retry_after_seconds": retry_after_seconds })),
+            ApiError::QuotaExceeded { quota } => Some(json!({ "quota": quota })),
+            ApiError::MonthlyQuotaExceeded {
+                quota,
+                retry_after_seconds,
+            } => Some(json!({ "quota": quota, "retry_after_seconds": retry_after_seconds })),
+            ApiError::Storage(StorageError::Unavailable {
+                retry_after_seconds,
+            })
+            | ApiError::Overloaded {
+                retry_after_seconds,
+            } => Some(json!({ "retry_after_seconds": retry_after_seconds })),
+            _ => None,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let message = match self {
+            // Backend details stay in the logs
+            ApiError::Storage(_) => {
+                "The storage backend is unavailable, try again later.".to_string()
+            }
+            ApiError::Internal(_) => "Something went wrong on our side.".to_string(),
+            _ => self.to_string(),
+        };
+        ErrorBody {
+            code: self.code(),
+            message,
+            details: self.details(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Validation { message, .. }
+            | ApiError::InvalidBody { message, .. }
+            | ApiError::InvalidAlias { message, .. }
+            | ApiError::Conflict { message, .. }
+            | ApiError::DomainRejected { message, .. }
+            | ApiError::NotFound { message }
+            | ApiError::Gone { message }
+            | ApiError::Unauthorized { message, .. }
+            | ApiError::Forbidden { message } => write!(f, "{}", message),
+            ApiError::PayloadTooLarge { limit } => write!(
+                f,
+                "The request body is larger than the limit of {} bytes.",
+                limit
+            ),
+            ApiError::AliasTaken { alias } => write!(f, "The alias '{}' is already in use.", alias),
+            ApiError::NotYetActive { slug, active_from } => write!(
+                f,
+                "The link '{}' goes live at {}.",
+                slug,
+                active_from.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            ),
+            ApiError::UnsafeDestination { threat_type } => write!(
+                f,
+                "The URL was flagged as {} and can't be shortened.",
+                threat_type
+            ),
+            ApiError::Collision { attempts, .. } => write!(
+                f,
+                "Unable to generate a unique shortened URL after {} attempts. Please try again later.",
+                attempts
+            ),
+            ApiError::RateLimited {
+                retry_after_seconds,
+            } => write!(
+                f,
+                "Rate limit exceeded, retry in {} seconds.",
+                retry_after_seconds
+            ),
+            ApiError::QuotaExceeded { quota } => write!(
+                f,
+                "The quota of {} links of this API key is used up.",
+                quota
+            ),
+            ApiError::MonthlyQuotaExceeded { quota, .. } => write!(
+                f,
+                "This API key created its {} links for this month, try again next month.",
+                quota
+            ),
+            ApiError::Storage(err) => write!(f, "Storage error: {}", err),
+            ApiError::Overloaded {
+                retry_after_seconds,
+            } => write!(
+                f,
+                "The service is busy, retry in {} seconds.",
+                retry_after_seconds
+            ),
+            ApiError::Timeout => write!(f, "The request took too long, try again later."),
+            ApiError::Internal(message) => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl From<StorageError> for ApiError {
+    fn from(err: StorageError) -> Self {
+        ApiError::Storage(err)
+    }
+}
+
+/// Rejects unparsable JSON bodies with an `invalid_body` error instead of actix' plain text response
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::Overflow { limit }
+        | JsonPayloadError::OverflowKnownLength { limit, .. } => {
+            ApiError::PayloadTooLarge { limit }.into()
+        }
+        err => ApiError::InvalidBody {
+            message: err.to_string(),
+            field: None,
+        }
+        .into(),
+    }
+}
+
+/// Like `json_error_handler`, for form bodies
+pub fn form_error_handler(err: UrlencodedError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        UrlencodedError::Overflow { limit, .. } => ApiError::PayloadTooLarge { limit }.into(),
+        err => ApiError::InvalidBody {
+            message: err.to_string(),
+            field: None,
+        }
+        .into(),
+    }
+}
+
+/// Rejects query strings missing a parameter or holding one of the wrong type with `invalid_query`
+pub fn query_error_handler(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::validation("invalid_query", err.to_string()).into()
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Validation { .. }
+            | ApiError::InvalidBody { .. }
+            | ApiError::InvalidAlias { .. } => StatusCode::BAD_REQUEST,
+            ApiError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::AliasTaken { .. } | ApiError::Conflict { .. } => StatusCode::CONFLICT,
+            ApiError::DomainRejected { .. } | ApiError::UnsafeDestination { .. } => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ApiError::Collision { .. } => StatusCode::LOOP_DETECTED,
+            ApiError::NotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::Gone { .. } => StatusCode::GONE,
+            ApiError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden { .. } | ApiError::NotYetActive { .. } => StatusCode::FORBIDDEN,
+            ApiError::QuotaExceeded { .. } => StatusCode::PAYMENT_REQUIRED,
+            ApiError::RateLimited { .. } | ApiError::MonthlyQuotaExceeded { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ApiError::Storage(StorageError::Unavailable { .. }) | ApiError::Overloaded { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ApiError::Storage(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // Shed requests are counted instead, logging each would add to the load
+        if self.status_code().is_server_error() && !matches!(self, ApiError::Overloaded { .. }) {
+            log::error!("{}", self);
+        }
+        let mut response = HttpResponse::build(self.status_code());
+        if let ApiError::RateLimited {
+            retry_after_seconds,
+        }
+        | ApiError::MonthlyQuotaExceeded {
+            retry_after_seconds,
+            ..
+        }
+        | ApiError::Storage(StorageError::Unavailable {
+            retry_after_seconds,
+        })
+        | ApiError::Overloaded {
+            retry_after_seconds,
+        } = self
+        {
+            response.insert_header((header::RETRY_AFTER, retry_after_seconds.to_string()));
+        }
+        response.json(self.body())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body_carries_code_and_details() {
+        let body = serde_json::to_value(
+            ApiError::Collision {
+                attempts: 5,
+                url: "https://example.com".to_string(),
+            }
+            .body(),
+        )
+        .unwrap();
+
+        assert_eq!(body["code"], "collision");
+        assert_eq!(body["details"]["attempts"], 5);
+        assert!(body["message"].as_str().unwrap().contains("5 attempts"));
+
+        let body = serde_json::to_value(ApiError::validation("invalid_url", "bad").body()).unwrap();
+        assert_eq!(
+            body,
+            json!({ "code": "invalid_url", "message": "bad", "details": null })
+        );
+    }
+
+    #[test]
+    fn test_status_codes_and_headers() {
+        let collision = ApiError::Collision {
+            attempts: 1,
+            url: String::new(),
+        };
+        assert_eq!(collision.status_code(), StatusCode::LOOP_DETECTED);
+        assert_eq!(
+            ApiError::not_found("abc").status_code(),
+            StatusCode::NOT_FOUND
+        );
+
+        let response = ApiError::RateLimited {
+            retry_after_seconds: 7,
+        }
+        .error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "7");
+
+        let response = ApiError::Storage(StorageError::Unavailable {
+            retry_after_seconds: 3,
+        })
+        .error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "3");
+    }
+
+    #[test]
+    fn test_internal_details_are_not_exposed() {
+        let body = ApiError::Internal("connection pool poisoned".to_string()).body();
+
+        assert_eq!(body.code, "internal_error");
+        assert!(!body.message.contains("poisoned"));
+    }
+}
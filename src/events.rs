@@ -0,0 +1,296 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::breakdown::Visit;
+use crate::metrics::{Counter, Metrics};
+
+/// Events waiting to be published before new ones are dropped
+const EVENT_BUFFER_SIZE: usize = 4096;
+/// Most events handed to the sink at once
+const MAX_EVENT_BATCH: usize = 500;
+
+/// Something that happened to a link, published as JSON with its kind in `type`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LinkEvent {
+    LinkCreated {
+        slug: String,
+        url: String,
+        owner: Option<String>,
+        /// `null` for links that never expire
+        expires_at: Option<DateTime<Utc>>,
+        at: DateTime<Utc>,
+    },
+    /// A redirect, with the same coarse referrer and device the click stats keep
+    LinkResolved {
+        slug: String,
+        #[serde(flatten)]
+        visit: Visit,
+        at: DateTime<Utc>,
+    },
+    /// Noticed when the slug of an expired link is dropped from its owner's links, anonymous links have no owner
+    /// to notice it. With `REDIS_EXPIRY_EVENTS` every link is announced as Redis expires it instead, with the
+    /// owner kept next to the link.
+    LinkExpired {
+        slug: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        owner: Option<String>,
+        at: DateTime<Utc>,
+    },
+}
+
+impl LinkEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LinkEvent::LinkCreated { .. } => "link_created",
+            LinkEvent::LinkResolved { .. } => "link_resolved",
+            LinkEvent::LinkExpired { .. } => "link_expired",
+        }
+    }
+
+    pub fn slug(&self) -> &str {
+        match self {
+            LinkEvent::LinkCreated { slug, .. }
+            | LinkEvent::LinkResolved { slug, .. }
+            | LinkEvent::LinkExpired { slug, .. } => slug,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("serializable")
+    }
+}
+
+/// Destination of link events, e.g. a message broker. Events that fail to publish are dropped, so
+/// implementations retry on their own if they need to.
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn publish(&self, events: &[LinkEvent]) -> Result<(), String>;
+}
+
+/// Broker link events are published to, selected with `EVENTS_SINK`
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventSinkConfig {
+    /// Each event goes to `<subject_prefix>.<type>`
+    Nats { url: String, subject_prefix: String },
+    /// Each event goes to `topic`, keyed by slug so the events of a link stay in order
+    Kafka { brokers: String, topic: String },
+}
+
+/// Connects to the configured broker
+pub async fn connect(config: &EventSinkConfig) -> Result<Arc<dyn EventSink>, String> {
+    match config {
+        #[cfg(feature = "nats")]
+        EventSinkConfig::Nats {
+            url,
+            subject_prefix,
+        } => Ok(Arc::new(NatsSink::connect(url, subject_prefix).await?)),
+        #[cfg(feature = "kafka")]
+        EventSinkConfig::Kafka { brokers, topic } => Ok(Arc::new(KafkaSink::new(brokers, topic)?)),
+        // The config only accepts sinks the build includes
+        #[allow(unreachable_patterns)]
+        _ => Err(format!("{:?} is not supported by this build", config)),
+    }
+}
+
+#[cfg(feature = "nats")]
+pub struct NatsSink {
+    client: async_nats::Client,
+    subject_prefix: String,
+}
+
+#[cfg(feature = "nats")]
+impl NatsSink {
+    pub async fn connect(url: &str, subject_prefix: &str) -> Result<Self, String> {
+        let client = async_nats::connect(url)
+            .await
+            .map_err(|err| format!("failed to connect to NATS at {}: {}", url, err))?;
+        Ok(NatsSink {
+            client,
+            subject_prefix: subject_prefix.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl EventSink for NatsSink {
+    async fn publish(&self, events: &[LinkEvent]) -> Result<(), String> {
+        for event in events {
+            let subject = format!("{}.{}", self.subject_prefix, event.kind());
+            self.client
+                .publish(subject, event.to_json().into())
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        // Publishing only buffers, the batch is out once the server has it
+        self.client.flush().await.map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub struct KafkaSink {
+    producer: rdkafka::producer::FutureProducer,
+    topic: String,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaSink {
+    /// The producer connects lazily, unreachable brokers show up as failed publishes
+    pub fn new(brokers: &str, topic: &str) -> Result<Self, String> {
+        let producer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .map_err(|err| format!("failed to create Kafka producer: {}", err))?;
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl EventSink for KafkaSink {
+    async fn publish(&self, events: &[LinkEvent]) -> Result<(), String> {
+        use rdkafka::producer::FutureRecord;
+        use std::time::Duration;
+
+        let payloads: Vec<String> = events.iter().map(LinkEvent::to_json).collect();
+        let deliveries = events.iter().zip(&payloads).map(|(event, payload)| {
+            self.producer.send(
+                FutureRecord::to(&self.topic)
+                    .key(event.slug())
+                    .payload(payload),
+                Duration::ZERO,
+            )
+        });
+        for delivery in futures_util::future::join_all(deliveries).await {
+            delivery.map_err(|(err, _)| err.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Publishes link events from a background task, so requests never wait on the broker
+pub struct EventPublisher {
+    sender: mpsc::Sender<LinkEvent>,
+    metrics: Arc<Metrics>,
+    publisher: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl EventPublisher {
+    pub fn start(sink: Arc<dyn EventSink>, metrics: Arc<Metrics>) -> Self {
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER_SIZE);
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(publish_events(receiver, stopped, sink, metrics.clone()));
+        EventPublisher {
+            sender,
+            metrics,
+            publisher: Mutex::new(Some((stop, task))),
+        }
+    }
+
+    /// Queues the event, it is dropped when the broker can't keep up
+    pub fn send(&self, event: LinkEvent) {
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                self.metrics.incr(Counter::EventsDropped);
+                log::warn!("Dropped {} event, the event buffer is full", event.kind());
+            }
+            // The publisher is gone during shutdown
+            Err(TrySendError::Closed(_)) => self.metrics.incr(Counter::EventsDropped),
+        }
+    }
+
+    /// Publishes the events still buffered and stops the publisher
+    pub async fn shutdown(&self) {
+        let publisher = self.publisher.lock().unwrap().take();
+        if let Some((stop, task)) = publisher {
+            let _ = stop.send(());
+            if let Err(err) = task.await {
+                log::warn!("Event publisher stopped abnormally: {}", err);
+            }
+        }
+    }
+}
+
+async fn publish_events(
+    mut receiver: mpsc::Receiver<LinkEvent>,
+    mut stopped: oneshot::Receiver<()>,
+    sink: Arc<dyn EventSink>,
+    metrics: Arc<Metrics>,
+) {
+    let mut events = Vec::with_capacity(MAX_EVENT_BATCH);
+    let mut stopping = false;
+    loop {
+        tokio::select! {
+            received = receiver.recv_many(&mut events, MAX_EVENT_BATCH) => {
+                // Zero once the channel is closed and empty
+                if received == 0 {
+                    return;
+                }
+            }
+            // Also resolves when the `EventPublisher` is dropped
+            _ = &mut stopped, if !stopping => {
+                // Keeps receiving what is already buffered
+                stopping = true;
+                receiver.close();
+                continue;
+            }
+        }
+        if let Err(err) = sink.publish(&events).await {
+            metrics.add(Counter::EventsDropped, events.len() as u64);
+            log::warn!("Failed to publish {} link events: {}", events.len(), err);
+        }
+        events.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        published: Mutex<Vec<LinkEvent>>,
+    }
+
+    #[async_trait]
+    impl EventSink for RecordingSink {
+        async fn publish(&self, events: &[LinkEvent]) -> Result<(), String> {
+            self.published.lock().unwrap().extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buffered_events_are_published_on_shutdown() {
+        let sink = Arc::new(RecordingSink::default());
+        let publisher = EventPublisher::start(sink.clone(), Arc::new(Metrics::default()));
+        let at = Utc::now();
+        let expired = |slug: &str| LinkEvent::LinkExpired {
+            slug: slug.to_string(),
+            owner: Some("user-1".to_string()),
+            at,
+        };
+        publisher.send(expired("first"));
+        publisher.send(expired("second"));
+        publisher.shutdown().await;
+
+        assert_eq!(
+            *sink.published.lock().unwrap(),
+            vec![expired("first"), expired("second")]
+        );
+        let json: serde_json::Value = serde_json::from_str(&expired("first").to_json()).unwrap();
+        assert_eq!(json["type"], "link_expired");
+        assert_eq!(json["slug"], "first");
+    }
+}
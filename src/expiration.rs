@@ -0,0 +1,141 @@
+use chrono::{DateTime, Duration, Utc};
+use std::fmt;
+
+/// Allowed range for per-link lifetimes, in seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TtlBounds {
+    pub min_seconds: usize,
+    pub max_seconds: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExpirationError {
+    BothProvided,
+    InPast,
+    TooShort(usize),
+    TooLong(usize),
+}
+
+impl fmt::Display for ExpirationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpirationError::BothProvided => {
+                write!(
+                    f,
+                    "Provide either expires_in_seconds or expires_at, not both"
+                )
+            }
+            ExpirationError::InPast => write!(f, "expires_at must be in the future"),
+            ExpirationError::TooShort(min) => {
+                write!(f, "Links must live for at least {} seconds", min)
+            }
+            ExpirationError::TooLong(max) => {
+                write!(f, "Links can live for at most {} seconds", max)
+            }
+        }
+    }
+}
+
+/// Works out the TTL for a new link from the requested relative or absolute expiry, falling back to the default
+pub fn compute_ttl(
+    expires_in_seconds: Option<u64>,
+    expires_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    default_ttl_seconds: usize,
+    bounds: TtlBounds,
+) -> Result<usize, ExpirationError> {
+    let ttl = match (expires_in_seconds, expires_at) {
+        (Some(_), Some(_)) => return Err(ExpirationError::BothProvided),
+        (Some(seconds), None) => usize::try_from(seconds).unwrap_or(usize::MAX),
+        (None, Some(at)) => {
+            let seconds = (at - now).num_seconds();
+            if seconds <= 0 {
+                return Err(ExpirationError::InPast);
+            }
+            seconds as usize
+        }
+        (None, None) => return Ok(default_ttl_seconds),
+    };
+
+    if ttl < bounds.min_seconds {
+        return Err(ExpirationError::TooShort(bounds.min_seconds));
+    }
+    if ttl > bounds.max_seconds {
+        return Err(ExpirationError::TooLong(bounds.max_seconds));
+    }
+    Ok(ttl)
+}
+
+/// Ranges like `24h` or `7d`
+pub fn parse_range(range: &str) -> Option<Duration> {
+    let (number, hours) = match range.strip_suffix('h') {
+        Some(number) => (number, true),
+        None => (range.strip_suffix('d')?, false),
+    };
+    let number: i64 = number.parse().ok().filter(|number| *number > 0)?;
+    if hours {
+        Duration::try_hours(number)
+    } else {
+        Duration::try_days(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: TtlBounds = TtlBounds {
+        min_seconds: 60,
+        max_seconds: 3600,
+    };
+
+    #[test]
+    fn test_default_ttl_when_nothing_requested() {
+        assert_eq!(compute_ttl(None, None, Utc::now(), 600, BOUNDS), Ok(600));
+    }
+
+    #[test]
+    fn test_relative_and_absolute_expiry() {
+        let now = Utc::now();
+
+        assert_eq!(compute_ttl(Some(120), None, now, 600, BOUNDS), Ok(120));
+        assert_eq!(
+            compute_ttl(None, Some(now + Duration::seconds(900)), now, 600, BOUNDS),
+            Ok(900)
+        );
+    }
+
+    #[test]
+    fn test_expiry_validation() {
+        let now = Utc::now();
+
+        assert_eq!(
+            compute_ttl(Some(120), Some(now), now, 600, BOUNDS),
+            Err(ExpirationError::BothProvided)
+        );
+        assert_eq!(
+            compute_ttl(None, Some(now - Duration::seconds(1)), now, 600, BOUNDS),
+            Err(ExpirationError::InPast)
+        );
+        assert_eq!(
+            compute_ttl(Some(10), None, now, 600, BOUNDS),
+            Err(ExpirationError::TooShort(60))
+        );
+        assert_eq!(
+            compute_ttl(Some(7200), None, now, 600, BOUNDS),
+            Err(ExpirationError::TooLong(3600))
+        );
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("24h"), Some(Duration::hours(24)));
+        assert_eq!(parse_range("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_range("0d"), None);
+        assert_eq!(parse_range("7w"), None);
+        assert_eq!(parse_range("d"), None);
+        assert_eq!(parse_range(""), None);
+        assert_eq!(parse_range("1é"), None);
+        assert_eq!(parse_range("é"), None);
+    }
+}
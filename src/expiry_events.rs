@@ -0,0 +1,258 @@
+use actix_web::web::Data;
+use chrono::Utc;
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::events::LinkEvent;
+use crate::ownership::{forget_owned_links, leftover_keys};
+use crate::redis::{expired_keys, RedisConfig};
+use crate::storage::StorageError;
+use crate::tags;
+use crate::AppState;
+
+/// Leftover keys of an expired link deleted at a time, there are a few hundred of them
+const CLEANUP_CONCURRENCY: usize = 16;
+
+/// Every running instance receives each expiry, the first to claim it handles it
+const CLAIM_TTL_SECONDS: usize = 60;
+
+/// How long the owner and tags of a link are kept after it expires, Redis may report the expiry a while later
+const INDEXES_GRACE_SECONDS: usize = 60 * 60;
+
+fn claim_key(key: &str) -> String {
+    format!("expired:{}", key)
+}
+
+/// Owner and tags of a link with an expiry, they outlive the link so its slug can be dropped from their sets
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct LinkIndexes {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+pub fn indexes_key(key: &str) -> String {
+    format!("indexes:{}", key)
+}
+
+/// Owner and tags stored next to a link under `key` as `(key, value, ttl)`, `None` for links that never
+/// expire or are in neither set
+pub fn indexes_entry(
+    key: &str,
+    owner: Option<&str>,
+    tags: &[String],
+    ttl: Option<usize>,
+) -> Option<(String, String, Option<usize>)> {
+    let ttl = ttl?;
+    if owner.is_none() && tags.is_empty() {
+        return None;
+    }
+    let indexes = LinkIndexes {
+        owner: owner.map(str::to_string),
+        tags: tags.to_vec(),
+    };
+    Some((
+        indexes_key(key),
+        serde_json::to_string(&indexes).expect("serializable"),
+        Some(indexes_ttl(ttl)),
+    ))
+}
+
+/// TTL of the owner and tags of a link expiring in `ttl` seconds
+pub fn indexes_ttl(ttl: usize) -> usize {
+    ttl + INDEXES_GRACE_SECONDS
+}
+
+/// Deletes the counters and analytics kept next to a link Redis expired, drops it from the sets of its owner
+/// and tags, and announces it
+pub async fn link_expired(state: &AppState, key: &str) {
+    // Internal records share the keyspace, link keys never contain ':'
+    if key.contains(':') {
+        return;
+    }
+    state.link_cache.invalidate(key);
+    match state
+        .store
+        .set(&claim_key(key), "1", Some(CLAIM_TTL_SECONDS))
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return,
+        // Announcing it twice beats missing it
+        Err(err) => log::warn!("Failed to claim the expiry of link {}: {}", key, err),
+    }
+    let now = Utc::now();
+    let owner = forget_indexes(state, key).await;
+    // Leftovers only cost space, the link itself is gone
+    let failed: Vec<StorageError> = stream::iter(leftover_keys(key, None, now))
+        .map(|leftover| async move { state.store.delete(&leftover).await })
+        .buffer_unordered(CLEANUP_CONCURRENCY)
+        .filter_map(|deleted| std::future::ready(deleted.err()))
+        .collect()
+        .await;
+    if let Some(err) = failed.first() {
+        log::warn!("Failed to clean up after link {} expired: {}", key, err);
+    }
+    state.publish(LinkEvent::LinkExpired {
+        slug: key.to_string(),
+        owner,
+        at: now,
+    });
+}
+
+/// Drops an expired link from the sets of its owner and tags, returns its owner. Failures only leave the slug
+/// for the next listing to drop.
+async fn forget_indexes(state: &AppState, key: &str) -> Option<String> {
+    let indexes = match state.store.get(&indexes_key(key)).await {
+        Ok(indexes) => {
+            indexes.and_then(|indexes| serde_json::from_str::<LinkIndexes>(&indexes).ok())
+        }
+        Err(err) => {
+            log::warn!("Failed to load the owner and tags of link {}: {}", key, err);
+            None
+        }
+    }?;
+    // The slug may have been taken again right away, its sets then hold the new link
+    if !matches!(state.store.get(key).await, Ok(None)) {
+        return indexes.owner;
+    }
+    let slugs = [key.to_string()];
+    if let Some(owner) = &indexes.owner {
+        if let Err(err) = forget_owned_links(state, owner, &slugs).await {
+            log::warn!(
+                "Failed to drop expired link {} from its owner: {}",
+                key,
+                err
+            );
+        }
+    }
+    tags::unindex_slug(state, key, &indexes.tags).await;
+    indexes.owner
+}
+
+/// Listens for links expiring in Redis, resubscribing after losing the connection. Links expiring while the
+/// listener is away are cleaned up lazily, as without it.
+pub fn spawn_expiry_listener(
+    state: Data<AppState>,
+    config: &RedisConfig,
+    enabled: bool,
+) -> Option<JoinHandle<()>> {
+    if !enabled {
+        return None;
+    }
+    let config = config.clone();
+    Some(tokio::spawn(async move {
+        loop {
+            match expired_keys(&config).await {
+                Ok(keys) => {
+                    log::info!("Listening for expired links");
+                    let mut keys = std::pin::pin!(keys);
+                    while let Some(key) = keys.next().await {
+                        link_expired(&state, &key).await;
+                    }
+                    log::warn!("Lost the subscription to expired keys, resubscribing");
+                }
+                Err(err) => log::error!("Failed to subscribe to expired keys: {}", err),
+            }
+            sleep(config.connect_backoff).await;
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clicks;
+    use crate::memory::MemoryStore;
+    use crate::split;
+    use crate::storage::UrlStore;
+    use crate::users::owned_links_key;
+    use crate::{AppConfig, UrlShortener};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_expired_links_leave_no_counters_behind() {
+        let store = Arc::new(MemoryStore::new());
+        let shortener = UrlShortener::with_store(&AppConfig::default(), store.clone())
+            .await
+            .unwrap();
+        for key in [
+            clicks::total_key("launch"),
+            split::served_key("launch", 1),
+            clicks::total_key("docs"),
+        ] {
+            store.increment(&key, 1).await.unwrap();
+        }
+
+        link_expired(&shortener.state, "launch").await;
+        // Only link keys are cleaned up after
+        link_expired(&shortener.state, "clicks:total:docs").await;
+
+        assert_eq!(store.get(&clicks::total_key("launch")).await.unwrap(), None);
+        assert_eq!(
+            store.get(&split::served_key("launch", 1)).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            store.get(&clicks::total_key("docs")).await.unwrap(),
+            Some("1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expired_links_leave_their_owner_and_tags_once() {
+        let store = Arc::new(MemoryStore::new());
+        let shortener = UrlShortener::with_store(&AppConfig::default(), store.clone())
+            .await
+            .unwrap();
+        let slugs = ["launch".to_string()];
+        let (key, indexes, ttl) =
+            indexes_entry("launch", Some("alice"), &["promo".to_string()], Some(60)).unwrap();
+        store.set(&key, &indexes, ttl).await.unwrap();
+        store
+            .add_to_set(&owned_links_key("alice"), &slugs)
+            .await
+            .unwrap();
+        store
+            .add_to_set(&tags::tag_key("promo"), &slugs)
+            .await
+            .unwrap();
+
+        link_expired(&shortener.state, "launch").await;
+        assert!(store
+            .set_members(&owned_links_key("alice"))
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(store
+            .set_members(&tags::tag_key("promo"))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.get(&indexes_key("launch")).await.unwrap(), None);
+
+        // Other instances receiving the same expiry leave it to the one that claimed it
+        store
+            .increment(&clicks::total_key("launch"), 1)
+            .await
+            .unwrap();
+        link_expired(&shortener.state, "launch").await;
+        assert_eq!(
+            store.get(&clicks::total_key("launch")).await.unwrap(),
+            Some("1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_links_without_expiry_or_sets_keep_no_indexes() {
+        assert_eq!(indexes_entry("launch", Some("alice"), &[], None), None);
+        assert_eq!(indexes_entry("launch", None, &[], Some(60)), None);
+        let (_, indexes, ttl) =
+            indexes_entry("launch", None, &["promo".to_string()], Some(60)).unwrap();
+        assert_eq!(indexes, r#"{"tags":["promo"]}"#);
+        assert_eq!(ttl, Some(60 + INDEXES_GRACE_SECONDS));
+    }
+}
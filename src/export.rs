@@ -0,0 +1,258 @@
+use actix_web::http::header;
+use actix_web::web::{self, Bytes, Data};
+use actix_web::{get, HttpRequest, HttpResponse};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::clicks;
+use crate::error::ApiError;
+use crate::link::Link;
+use crate::ownership::Manager;
+use crate::split;
+use crate::users::MaybeUser;
+use crate::AppState;
+
+/// Keys looked at per `SCAN` page, each page becomes one chunk of the response
+const EXPORT_PAGE_SIZE: usize = 500;
+const CSV_HEADER: &str = "slug,short_url,url,owner,enabled,suspended,tags,max_clicks,clicks\n";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    tag: Option<String>,
+    owner: Option<String>,
+    tenant: Option<String>,
+}
+
+/// Which links end up in the export, on top of the ones the manager can't see being left out
+struct ExportFilter {
+    manager: Manager,
+    tag: Option<String>,
+    owner: Option<String>,
+    tenant: Option<String>,
+}
+
+impl ExportFilter {
+    fn matches(&self, link: &Link) -> bool {
+        self.manager.ensure_can_view(link).is_ok()
+            && self.tag.as_ref().is_none_or(|tag| link.tags.contains(tag))
+            && self
+                .owner
+                .as_ref()
+                .is_none_or(|owner| link.owner.as_ref() == Some(owner))
+            && self
+                .tenant
+                .as_ref()
+                .is_none_or(|tenant| link.tenant.as_ref() == Some(tenant))
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct ExportedLink {
+    slug: String,
+    short_url: String,
+    url: String,
+    owner: Option<String>,
+    enabled: bool,
+    suspended: bool,
+    tags: Vec<String>,
+    max_clicks: Option<u64>,
+    /// Redirects counted so far, `None` for links that don't keep a counter
+    clicks: Option<u64>,
+}
+
+impl ExportedLink {
+    fn to_csv(&self) -> String {
+        let optional =
+            |value: Option<u64>| value.map(|value| value.to_string()).unwrap_or_default();
+        let fields = [
+            csv_field(&self.slug),
+            csv_field(&self.short_url),
+            csv_field(&self.url),
+            csv_field(self.owner.as_deref().unwrap_or_default()),
+            self.enabled.to_string(),
+            self.suspended.to_string(),
+            csv_field(&self.tags.join(" ")),
+            optional(self.max_clicks),
+            optional(self.clicks),
+        ];
+        fields.join(",") + "\n"
+    }
+}
+
+/// Quotes a field when it holds a separator, quote or line break, as RFC 4180 asks
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Redirects counted for the link so far. Links created before every link got a click counter are only
+/// counted when they have `max_clicks` or are split tests.
+async fn counted_clicks(
+    state: &AppState,
+    slug: &str,
+    link: &Link,
+    total: Option<u64>,
+) -> Result<Option<u64>, ApiError> {
+    if total.is_some() {
+        return Ok(total);
+    }
+    if let Some(max_clicks) = link.max_clicks {
+        let left = clicks::clicks_left(state.store.as_ref(), slug, link)
+            .await?
+            .unwrap_or(0);
+        return Ok(Some(max_clicks.saturating_sub(left)));
+    }
+    if !link.variants.is_empty() {
+        let keys: Vec<String> = (0..link.variants.len())
+            .map(|index| split::served_key(slug, index))
+            .collect();
+        let served = state.store.get_many(&keys).await?;
+        return Ok(Some(
+            served
+                .into_iter()
+                .flatten()
+                .filter_map(|count| count.parse::<u64>().ok())
+                .sum(),
+        ));
+    }
+    Ok(None)
+}
+
+/// Exports the matching links of one `SCAN` page, returns the chunk and the cursor of the next page
+async fn export_page(
+    state: &AppState,
+    filter: &ExportFilter,
+    format: ExportFormat,
+    cursor: u64,
+) -> Result<(Bytes, u64), ApiError> {
+    let (next, keys) = state.store.scan_keys(cursor, EXPORT_PAGE_SIZE).await?;
+    // Internal records share the keyspace, slugs never contain ':'
+    let slugs: Vec<String> = keys.into_iter().filter(|key| !key.contains(':')).collect();
+    let records = state.store.get_many(&slugs).await?;
+    let totals = clicks::total_clicks(state.store.as_ref(), &slugs).await?;
+
+    let mut chunk = String::new();
+    for ((slug, record), total) in slugs.into_iter().zip(records).zip(totals) {
+        let Some(link) = record.map(|record| Link::decode(&record)) else {
+            continue;
+        };
+        if !filter.matches(&link) {
+            continue;
+        }
+        let exported = ExportedLink {
+            clicks: counted_clicks(state, &slug, &link, total).await?,
+            short_url: state.short_url(&slug),
+            slug,
+            url: link.url,
+            owner: link.owner,
+            enabled: !link.disabled,
+            suspended: link.suspension.is_some(),
+            tags: link.tags,
+            max_clicks: link.max_clicks,
+        };
+        match format {
+            ExportFormat::Csv => chunk.push_str(&exported.to_csv()),
+            ExportFormat::Jsonl => {
+                chunk.push_str(&serde_json::to_string(&exported).expect("serializable"));
+                chunk.push('\n');
+            }
+        }
+    }
+    Ok((Bytes::from(chunk), next))
+}
+
+/// Streams every link the caller can see, or those matching `tag`, `owner` and `tenant`, as CSV or JSON lines.
+/// The keyspace is read one page at a time, so the export is never held in memory as a whole.
+#[get("/api/export/links")]
+async fn export_links(
+    req: HttpRequest,
+    query: web::Query<ExportQuery>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let manager = Manager::identify(&req, user).await?;
+    let ExportQuery {
+        format,
+        tag,
+        owner,
+        tenant,
+    } = query.into_inner();
+    let filter = Arc::new(ExportFilter {
+        manager,
+        tag: tag.map(|tag| tag.trim().to_lowercase()),
+        owner,
+        tenant,
+    });
+
+    let pages = stream::try_unfold(Some(0), move |cursor| {
+        let state = state.clone();
+        let filter = filter.clone();
+        async move {
+            let Some(cursor) = cursor else {
+                return Ok(None);
+            };
+            let (chunk, next) = export_page(&state, &filter, format, cursor).await?;
+            Ok::<_, ApiError>(Some((chunk, (next != 0).then_some(next))))
+        }
+    })
+    // Headers are already sent, all that is left is cutting the response short
+    .map_err(|err| {
+        log::error!("Export failed: {}", err);
+        std::io::Error::other(err.to_string())
+    });
+    let (content_type, file_name, header_row) = match format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "links.csv", CSV_HEADER),
+        ExportFormat::Jsonl => ("application/x-ndjson", "links.jsonl", ""),
+    };
+    let body = stream::once(async move { Ok(Bytes::from_static(header_row.as_bytes())) })
+        .chain(pages)
+        .try_filter(|chunk| std::future::ready(!chunk.is_empty()));
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        ))
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_rows_quote_special_characters() {
+        let exported = ExportedLink {
+            slug: "launch".to_string(),
+            short_url: "https://short.me/launch".to_string(),
+            url: "https://example.com/?a=1,2&q=\"x\"".to_string(),
+            owner: None,
+            enabled: true,
+            suspended: false,
+            tags: vec!["campaign-q3".to_string(), "email".to_string()],
+            max_clicks: Some(10),
+            clicks: Some(3),
+        };
+
+        assert_eq!(
+            exported.to_csv(),
+            "launch,https://short.me/launch,\"https://example.com/?a=1,2&q=\"\"x\"\"\",,true,false,campaign-q3 email,10,3\n"
+        );
+        assert_eq!(CSV_HEADER.split(',').count(), 9);
+    }
+}
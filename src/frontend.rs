@@ -0,0 +1,78 @@
+use actix_web::http::header::{self, EntityTag, Header, IfNoneMatch};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use rust_embed::{EmbeddedFile, RustEmbed};
+
+/// The page for creating links without curl, compiled into the binary from `static/`
+#[derive(RustEmbed)]
+#[folder = "static/"]
+struct Assets;
+
+/// Browsers revalidate with the ETag, so a new release is picked up right away
+const ASSET_CACHE_CONTROL: &str = "no-cache";
+
+/// Answers with an embedded file, or `304 Not Modified` if the client's copy is current
+fn serve(req: &HttpRequest, path: &str) -> HttpResponse {
+    let Some(EmbeddedFile { data, metadata }) = Assets::get(path) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let hash: String = metadata
+        .sha256_hash()
+        .iter()
+        .take(16)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let etag = EntityTag::new_strong(hash);
+    let fresh = match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        Err(_) => false,
+    };
+    let mut response = if fresh {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header(header::ETag(etag))
+        .insert_header((header::CACHE_CONTROL, ASSET_CACHE_CONTROL));
+    if fresh {
+        return response.finish();
+    }
+    response
+        .content_type(metadata.mimetype())
+        .body(data.into_owned())
+}
+
+#[get("/")]
+async fn index(req: HttpRequest) -> HttpResponse {
+    serve(&req, "index.html")
+}
+
+#[get("/static/{file}")]
+async fn asset(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    serve(&req, &path.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_assets_are_served_with_their_type_and_revalidated() {
+        let response = serve(&TestRequest::default().to_http_request(), "app.js");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/javascript"
+        );
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+
+        let req = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_http_request();
+        assert_eq!(serve(&req, "app.js").status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(serve(&req, "missing.js").status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,226 @@
+use actix_web::web::Data;
+use actix_web::{post, HttpRequest, HttpResponse, ResponseError};
+use async_graphql::{
+    Context, EmptySubscription, ErrorExtensions, Object, ResultExt, Schema, SimpleObject,
+};
+
+use crate::body::JsonBody;
+use crate::clicks::{clicks_left, total_clicks};
+use crate::error::ApiError;
+use crate::ownership::{
+    change_link, count_clicks, load_link, load_metadata, owned_links, remove_link, Manager,
+    OwnedLink, UpdateLinkRequest,
+};
+use crate::split::{variant_stats_of, VariantStats};
+use crate::tags::tagged_links;
+use crate::users::MaybeUser;
+use crate::{create_link, AppState, Creator, UrlShortenData, UrlShortenOptions};
+
+/// Nesting deeper than this is rejected before anything is resolved
+const MAX_DEPTH: usize = 8;
+const MAX_COMPLEXITY: usize = 200;
+
+pub type LinkSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+pub fn schema() -> LinkSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// Errors carry the same `code` and `details` as REST error bodies in their `extensions`
+impl ErrorExtensions for ApiError {
+    fn extend(&self) -> async_graphql::Error {
+        if self.status_code().is_server_error() {
+            log::error!("{}", self);
+        }
+        let body = self.body();
+        async_graphql::Error::new(body.message).extend_with(|_, extensions| {
+            extensions.set("code", body.code);
+            if let Some(details) = body
+                .details
+                .and_then(|details| async_graphql::Value::from_json(details).ok())
+            {
+                extensions.set("details", details);
+            }
+        })
+    }
+}
+
+fn manager<'a>(ctx: &Context<'a>) -> &'a Manager {
+    ctx.data_unchecked::<Manager>()
+}
+
+fn state<'a>(ctx: &Context<'a>) -> &'a AppState {
+    ctx.data_unchecked::<Data<AppState>>()
+}
+
+#[derive(SimpleObject)]
+struct LinkStats {
+    slug: String,
+    /// Redirects counted so far, `null` for links created before clicks were counted
+    clicks: Option<u64>,
+    /// Redirects left for `maxClicks` links, `null` for unlimited ones
+    clicks_left: Option<u64>,
+    /// Served counts of split test links, empty for other links
+    variants: Vec<VariantStats>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// A link of the logged in user, or any link for admin API keys
+    async fn link(&self, ctx: &Context<'_>, slug: String) -> async_graphql::Result<OwnedLink> {
+        let manager = manager(ctx);
+        manager.ensure_identified().extend()?;
+        let state = state(ctx);
+        let slug = state.slug(slug);
+        let (_, link) = load_link(state, &slug).await.extend()?;
+        manager.ensure_can_view(&link).extend()?;
+        let mut link = OwnedLink::new(state, slug, link);
+        count_clicks(state, std::slice::from_mut(&mut link))
+            .await
+            .extend()?;
+        load_metadata(state, std::slice::from_mut(&mut link))
+            .await
+            .extend()?;
+        Ok(link)
+    }
+
+    /// Links created by the logged in user
+    async fn my_links(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<OwnedLink>> {
+        let user_id = manager(ctx).user().extend()?;
+        owned_links(state(ctx), user_id).await.extend()
+    }
+
+    /// Links carrying `tag`, only the user's own ones unless it is an admin API key
+    async fn links_by_tag(
+        &self,
+        ctx: &Context<'_>,
+        tag: String,
+    ) -> async_graphql::Result<Vec<OwnedLink>> {
+        let manager = manager(ctx);
+        manager.ensure_identified().extend()?;
+        let tag = tag.trim().to_lowercase();
+        tagged_links(state(ctx), &tag, manager).await.extend()
+    }
+
+    /// Remaining clicks and split test counts of a link
+    async fn stats(&self, ctx: &Context<'_>, slug: String) -> async_graphql::Result<LinkStats> {
+        let manager = manager(ctx);
+        manager.ensure_identified().extend()?;
+        let state = state(ctx);
+        let slug = state.slug(slug);
+        let (_, link) = load_link(state, &slug).await.extend()?;
+        manager.ensure_can_view(&link).extend()?;
+        let clicks_left = clicks_left(state.store.as_ref(), &slug, &link)
+            .await
+            .map_err(ApiError::from)
+            .extend()?;
+        let clicks = total_clicks(state.store.as_ref(), std::slice::from_ref(&slug))
+            .await
+            .map_err(ApiError::from)
+            .extend()?
+            .pop()
+            .flatten();
+        let variants = variant_stats_of(state, &slug, link).await.extend()?;
+        Ok(LinkStats {
+            slug,
+            clicks,
+            clicks_left,
+            variants,
+        })
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Shortens a URL, takes the same options as `POST /shorten-url`
+    async fn create_link(
+        &self,
+        ctx: &Context<'_>,
+        input: UrlShortenOptions,
+    ) -> async_graphql::Result<UrlShortenData> {
+        let creator = ctx.data_unchecked::<Creator>();
+        create_link(state(ctx), input, creator).await.extend()
+    }
+
+    /// Changes a link like `PATCH /api/links/{slug}`
+    async fn update_link(
+        &self,
+        ctx: &Context<'_>,
+        slug: String,
+        input: UpdateLinkRequest,
+    ) -> async_graphql::Result<OwnedLink> {
+        let manager = manager(ctx);
+        manager.ensure_identified().extend()?;
+        let state = state(ctx);
+        change_link(state, state.slug(slug), input, manager)
+            .await
+            .extend()
+    }
+
+    /// Deletes a link of the logged in user
+    async fn delete_link(&self, ctx: &Context<'_>, slug: String) -> async_graphql::Result<bool> {
+        let user_id = manager(ctx).user().extend()?;
+        let state = state(ctx);
+        remove_link(state, &state.slug(slug), user_id, &manager(ctx).audit)
+            .await
+            .extend()?;
+        Ok(true)
+    }
+}
+
+/// Runs a GraphQL request, failures are reported in the `errors` of the response like any GraphQL server does
+#[post(
+    "/graphql",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_api_key)"
+)]
+async fn graphql(
+    req: HttpRequest,
+    body: JsonBody<async_graphql::Request>,
+    user: MaybeUser,
+    schema: Data<LinkSchema>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let manager = Manager::from_request(&req, user).await?;
+    let creator = Creator::from_request(&req, manager.user_id.clone());
+    let request = body.into_inner().data(state).data(manager).data(creator);
+    Ok(HttpResponse::Ok().json(schema.execute(request).await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_mirrors_the_rest_api() {
+        let sdl = schema().sdl();
+
+        assert!(sdl.contains("createLink(input: CreateLinkInput!): ShortenedLink!"));
+        assert!(sdl.contains("updateLink(slug: String!, input: UpdateLinkInput!): Link!"));
+        assert!(sdl.contains("queryPassthrough: QueryPassthrough! = OFF"));
+    }
+
+    #[tokio::test]
+    async fn test_errors_carry_the_api_error_code() {
+        let anonymous = Manager {
+            user_id: None,
+            admin: false,
+            tenant: None,
+            audit: Default::default(),
+        };
+        let response = schema()
+            .execute(async_graphql::Request::new("{ myLinks { slug } }").data(anonymous))
+            .await;
+
+        let error = serde_json::to_value(&response.errors[0]).unwrap();
+        assert_eq!(error["extensions"]["code"], "missing_token");
+        assert_eq!(error["path"][0], "myLinks");
+    }
+}
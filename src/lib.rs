@@ -0,0 +1,1654 @@
+//! URL shortener service. `UrlShortener` holds the shortening core, which other services can embed
+//! directly or mount as the HTTP API on their own actix `App`.
+
+use actix_web::middleware::Logger;
+use actix_web::web::Data;
+use actix_web::{
+    get,
+    http::{header, StatusCode},
+    post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer,
+    Responder,
+};
+use async_graphql::{InputObject, MaybeUndefined, SimpleObject};
+use chrono::{DateTime, Duration, Utc};
+use listenfd::ListenFd;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+mod url_shortener;
+use url_shortener::{slug_strategy, validate_alias};
+pub use url_shortener::{
+    CollisionPolicy, CounterSlugs, DeterministicSlugs, HashSlugs, RandomSlugs, SequentialSlugs,
+    SlugAlphabet, SlugStrategy, SlugStrategyKind, SnowflakeSlugs,
+};
+mod memory;
+pub use memory::MemoryStore;
+mod redis;
+pub use redis::{RedisConfig, RedisService};
+mod sqlite;
+pub use sqlite::{SqliteConfig, SqliteStore};
+mod breaker;
+pub use breaker::CircuitBreaker;
+mod tiered;
+pub use tiered::TieredStore;
+pub mod storage;
+use storage::get_store;
+pub use storage::{CountBatch, LinkWrites, StorageError, UrlStore};
+pub mod config;
+pub use config::AppConfig;
+pub mod error;
+pub use error::ApiError;
+mod expiration;
+use expiration::{compute_ttl, ExpirationError, TtlBounds};
+mod validation;
+use validation::validate_and_normalize;
+mod auth;
+use auth::{AuthConfig, AuthenticatedKey};
+mod ratelimit;
+mod reserved;
+mod short_domains;
+mod slug_filter;
+use short_domains::{link_key, ShortDomains};
+use slug_filter::SlugFilter;
+pub use slug_filter::{SlugFilterConfig, SlugFilterList};
+mod metrics;
+use metrics::{Counter, Metrics};
+mod statsd;
+mod threats;
+use statsd::{spawn_statsd_exporter, StatsdConfig};
+use threats::{spawn_threat_rescanner, threat_checker, ThreatChecker};
+mod shutdown;
+mod sso;
+use shutdown::shutdown_signal;
+pub use sso::SsoConfig;
+use sso::SsoTokens;
+mod consul;
+use consul::{ConsulConfig, ConsulRegistration};
+mod backup;
+pub use backup::RestoreSummary;
+mod batch;
+mod body;
+mod breakdown;
+use body::JsonBody;
+mod access;
+mod proxy;
+pub use proxy::TrustedProxies;
+mod card;
+pub mod cli;
+mod clicks;
+mod cloning;
+mod dedup;
+mod device;
+mod domains;
+mod dry_run;
+mod export;
+mod frontend;
+mod graphql;
+use domains::{spawn_domain_list_reloader, DomainLists};
+mod ownership;
+mod users;
+use users::{hash_password, owned_links_key, MaybeUser, SessionTokens};
+mod compression;
+mod deadline;
+mod deadlinks;
+mod shedding;
+use deadlinks::{spawn_dead_link_checker, DeadLinkChecker};
+mod expiry_events;
+use expiry_events::spawn_expiry_listener;
+pub mod link;
+mod link_cache;
+mod live_config;
+use live_config::{spawn_config_reloader, LiveSettings};
+mod metadata;
+use link_cache::LinkCache;
+pub use link_cache::LinkCacheConfig;
+use metadata::MetadataFetcher;
+pub use metadata::{LinkMetadata, LinkMetadataConfig};
+use reachability::ReachabilityCheck;
+mod moderation;
+mod pages;
+mod previews;
+mod protection;
+mod reachability;
+mod reports;
+mod reservations;
+mod split;
+mod tags;
+mod telemetry;
+mod tenants;
+mod timeseries;
+mod tls;
+mod unfurl;
+mod usage;
+mod visitors;
+use access::AccessRules;
+use device::DeviceType;
+use link::{DeviceTargets, Link, QueryPassthrough, UtmParams, Variant, REDIRECT_STATUSES};
+use tls::{spawn_cert_reloader, ReloadableCert};
+mod analytics;
+use analytics::Analytics;
+mod audit;
+use audit::{AuditAction, AuditContext};
+mod events;
+mod notifications;
+use events::EventPublisher;
+pub use events::{EventSink, LinkEvent};
+use notifications::spawn_expiry_notifier;
+
+#[get("/{path}")]
+async fn resolve(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = path.into_inner();
+    let followed = match state.visited_key(&req, &slug) {
+        Ok(key) => follow_link(&req, &state, &key).await,
+        Err(err) => Err(err),
+    };
+    followed.or_else(|err| match (&err, &state.settings.get().fallback_url) {
+        (ApiError::NotFound { .. }, Some(fallback_url)) => {
+            Ok(pages::fallback_redirect(fallback_url, &slug))
+        }
+        _ => pages::error_page(&req, &state, &slug, err),
+    })
+}
+
+/// Redirects the visitor, or answers with the password form or suspension page
+async fn follow_link(
+    req: &HttpRequest,
+    state: &AppState,
+    slug: &str,
+) -> Result<HttpResponse, ApiError> {
+    // Internal records (`apikey:`, `url:`, `clicks:`, ...) share the keyspace, slugs never contain ':'
+    if slug.contains(':') {
+        state.metrics.incr(Counter::ResolveMisses);
+        return Err(ApiError::not_found(slug));
+    }
+    let link = match link_cache::resolve_record(state, slug).await {
+        Ok(Some(raw_link)) => Link::decode(&raw_link),
+        Ok(None) => {
+            state.metrics.incr(Counter::ResolveMisses);
+            return Err(ApiError::not_found(slug));
+        }
+        Err(err) => {
+            state.metrics.incr(Counter::StorageErrors);
+            return Err(err.into());
+        }
+    };
+    if let Some(token) = previews::token(req) {
+        return previews::show(state, slug, link, &token).await;
+    }
+    if link.suspension.is_some() {
+        state.metrics.incr(Counter::ResolveMisses);
+        return Ok(moderation::suspended_page(slug));
+    }
+    ownership::ensure_enabled(slug, &link)?;
+    access::check(&link.access, proxy::client_ip(req))?;
+    // Previews neither need the password nor count as clicks
+    if unfurl::wants_preview(req, state) {
+        return unfurl::preview(state, slug, &link).await;
+    }
+    let query = match protection::unlock(req, state, slug, &link, None).await {
+        Ok(query) => query,
+        Err(response) => return Ok(response),
+    };
+    // Only counted once the visitor gets through the password form
+    clicks::consume_click(state, slug, &link).await?;
+
+    state.metrics.incr(Counter::ResolveHits);
+    clicks::record_click(state, req, slug, &link);
+    // Temporary redirect by default, permanent ones get cached by browsers which limits our ability to do analytics
+    let status = link
+        .redirect_status
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(state.redirect_status);
+    let mut response = HttpResponse::build(status);
+    redirect_to(&mut response, req, state, slug, &link, &query).await
+}
+
+/// Points `response` at the destination for this visitor, picking the device target or split test variant.
+/// Links that ask for it and destinations outside `TRUSTED_DOMAINS` get the interstitial page instead.
+async fn redirect_to(
+    response: &mut HttpResponseBuilder,
+    req: &HttpRequest,
+    state: &AppState,
+    slug: &str,
+    link: &Link,
+    query: &str,
+) -> Result<HttpResponse, ApiError> {
+    let device = device::from_request(req);
+    let variant = match split::choose(req, slug, link) {
+        Some((index, cookie)) => {
+            split::record_served(state, slug, index).await;
+            if let Some(cookie) = cookie {
+                response.cookie(cookie);
+            }
+            Some(index)
+        }
+        None => None,
+    };
+    let destination = link.destination(query, device, variant);
+    let interstitial = link.interstitial || !state.domains.is_trusted(&destination);
+    // Every visit of these links has to reach us, to pick a variant, count the click or check the password.
+    // A cached redirect would keep sending browsers to the variant they got first, or past a used up link.
+    if variant.is_some()
+        || link.max_clicks.is_some()
+        || link.password_hash.is_some()
+        || !link.access.is_empty()
+        || link.active_until.is_some()
+        || interstitial
+    {
+        response.append_header((header::CACHE_CONTROL, "private, no-store"));
+    } else if let Some(cache_control) = &state.redirect_cache_control {
+        response.append_header((header::CACHE_CONTROL, cache_control.as_str()));
+    }
+    if !link.device_targets.is_empty() {
+        // Caches must not hand the redirect for one device to another
+        response.append_header((header::VARY, "User-Agent"));
+    }
+    if interstitial {
+        let page = pages::interstitial_page(state, slug, &destination)?;
+        return Ok(response
+            .status(StatusCode::OK)
+            .content_type("text/html; charset=utf-8")
+            .body(page));
+    }
+    Ok(response
+        .append_header((header::LOCATION, destination))
+        .finish())
+}
+
+/// Liveness probe used by Consul and container orchestrators
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+#[derive(Default, Deserialize, InputObject)]
+#[graphql(name = "CreateLinkInput")]
+pub struct UrlShortenOptions {
+    /// Destination, left out for split test links
+    #[serde(default)]
+    #[graphql(default)]
+    pub url: String,
+    /// Whether the query string of the short URL request is carried over to the destination
+    #[serde(default)]
+    #[graphql(default)]
+    pub query_passthrough: QueryPassthrough,
+    /// Fragment appended to the destination when redirecting
+    pub fragment: Option<String>,
+    /// Lets clients override the fragment with a `_fragment` query parameter
+    #[serde(default)]
+    #[graphql(default)]
+    pub preserve_fragment_hint: bool,
+    /// Custom slug requested instead of a generated one
+    pub alias: Option<String>,
+    /// Domain to mint the link under, e.g. `go.corp.com`, defaults to `SHORTENER_DOMAIN` or the domain of the API key
+    pub domain: Option<String>,
+    /// Lifetime of the link, mutually exclusive with `expires_at`. An explicit `null` stores the link without
+    /// an expiry, which needs `ALLOW_PERMANENT_LINKS` or an API key allowed to create permanent links.
+    #[serde(default)]
+    pub expires_in_seconds: MaybeUndefined<u64>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Return the existing short URL if the same link was shortened before, defaults to `DEDUPLICATE_URLS`
+    pub deduplicate: Option<bool>,
+    /// Password visitors have to enter before being redirected
+    pub password: Option<String>,
+    /// Number of redirects after which the link answers `410 Gone`
+    pub max_clicks: Option<u64>,
+    /// `utm_source`, `utm_medium`, ... added to the destination when redirecting
+    #[serde(flatten)]
+    #[graphql(default)]
+    pub utm: UtmParams,
+    /// Alternate destinations for iOS, Android and desktop visitors
+    #[serde(default)]
+    #[graphql(default)]
+    pub device_targets: DeviceTargets,
+    /// Weighted destinations of a split test link, replaces `url`
+    #[serde(default)]
+    #[graphql(default)]
+    pub variants: Vec<Variant>,
+    /// Keeps serving visitors the variant they got first
+    #[serde(default)]
+    #[graphql(default)]
+    pub sticky_variants: bool,
+    /// Shows visitors a page naming the destination instead of redirecting right away
+    #[serde(default)]
+    #[graphql(default)]
+    pub interstitial: bool,
+    /// Status of the redirects, `301`, `302`, `307` or `308`, defaults to `REDIRECT_STATUS`
+    pub redirect_status: Option<u16>,
+    /// The link only redirects from this moment on, before it visitors get a "not yet live" page
+    pub active_from: Option<DateTime<Utc>>,
+    /// The link stops redirecting at this moment and answers like an expired link
+    pub active_until: Option<DateTime<Utc>>,
+    /// Checks the destination answers before creating the link, a broken one is reported in `warning`
+    #[serde(default)]
+    #[graphql(default)]
+    pub verify: bool,
+    /// Labels for finding the link with `GET /api/links?tag=`
+    #[serde(default)]
+    #[graphql(default)]
+    pub tags: Vec<String>,
+    /// IP addresses and CIDR networks the link can only be opened from
+    #[serde(default)]
+    #[graphql(default)]
+    pub allowed_ips: Vec<String>,
+    /// IP addresses and CIDR networks the link can't be opened from
+    #[serde(default)]
+    #[graphql(default)]
+    pub denied_ips: Vec<String>,
+}
+
+#[derive(Debug, Serialize, SimpleObject)]
+#[graphql(name = "ShortenedLink")]
+pub struct UrlShortenData {
+    pub short_url: String,
+    /// `null` for links that never expire
+    pub expires_at: Option<DateTime<Utc>>,
+    /// How the slug was found when the first candidates were taken, left out otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collisions: Option<CollisionReport>,
+    /// Why the destination looks broken, for requests with `verify`. The link is created anyway.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, SimpleObject)]
+pub struct CollisionReport {
+    /// `COLLISION_POLICY` the slug was found with, `retry` or `grow`
+    pub policy: String,
+    /// Candidates tried, the last one got the link
+    pub attempts: u32,
+}
+
+/// A shorten request that passed validation, ready to be stored
+struct PreparedLink {
+    /// Normalized destination, handed to the slug strategy
+    url: String,
+    /// Encoded `Link` record
+    link: String,
+    /// `None` for links that never expire
+    ttl: Option<usize>,
+    expires_at: Option<DateTime<Utc>>,
+    alias: Option<String>,
+    /// Host of the domain the link is minted under, `None` for the primary domain
+    host: Option<String>,
+    deduplicate: bool,
+    /// User the link is created for, `None` for anonymous requests
+    owner: Option<String>,
+    max_clicks: Option<u64>,
+    /// Number of split test variants
+    variants: usize,
+    tags: Vec<String>,
+    /// Whether the destination is checked with `ReachabilityCheck`
+    verify: bool,
+}
+
+impl PreparedLink {
+    /// Storage key of the link once it gets `slug`
+    fn key(&self, slug: &str) -> String {
+        link_key(slug, self.host.as_deref())
+    }
+
+    /// Counters stored next to the link under `slug` as `(key, value, ttl)`, they expire together with it.
+    /// The owner and tags of links with an expiry are kept a while longer.
+    fn counter_entries(&self, slug: &str) -> Vec<(String, String, Option<usize>)> {
+        let mut entries =
+            ownership::counter_entries(slug, self.max_clicks, self.variants, self.ttl);
+        entries.extend(expiry_events::indexes_entry(
+            slug,
+            self.owner.as_deref(),
+            &self.tags,
+            self.ttl,
+        ));
+        entries
+    }
+
+    /// Reverse index entry for deduplication, `None` when the link is not deduplicated
+    fn dedup_entry(&self, slug: &str) -> Option<(String, String, Option<usize>)> {
+        match (self.deduplicate, self.expires_at, self.ttl) {
+            (true, Some(expires_at), Some(ttl)) => {
+                Some(dedup::index_entry(&self.link, slug, expires_at, ttl))
+            }
+            _ => None,
+        }
+    }
+
+    /// Everything stored along with the link when it ends up under `slug`: its counters, the dedup entry and its
+    /// entries in the listings of its owner and tags and in the owner's indexes
+    fn link_writes(&self, slug: &str) -> LinkWrites {
+        let mut entries = self.counter_entries(slug);
+        entries.extend(self.dedup_entry(slug));
+        let set_members = self
+            .owner
+            .iter()
+            .map(|owner| owned_links_key(owner))
+            .chain(self.tags.iter().map(|tag| tags::tag_key(tag)))
+            .map(|key| (key, slug.to_string()))
+            .collect();
+        let scored_members = self
+            .owner
+            .iter()
+            .flat_map(|owner| ownership::index_entries(owner, slug, Utc::now()))
+            .collect();
+        LinkWrites {
+            entries,
+            set_members,
+            scored_members,
+        }
+    }
+
+    /// Announces the link once it is stored under `slug`
+    fn created_event(&self, slug: &str) -> LinkEvent {
+        LinkEvent::LinkCreated {
+            slug: slug.to_string(),
+            url: self.url.clone(),
+            owner: self.owner.clone(),
+            expires_at: self.expires_at,
+            at: Utc::now(),
+        }
+    }
+}
+
+/// Who a link is created for, and whether they may store it without an expiry
+#[derive(Clone, Debug, Default)]
+struct Creator {
+    /// User the link is created for, `None` for anonymous requests
+    owner: Option<String>,
+    /// Set for API keys allowed to create permanent links, regardless of `ALLOW_PERMANENT_LINKS`
+    permanent_links: bool,
+    /// Domain of API keys bound to one, their links can't be minted under another
+    domain: Option<String>,
+    /// Tenant of the API key, recorded on its links
+    tenant: Option<String>,
+    /// Id of the API key, its links and their redirects are counted for it
+    api_key: Option<String>,
+    /// Links the API key may create in total, `None` for unlimited. Keys of a tenant count against the quota
+    /// of the tenant instead, see `tenants::quota`.
+    quota: Option<u64>,
+    /// Links the API key may create per calendar month, `None` for unlimited
+    monthly_quota: Option<u64>,
+    /// Caller and id of the request, `None` for links created by embedding services, which aren't audited
+    audit: Option<AuditContext>,
+}
+
+impl Creator {
+    /// The caller of a shorten request that went through `require_api_key`, creating links for `owner`
+    fn from_request(req: &HttpRequest, owner: Option<String>) -> Self {
+        let Some(AuthenticatedKey { id, key }) =
+            req.extensions().get::<AuthenticatedKey>().cloned()
+        else {
+            return Creator {
+                audit: Some(AuditContext::from_request(req, owner.as_deref())),
+                owner,
+                ..Default::default()
+            };
+        };
+        let default_monthly_quota = req
+            .app_data::<Data<AppState>>()
+            .and_then(|state| state.auth.default_monthly_quota)
+            .filter(|_| !key.admin);
+        Creator {
+            audit: Some(AuditContext::from_request(req, owner.as_deref())),
+            owner,
+            permanent_links: key.may_create_permanent_links(),
+            domain: key.domain,
+            tenant: key.tenant,
+            api_key: Some(id),
+            quota: key.quota,
+            monthly_quota: key.monthly_quota.or(default_monthly_quota),
+        }
+    }
+}
+
+/// Hashes the password of a protected link on the blocking pool, Argon2 is deliberately slow
+async fn hash_link_password(password: String) -> Result<String, ApiError> {
+    if password.is_empty() || password.len() > 1024 {
+        return Err(ApiError::validation(
+            "invalid_password",
+            "Password must be between 1 and 1024 characters long",
+        ));
+    }
+    web::block(move || hash_password(&password))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to hash link password: {}", err)))?
+        .map_err(|err| ApiError::Internal(format!("Failed to hash link password: {}", err)))
+}
+
+/// Validates and normalizes a destination of a new link, then checks it against the domain lists and threat checker
+async fn check_destination_url(
+    state: &AppState,
+    url: &str,
+    device: Option<DeviceType>,
+) -> Result<String, ApiError> {
+    let url = validate_and_normalize(url, state.max_url_length).map_err(|err| match device {
+        Some(device) => ApiError::validation(
+            "invalid_url",
+            format!("{} destination: {}", device.name(), err),
+        ),
+        None => ApiError::validation("invalid_url", err.to_string()),
+    })?;
+    state.short_domains.ensure_not_short_link(&url)?;
+    state.domains.check_url(&url)?;
+    threats::check_destination(state, &url).await?;
+    Ok(url)
+}
+
+/// Host of the domain a link of `creator` is minted under, the requested domain or the one the API key is
+/// bound to. `None` for the primary domain.
+fn link_host(
+    state: &AppState,
+    requested: Option<&str>,
+    creator: &Creator,
+) -> Result<Option<String>, ApiError> {
+    let requested = requested
+        .map(|domain| state.short_domains.lookup(domain))
+        .transpose()?;
+    let bound = creator
+        .domain
+        .as_deref()
+        .map(|domain| state.short_domains.lookup(domain))
+        .transpose()?;
+    match (requested, bound) {
+        (Some(requested), Some(bound)) if requested != bound => Err(ApiError::Forbidden {
+            message: "This API key can only create links on its own domain.".to_string(),
+        }),
+        (requested, bound) => Ok(requested.or(bound).flatten()),
+    }
+}
+
+/// Validates the destination and expiry of a shorten request and encodes the link record to store
+async fn prepare_link(
+    options: UrlShortenOptions,
+    creator: &Creator,
+    state: &AppState,
+    now: DateTime<Utc>,
+) -> Result<PreparedLink, ApiError> {
+    let UrlShortenOptions {
+        url,
+        query_passthrough,
+        fragment,
+        preserve_fragment_hint,
+        alias,
+        domain,
+        expires_in_seconds,
+        expires_at,
+        deduplicate,
+        password,
+        max_clicks,
+        utm,
+        device_targets,
+        variants,
+        sticky_variants,
+        interstitial,
+        redirect_status,
+        active_from,
+        active_until,
+        verify,
+        tags,
+        allowed_ips,
+        denied_ips,
+    } = options;
+
+    if max_clicks == Some(0) {
+        return Err(ApiError::validation(
+            "invalid_max_clicks",
+            "max_clicks must be at least 1",
+        ));
+    }
+    if redirect_status.is_some_and(|status| !REDIRECT_STATUSES.contains(&status)) {
+        return Err(ApiError::validation(
+            "invalid_redirect_status",
+            "redirect_status must be one of 301, 302, 307 or 308",
+        ));
+    }
+    if active_until
+        .is_some_and(|until| until <= now || active_from.is_some_and(|from| from >= until))
+    {
+        return Err(ApiError::validation(
+            "invalid_schedule",
+            "active_until must be in the future and after active_from",
+        ));
+    }
+
+    let host = link_host(state, domain.as_deref(), creator)?;
+
+    let utm = utm
+        .normalize()
+        .map_err(|message| ApiError::validation("invalid_utm", message))?;
+    let tags = tags::normalize_tags(tags)
+        .map_err(|message| ApiError::validation("invalid_tags", message))?;
+    let access = AccessRules::parse(&allowed_ips, &denied_ips)
+        .map_err(|message| ApiError::validation("invalid_access_rules", message))?;
+
+    if !variants.is_empty() {
+        if !url.is_empty() {
+            return Err(ApiError::validation(
+                "invalid_variants",
+                "Pass either url or variants, not both",
+            ));
+        }
+        split::validate_variants(&variants)
+            .map_err(|message| ApiError::validation("invalid_variants", message))?;
+    }
+    let mut checked_variants = Vec::with_capacity(variants.len());
+    for variant in variants {
+        checked_variants.push(Variant {
+            url: check_destination_url(state, &variant.url, None).await?,
+            weight: variant.weight,
+        });
+    }
+    // The first variant stands in for the link wherever a single destination is needed, e.g. previews
+    let url = match checked_variants.first() {
+        Some(first) => first.url.clone(),
+        None => check_destination_url(state, &url, None).await?,
+    };
+    let variants = checked_variants.len();
+    let mut targets = DeviceTargets::default();
+    for device in [DeviceType::Ios, DeviceType::Android, DeviceType::Desktop] {
+        if let Some(target) = device_targets.get(device) {
+            *targets.get_mut(device) =
+                Some(check_destination_url(state, target, Some(device)).await?);
+        }
+    }
+    let link = Link {
+        url: url.clone(),
+        query_passthrough,
+        fragment: fragment
+            .map(|fragment| fragment.trim_start_matches('#').to_string())
+            .filter(|fragment| !fragment.is_empty()),
+        preserve_fragment_hint,
+        domain: host.clone(),
+        tenant: creator.tenant.clone(),
+        api_key: creator.api_key.clone(),
+        owner: creator.owner.clone(),
+        password_hash: match password {
+            Some(password) => Some(hash_link_password(password).await?),
+            None => None,
+        },
+        max_clicks,
+        disabled: false,
+        suspension: None,
+        utm,
+        access,
+        device_targets: targets,
+        sticky_variants: sticky_variants && !checked_variants.is_empty(),
+        variants: checked_variants,
+        interstitial,
+        redirect_status,
+        active_from,
+        active_until,
+        tags: tags.clone(),
+        reserved: false,
+    }
+    .encode();
+
+    let ttl = if expires_in_seconds.is_null() {
+        if expires_at.is_some() {
+            return Err(ApiError::validation(
+                "invalid_expiration",
+                ExpirationError::BothProvided.to_string(),
+            ));
+        }
+        if !state.allow_permanent_links && !creator.permanent_links {
+            return Err(ApiError::Forbidden {
+                message: "Links without an expiry need an API key allowed to create them."
+                    .to_string(),
+            });
+        }
+        None
+    } else {
+        let ttl = compute_ttl(
+            expires_in_seconds.value().copied(),
+            expires_at,
+            now,
+            state.default_ttl_seconds,
+            state.ttl_bounds,
+        )
+        .map_err(|err| ApiError::validation("invalid_expiration", err.to_string()))?;
+        Some(ttl)
+    };
+
+    Ok(PreparedLink {
+        url,
+        link,
+        ttl,
+        expires_at: ttl.map(|ttl| now + Duration::seconds(ttl as i64)),
+        alias: alias.map(|alias| state.slug(alias)),
+        host,
+        // A used up link must not be handed out again, and every split test keeps its own stats.
+        // Permanent links could otherwise be answered with an existing link that expires.
+        deduplicate: deduplicate.unwrap_or(state.deduplicate)
+            && max_clicks.is_none()
+            && variants == 0
+            && ttl.is_some(),
+        owner: creator.owner.clone(),
+        max_clicks,
+        variants,
+        tags,
+        verify,
+    })
+}
+
+#[post(
+    "/shorten-url",
+    wrap = "actix_web::middleware::from_fn(ratelimit::rate_limit)",
+    wrap = "actix_web::middleware::from_fn(auth::require_api_key)"
+)]
+async fn shorten_url(
+    req: HttpRequest,
+    req_body: JsonBody<UrlShortenOptions>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let creator = Creator::from_request(&req, user.0.map(|user| user.id));
+    let created = create_link(&state, req_body.into_inner(), &creator).await?;
+    Ok(HttpResponse::Ok().json(created))
+}
+
+/// Validates and stores a new link, shared by the REST and GraphQL APIs
+async fn create_link(
+    state: &AppState,
+    options: UrlShortenOptions,
+    creator: &Creator,
+) -> Result<UrlShortenData, ApiError> {
+    state.metrics.incr(Counter::ShortenRequests);
+    let prepared = prepare_link(options, creator, state, Utc::now()).await?;
+    let warning = match prepared.verify {
+        true => state.reachability.check(&prepared.url).await,
+        false => None,
+    };
+    let mut created = store_link(state, prepared, creator).await?;
+    created.warning = warning;
+    Ok(created)
+}
+
+/// Stores a prepared link under its alias or a generated slug, with its counters and index entries
+async fn store_link(
+    state: &AppState,
+    mut prepared: PreparedLink,
+    creator: &Creator,
+) -> Result<UrlShortenData, ApiError> {
+    let storage_error = |_: &StorageError| state.metrics.incr(Counter::StorageErrors);
+
+    if let Some(alias) = prepared.alias.take() {
+        return shorten_with_alias(alias, &prepared, creator, state).await;
+    }
+
+    if prepared.deduplicate {
+        let found = dedup::find_existing(state.store.as_ref(), &[&prepared.link])
+            .await
+            .inspect_err(storage_error)?;
+        if let Some(existing) = found.into_iter().next().flatten() {
+            return Ok(UrlShortenData {
+                short_url: state.short_url(&existing.slug),
+                expires_at: Some(existing.expires_at),
+                collisions: None,
+                warning: None,
+            });
+        }
+    }
+
+    usage::claim_link(state, creator).await?;
+    let max_attempts = state.collision_attempts();
+    // Candidates are written with a single `set_first`, so resolving collisions costs one round trip
+    // instead of one per attempt. Strategies that can't take candidates ahead get a round trip each.
+    let per_round = if state.slugs.batches_candidates() {
+        max_attempts
+    } else {
+        1
+    };
+    let mut stored_as = None;
+    let mut attempt = 0;
+    while stored_as.is_none() && attempt < max_attempts {
+        let mut keys = Vec::new();
+        let mut attempts = Vec::new();
+        while attempt < max_attempts && keys.len() < per_round as usize {
+            attempt += 1;
+            let slug = state
+                .slugs
+                .next_slug(&prepared.url, attempt)
+                .await
+                .inspect_err(storage_error)?;
+            let slug = state
+                .collision_policy
+                .candidate(slug, attempt, state.slug_alphabet);
+            if state.settings.get().reserved_slugs.is_reserved(&slug) {
+                // Treated like a collision, so running out of attempts still ends in a 508
+                log::warn!("Generated slug '{}' is reserved, retrying", slug);
+                continue;
+            }
+            if state.filters_slug(&slug) {
+                continue;
+            }
+            keys.push(prepared.key(&slug));
+            attempts.push(attempt);
+        }
+        if keys.is_empty() {
+            continue;
+        }
+        // The link is stored with its records in one round trip, so it is never seen without them
+        let writes: Vec<LinkWrites> = keys.iter().map(|key| prepared.link_writes(key)).collect();
+        let stored = state
+            .store
+            .set_first_with(&keys, &prepared.link, prepared.ttl, &writes)
+            .await
+            .inspect_err(storage_error)?;
+        if stored.is_none() && state.slugs.shares_slugs() {
+            if let Some(shared) = shared_link(state, &keys, &prepared.link)
+                .await
+                .inspect_err(storage_error)?
+            {
+                usage::release_link(state, creator).await;
+                return Ok(shared);
+            }
+        }
+        let collisions = stored.unwrap_or(keys.len());
+        if collisions > 0 {
+            state
+                .metrics
+                .add(Counter::ShortenCollisions, collisions as u64);
+            log::warn!(
+                "{} collisions detected by attempt {} for URL: {}",
+                collisions,
+                attempt,
+                prepared.url
+            );
+        }
+        stored_as = stored.map(|index| (keys.swap_remove(index), attempts[index]));
+    }
+
+    let Some((short_url, attempts)) = stored_as else {
+        usage::release_link(state, creator).await;
+        state.metrics.incr(Counter::ShortenFailures);
+        log::error!(
+            "Failed to generate unique short URL after {} attempts for URL: {}",
+            max_attempts,
+            prepared.url
+        );
+        return Err(ApiError::Collision {
+            attempts: max_attempts,
+            url: prepared.url,
+        });
+    };
+
+    state.publish(prepared.created_event(&short_url));
+    state
+        .metadata
+        .fetch_later(&short_url, &prepared.url, prepared.ttl);
+    audit_created(state, creator, &short_url).await;
+
+    Ok(UrlShortenData {
+        short_url: state.short_url(&short_url),
+        expires_at: prepared.expires_at,
+        collisions: state.collision_report(attempts),
+        warning: None,
+    })
+}
+
+/// The first of `keys` already holding exactly `link`, for strategies that hand identical links the same slug
+async fn shared_link(
+    state: &AppState,
+    keys: &[String],
+    link: &str,
+) -> Result<Option<UrlShortenData>, StorageError> {
+    let records = state.store.get_many(keys).await?;
+    let Some(key) = keys
+        .iter()
+        .zip(records)
+        .find_map(|(key, record)| (record.as_deref() == Some(link)).then_some(key))
+    else {
+        return Ok(None);
+    };
+    let ttl = state.store.ttl_many(std::slice::from_ref(key)).await?;
+    Ok(Some(UrlShortenData {
+        short_url: state.short_url(key),
+        expires_at: ttl
+            .into_iter()
+            .next()
+            .flatten()
+            .map(|seconds| Utc::now() + Duration::seconds(seconds as i64)),
+        collisions: None,
+        warning: None,
+    }))
+}
+
+/// Records a link stored under `key` in the audit log
+async fn audit_created(state: &AppState, creator: &Creator, key: &str) {
+    if let Some(context) = &creator.audit {
+        audit::record(
+            state,
+            context,
+            AuditAction::LinkCreated,
+            key,
+            creator.tenant.as_deref(),
+        )
+        .await;
+    }
+}
+
+/// Stores the counters kept next to a new link
+async fn start_counters(
+    state: &AppState,
+    slug: &str,
+    prepared: &PreparedLink,
+) -> Result<(), ApiError> {
+    let counters = prepared.counter_entries(slug);
+    state.store.set_many(&counters).await.inspect_err(|_| {
+        state.metrics.incr(Counter::StorageErrors);
+    })?;
+    Ok(())
+}
+
+/// Stores the link under a user chosen slug, there is no collision resolution since the user asked for this exact slug
+async fn shorten_with_alias(
+    alias: String,
+    prepared: &PreparedLink,
+    creator: &Creator,
+    state: &AppState,
+) -> Result<UrlShortenData, ApiError> {
+    let storage_error = |_: &StorageError| state.metrics.incr(Counter::StorageErrors);
+    if let Err(err) = validate_alias(&alias, &state.settings.get().reserved_slugs) {
+        return Err(ApiError::InvalidAlias {
+            message: err.to_string(),
+            alias,
+        });
+    }
+
+    let key = prepared.key(&alias);
+    usage::claim_link(state, creator).await?;
+    let stored = state
+        .store
+        .set_first_with(
+            std::slice::from_ref(&key),
+            &prepared.link,
+            prepared.ttl,
+            &[prepared.link_writes(&key)],
+        )
+        .await
+        .inspect_err(storage_error);
+    if !stored.as_ref().is_ok_and(|stored| stored.is_some()) {
+        usage::release_link(state, creator).await;
+        stored?;
+        return Err(ApiError::AliasTaken { alias });
+    }
+    state.publish(prepared.created_event(&key));
+    state
+        .metadata
+        .fetch_later(&key, &prepared.url, prepared.ttl);
+    audit_created(state, creator, &key).await;
+    Ok(UrlShortenData {
+        short_url: state.short_url(&key),
+        expires_at: prepared.expires_at,
+        collisions: None,
+        warning: None,
+    })
+}
+
+struct AppState {
+    short_domains: ShortDomains,
+    store: Arc<dyn UrlStore>,
+    default_ttl_seconds: usize,
+    ttl_bounds: TtlBounds,
+    max_collision_attempts: u32,
+    collision_policy: CollisionPolicy,
+    /// Alphabet of generated slugs, for the characters `CollisionPolicy::Grow` adds
+    slug_alphabet: SlugAlphabet,
+    slugs: Arc<dyn SlugStrategy>,
+    domains: Arc<DomainLists>,
+    /// `None` when threat checks are disabled
+    threats: Option<Arc<dyn ThreatChecker>>,
+    max_batch_size: usize,
+    max_url_length: usize,
+    max_body_bytes: usize,
+    slug_filter: SlugFilter,
+    /// Fetches titles and icons of new links' destinations, when `LINK_METADATA` is on
+    metadata: MetadataFetcher,
+    /// Checks destinations of links created with `verify`, and those of stored links for the dead link checker
+    reachability: ReachabilityCheck,
+    dead_links: DeadLinkChecker,
+    /// Expired links are announced as Redis expires them, see `expiry_events`
+    expiry_events: bool,
+    deduplicate: bool,
+    case_insensitive_slugs: bool,
+    /// Lets anyone store links without an expiry, not only API keys allowed to
+    allow_permanent_links: bool,
+    sessions: SessionTokens,
+    /// Verifies tokens of the identity provider, `None` when `JWT_JWKS_URL` is unset
+    sso: Option<SsoTokens>,
+    redirect_status: StatusCode,
+    redirect_cache_control: Option<String>,
+    /// Proxies whose forwarding headers name the client
+    trusted_proxies: TrustedProxies,
+    interstitial_delay_seconds: u64,
+    /// Answers link preview bots with Open Graph tags instead of the redirect
+    unfurl_crawlers: bool,
+    auth: AuthConfig,
+    /// Rate limits, reserved slugs and the fallback URL, which change with a config reload
+    settings: LiveSettings,
+    metrics: Arc<Metrics>,
+    analytics: Analytics,
+    /// `None` when no event sink is configured
+    events: Option<EventPublisher>,
+    /// Newest entries kept in the audit log
+    audit_log_max_entries: usize,
+    preview_token_ttl_seconds: u64,
+    /// Abuse reports accepted per IP and hour
+    report_rate_limit: u64,
+    /// `REQUEST_TIMEOUT_MS`, `None` when requests have no deadline
+    request_timeout: Option<std::time::Duration>,
+    /// Recently resolved links
+    link_cache: LinkCache,
+}
+
+impl AppState {
+    /// Candidates tried for a generated slug before giving up with `508`
+    fn collision_attempts(&self) -> u32 {
+        self.collision_policy.attempts(self.max_collision_attempts)
+    }
+
+    /// Whether a generated slug is rejected by the slug filter and has to be regenerated
+    fn filters_slug(&self, slug: &str) -> bool {
+        let filtered = self.slug_filter.rejects(slug);
+        if filtered {
+            self.metrics.incr(Counter::FilteredSlugs);
+            log::debug!("Generated slug '{}' is filtered, regenerating", slug);
+        }
+        filtered
+    }
+
+    /// Response metadata of a link stored with the candidate of `attempt`, `None` for the first one
+    fn collision_report(&self, attempts: u32) -> Option<CollisionReport> {
+        (attempts > 1).then(|| CollisionReport {
+            policy: self.collision_policy.name().to_string(),
+            attempts,
+        })
+    }
+
+    /// A slug taken from a request as it is stored, lowercased when slugs are case-insensitive
+    fn slug(&self, slug: String) -> String {
+        if self.case_insensitive_slugs {
+            slug.to_ascii_lowercase()
+        } else {
+            slug
+        }
+    }
+
+    /// Short URL of the link stored under `key`
+    fn short_url(&self, key: &str) -> String {
+        self.short_domains.short_url(key)
+    }
+
+    /// Storage key of the link a visitor asked for with `slug`, by the domain the request came in on.
+    /// Links of the other domains are only found on their own domain.
+    fn visited_key(&self, req: &HttpRequest, slug: &str) -> Result<String, ApiError> {
+        let slug = self.slug(slug.to_string());
+        if slug.contains('@') {
+            return Err(ApiError::not_found(&slug));
+        }
+        let host = self
+            .short_domains
+            .request_host(req.connection_info().host())
+            .map(str::to_string);
+        Ok(link_key(&slug, host.as_deref()))
+    }
+
+    /// Queues a link event for the event sink, if there is one
+    fn publish(&self, event: LinkEvent) {
+        if let Some(events) = &self.events {
+            events.send(event);
+        }
+    }
+}
+
+/// Shortening core for embedding in other services, the HTTP API is a thin layer mounted with `configure`
+#[derive(Clone)]
+pub struct UrlShortener {
+    state: Data<AppState>,
+    schema: Data<graphql::LinkSchema>,
+}
+
+impl UrlShortener {
+    /// Connects to the storage backend selected in `config` and loads the domain lists
+    pub async fn from_config(config: &AppConfig) -> std::io::Result<Self> {
+        let store = get_store(config).await.map_err(|err| {
+            log::error!("Failed to connect to storage: {}", err);
+            std::io::Error::other(err.to_string())
+        })?;
+        Self::with_store(config, store).await
+    }
+
+    /// Uses `store` instead of the backend selected in `config`, e.g. a `MemoryStore` in tests
+    pub async fn with_store(config: &AppConfig, store: Arc<dyn UrlStore>) -> std::io::Result<Self> {
+        let events = match &config.events {
+            Some(sink_config) => Some(events::connect(sink_config).await.map_err(|err| {
+                log::error!("Failed to connect to the event sink: {}", err);
+                std::io::Error::other(err)
+            })?),
+            None => None,
+        };
+        Self::build(config, store, events).await
+    }
+
+    /// Publishes link events to `sink` instead of the broker selected in `config`
+    pub async fn with_event_sink(
+        config: &AppConfig,
+        store: Arc<dyn UrlStore>,
+        sink: Arc<dyn EventSink>,
+    ) -> std::io::Result<Self> {
+        Self::build(config, store, Some(sink)).await
+    }
+
+    async fn build(
+        config: &AppConfig,
+        store: Arc<dyn UrlStore>,
+        events: Option<Arc<dyn EventSink>>,
+    ) -> std::io::Result<Self> {
+        let domains = Arc::new(
+            DomainLists::load(config.domain_lists.clone(), store.as_ref())
+                .await
+                .map_err(|err| {
+                    log::error!("Failed to load domain lists: {}", err);
+                    std::io::Error::other(err)
+                })?,
+        );
+        let slug_filter = SlugFilter::load(&config.slug_filter).map_err(|err| {
+            log::error!("Failed to load the slug filter: {}", err);
+            std::io::Error::other(err)
+        })?;
+        let metadata = MetadataFetcher::new(&config.link_metadata, store.clone());
+        let metrics = Arc::new(Metrics::default());
+        let analytics = Analytics::start(config.analytics, store.clone(), metrics.clone());
+        let events = events.map(|sink| EventPublisher::start(sink, metrics.clone()));
+        let state = AppState {
+            short_domains: ShortDomains::new(config.domain.clone(), &config.extra_domains),
+            slugs: slug_strategy(
+                config.slug_strategy,
+                config.slug_length,
+                config.slug_alphabet(),
+                config.slug_block_size,
+                config.slug_worker_id,
+                &config.slug_hash_key,
+                store.clone(),
+            ),
+            domains,
+            threats: threat_checker(&config.threats),
+            store,
+            default_ttl_seconds: config.default_ttl_seconds,
+            ttl_bounds: config.ttl_bounds,
+            max_collision_attempts: config.max_collision_attempts,
+            collision_policy: config.collision_policy,
+            slug_alphabet: config.slug_alphabet(),
+            max_batch_size: config.max_batch_size,
+            max_url_length: config.max_url_length,
+            max_body_bytes: config.max_body_bytes,
+            slug_filter,
+            metadata,
+            reachability: ReachabilityCheck::new(config.verify_timeout),
+            dead_links: DeadLinkChecker::new(&config.dead_links),
+            expiry_events: config.redis_expiry_events,
+            deduplicate: config.deduplicate,
+            case_insensitive_slugs: config.case_insensitive_slugs,
+            allow_permanent_links: config.allow_permanent_links,
+            sessions: SessionTokens::from_config(&config.users),
+            sso: config.users.sso.clone().map(SsoTokens::new),
+            redirect_status: config.redirect_status,
+            redirect_cache_control: config.redirect_cache_control.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            interstitial_delay_seconds: config.interstitial_delay_seconds,
+            unfurl_crawlers: config.unfurl_crawlers,
+            auth: config.auth.clone(),
+            settings: LiveSettings::new(config),
+            metrics,
+            analytics,
+            events,
+            audit_log_max_entries: config.audit_log_max_entries,
+            preview_token_ttl_seconds: config.preview_token_ttl_seconds,
+            report_rate_limit: config.report_rate_limit,
+            request_timeout: config.server.request_timeout,
+            link_cache: LinkCache::new(&config.link_cache),
+        };
+        Ok(UrlShortener {
+            state: Data::new(state),
+            schema: Data::new(graphql::schema()),
+        })
+    }
+
+    /// Shortens a URL with the same validation as `POST /shorten-url`, the link has no owner.
+    /// Embedding services are trusted to store links without an expiry.
+    pub async fn shorten(&self, options: UrlShortenOptions) -> Result<UrlShortenData, ApiError> {
+        let creator = Creator {
+            permanent_links: true,
+            ..Default::default()
+        };
+        create_link(&self.state, options, &creator).await
+    }
+
+    /// Looks up the link behind a slug or a short URL minted by this service, without counting a click
+    pub async fn resolve(&self, slug: &str) -> Result<Link, ApiError> {
+        let key = self
+            .state
+            .short_domains
+            .key_of_short_url(slug)
+            .unwrap_or_else(|| slug.to_string());
+        let key = self.state.slug(key);
+        ownership::load_link(&self.state, &key)
+            .await
+            .map(|(_, link)| link)
+    }
+
+    /// Writes the clicks still waiting in the analytics buffer and publishes the pending link events,
+    /// then stops counting clicks and publishing events
+    pub async fn shutdown(&self) {
+        self.state.analytics.shutdown().await;
+        if let Some(events) = &self.state.events {
+            events.shutdown().await;
+        }
+    }
+
+    /// Drops the slugs of expired links from the link listings of users,
+    /// returns the number of users visited and the number of slugs dropped
+    pub async fn purge_expired(&self) -> Result<(usize, usize), ApiError> {
+        ownership::purge_expired(&self.state).await
+    }
+
+    /// Writes every link record to `out` as gzip compressed JSON lines, expiry included.
+    /// Returns the number of links written.
+    pub async fn backup(&self, out: impl std::io::Write) -> Result<usize, ApiError> {
+        backup::write_backup(&self.state, out).await
+    }
+
+    /// Stores the links of a backup written by `backup`, keeping links whose key is taken already
+    pub async fn restore(&self, backup: impl std::io::Read) -> Result<RestoreSummary, ApiError> {
+        backup::restore(&self.state, backup).await
+    }
+
+    /// Checks the destination of every stored link once, like the runs of the dead link checker do for a
+    /// sample. Returns the number of links that reached the failure threshold.
+    pub async fn check_dead_links(&self) -> Result<usize, ApiError> {
+        let (mut dead, mut cursor) = (0, 0);
+        loop {
+            let (next, found) = deadlinks::check_sample(&self.state, cursor).await?;
+            dead += found;
+            if next == 0 {
+                return Ok(dead);
+            }
+            cursor = next;
+        }
+    }
+
+    /// Mounts the HTTP API on an actix `App`, along with the state its handlers need
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.service(healthz)
+            .service(frontend::index)
+            .service(frontend::asset)
+            .service(metrics::prometheus_metrics)
+            .service(users::register)
+            .service(users::login)
+            .service(ownership::my_links)
+            .service(ownership::update_link)
+            .service(ownership::delete_link)
+            .service(tags::links_by_tag)
+            .service(export::export_links)
+            .service(split::variant_stats)
+            .service(timeseries::click_timeseries)
+            .service(breakdown::top_referrers)
+            .service(breakdown::device_split)
+            .service(previews::create_preview_token)
+            .service(cloning::clone_link)
+            .service(graphql::graphql)
+            .service(auth::create_key)
+            .service(auth::revoke_key)
+            .service(tenants::tenant_usage)
+            .service(tenants::set_tenant_quota)
+            .service(audit::audit_log)
+            .service(usage::my_usage)
+            .service(moderation::disable_link)
+            .service(moderation::enable_link)
+            .service(deadlinks::admin_links)
+            .service(backup::backup_links)
+            .service(backup::restore_links)
+            .service(live_config::active_config)
+            .service(reports::report_queue)
+            .service(reports::disable_reported_link)
+            .service(reports::dismiss_reports)
+            .service(reports::report_link)
+            .service(reservations::reserve_slug)
+            .service(reservations::alias_available)
+            .service(reservations::attach_url)
+            .service(card::social_card)
+            .service(resolve)
+            .service(shorten_url)
+            .service(dry_run::preview_shorten)
+            .service(batch::shorten_batch)
+            .service(batch::resolve_batch)
+            // Catch-all POST, has to come after every other POST route
+            .service(protection::unlock_link)
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(self.state.max_body_bytes)
+                    .error_handler(error::json_error_handler),
+            )
+            .app_data(
+                web::FormConfig::default()
+                    .limit(self.state.max_body_bytes)
+                    .error_handler(error::form_error_handler),
+            )
+            .app_data(web::QueryConfig::default().error_handler(error::query_error_handler))
+            .app_data(self.state.clone())
+            .app_data(self.schema.clone());
+    }
+}
+
+/// Runs the HTTP server until a shutdown signal arrives
+pub async fn serve(config: AppConfig) -> std::io::Result<()> {
+    log::info!("Starting URL Shortener service");
+    let tracer_provider = telemetry::init_tracer_from_env().map_err(std::io::Error::other)?;
+    if tracer_provider.is_some() {
+        log::info!("Exporting traces over OTLP");
+    }
+    let shortener = UrlShortener::from_config(&config).await?;
+    let state = shortener.state.clone();
+    if config.link_cache.warmup > 0 {
+        match link_cache::warm_up(&state, config.link_cache.warmup).await {
+            Ok(warmed) => log::info!("Warmed up the link cache with {} links", warmed),
+            Err(err) => log::warn!("Failed to warm up the link cache: {}", err),
+        }
+    }
+    let statsd = match StatsdConfig::from_env() {
+        Some(statsd_config) => {
+            Some(spawn_statsd_exporter(statsd_config, state.metrics.clone()).await?)
+        }
+        None => None,
+    };
+    let domain_list_reloader =
+        spawn_domain_list_reloader(state.domains.clone(), state.store.clone());
+    let config_reloader = spawn_config_reloader(state.clone());
+
+    let threat_rescanner = spawn_threat_rescanner(state.clone(), &config.threats);
+    let dead_link_checker = spawn_dead_link_checker(state.clone(), &config.dead_links);
+    let expiry_listener =
+        spawn_expiry_listener(state.clone(), &config.redis, config.redis_expiry_events);
+    let expiry_notifier =
+        spawn_expiry_notifier(state.clone(), &config.expiry_notices).map_err(|err| {
+            log::error!("Failed to set up expiry notices: {}", err);
+            std::io::Error::other(err)
+        })?;
+
+    // Loaded before binding so a broken certificate stops startup instead of failing every handshake
+    let tls = match &config.tls {
+        Some(tls_config) => {
+            let cert = ReloadableCert::load(tls_config.clone()).map_err(std::io::Error::other)?;
+            let server_config = cert.server_config().map_err(std::io::Error::other)?;
+            Some((server_config, spawn_cert_reloader(cert)?))
+        }
+        None => None,
+    };
+
+    // Under systemd socket activation the socket is already bound and handed over as fd 3,
+    // which keeps it open across restarts so no connection is refused while we start up
+    let inherited_listener = ListenFd::from_env().take_tcp_listener(0)?;
+    let port = match &inherited_listener {
+        Some(listener) => listener.local_addr()?.port(),
+        None => config.bind_addr.port(),
+    };
+    let consul = ConsulConfig::from_env(port).map(ConsulRegistration::new);
+    if let Some(consul) = &consul {
+        if let Err(err) = consul.register().await {
+            log::error!("Failed to register service with Consul: {}", err);
+        }
+    }
+
+    let compress = config.server.compress;
+    let tuning = config.server.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .configure(|cfg| shortener.configure(cfg))
+            // Built per worker, every worker gets limits of its own
+            .app_data(Data::new(shedding::LoadShedder::new(&tuning)))
+            .app_data(Data::new(tuning.compression))
+            .wrap(actix_web::middleware::Condition::new(
+                compress,
+                actix_web::middleware::from_fn(compression::compress),
+            ))
+            // Inside the request id, tracing and logging, so shed requests get an id and show up like any other
+            .wrap(actix_web::middleware::from_fn(shedding::shed_load))
+            .wrap(actix_web::middleware::from_fn(audit::request_id))
+            .wrap(actix_web::middleware::from_fn(telemetry::trace_request))
+            .wrap(Logger::default())
+            .wrap(Logger::new("%a %{User-Agent}i"))
+            .wrap(actix_web::middleware::from_fn(deadline::request_deadline))
+    })
+    // Signals are handled below, so Consul deregistration happens before draining starts
+    .disable_signals()
+    .shutdown_timeout(config.shutdown_timeout_seconds)
+    .keep_alive(config.server.keep_alive)
+    .client_request_timeout(config.server.client_request_timeout)
+    .max_connections(config.server.max_connections)
+    // Applies to sockets bound below, an inherited socket keeps the backlog it was created with
+    .backlog(config.server.backlog);
+    let server = match config.server.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let scheme = if tls.is_some() { "HTTPS" } else { "HTTP" };
+    let server_config = tls.as_ref().map(|(server_config, _)| server_config.clone());
+    let server = match inherited_listener {
+        Some(listener) => {
+            log::info!(
+                "{} server listening on inherited socket {}",
+                scheme,
+                listener.local_addr()?
+            );
+            match server_config {
+                Some(server_config) => server.listen_rustls_0_23(listener, server_config)?,
+                None => server.listen(listener)?,
+            }
+        }
+        None => {
+            log::info!("{} server binding on {}", scheme, config.bind_addr);
+            match server_config {
+                Some(server_config) => server.bind_rustls_0_23(config.bind_addr, server_config)?,
+                None => server.bind(config.bind_addr)?,
+            }
+        }
+    };
+    let server = server.run();
+    let handle = server.handle();
+    let signal = shutdown_signal()?;
+    let shutdown_timeout_seconds = config.shutdown_timeout_seconds;
+    actix_web::rt::spawn(async move {
+        let signal = signal.await;
+        log::info!(
+            "Received {}, draining connections for up to {}s",
+            signal,
+            shutdown_timeout_seconds
+        );
+        // Leave the catalog first so no new traffic is routed here while draining
+        if let Some(consul) = &consul {
+            if let Err(err) = consul.deregister().await {
+                log::error!("Failed to deregister service from Consul: {}", err);
+            }
+        }
+        // Stops accepting, then waits for in-flight requests up to the shutdown timeout
+        handle.stop(true).await;
+    });
+    let result = server.await;
+
+    // Before the StatsD flush, so clicks dropped in the last moment are still reported
+    state.analytics.shutdown().await;
+    if let Some(events) = &state.events {
+        events.shutdown().await;
+    }
+    if let Some(statsd) = statsd {
+        statsd.shutdown().await;
+    }
+    domain_list_reloader.abort();
+    if let Some(reloader) = config_reloader {
+        reloader.abort();
+    }
+    if let Some(rescanner) = threat_rescanner {
+        rescanner.abort();
+    }
+    if let Some(checker) = dead_link_checker {
+        checker.abort();
+    }
+    if let Some(listener) = expiry_listener {
+        listener.abort();
+    }
+    if let Some(notifier) = expiry_notifier {
+        notifier.abort();
+    }
+    if let Some((_, cert_reloader)) = tls {
+        cert_reloader.abort();
+    }
+    if let Some(provider) = tracer_provider {
+        // Exports the spans still buffered, blocking until the collector answered
+        let flushed = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+        if let Ok(Err(err)) = flushed {
+            log::error!("Failed to export the remaining traces: {}", err);
+        }
+    }
+    // The workers are gone, dropping the last reference to the store closes its connections
+    drop(state);
+    log::info!("Shutdown complete");
+    result
+}
+
+#[cfg(test)]
+mod e2e_tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::url_shortener::{RandomSlugs, SlugAlphabet, DEFAULT_SLUG_LENGTH};
+
+    struct TestApp {
+        store: Arc<dyn UrlStore>,
+    }
+
+    impl TestApp {
+        async fn new() -> Self {
+            // In-memory store so the E2E tests don't need a running Redis
+            let store = Arc::new(MemoryStore::new());
+
+            TestApp { store }
+        }
+
+        async fn cleanup_store(&self) {
+            // Clean up all test data from the store
+            let _ = self.store.cleanup().await;
+        }
+    }
+
+    impl Clone for TestApp {
+        fn clone(&self) -> Self {
+            TestApp {
+                store: self.store.clone(),
+            }
+        }
+    }
+
+    // Test setup and teardown functions
+    async fn setup_test() -> TestApp {
+        let test_app = TestApp::new().await;
+        // Clean up the store before each test
+        test_app.cleanup_store().await;
+        test_app
+    }
+
+    async fn teardown_test(test_app: TestApp) {
+        // Clean up the store after each test
+        test_app.cleanup_store().await;
+    }
+
+    #[tokio::test]
+    async fn test_url_shortening_flow() {
+        let test_app = setup_test().await;
+
+        // Use a real external URL for testing
+        let target_url = "https://httpbin.org/get";
+
+        // Step 1: Test URL shortening logic directly
+        let shortened_url = RandomSlugs {
+            length: DEFAULT_SLUG_LENGTH,
+            alphabet: SlugAlphabet::Base62,
+        }
+        .next_slug(target_url, 1)
+        .await
+        .unwrap();
+
+        // Verify the shortened URL format
+        assert!(!shortened_url.contains(target_url));
+
+        let save_result = test_app
+            .store
+            .set(&shortened_url, target_url, Some(60 * 60 * 24))
+            .await;
+        assert!(save_result.is_ok());
+        assert!(
+            save_result.unwrap(),
+            "Key should have been set successfully"
+        );
+
+        // Step 3: Test store retrieval
+        let retrieved_url = test_app.store.get(shortened_url.as_str()).await;
+        assert!(retrieved_url.is_ok());
+        assert_eq!(retrieved_url.unwrap(), Some(target_url.to_string()));
+
+        teardown_test(test_app).await;
+    }
+
+    #[tokio::test]
+    async fn test_url_shortening_with_different_urls() {
+        let test_app = setup_test().await;
+
+        // Test multiple URLs
+        let test_urls = vec![
+            "https://httpbin.org/get",
+            "https://httpbin.org/status/200",
+            "https://httpbin.org/headers",
+        ];
+
+        for test_url in test_urls {
+            // Test URL shortening logic
+            let shortened_url = RandomSlugs {
+                length: DEFAULT_SLUG_LENGTH,
+                alphabet: SlugAlphabet::Base62,
+            }
+            .next_slug(test_url, 1)
+            .await
+            .unwrap();
+
+            // Extract short code
+            // Test storage and retrieval
+            let save_result = test_app
+                .store
+                .set(&shortened_url, test_url, Some(60 * 60 * 24))
+                .await;
+            assert!(save_result.is_ok());
+            assert!(
+                save_result.unwrap(),
+                "Key should have been set successfully"
+            );
+
+            let retrieved_url = test_app.store.get(shortened_url.as_str()).await;
+            assert!(retrieved_url.is_ok());
+            assert_eq!(retrieved_url.unwrap(), Some(test_url.to_string()));
+        }
+
+        teardown_test(test_app).await;
+    }
+
+    #[tokio::test]
+    async fn test_nonexistent_short_url() {
+        let test_app = setup_test().await;
+
+        // Test retrieval of non-existent key
+        let retrieved_url = test_app.store.get("nonexistent").await;
+        assert!(retrieved_url.is_ok());
+        assert_eq!(retrieved_url.unwrap(), None);
+
+        teardown_test(test_app).await;
+    }
+}
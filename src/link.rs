@@ -0,0 +1,603 @@
+use async_graphql::{Enum, InputObject};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::access::AccessRules;
+use crate::device::DeviceType;
+
+/// How the query string of the incoming short URL request is merged into the destination
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryPassthrough {
+    /// Incoming query parameters are dropped
+    #[default]
+    Off,
+    /// Incoming parameters are added unless the destination already defines them
+    KeepDestination,
+    /// Incoming parameters replace destination parameters with the same name
+    PreferRequest,
+    /// Incoming parameters are appended, duplicates included
+    Append,
+}
+
+impl QueryPassthrough {
+    fn is_off(&self) -> bool {
+        *self == QueryPassthrough::Off
+    }
+}
+
+/// Query parameter clients use to hand over a fragment, browsers never send `#...` to the server
+pub const FRAGMENT_HINT_PARAM: &str = "_fragment";
+
+/// Longest accepted UTM value
+pub const MAX_UTM_LENGTH: usize = 256;
+
+/// Where a moment falls in the window a scheduled link is live in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// Before `active_from`
+    Pending,
+    Live,
+    /// After `active_until`
+    Ended,
+}
+
+/// Statuses redirects can be answered with, by `REDIRECT_STATUS` or per link
+pub const REDIRECT_STATUSES: [u16; 4] = [301, 302, 307, 308];
+
+/// Campaign parameters added to the destination query at redirect time
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, InputObject)]
+#[graphql(name = "UtmInput")]
+pub struct UtmParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utm_source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utm_medium: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utm_campaign: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utm_term: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utm_content: Option<String>,
+}
+
+impl UtmParams {
+    fn pairs(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("utm_source", &self.utm_source),
+            ("utm_medium", &self.utm_medium),
+            ("utm_campaign", &self.utm_campaign),
+            ("utm_term", &self.utm_term),
+            ("utm_content", &self.utm_content),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+        .collect()
+    }
+
+    /// Trims the values and drops empty ones, rejects values longer than `MAX_UTM_LENGTH`
+    pub fn normalize(self) -> Result<Self, String> {
+        let clean = |name: &str, value: Option<String>| -> Result<Option<String>, String> {
+            let value = value
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty());
+            if value
+                .as_ref()
+                .is_some_and(|value| value.chars().count() > MAX_UTM_LENGTH)
+            {
+                return Err(format!(
+                    "{} can be at most {} characters long",
+                    name, MAX_UTM_LENGTH
+                ));
+            }
+            Ok(value)
+        };
+        Ok(UtmParams {
+            utm_source: clean("utm_source", self.utm_source)?,
+            utm_medium: clean("utm_medium", self.utm_medium)?,
+            utm_campaign: clean("utm_campaign", self.utm_campaign)?,
+            utm_term: clean("utm_term", self.utm_term)?,
+            utm_content: clean("utm_content", self.utm_content)?,
+        })
+    }
+}
+
+/// Alternate destinations by the visitor's device, visitors on other devices get the link's `url`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, InputObject)]
+#[graphql(name = "DeviceTargetsInput")]
+pub struct DeviceTargets {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ios: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub android: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desktop: Option<String>,
+}
+
+impl DeviceTargets {
+    pub fn is_empty(&self) -> bool {
+        self.ios.is_none() && self.android.is_none() && self.desktop.is_none()
+    }
+
+    pub fn get(&self, device: DeviceType) -> Option<&str> {
+        match device {
+            DeviceType::Ios => self.ios.as_deref(),
+            DeviceType::Android => self.android.as_deref(),
+            DeviceType::Desktop => self.desktop.as_deref(),
+        }
+    }
+
+    pub fn get_mut(&mut self, device: DeviceType) -> &mut Option<String> {
+        match device {
+            DeviceType::Ios => &mut self.ios,
+            DeviceType::Android => &mut self.android,
+            DeviceType::Desktop => &mut self.desktop,
+        }
+    }
+}
+
+/// Destination of a split test link, served with a probability proportional to its weight
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, InputObject)]
+#[graphql(name = "VariantInput")]
+pub struct Variant {
+    pub url: String,
+    pub weight: u32,
+}
+
+/// Set when an admin takes a link down, kept with the record for abuse investigations
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Suspension {
+    pub reason: Option<String>,
+    /// Name of the admin API key that suspended the link
+    pub suspended_by: String,
+    pub suspended_at: DateTime<Utc>,
+}
+
+/// Record stored under a slug
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "QueryPassthrough::is_off")]
+    pub query_passthrough: QueryPassthrough,
+    /// Fragment (without `#`) appended to the destination at redirect time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fragment: Option<String>,
+    /// Whether a `_fragment` hint from the request overrides `fragment`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preserve_fragment_hint: bool,
+    /// Host of the domain the link was minted under, links of `SHORTENER_DOMAIN` have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Tenant of the API key the link was created with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Id of the API key the link was created with, its redirects count towards the key's usage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Id of the user who created the link, anonymous links have no owner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Argon2 hash of the password required to follow the link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    /// Number of redirects the link was created with, the remaining ones are counted under `clicks:<slug>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_clicks: Option<u64>,
+    /// Disabled links answer `410 Gone` until they are enabled again
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    /// Suspended links answer `410 Gone` with a warning page, only admins can lift a suspension
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspension: Option<Suspension>,
+    #[serde(flatten)]
+    pub utm: UtmParams,
+    /// Networks the link can be opened from
+    #[serde(flatten)]
+    pub access: AccessRules,
+    #[serde(default, skip_serializing_if = "DeviceTargets::is_empty")]
+    pub device_targets: DeviceTargets,
+    /// Destinations of a split test link, `url` holds the first one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
+    /// Whether visitors keep getting the variant they were served first, remembered in a cookie
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sticky_variants: bool,
+    /// Visitors see a page naming the destination before they are sent on
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interstitial: bool,
+    /// Status of this link's redirects, overriding `REDIRECT_STATUS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_status: Option<u16>,
+    /// The link only redirects from this moment on, visitors before it get the "not yet live" page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_from: Option<DateTime<Utc>>,
+    /// The link no longer redirects after this moment and answers like an expired link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_until: Option<DateTime<Utc>>,
+    /// Lowercased labels for grouping links, each tag keeps a set of its slugs under `tag:<tag>`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Placeholder of a slug reserved with `POST /api/slugs/reserve`, it doesn't resolve until a destination
+    /// is attached and replaces it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reserved: bool,
+}
+
+impl Link {
+    pub fn new(url: String) -> Self {
+        Link {
+            url,
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("link record is always serializable")
+    }
+
+    /// Links created before records were introduced are stored as the bare destination URL
+    pub fn decode(raw: &str) -> Self {
+        if raw.starts_with('{') {
+            if let Ok(link) = serde_json::from_str(raw) {
+                return link;
+            }
+        }
+        Link::new(raw.to_string())
+    }
+
+    /// Whether the link is live at `now`, links without `active_from` and `active_until` always are
+    pub fn schedule(&self, now: DateTime<Utc>) -> Schedule {
+        if self.active_from.is_some_and(|from| now < from) {
+            Schedule::Pending
+        } else if self.active_until.is_some_and(|until| now >= until) {
+            Schedule::Ended
+        } else {
+            Schedule::Live
+        }
+    }
+
+    /// Every destination the link can redirect to, the main URL first
+    pub fn destinations(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.url.as_str())
+            .chain(
+                self.variants
+                    .iter()
+                    .map(|variant| variant.url.as_str())
+                    .filter(|url| *url != self.url),
+            )
+            .chain(
+                [DeviceType::Ios, DeviceType::Android, DeviceType::Desktop]
+                    .into_iter()
+                    .filter_map(|device| self.device_targets.get(device)),
+            )
+    }
+
+    /// Destination to redirect to for a request carrying `request_query`, sent from `device`.
+    /// Device targets take precedence over the `variant` picked for split test links.
+    pub fn destination(
+        &self,
+        request_query: &str,
+        device: Option<DeviceType>,
+        variant: Option<usize>,
+    ) -> String {
+        let base = device
+            .and_then(|device| self.device_targets.get(device))
+            .or_else(|| {
+                variant
+                    .and_then(|index| self.variants.get(index))
+                    .map(|variant| variant.url.as_str())
+            })
+            .unwrap_or(&self.url);
+        let (request_query, fragment_hint) = if self.preserve_fragment_hint {
+            take_fragment_hint(request_query)
+        } else {
+            (request_query.to_string(), None)
+        };
+        let destination = with_utm(base, &self.utm);
+        let destination = merge_query(&destination, &request_query, self.query_passthrough);
+        match fragment_hint.or_else(|| self.fragment.clone()) {
+            Some(fragment) => with_fragment(&destination, &fragment),
+            None => destination,
+        }
+    }
+}
+
+/// Splits the fragment hint off the request query so it is not passed through as a regular parameter
+fn take_fragment_hint(request_query: &str) -> (String, Option<String>) {
+    let mut hint = None;
+    let mut rest = url::form_urlencoded::Serializer::new(String::new());
+    for (key, value) in url::form_urlencoded::parse(request_query.as_bytes()) {
+        if key == FRAGMENT_HINT_PARAM {
+            hint = Some(value.into_owned()).filter(|v| !v.is_empty());
+        } else {
+            rest.append_pair(&key, &value);
+        }
+    }
+    (rest.finish(), hint)
+}
+
+fn with_fragment(destination: &str, fragment: &str) -> String {
+    let fragment = fragment.trim_start_matches('#');
+    match Url::parse(destination) {
+        Ok(mut url) => {
+            url.set_fragment(Some(fragment));
+            url.to_string()
+        }
+        Err(_) => {
+            let base = destination.split('#').next().unwrap_or(destination);
+            format!("{}#{}", base, fragment)
+        }
+    }
+}
+
+/// Sets the link's UTM parameters on the destination, replacing ones the destination already carries.
+/// They become part of the destination, so the passthrough policy decides if the request can override them.
+fn with_utm(destination: &str, utm: &UtmParams) -> String {
+    let injected = utm.pairs();
+    if injected.is_empty() {
+        return destination.to_string();
+    }
+    let Ok(mut url) = Url::parse(destination) else {
+        return destination.to_string();
+    };
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .into_owned()
+        .filter(|(k, _)| !injected.iter().any(|(name, _)| name == k))
+        .collect();
+    pairs.extend(
+        injected
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+    );
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+    url.to_string()
+}
+
+fn merge_query(destination: &str, request_query: &str, policy: QueryPassthrough) -> String {
+    if policy.is_off() || request_query.is_empty() {
+        return destination.to_string();
+    }
+    let Ok(mut url) = Url::parse(destination) else {
+        return destination.to_string();
+    };
+
+    let incoming: Vec<(String, String)> = url::form_urlencoded::parse(request_query.as_bytes())
+        .into_owned()
+        .collect();
+    let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    match policy {
+        QueryPassthrough::Off => {}
+        QueryPassthrough::KeepDestination => {
+            let existing: Vec<String> = pairs.iter().map(|(k, _)| k.clone()).collect();
+            pairs.extend(incoming.into_iter().filter(|(k, _)| !existing.contains(k)));
+        }
+        QueryPassthrough::PreferRequest => {
+            pairs.retain(|(k, _)| !incoming.iter().any(|(ik, _)| ik == k));
+            pairs.extend(incoming);
+        }
+        QueryPassthrough::Append => pairs.extend(incoming),
+    }
+
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+    url.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_legacy_plain_url() {
+        let link = Link::decode("https://example.com/a?b=c");
+        assert_eq!(link, Link::new("https://example.com/a?b=c".to_string()));
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let link = Link {
+            url: "https://example.com".to_string(),
+            query_passthrough: QueryPassthrough::PreferRequest,
+            fragment: Some("pricing".to_string()),
+            preserve_fragment_hint: true,
+            domain: Some("go.corp.com".to_string()),
+            tenant: Some("acme".to_string()),
+            api_key: Some("5e1f".to_string()),
+            owner: Some("u_123".to_string()),
+            password_hash: Some("$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA".to_string()),
+            max_clicks: Some(1),
+            disabled: true,
+            suspension: Some(Suspension {
+                reason: Some("phishing".to_string()),
+                suspended_by: "abuse team".to_string(),
+                suspended_at: DateTime::UNIX_EPOCH,
+            }),
+            utm: UtmParams {
+                utm_source: Some("newsletter".to_string()),
+                utm_campaign: Some("spring".to_string()),
+                ..Default::default()
+            },
+            access: AccessRules {
+                allowed_ips: vec!["10.0.0.0/8".parse().unwrap()],
+                denied_ips: vec!["10.0.13.0/24".parse().unwrap()],
+            },
+            device_targets: DeviceTargets {
+                ios: Some("https://apps.apple.com/app/id1".to_string()),
+                ..Default::default()
+            },
+            variants: vec![
+                Variant {
+                    url: "https://example.com".to_string(),
+                    weight: 3,
+                },
+                Variant {
+                    url: "https://example.com/b".to_string(),
+                    weight: 1,
+                },
+            ],
+            sticky_variants: true,
+            interstitial: true,
+            redirect_status: Some(301),
+            active_from: Some(DateTime::UNIX_EPOCH),
+            active_until: None,
+            tags: vec!["campaign-q3".to_string()],
+            reserved: true,
+        };
+        assert_eq!(Link::decode(&link.encode()), link);
+        // Default options are not written so records stay small
+        assert_eq!(
+            Link::new("https://example.com".to_string()).encode(),
+            r#"{"url":"https://example.com"}"#
+        );
+    }
+
+    #[test]
+    fn test_query_passthrough_conflict_rules() {
+        let destination = "https://example.com/landing?ref=site&lang=en";
+        let incoming = "ref=tw&utm=x";
+
+        assert_eq!(
+            merge_query(destination, incoming, QueryPassthrough::Off),
+            destination
+        );
+        assert_eq!(
+            merge_query(destination, incoming, QueryPassthrough::KeepDestination),
+            "https://example.com/landing?ref=site&lang=en&utm=x"
+        );
+        assert_eq!(
+            merge_query(destination, incoming, QueryPassthrough::PreferRequest),
+            "https://example.com/landing?lang=en&ref=tw&utm=x"
+        );
+        assert_eq!(
+            merge_query(destination, incoming, QueryPassthrough::Append),
+            "https://example.com/landing?ref=site&lang=en&ref=tw&utm=x"
+        );
+    }
+
+    #[test]
+    fn test_query_passthrough_without_destination_query() {
+        assert_eq!(
+            merge_query(
+                "https://example.com/promo",
+                "ref=tw",
+                QueryPassthrough::Append
+            ),
+            "https://example.com/promo?ref=tw"
+        );
+        assert_eq!(
+            merge_query("https://example.com/promo", "", QueryPassthrough::Append),
+            "https://example.com/promo"
+        );
+    }
+
+    #[test]
+    fn test_destination_injects_utm_parameters() {
+        let link = Link {
+            query_passthrough: QueryPassthrough::KeepDestination,
+            utm: UtmParams {
+                utm_source: Some("newsletter".to_string()),
+                utm_medium: Some("email".to_string()),
+                ..Default::default()
+            },
+            ..Link::new("https://example.com/sale?utm_source=old&id=7".to_string())
+        };
+
+        assert_eq!(
+            link.destination("utm_medium=tw&ref=x", None, None),
+            "https://example.com/sale?id=7&utm_source=newsletter&utm_medium=email&ref=x"
+        );
+        assert_eq!(
+            UtmParams {
+                utm_term: Some("  ".to_string()),
+                utm_content: Some(" banner ".to_string()),
+                ..Default::default()
+            }
+            .normalize()
+            .unwrap()
+            .pairs(),
+            vec![("utm_content", "banner")]
+        );
+    }
+
+    #[test]
+    fn test_destination_by_device() {
+        let link = Link {
+            query_passthrough: QueryPassthrough::Append,
+            device_targets: DeviceTargets {
+                ios: Some("https://apps.apple.com/app/id1".to_string()),
+                android: Some("https://play.google.com/store/apps/details?id=app".to_string()),
+                desktop: None,
+            },
+            ..Link::new("https://example.com/app".to_string())
+        };
+
+        assert_eq!(
+            link.destination("ref=tw", Some(DeviceType::Ios), None),
+            "https://apps.apple.com/app/id1?ref=tw"
+        );
+        assert_eq!(
+            link.destination("", Some(DeviceType::Android), None),
+            "https://play.google.com/store/apps/details?id=app"
+        );
+        assert_eq!(
+            link.destination("", Some(DeviceType::Desktop), None),
+            "https://example.com/app"
+        );
+        assert_eq!(link.destination("", None, None), "https://example.com/app");
+        assert_eq!(link.destinations().count(), 3);
+    }
+
+    #[test]
+    fn test_destination_appends_stored_fragment() {
+        let link = Link {
+            fragment: Some("#pricing".to_string()),
+            ..Link::new("https://example.com/docs#intro".to_string())
+        };
+
+        assert_eq!(
+            link.destination("_fragment=faq", None, None),
+            "https://example.com/docs#pricing"
+        );
+    }
+
+    #[test]
+    fn test_destination_prefers_fragment_hint_when_enabled() {
+        let link = Link {
+            query_passthrough: QueryPassthrough::Append,
+            fragment: Some("pricing".to_string()),
+            preserve_fragment_hint: true,
+            ..Link::new("https://example.com/docs".to_string())
+        };
+
+        assert_eq!(
+            link.destination("ref=tw&_fragment=faq", None, None),
+            "https://example.com/docs?ref=tw#faq"
+        );
+        assert_eq!(
+            link.destination("ref=tw", None, None),
+            "https://example.com/docs?ref=tw#pricing"
+        );
+    }
+
+    #[test]
+    fn test_schedule() {
+        let from = DateTime::UNIX_EPOCH + chrono::Duration::days(1);
+        let link = Link {
+            active_from: Some(from),
+            active_until: Some(from + chrono::Duration::hours(1)),
+            ..Link::new("https://example.com".to_string())
+        };
+
+        assert_eq!(link.schedule(DateTime::UNIX_EPOCH), Schedule::Pending);
+        assert_eq!(link.schedule(from), Schedule::Live);
+        assert_eq!(
+            link.schedule(from + chrono::Duration::hours(1)),
+            Schedule::Ended
+        );
+        assert_eq!(
+            Link::new("https://example.com".to_string()).schedule(DateTime::UNIX_EPOCH),
+            Schedule::Live
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use chrono::{Days, Utc};
+use futures_util::{stream, StreamExt};
+use moka::sync::Cache;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::clicks;
+use crate::metrics::Counter;
+use crate::storage::StorageError;
+use crate::AppState;
+
+/// Links read at a time while warming up
+const WARM_UP_CONCURRENCY: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkCacheConfig {
+    /// Links kept, 0 turns the cache off
+    pub size: u64,
+    /// How long a link is served from the cache before it is read again
+    pub ttl: Duration,
+    /// Most clicked links resolved at startup, before the server accepts requests. 0 turns the warm-up off
+    pub warmup: usize,
+}
+
+impl Default for LinkCacheConfig {
+    fn default() -> Self {
+        LinkCacheConfig {
+            size: 10_000,
+            ttl: Duration::from_secs(1),
+            warmup: 0,
+        }
+    }
+}
+
+/// Records of recently resolved links, so a viral link costs one storage read per TTL instead of one per click.
+/// Updates and deletes through this instance drop the cached record, those made through other instances show
+/// once the TTL is over.
+#[derive(Clone, Default)]
+pub struct LinkCache(Option<Cache<String, String>>);
+
+impl LinkCache {
+    pub fn new(config: &LinkCacheConfig) -> Self {
+        if config.size == 0 {
+            return LinkCache(None);
+        }
+        LinkCache(Some(
+            Cache::builder()
+                .max_capacity(config.size)
+                .time_to_live(config.ttl)
+                .build(),
+        ))
+    }
+
+    pub fn invalidate(&self, slug: &str) {
+        if let Some(cache) = &self.0 {
+            cache.invalidate(slug);
+        }
+    }
+}
+
+/// The record of the link under `slug` for resolving it, from the cache when it was read recently.
+/// Unknown slugs aren't cached, links created through other instances resolve right away.
+pub async fn resolve_record(state: &AppState, slug: &str) -> Result<Option<String>, StorageError> {
+    let Some(cache) = &state.link_cache.0 else {
+        return state.store.get_for_resolve(slug).await;
+    };
+    if let Some(record) = cache.get(slug) {
+        state.metrics.incr(Counter::LinkCacheHits);
+        return Ok(Some(record));
+    }
+    let record = state.store.get_for_resolve(slug).await?;
+    if let Some(record) = &record {
+        cache.insert(slug.to_string(), record.clone());
+    }
+    Ok(record)
+}
+
+/// Resolves the `count` links clicked most today, topped up with yesterday's, so a restarted instance serves
+/// them from its caches instead of sending every first click to storage at once. Returns the links cached.
+pub async fn warm_up(state: &AppState, count: usize) -> Result<usize, StorageError> {
+    let today = Utc::now().date_naive();
+    let mut slugs = Vec::with_capacity(count);
+    let mut seen = HashSet::new();
+    for day in [today, today - Days::new(1)] {
+        for (slug, _) in state
+            .store
+            .top_scores(&clicks::ranking_key(day), count)
+            .await?
+        {
+            if slugs.len() < count && seen.insert(slug.clone()) {
+                slugs.push(slug);
+            }
+        }
+    }
+    // Links deleted since their clicks were counted aren't found, failed reads are left to the first click
+    Ok(stream::iter(slugs)
+        .map(|slug| async move { resolve_record(state, &slug).await })
+        .buffer_unordered(WARM_UP_CONCURRENCY)
+        .filter(|record| std::future::ready(matches!(record, Ok(Some(_)))))
+        .count()
+        .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::storage::{CountBatch, UrlStore};
+    use crate::{AppConfig, UrlShortener};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_warm_up_caches_the_most_clicked_links() {
+        let store = Arc::new(MemoryStore::new());
+        let shortener = UrlShortener::with_store(&AppConfig::default(), store.clone())
+            .await
+            .unwrap();
+        let today = Utc::now().date_naive();
+        let mut counts = CountBatch::default();
+        for (slug, clicks) in [("launch", 3), ("docs", 2), ("gone", 1)] {
+            for _ in 0..clicks {
+                counts.increment_score(&clicks::ranking_key(today), slug, 60);
+            }
+        }
+        counts.increment_score(&clicks::ranking_key(today - Days::new(1)), "blog", 60);
+        store.write_counts(&counts).await.unwrap();
+        for slug in ["launch", "docs", "blog"] {
+            store.set(slug, "record", None).await.unwrap();
+        }
+
+        assert_eq!(warm_up(&shortener.state, 2).await.unwrap(), 2);
+        let cache = shortener.state.link_cache.0.as_ref().unwrap();
+        assert!(cache.contains_key("launch") && cache.contains_key("docs"));
+        assert!(!cache.contains_key("blog"));
+
+        // Yesterday's links top up today's, deleted ones are skipped
+        assert_eq!(warm_up(&shortener.state, 10).await.unwrap(), 3);
+        assert!(cache.contains_key("blog"));
+    }
+}
@@ -0,0 +1,307 @@
+use actix_web::web::Data;
+use actix_web::{get, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::config::read_config_file;
+use crate::error::ApiError;
+use crate::ratelimit::RateLimitConfig;
+use crate::reserved::ReservedSlugs;
+use crate::{AppConfig, AppState};
+
+/// Where the settings changing without a restart are reread from
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigReload {
+    /// The `--config` file, set by the binary after reading it
+    pub file: Option<PathBuf>,
+    /// Variables the file handed to the environment at startup
+    pub file_variables: HashSet<String>,
+    /// Hash in the store whose fields are settings, e.g. maintained with `HSET`. They win over the
+    /// environment and the file.
+    pub hash: Option<String>,
+    /// How often the file and the hash are reread, zero never rereads them
+    pub interval: Duration,
+}
+
+impl Default for ConfigReload {
+    fn default() -> Self {
+        ConfigReload {
+            file: None,
+            file_variables: HashSet::new(),
+            hash: None,
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ConfigReload {
+    fn is_configured(&self) -> bool {
+        !self.interval.is_zero() && (self.file.is_some() || self.hash.is_some())
+    }
+}
+
+/// Settings that take effect without a restart. The domain lists are reloaded too, they live in
+/// `DomainLists`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicSettings {
+    pub rate_limit: RateLimitConfig,
+    pub reserved_slugs: ReservedSlugs,
+    pub fallback_url: Option<String>,
+}
+
+impl DynamicSettings {
+    pub fn from_config(config: &AppConfig) -> Self {
+        DynamicSettings {
+            rate_limit: config.rate_limit,
+            reserved_slugs: config.reserved_slugs.clone(),
+            fallback_url: config.fallback_url.clone(),
+        }
+    }
+
+    /// Names of the settings that differ from `other`, for the reload log
+    fn changes(&self, other: &DynamicSettings) -> Vec<&'static str> {
+        [
+            ("rate limits", self.rate_limit != other.rate_limit),
+            (
+                "reserved slugs",
+                self.reserved_slugs != other.reserved_slugs,
+            ),
+            ("fallback URL", self.fallback_url != other.fallback_url),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+}
+
+/// The dynamic settings in effect. They are swapped as a whole, a request reads either the old settings
+/// or the new ones.
+pub struct LiveSettings {
+    current: RwLock<Arc<DynamicSettings>>,
+    reloaded_at: RwLock<Option<DateTime<Utc>>>,
+    sources: ConfigReload,
+}
+
+impl LiveSettings {
+    pub fn new(config: &AppConfig) -> Self {
+        LiveSettings {
+            current: RwLock::new(Arc::new(DynamicSettings::from_config(config))),
+            reloaded_at: RwLock::new(None),
+            sources: config.config_reload.clone(),
+        }
+    }
+
+    pub fn get(&self) -> Arc<DynamicSettings> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// Rereads the file and the hash and applies the dynamic settings in them, returns whether any changed.
+/// Settings that aren't valid keep the previous ones, all of them.
+pub async fn reload(state: &AppState) -> Result<bool, String> {
+    let sources = &state.settings.sources;
+    let file = match &sources.file {
+        Some(path) => {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || read_config_file(&path))
+                .await
+                .map_err(|err| err.to_string())?
+                .map_err(|err| err.to_string())?
+                .into_iter()
+                .collect()
+        }
+        None => HashMap::new(),
+    };
+    let hash = match &sources.hash {
+        Some(key) => state
+            .store
+            .hash_fields(key)
+            .await
+            .map_err(|err| format!("failed to read hash {}: {}", key, err))?
+            .into_iter()
+            .map(|(field, value)| (field.to_ascii_uppercase(), value))
+            .collect(),
+        None => HashMap::new(),
+    };
+    let config = AppConfig::reloaded(sources, &file, &hash).map_err(|err| err.to_string())?;
+
+    let settings = DynamicSettings::from_config(&config);
+    let mut changes = settings.changes(&state.settings.get());
+    if state
+        .domains
+        .reconfigure(config.domain_lists, state.store.as_ref())
+        .await?
+    {
+        changes.push("domain lists");
+    }
+    if changes.is_empty() {
+        return Ok(false);
+    }
+    log::info!("Reloaded settings, changed: {}", changes.join(", "));
+    *state.settings.current.write().unwrap() = Arc::new(settings);
+    *state.settings.reloaded_at.write().unwrap() = Some(Utc::now());
+    Ok(true)
+}
+
+/// Rereads the settings every `CONFIG_RELOAD_SECONDS` when there is a config file or hash, a failed reload
+/// keeps the settings in effect
+pub fn spawn_config_reloader(state: Data<AppState>) -> Option<JoinHandle<()>> {
+    if !state.settings.sources.is_configured() {
+        return None;
+    }
+    Some(tokio::spawn(async move {
+        let mut ticker = interval(state.settings.sources.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, which applies the hash right after startup
+        loop {
+            ticker.tick().await;
+            if let Err(err) = reload(&state).await {
+                log::error!(
+                    "Failed to reload settings, keeping the previous ones: {}",
+                    err
+                );
+            }
+        }
+    }))
+}
+
+#[derive(Serialize)]
+struct DomainListsView {
+    blocklist: Option<String>,
+    allowlist: Option<String>,
+    trusted: Option<String>,
+    reload_seconds: u64,
+}
+
+#[derive(Serialize)]
+struct SourcesView {
+    file: Option<String>,
+    hash: Option<String>,
+    reload_seconds: u64,
+}
+
+#[derive(Serialize)]
+struct ActiveConfig {
+    rate_limit: RateLimitConfig,
+    reserved_slugs: Vec<String>,
+    fallback_url: Option<String>,
+    domain_lists: DomainListsView,
+    /// `None` until a reload changed something
+    reloaded_at: Option<DateTime<Utc>>,
+    sources: SourcesView,
+}
+
+/// The dynamic settings in effect and where they are reloaded from
+#[get(
+    "/api/admin/config",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn active_config(req: HttpRequest, state: Data<AppState>) -> Result<HttpResponse, ApiError> {
+    crate::auth::ensure_global_admin(&req, "see the service configuration")?;
+    let settings = state.settings.get();
+    let domain_lists = state.domains.config();
+    let sources = &state.settings.sources;
+    Ok(HttpResponse::Ok().json(ActiveConfig {
+        rate_limit: settings.rate_limit,
+        reserved_slugs: settings.reserved_slugs.words(),
+        fallback_url: settings.fallback_url.clone(),
+        domain_lists: DomainListsView {
+            blocklist: domain_lists.blocklist.as_ref().map(ToString::to_string),
+            allowlist: domain_lists.allowlist.as_ref().map(ToString::to_string),
+            trusted: domain_lists.trusted.as_ref().map(ToString::to_string),
+            reload_seconds: domain_lists.reload_interval.as_secs(),
+        },
+        reloaded_at: *state.settings.reloaded_at.read().unwrap(),
+        sources: SourcesView {
+            file: sources.file.as_ref().map(|path| path.display().to_string()),
+            hash: sources.hash.clone(),
+            reload_seconds: sources.interval.as_secs(),
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::UrlShortener;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn test_settings_follow_the_config_file() {
+        let path = std::env::temp_dir().join(format!("live-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "rate_limit_requests = 5\nreserved_slugs = [\"admin\"]\n",
+        )
+        .unwrap();
+        let mut config = AppConfig::default();
+        config.auth.admin_api_key = Some("admin-secret".to_string());
+        config.rate_limit.requests = 5;
+        config.reserved_slugs = ReservedSlugs::new(["admin"]);
+        config.config_reload.file = Some(path.clone());
+        config.config_reload.file_variables = HashSet::from([
+            "RATE_LIMIT_REQUESTS".to_string(),
+            "RESERVED_SLUGS".to_string(),
+        ]);
+        let shortener = UrlShortener::with_store(&config, Arc::new(MemoryStore::new()))
+            .await
+            .unwrap();
+        let state = &shortener.state;
+        assert!(!reload(state).await.unwrap());
+
+        // Removing a setting from the file brings back its default
+        std::fs::write(
+            &path,
+            "rate_limit_requests = 20\nfallback_url = \"https://example.com/?missing={slug}\"\ndomain_blocklist = \"redis:domains:blocked\"\n",
+        )
+        .unwrap();
+        assert!(reload(state).await.unwrap());
+        let settings = state.settings.get();
+        assert_eq!(settings.rate_limit.requests, 20);
+        assert!(!settings.reserved_slugs.is_reserved("admin"));
+        assert_eq!(
+            settings.fallback_url.as_deref(),
+            Some("https://example.com/?missing={slug}")
+        );
+        assert!(state.domains.config().blocklist.is_some());
+
+        // An invalid file changes nothing
+        std::fs::write(
+            &path,
+            "rate_limit_requests = 30\nrate_limit_window_seconds = 0\n",
+        )
+        .unwrap();
+        assert!(reload(state).await.is_err());
+        assert_eq!(state.settings.get().rate_limit.requests, 20);
+
+        let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/admin/config")
+                .insert_header(("X-Api-Key", "admin-secret"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let active: Value = test::read_body_json(res).await;
+        assert_eq!(active["rate_limit"]["requests"], 20);
+        assert_eq!(active["domain_lists"]["blocklist"], "redis:domains:blocked");
+        assert_eq!(active["sources"]["file"], path.display().to_string());
+        assert!(active["reloaded_at"].is_string());
+        assert!(active["reserved_slugs"]
+            .as_array()
+            .unwrap()
+            .contains(&Value::from("healthz")));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,743 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::storage::{CountBatch, LinkWrites, StorageError, UrlStore};
+
+struct Entry {
+    value: String,
+    inserted_at: Instant,
+    ttl: Option<Duration>,
+}
+
+impl Entry {
+    fn is_expired(&self) -> bool {
+        self.ttl
+            .is_some_and(|ttl| self.inserted_at.elapsed() >= ttl)
+    }
+}
+
+/// Collection whose TTL is renewed on every write
+struct Expiring<T> {
+    value: T,
+    inserted_at: Instant,
+    ttl: Duration,
+}
+
+impl<T: Default> Expiring<T> {
+    fn is_live(&self) -> bool {
+        self.inserted_at.elapsed() < self.ttl
+    }
+
+    /// The live collection under `key` with its TTL reset, an expired one is emptied first
+    fn renew<'a>(map: &'a mut HashMap<String, Self>, key: &str, ttl: usize) -> &'a mut T {
+        let entry = map.entry(key.to_string()).or_insert_with(|| Expiring {
+            value: T::default(),
+            inserted_at: Instant::now(),
+            ttl: Duration::ZERO,
+        });
+        if !entry.is_live() {
+            entry.value = T::default();
+        }
+        entry.inserted_at = Instant::now();
+        entry.ttl = Duration::from_secs(ttl as u64);
+        &mut entry.value
+    }
+}
+
+/// Process-local store for development and tests, expired entries are dropped lazily when they are touched
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: RwLock<HashMap<String, Entry>>,
+    sets: RwLock<HashMap<String, HashSet<String>>>,
+    hashes: RwLock<HashMap<String, Expiring<HashMap<String, i64>>>>,
+    /// Counted exactly, the estimates only approximate on Redis
+    estimates: RwLock<HashMap<String, Expiring<HashSet<String>>>>,
+    sorted_sets: RwLock<HashMap<String, Expiring<HashMap<String, i64>>>>,
+    /// Sorted sets that never expire
+    indexes: RwLock<HashMap<String, HashMap<String, i64>>>,
+    lists: RwLock<HashMap<String, VecDeque<String>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts the entry unless a live one exists, like `SET NX`
+    fn insert_new(
+        entries: &mut HashMap<String, Entry>,
+        key: &str,
+        value: &str,
+        ttl: Option<usize>,
+    ) -> bool {
+        if entries.get(key).is_some_and(|entry| !entry.is_expired()) {
+            return false;
+        }
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value: value.to_string(),
+                inserted_at: Instant::now(),
+                ttl: ttl.map(|seconds| Duration::from_secs(seconds as u64)),
+            },
+        );
+        true
+    }
+
+    fn add_if_exists(&self, key: &str, delta: i64) -> Option<i64> {
+        let mut entries = self.entries.write().unwrap();
+        match entries.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                let value = entry.value.parse::<i64>().unwrap_or(0) + delta;
+                entry.value = value.to_string();
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl UrlStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        {
+            let entries = self.entries.read().unwrap();
+            match entries.get(key) {
+                None => return Ok(None),
+                Some(entry) if !entry.is_expired() => return Ok(Some(entry.value.clone())),
+                Some(_) => {}
+            }
+        }
+        // Expired, take the write lock to evict it
+        let mut entries = self.entries.write().unwrap();
+        if entries.get(key).is_some_and(Entry::is_expired) {
+            entries.remove(key);
+        }
+        Ok(None)
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<usize>) -> Result<bool, StorageError> {
+        let mut entries = self.entries.write().unwrap();
+        Ok(Self::insert_new(&mut entries, key, value, ttl))
+    }
+
+    async fn set_first_with(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+        writes: &[LinkWrites],
+    ) -> Result<Option<usize>, StorageError> {
+        // The locks are held until everything is written, so nobody sees the link without its records
+        let mut entries = self.entries.write().unwrap();
+        let mut sets = self.sets.write().unwrap();
+        let mut indexes = self.indexes.write().unwrap();
+        let Some(index) = keys
+            .iter()
+            .position(|key| Self::insert_new(&mut entries, key, value, ttl))
+        else {
+            return Ok(None);
+        };
+        for (key, value, ttl) in &writes[index].entries {
+            Self::insert_new(&mut entries, key, value, *ttl);
+        }
+        for (key, member) in &writes[index].set_members {
+            sets.entry(key.clone()).or_default().insert(member.clone());
+        }
+        for (key, member, score) in &writes[index].scored_members {
+            indexes
+                .entry(key.clone())
+                .or_default()
+                .entry(member.clone())
+                .or_insert(*score);
+        }
+        Ok(Some(index))
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<bool, StorageError> {
+        let mut entries = self.entries.write().unwrap();
+        match entries.get_mut(key) {
+            Some(entry) if !entry.is_expired() && entry.value == expected => {
+                entry.value = value.to_string();
+                if let Some(seconds) = ttl {
+                    entry.inserted_at = Instant::now();
+                    entry.ttl = Some(Duration::from_secs(seconds as u64));
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn ttl_many(&self, keys: &[String]) -> Result<Vec<Option<usize>>, StorageError> {
+        let entries = self.entries.read().unwrap();
+        Ok(keys
+            .iter()
+            .map(|key| {
+                let entry = entries.get(key).filter(|entry| !entry.is_expired())?;
+                let left = entry.ttl? - entry.inserted_at.elapsed();
+                // Rounded like Redis `TTL`
+                Some(left.as_secs_f64().round() as usize)
+            })
+            .collect())
+    }
+
+    async fn expire(&self, key: &str, ttl: usize) -> Result<bool, StorageError> {
+        let mut entries = self.entries.write().unwrap();
+        match entries.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.inserted_at = Instant::now();
+                entry.ttl = Some(Duration::from_secs(ttl as u64));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, StorageError> {
+        let removed = self.entries.write().unwrap().remove(key);
+        let removed_set = self.sets.write().unwrap().remove(key);
+        let removed_hash = self.hashes.write().unwrap().remove(key);
+        let removed_estimate = self.estimates.write().unwrap().remove(key);
+        let removed_sorted_set = self.sorted_sets.write().unwrap().remove(key);
+        let removed_index = self.indexes.write().unwrap().remove(key);
+        Ok(removed.is_some_and(|entry| !entry.is_expired())
+            || removed_set.is_some()
+            || removed_index.is_some()
+            || removed_hash.is_some_and(|hash| hash.is_live())
+            || removed_estimate.is_some_and(|estimate| estimate.is_live())
+            || removed_sorted_set.is_some_and(|sorted_set| sorted_set.is_live()))
+    }
+
+    async fn add_to_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        self.sets
+            .write()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .extend(members.iter().cloned());
+        Ok(())
+    }
+
+    async fn remove_from_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        let mut sets = self.sets.write().unwrap();
+        if let Some(set) = sets.get_mut(key) {
+            for member in members {
+                set.remove(member);
+            }
+            // Like Redis, empty sets don't exist
+            if set.is_empty() {
+                sets.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .sets
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn incr_window(
+        &self,
+        key: &str,
+        window_seconds: usize,
+    ) -> Result<(u64, usize), StorageError> {
+        let window = Duration::from_secs(window_seconds as u64);
+        let fresh = || Entry {
+            value: "0".to_string(),
+            inserted_at: Instant::now(),
+            ttl: Some(window),
+        };
+
+        let mut entries = self.entries.write().unwrap();
+        let entry = entries
+            .entry(key.to_string())
+            .and_modify(|entry| {
+                if entry.is_expired() {
+                    *entry = fresh();
+                }
+            })
+            .or_insert_with(fresh);
+
+        let count = entry.value.parse::<u64>().unwrap_or(0) + 1;
+        entry.value = count.to_string();
+        let remaining = entry.ttl.map_or(window, |ttl| {
+            ttl.saturating_sub(entry.inserted_at.elapsed())
+        });
+        Ok((count, remaining.as_secs_f64().ceil() as usize))
+    }
+
+    async fn scan_keys(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<String>), StorageError> {
+        // Sorted so the cursor is a stable position, sets are left out like every other method leaves them out
+        let mut keys: Vec<String> = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        let start = cursor as usize;
+        let page: Vec<String> = keys
+            .iter()
+            .skip(start)
+            .take(count.max(1))
+            .cloned()
+            .collect();
+        let next = start + page.len();
+        Ok((if next >= keys.len() { 0 } else { next as u64 }, page))
+    }
+
+    async fn increment(&self, key: &str, amount: u64) -> Result<u64, StorageError> {
+        let mut entries = self.entries.write().unwrap();
+        let entry = entries.entry(key.to_string()).or_insert_with(|| Entry {
+            value: "0".to_string(),
+            inserted_at: Instant::now(),
+            ttl: None,
+        });
+        let value = entry.value.parse::<u64>().unwrap_or(0) + amount;
+        entry.value = value.to_string();
+        Ok(value)
+    }
+
+    async fn increment_existing(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        Ok(self.add_if_exists(key, 1))
+    }
+
+    async fn decrement(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        Ok(self.add_if_exists(key, -1))
+    }
+
+    async fn hash_fields(&self, key: &str) -> Result<HashMap<String, String>, StorageError> {
+        Ok(self
+            .hashes
+            .read()
+            .unwrap()
+            .get(key)
+            .filter(|hash| hash.is_live())
+            .map(|hash| {
+                hash.value
+                    .iter()
+                    .map(|(field, value)| (field.clone(), value.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn count_estimate(&self, keys: &[String]) -> Result<u64, StorageError> {
+        let estimates = self.estimates.read().unwrap();
+        let members: HashSet<&String> = keys
+            .iter()
+            .filter_map(|key| estimates.get(key))
+            .filter(|estimate| estimate.is_live())
+            .flat_map(|estimate| &estimate.value)
+            .collect();
+        Ok(members.len() as u64)
+    }
+
+    async fn write_counts(&self, counts: &CountBatch) -> Result<(), StorageError> {
+        for (key, by) in &counts.existing {
+            self.add_if_exists(key, *by);
+        }
+        let mut hashes = self.hashes.write().unwrap();
+        for (key, (fields, ttl)) in &counts.fields {
+            let hash = Expiring::renew(&mut hashes, key, *ttl);
+            for (field, by) in fields {
+                *hash.entry(field.clone()).or_insert(0) += by;
+            }
+        }
+        drop(hashes);
+        let mut estimates = self.estimates.write().unwrap();
+        for (key, (members, ttl)) in &counts.estimates {
+            Expiring::renew(&mut estimates, key, *ttl).extend(members.iter().cloned());
+        }
+        drop(estimates);
+        let mut sorted_sets = self.sorted_sets.write().unwrap();
+        for (key, (scores, ttl)) in &counts.scores {
+            let sorted_set = Expiring::renew(&mut sorted_sets, key, *ttl);
+            for (member, by) in scores {
+                *sorted_set.entry(member.clone()).or_insert(0) += by;
+            }
+        }
+        drop(sorted_sets);
+        let mut indexes = self.indexes.write().unwrap();
+        for (key, scores) in &counts.index_scores {
+            let Some(index) = indexes.get_mut(key) else {
+                continue;
+            };
+            for (member, by) in scores {
+                if let Some(score) = index.get_mut(member) {
+                    *score += by;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn top_scores(
+        &self,
+        key: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, u64)>, StorageError> {
+        let mut scores: Vec<(String, u64)> = self
+            .sorted_sets
+            .read()
+            .unwrap()
+            .get(key)
+            .filter(|sorted_set| sorted_set.is_live())
+            .map(|sorted_set| {
+                sorted_set
+                    .value
+                    .iter()
+                    .map(|(member, score)| (member.clone(), (*score).max(0) as u64))
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Highest first, ties in reverse member order like ZREVRANGE
+        scores.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| b.0.cmp(&a.0)));
+        scores.truncate(limit);
+        Ok(scores)
+    }
+
+    async fn add_scored(&self, key: &str, members: &[(String, i64)]) -> Result<(), StorageError> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let mut indexes = self.indexes.write().unwrap();
+        let index = indexes.entry(key.to_string()).or_default();
+        for (member, score) in members {
+            index.entry(member.clone()).or_insert(*score);
+        }
+        Ok(())
+    }
+
+    async fn remove_scored(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        let mut indexes = self.indexes.write().unwrap();
+        if let Some(index) = indexes.get_mut(key) {
+            for member in members {
+                index.remove(member);
+            }
+            if index.is_empty() {
+                indexes.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    async fn scores(
+        &self,
+        key: &str,
+        members: &[String],
+    ) -> Result<Vec<Option<i64>>, StorageError> {
+        let indexes = self.indexes.read().unwrap();
+        let index = indexes.get(key);
+        Ok(members
+            .iter()
+            .map(|member| index.and_then(|index| index.get(member).copied()))
+            .collect())
+    }
+
+    async fn scored_page(
+        &self,
+        key: &str,
+        after: Option<(i64, &str)>,
+        descending: bool,
+        limit: usize,
+    ) -> Result<Vec<(String, i64)>, StorageError> {
+        let indexes = self.indexes.read().unwrap();
+        let Some(index) = indexes.get(key) else {
+            return Ok(Vec::new());
+        };
+        let mut page: Vec<(i64, &String)> = index
+            .iter()
+            .map(|(member, score)| (*score, member))
+            .filter(|(score, member)| match after {
+                None => true,
+                Some(after) if descending => (*score, member.as_str()) < after,
+                Some(after) => (*score, member.as_str()) > after,
+            })
+            .collect();
+        page.sort();
+        if descending {
+            page.reverse();
+        }
+        Ok(page
+            .into_iter()
+            .take(limit)
+            .map(|(score, member)| (member.clone(), score))
+            .collect())
+    }
+
+    async fn push_capped(
+        &self,
+        key: &str,
+        entry: &str,
+        max_len: usize,
+    ) -> Result<(), StorageError> {
+        let mut lists = self.lists.write().unwrap();
+        let list = lists.entry(key.to_string()).or_default();
+        list.push_front(entry.to_string());
+        list.truncate(max_len.max(1));
+        Ok(())
+    }
+
+    async fn list_range(
+        &self,
+        key: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .lists
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|list| list.iter().skip(offset).take(count).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    #[cfg(test)]
+    async fn cleanup(&self) -> Result<(), StorageError> {
+        self.entries.write().unwrap().clear();
+        self.sets.write().unwrap().clear();
+        self.hashes.write().unwrap().clear();
+        self.estimates.write().unwrap().clear();
+        self.sorted_sets.write().unwrap().clear();
+        self.indexes.write().unwrap().clear();
+        self.lists.write().unwrap().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_store_set_first_with_stores_the_records_of_the_free_key() {
+        let store = MemoryStore::new();
+
+        store.set("taken", "old", None).await.unwrap();
+        let keys = vec!["taken".to_string(), "free".to_string()];
+        let writes: Vec<LinkWrites> = keys
+            .iter()
+            .map(|key| LinkWrites {
+                entries: vec![(format!("clicks:{}", key), "0".to_string(), Some(60))],
+                set_members: vec![("links:alice".to_string(), key.clone())],
+                scored_members: vec![("by_created:alice".to_string(), key.clone(), 7)],
+            })
+            .collect();
+        assert_eq!(
+            store
+                .set_first_with(&keys, "new", Some(60), &writes)
+                .await
+                .unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            store.get("clicks:free").await.unwrap().as_deref(),
+            Some("0")
+        );
+        assert_eq!(store.get("clicks:taken").await.unwrap(), None);
+        assert_eq!(
+            store.set_members("links:alice").await.unwrap(),
+            vec!["free".to_string()]
+        );
+        assert_eq!(
+            store.scores("by_created:alice", &keys).await.unwrap(),
+            vec![None, Some(7)]
+        );
+        assert_eq!(
+            store
+                .set_first_with(&keys, "new", None, &writes)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_index_pages() {
+        let store = MemoryStore::new();
+
+        store
+            .add_scored(
+                "index",
+                &[
+                    ("a".to_string(), 1),
+                    ("b".to_string(), 2),
+                    ("c".to_string(), 2),
+                    ("d".to_string(), 3),
+                ],
+            )
+            .await
+            .unwrap();
+        // Members already in the index keep their score
+        store
+            .add_scored("index", &[("a".to_string(), 9)])
+            .await
+            .unwrap();
+        let page = |after, descending| store.scored_page("index", after, descending, 2);
+        let members = |page: Vec<(String, i64)>| -> Vec<String> {
+            page.into_iter().map(|(member, _)| member).collect()
+        };
+        assert_eq!(members(page(None, true).await.unwrap()), ["d", "c"]);
+        assert_eq!(
+            members(page(Some((2, "c")), true).await.unwrap()),
+            ["b", "a"]
+        );
+        assert_eq!(
+            members(page(Some((2, "b")), false).await.unwrap()),
+            ["c", "d"]
+        );
+        assert_eq!(
+            store.scored_page("index", None, false, 1).await.unwrap(),
+            vec![("a".to_string(), 1)]
+        );
+
+        let mut counts = CountBatch::default();
+        counts.increment_index_score("index", "a");
+        counts.increment_index_score("index", "gone");
+        store.write_counts(&counts).await.unwrap();
+        store
+            .remove_scored("index", &["d".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .scores(
+                    "index",
+                    &["a".to_string(), "d".to_string(), "gone".to_string()]
+                )
+                .await
+                .unwrap(),
+            vec![Some(2), None, None]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_set_then_get() {
+        let store = MemoryStore::new();
+
+        let set_result = store.set("key", "value", Some(60)).await.unwrap();
+        assert!(set_result, "Key should have been set successfully");
+
+        assert_eq!(store.get("key").await.unwrap(), Some("value".to_string()));
+        assert_eq!(store.get("missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_set_nx_prevents_overwrite() {
+        let store = MemoryStore::new();
+
+        assert!(store.set("key", "first", None).await.unwrap());
+        assert!(
+            !store.set("key", "second", None).await.unwrap(),
+            "Second set should return false like Redis SET NX"
+        );
+        assert_eq!(store.get("key").await.unwrap(), Some("first".to_string()));
+
+        let keys = vec!["key".to_string(), "other".to_string()];
+        assert_eq!(
+            store.set_first(&keys, "third", None).await.unwrap(),
+            Some(1)
+        );
+        assert_eq!(store.get("other").await.unwrap(), Some("third".to_string()));
+        assert_eq!(store.set_first(&keys, "fourth", None).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_ttl_functionality() {
+        let store = MemoryStore::new();
+
+        assert!(store.set("key", "value", Some(1)).await.unwrap());
+        assert_eq!(store.get("key").await.unwrap(), Some("value".to_string()));
+        store.set("long", "value", Some(60)).await.unwrap();
+        store.set("forever", "value", None).await.unwrap();
+        assert_eq!(
+            store
+                .ttl_many(&["long", "forever", "missing"].map(String::from))
+                .await
+                .unwrap(),
+            vec![Some(60), None, None]
+        );
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        assert_eq!(
+            store.get("key").await.unwrap(),
+            None,
+            "Expired key should return None"
+        );
+        assert!(
+            store.set("key", "new value", Some(1)).await.unwrap(),
+            "Expired key should be free again"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_incr_window_resets_after_expiry() {
+        let store = MemoryStore::new();
+
+        assert_eq!(store.incr_window("counter", 1).await.unwrap(), (1, 1));
+        assert_eq!(store.incr_window("counter", 1).await.unwrap().0, 2);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        assert_eq!(store.incr_window("counter", 1).await.unwrap(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_compare_and_set_and_sets() {
+        let store = MemoryStore::new();
+
+        assert!(!store
+            .compare_and_set("key", "first", "value", None)
+            .await
+            .unwrap());
+        assert!(store.set("key", "first", Some(60)).await.unwrap());
+        assert!(!store
+            .compare_and_set("key", "stale", "second", None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_set("key", "first", "second", Some(1))
+            .await
+            .unwrap());
+        assert_eq!(store.get("key").await.unwrap(), Some("second".to_string()));
+        assert!(store.expire("key", 60).await.unwrap());
+        assert!(!store.expire("missing", 60).await.unwrap());
+
+        let members = ["a".to_string(), "b".to_string()];
+        store.add_to_set("set", &members).await.unwrap();
+        store.remove_from_set("set", &members[..1]).await.unwrap();
+        assert_eq!(
+            store.set_members("set").await.unwrap(),
+            vec!["b".to_string()]
+        );
+        assert!(store.delete("set").await.unwrap());
+        assert!(store.set_members("set").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,453 @@
+use async_graphql::SimpleObject;
+use reqwest::{header, redirect};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+use crate::storage::UrlStore;
+use crate::validation::{is_private_ipv4, is_private_ipv6};
+
+/// Longest title kept, in characters
+const MAX_TITLE_LENGTH: usize = 300;
+/// Longest description kept, in characters
+const MAX_DESCRIPTION_LENGTH: usize = 1000;
+/// Redirects followed to the page, link destinations often go through a tracker or two
+const MAX_REDIRECTS: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkMetadataConfig {
+    /// Off by default, once on the service sends a request to every destination it is handed
+    pub enabled: bool,
+    /// How long a page may take, connecting and redirects included
+    pub timeout: Duration,
+    /// Bytes of the page read, metadata past them is missed
+    pub max_bytes: usize,
+}
+
+impl Default for LinkMetadataConfig {
+    fn default() -> Self {
+        LinkMetadataConfig {
+            enabled: false,
+            timeout: Duration::from_secs(2),
+            max_bytes: 256 * 1024,
+        }
+    }
+}
+
+/// What the destination page says about itself, for showing links nicely in UIs
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, SimpleObject)]
+#[graphql(name = "LinkMetadata")]
+pub struct LinkMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Absolute URL of the icon, `/favicon.ico` of the site when the page names none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub favicon: Option<String>,
+}
+
+/// Metadata of the link stored under `key` is kept next to it, changing the link record would break
+/// deduplication
+pub fn metadata_key(key: &str) -> String {
+    format!("meta:{}", key)
+}
+
+/// Reads the stored metadata of the links under `keys`, `None` for links without any yet
+pub async fn load(
+    store: &dyn UrlStore,
+    keys: &[String],
+) -> Result<Vec<Option<LinkMetadata>>, crate::storage::StorageError> {
+    let keys: Vec<String> = keys.iter().map(|key| metadata_key(key)).collect();
+    Ok(store
+        .get_many(&keys)
+        .await?
+        .into_iter()
+        .map(|raw| raw.and_then(|raw| serde_json::from_str(&raw).ok()))
+        .collect())
+}
+
+struct Fetcher {
+    http: reqwest::Client,
+    store: Arc<dyn UrlStore>,
+    max_bytes: usize,
+}
+
+/// Fetches the metadata of new links in the background, link creation never waits for the destination
+#[derive(Clone, Default)]
+pub struct MetadataFetcher(Option<Arc<Fetcher>>);
+
+impl MetadataFetcher {
+    pub fn new(config: &LinkMetadataConfig, store: Arc<dyn UrlStore>) -> Self {
+        if !config.enabled {
+            return MetadataFetcher(None);
+        }
+        MetadataFetcher(Some(Arc::new(Fetcher {
+            http: reqwest::Client::builder()
+                .timeout(config.timeout)
+                .redirect(external_redirects(MAX_REDIRECTS))
+                .user_agent(concat!("url-shortener/", env!("CARGO_PKG_VERSION")))
+                .build()
+                .expect("HTTP client settings are valid"),
+            store,
+            max_bytes: config.max_bytes,
+        })))
+    }
+
+    /// Fetches the page at `url` and stores its metadata for the link under `key`, expiring with it.
+    /// Pages that can't be fetched leave the link without metadata.
+    pub fn fetch_later(&self, key: &str, url: &str, ttl: Option<usize>) {
+        let Some(fetcher) = self.0.clone() else {
+            return;
+        };
+        let (key, url) = (key.to_string(), url.to_string());
+        tokio::spawn(async move {
+            let metadata = match fetcher.fetch(&url).await {
+                Ok(metadata) if metadata != LinkMetadata::default() => metadata,
+                Ok(_) => return,
+                Err(err) => {
+                    log::debug!("No metadata for {} from {}: {}", key, url, err);
+                    return;
+                }
+            };
+            let json = serde_json::to_string(&metadata).expect("metadata is always serializable");
+            let entries = [(metadata_key(&key), json, ttl)];
+            if let Err(err) = fetcher.store.set_many(&entries).await {
+                log::warn!("Failed to store the metadata of {}: {}", key, err);
+            }
+        });
+    }
+}
+
+/// Internal addresses are not fetched, a link must not become a way to probe the network the service runs in.
+/// Host names resolving to them are not caught.
+pub fn is_internal(url: &Url) -> bool {
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) => is_internal_ip(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_internal_ip(IpAddr::V6(ip)),
+        Some(url::Host::Domain(domain)) => {
+            domain == "localhost" || domain.ends_with(".localhost") || domain.ends_with(".internal")
+        }
+        None => true,
+    }
+}
+
+fn is_internal_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4(ip),
+        IpAddr::V6(ip) => is_private_ipv6(ip),
+    }
+}
+
+/// Follows up to `max_redirects` redirects but none to an internal address, so a public page can't
+/// redirect a request into the network the service runs in
+pub fn external_redirects(max_redirects: usize) -> redirect::Policy {
+    redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            attempt.error("too many redirects")
+        } else if is_internal(attempt.url()) {
+            attempt.error("redirect to an internal address")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+impl Fetcher {
+    async fn fetch(&self, url: &str) -> Result<LinkMetadata, String> {
+        let url = Url::parse(url).map_err(|err| err.to_string())?;
+        if is_internal(&url) {
+            return Err("internal address".to_string());
+        }
+        let mut response = self
+            .http
+            .get(url)
+            .header(header::ACCEPT, "text/html")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| err.to_string())?;
+        let is_html = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("html"));
+        if !is_html {
+            return Err("not an HTML page".to_string());
+        }
+        let page = response.url().clone();
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|err| err.to_string())? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= self.max_bytes {
+                body.truncate(self.max_bytes);
+                break;
+            }
+        }
+        Ok(parse_metadata(&String::from_utf8_lossy(&body), &page))
+    }
+}
+
+/// A start tag, its lowercased name and attributes
+struct Tag<'a> {
+    name: String,
+    attributes: Vec<(String, &'a str)>,
+    /// Offset right after the tag
+    end: usize,
+}
+
+impl Tag<'_> {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// Reads the tag starting at `html[start]`, `None` for closing tags, comments and text
+fn parse_tag(html: &str, start: usize) -> Option<Tag<'_>> {
+    let rest = &html[start + 1..];
+    let name_end = rest
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(rest.len());
+    let name = &rest[..name_end];
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let mut attributes = Vec::new();
+    let mut pos = name_end;
+    loop {
+        let skipped = rest[pos..].trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        pos = rest.len() - skipped.len();
+        if skipped.is_empty() {
+            return None;
+        }
+        if skipped.starts_with('>') {
+            break;
+        }
+        let attribute_end = skipped
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '>')
+            .unwrap_or(skipped.len());
+        let attribute = skipped[..attribute_end].to_ascii_lowercase();
+        pos += attribute_end;
+        let after = rest[pos..].trim_start();
+        if !after.starts_with('=') {
+            attributes.push((attribute, ""));
+            continue;
+        }
+        let value = after[1..].trim_start();
+        pos = rest.len() - value.len();
+        let (value, consumed) = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let end = value[1..].find(quote)?;
+                (&value[1..end + 1], end + 2)
+            }
+            _ => {
+                let end = value
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .unwrap_or(value.len());
+                (&value[..end], end)
+            }
+        };
+        attributes.push((attribute, value));
+        pos += consumed;
+    }
+    Some(Tag {
+        name: name.to_ascii_lowercase(),
+        attributes,
+        end: start + 1 + pos + 1,
+    })
+}
+
+/// Offset of the closing tag of `name` from `from` on, the end of `html` when it is missing
+fn find_closing(html: &str, from: usize, name: &str) -> usize {
+    let mut pos = from;
+    while let Some(offset) = html[pos..].find("</") {
+        let start = pos + offset;
+        let candidate = &html.as_bytes()[start + 2..];
+        if candidate.len() >= name.len()
+            && candidate[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+        {
+            return start;
+        }
+        pos = start + 2;
+    }
+    html.len()
+}
+
+/// Replaces the character references common in titles, the rest are left as they are
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semicolon) = rest.find(';').filter(|&semicolon| semicolon <= 10) else {
+            decoded.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..semicolon];
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match character {
+            Some(character) => {
+                decoded.push(character);
+                rest = &rest[semicolon + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Decodes the text, collapses whitespace and cuts it to `max` characters. Empty text is `None`.
+fn clean_text(text: &str, max: usize) -> Option<String> {
+    let text = decode_entities(text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!text.is_empty()).then(|| text.chars().take(max).collect())
+}
+
+/// Picks the title, description and icon out of the page at `page`. Open Graph tags are used where the
+/// plain ones are missing.
+pub fn parse_metadata(html: &str, page: &Url) -> LinkMetadata {
+    let (mut title, mut og_title) = (None, None);
+    let (mut description, mut og_description) = (None, None);
+    let mut icon = None;
+    let mut pos = 0;
+    while let Some(offset) = html[pos..].find('<') {
+        let start = pos + offset;
+        let Some(tag) = parse_tag(html, start) else {
+            pos = start + 1;
+            continue;
+        };
+        pos = tag.end.min(html.len());
+        match tag.name.as_str() {
+            "title" if title.is_none() => {
+                let text_end = find_closing(html, pos, "title");
+                title = clean_text(&html[pos..text_end], MAX_TITLE_LENGTH);
+                pos = text_end;
+            }
+            "meta" => {
+                let content = tag.attribute("content").unwrap_or_default();
+                let name = tag
+                    .attribute("name")
+                    .or_else(|| tag.attribute("property"))
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                match name.as_str() {
+                    "description" => description = clean_text(content, MAX_DESCRIPTION_LENGTH),
+                    "og:description" => {
+                        og_description = clean_text(content, MAX_DESCRIPTION_LENGTH)
+                    }
+                    "og:title" => og_title = clean_text(content, MAX_TITLE_LENGTH),
+                    _ => {}
+                }
+            }
+            "link" if icon.is_none() => {
+                let rel = tag
+                    .attribute("rel")
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                if rel.split_whitespace().any(|rel| rel == "icon") {
+                    icon = tag
+                        .attribute("href")
+                        .and_then(|href| page.join(&decode_entities(href)).ok());
+                }
+            }
+            // Everything of interest is in the head
+            "body" => break,
+            "script" | "style" => {
+                pos = find_closing(html, pos, &tag.name);
+            }
+            _ => {}
+        }
+    }
+    LinkMetadata {
+        title: title.or(og_title),
+        description: description.or(og_description),
+        favicon: icon
+            .or_else(|| page.join("/favicon.ico").ok())
+            .filter(|icon| icon.scheme() == "http" || icon.scheme() == "https")
+            .map(String::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metadata() {
+        let page = Url::parse("https://example.com/blog/post?id=1").unwrap();
+        let html = r#"<!DOCTYPE html>
+            <html><head>
+            <meta charset=utf-8>
+            <script>var t = "<title>not this</title>";</script>
+            <TITLE>
+              Launch &amp; Learn &#8211; Blog
+            </TITLE>
+            <meta property="og:description" content="Open Graph text">
+            <link rel="shortcut icon" href='/static/icon.png?v=2&amp;x=1'>
+            </head><body><meta name="description" content="In the body"></body></html>"#;
+
+        assert_eq!(
+            parse_metadata(html, &page),
+            LinkMetadata {
+                title: Some("Launch & Learn \u{2013} Blog".to_string()),
+                description: Some("Open Graph text".to_string()),
+                favicon: Some("https://example.com/static/icon.png?v=2&x=1".to_string()),
+            }
+        );
+        assert_eq!(
+            parse_metadata("<p>no head", &page),
+            LinkMetadata {
+                favicon: Some("https://example.com/favicon.ico".to_string()),
+                ..Default::default()
+            }
+        );
+        let long = format!("<title>{}</title>", "a".repeat(MAX_TITLE_LENGTH + 10));
+        assert_eq!(
+            parse_metadata(&long, &page).title.unwrap().len(),
+            MAX_TITLE_LENGTH
+        );
+    }
+
+    #[test]
+    fn test_internal_addresses_are_not_fetched() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://10.1.2.3:8080/",
+            "http://[::1]/",
+            "http://[::ffff:192.168.0.1]/",
+            "http://localhost:9000/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/",
+        ] {
+            assert!(is_internal(&Url::parse(url).unwrap()), "{}", url);
+        }
+        assert!(!is_internal(&Url::parse("https://example.com/").unwrap()));
+        assert!(!is_internal(&Url::parse("http://93.184.216.34/").unwrap()));
+    }
+}
@@ -0,0 +1,159 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::AppState;
+
+/// Service counters exposed to Prometheus on `/metrics` and pushed to StatsD when enabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    ShortenRequests,
+    ShortenCollisions,
+    ShortenFailures,
+    ResolveHits,
+    ResolveMisses,
+    StorageErrors,
+    RateLimited,
+    ThreatsDetected,
+    ClicksDropped,
+    EventsDropped,
+    RequestTimeouts,
+    LinkCacheHits,
+    FilteredSlugs,
+    RequestsShed,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 14] = [
+        Counter::ShortenRequests,
+        Counter::ShortenCollisions,
+        Counter::ShortenFailures,
+        Counter::ResolveHits,
+        Counter::ResolveMisses,
+        Counter::StorageErrors,
+        Counter::RateLimited,
+        Counter::ThreatsDetected,
+        Counter::ClicksDropped,
+        Counter::EventsDropped,
+        Counter::RequestTimeouts,
+        Counter::LinkCacheHits,
+        Counter::FilteredSlugs,
+        Counter::RequestsShed,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::ShortenRequests => "shorten_requests",
+            Counter::ShortenCollisions => "shorten_collisions",
+            Counter::ShortenFailures => "shorten_failures",
+            Counter::ResolveHits => "resolve_hits",
+            Counter::ResolveMisses => "resolve_misses",
+            Counter::StorageErrors => "storage_errors",
+            Counter::RateLimited => "rate_limited_requests",
+            Counter::ThreatsDetected => "threats_detected",
+            Counter::ClicksDropped => "clicks_dropped",
+            Counter::EventsDropped => "events_dropped",
+            Counter::RequestTimeouts => "request_timeouts",
+            Counter::LinkCacheHits => "link_cache_hits",
+            Counter::FilteredSlugs => "filtered_slugs",
+            Counter::RequestsShed => "requests_shed",
+        }
+    }
+
+    fn help(self) -> &'static str {
+        match self {
+            Counter::ShortenRequests => "Number of shorten requests received",
+            Counter::ShortenCollisions => "Number of slug collisions hit while shortening",
+            Counter::ShortenFailures => "Number of shorten requests that ran out of attempts",
+            Counter::ResolveHits => "Number of short URLs resolved successfully",
+            Counter::ResolveMisses => "Number of lookups for unknown short URLs",
+            Counter::StorageErrors => "Number of failed storage operations",
+            Counter::RateLimited => "Number of requests rejected by the rate limiter",
+            Counter::ThreatsDetected => "Number of destinations flagged by the threat checker",
+            Counter::ClicksDropped => {
+                "Number of clicks dropped from the analytics buffer uncounted"
+            }
+            Counter::EventsDropped => "Number of link events dropped unpublished",
+            Counter::RequestTimeouts => "Number of requests answered with 504 at their deadline",
+            Counter::LinkCacheHits => "Number of resolves answered from the local link cache",
+            Counter::FilteredSlugs => "Number of generated slugs rejected by the slug filter",
+            Counter::RequestsShed => {
+                "Number of requests answered with 503 over the in-flight limit"
+            }
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Lock-free counters shared between the handlers and the exporters
+#[derive(Default)]
+pub struct Metrics {
+    counters: [AtomicU64; Counter::ALL.len()],
+}
+
+impl Metrics {
+    pub fn incr(&self, counter: Counter) {
+        self.add(counter, 1);
+    }
+
+    pub fn add(&self, counter: Counter, amount: u64) {
+        self.counters[counter.index()].fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.counters[counter.index()].load(Ordering::Relaxed)
+    }
+
+    /// Renders all counters in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        for counter in Counter::ALL {
+            let name = format!("url_shortener_{}_total", counter.name());
+            let _ = writeln!(out, "# HELP {} {}", name, counter.help());
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, self.get(counter));
+        }
+        out
+    }
+}
+
+#[get("/metrics")]
+async fn prometheus_metrics(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render_prometheus())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_start_at_zero_and_increment() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.get(Counter::ResolveHits), 0);
+
+        metrics.incr(Counter::ResolveHits);
+        metrics.incr(Counter::ResolveHits);
+        metrics.incr(Counter::ResolveMisses);
+
+        assert_eq!(metrics.get(Counter::ResolveHits), 2);
+        assert_eq!(metrics.get(Counter::ResolveMisses), 1);
+        assert_eq!(metrics.get(Counter::ShortenRequests), 0);
+    }
+
+    #[test]
+    fn test_render_prometheus() {
+        let metrics = Metrics::default();
+        metrics.incr(Counter::ShortenRequests);
+
+        let rendered = metrics.render_prometheus();
+
+        assert!(rendered.contains("# TYPE url_shortener_shorten_requests_total counter"));
+        assert!(rendered.contains("\nurl_shortener_shorten_requests_total 1\n"));
+        assert!(rendered.contains("\nurl_shortener_resolve_hits_total 0\n"));
+    }
+}
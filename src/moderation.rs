@@ -0,0 +1,200 @@
+use actix_web::http::header;
+use actix_web::web::{self, Data};
+use actix_web::{post, HttpMessage, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{self, AuditAction, AuditContext};
+use crate::auth::AuthenticatedKey;
+use crate::body::JsonBody;
+use crate::error::ApiError;
+use crate::link::{Link, Suspension};
+use crate::ownership::modify_link;
+use crate::protection::escape_html;
+use crate::short_domains::split_key;
+use crate::tenants;
+use crate::AppState;
+
+pub const MAX_REASON_LENGTH: usize = 500;
+
+/// `410` page shown instead of redirecting to a suspended link, it never reveals the destination
+pub fn suspended_page(key: &str) -> HttpResponse {
+    let (slug, _) = split_key(key);
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>Link disabled</title>
+<style>
+body {{ font-family: sans-serif; max-width: 32rem; margin: 4rem auto; padding: 0 1rem; }}
+h1 {{ color: #b00020; }}
+</style>
+</head>
+<body>
+<h1>Link disabled</h1>
+<p>The link <strong>{slug}</strong> was disabled because it was reported as harmful, for example as phishing or malware.</p>
+<p>If you followed it from an email or a message, be careful with anything else that came with it.</p>
+</body>
+</html>
+"#,
+        slug = escape_html(slug),
+    );
+    HttpResponse::Gone()
+        .content_type("text/html; charset=utf-8")
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .body(body)
+}
+
+#[derive(Deserialize)]
+struct DisableRequest {
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct LinkModeration {
+    slug: String,
+    url: String,
+    owner: Option<String>,
+    suspension: Option<Suspension>,
+}
+
+impl LinkModeration {
+    pub fn new(slug: String, link: Link) -> Self {
+        LinkModeration {
+            slug,
+            url: link.url,
+            owner: link.owner,
+            suspension: link.suspension,
+        }
+    }
+}
+
+/// Suspends a link, the record is kept as it is so it can still be investigated
+#[post(
+    "/api/admin/links/{slug}/disable",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn disable_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: Option<JsonBody<DisableRequest>>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let reason = body
+        .and_then(|body| body.into_inner().reason)
+        .map(|reason| reason.trim().to_string())
+        .filter(|reason| !reason.is_empty());
+    if reason
+        .as_ref()
+        .is_some_and(|reason| reason.chars().count() > MAX_REASON_LENGTH)
+    {
+        return Err(ApiError::validation(
+            "invalid_reason",
+            format!(
+                "The reason can be at most {} characters long.",
+                MAX_REASON_LENGTH
+            ),
+        ));
+    }
+    let link = suspend(&req, &state, &slug, reason).await?;
+    Ok(HttpResponse::Ok().json(LinkModeration::new(slug, link)))
+}
+
+/// Suspends the link under `slug` on behalf of the admin key of `req`, returns the suspended link
+pub async fn suspend(
+    req: &HttpRequest,
+    state: &AppState,
+    slug: &str,
+    reason: Option<String>,
+) -> Result<Link, ApiError> {
+    let (admin, tenant) = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .map(|key| (key.key.name.clone(), key.key.tenant.clone()))
+        .unwrap_or_default();
+
+    let (_, link) = modify_link(state, slug, None, |link| {
+        tenants::ensure_same_tenant(tenant.as_deref(), link)?;
+        // Suspending twice keeps the original record of who did it and why
+        if link.suspension.is_none() {
+            link.suspension = Some(Suspension {
+                reason: reason.clone(),
+                suspended_by: admin.clone(),
+                suspended_at: Utc::now(),
+            });
+        }
+        Ok(())
+    })
+    .await?;
+    audit::record(
+        state,
+        &AuditContext::from_request(req, None),
+        AuditAction::LinkDisabled,
+        slug,
+        link.tenant.as_deref(),
+    )
+    .await;
+    log::warn!(
+        "Link {} to {} suspended by '{}': {}",
+        slug,
+        link.url,
+        admin,
+        reason.as_deref().unwrap_or("no reason given")
+    );
+    Ok(link)
+}
+
+/// Lifts a suspension, links disabled by their owner stay disabled
+#[post(
+    "/api/admin/links/{slug}/enable",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn enable_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let tenant = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .and_then(|key| key.key.tenant.clone());
+    let (_, link) = modify_link(&state, &slug, None, |link| {
+        tenants::ensure_same_tenant(tenant.as_deref(), link)?;
+        link.suspension = None;
+        Ok(())
+    })
+    .await?;
+    audit::record(
+        &state,
+        &AuditContext::from_request(&req, None),
+        AuditAction::LinkEnabled,
+        &slug,
+        link.tenant.as_deref(),
+    )
+    .await;
+    log::info!("Suspension of link {} lifted", slug);
+    Ok(HttpResponse::Ok().json(LinkModeration::new(slug, link)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_suspended_page_escapes_the_slug() {
+        let response = suspended_page("<evil>");
+        assert_eq!(response.status(), actix_web::http::StatusCode::GONE);
+
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("&lt;evil&gt;"));
+        assert!(body.contains("noindex"));
+    }
+}
@@ -0,0 +1,349 @@
+use actix_web::web::Data;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::error::ApiError;
+use crate::ownership::{self, OwnedLink};
+use crate::users;
+use crate::AppState;
+
+/// How long a webhook may take to answer
+const WEBHOOK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// When and how owners are told about their links expiring
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpiryNoticeConfig {
+    /// Links expiring within this window from a scan are announced
+    pub window: Duration,
+    /// How often the links are scanned
+    pub interval: std::time::Duration,
+    /// Receives a JSON `POST` per expiring link
+    pub webhook_url: Option<String>,
+    pub email: Option<EmailConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmailConfig {
+    /// `smtp://` or `smtps://` URL of the mail server, credentials included
+    pub smtp_url: String,
+    pub from: Mailbox,
+}
+
+/// A link about to expire, sent once per link and expiry
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ExpiryNotice {
+    pub owner: String,
+    /// Missing for accounts registered before emails were recorded by user id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub slug: String,
+    pub short_url: String,
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Channel expiry notices are sent through
+#[async_trait]
+pub trait ExpiryNotifier: Send + Sync {
+    async fn notify(&self, notice: &ExpiryNotice) -> Result<(), String>;
+}
+
+/// Posts each notice as JSON, any non-2xx answer is a failure
+pub struct Webhook {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        Webhook {
+            http: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .expect("HTTP client settings are valid"),
+            url,
+        }
+    }
+
+    pub async fn post(&self, body: &impl Serialize) -> Result<(), String> {
+        self.http
+            .post(&self.url)
+            .json(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[async_trait]
+impl ExpiryNotifier for Webhook {
+    async fn notify(&self, notice: &ExpiryNotice) -> Result<(), String> {
+        self.post(notice).await
+    }
+}
+
+/// Emails the owner, notices of owners without a known address are skipped
+pub struct Email {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Email {
+    pub fn new(config: &EmailConfig) -> Result<Self, String> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_url)
+            .map_err(|err| format!("invalid SMTP URL: {}", err))?
+            .build();
+        Ok(Email {
+            transport,
+            from: config.from.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl ExpiryNotifier for Email {
+    async fn notify(&self, notice: &ExpiryNotice) -> Result<(), String> {
+        let Some(email) = &notice.email else {
+            return Ok(());
+        };
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.parse().map_err(|err| format!("{}: {}", email, err))?)
+            .subject(format!("Your short link {} expires soon", notice.short_url))
+            .body(format!(
+                "Your short link {} to {} expires at {} UTC.\n\n\
+                 Change its expiry with PATCH /api/links/{} to keep it working.\n",
+                notice.short_url,
+                notice.url,
+                notice.expires_at.format("%Y-%m-%d %H:%M"),
+                notice.slug
+            ))
+            .map_err(|err| err.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Marks the link as announced until it expires. When the owner pushes the expiry out, the next
+/// notice goes out once the old expiry has passed.
+pub fn notice_key(slug: &str) -> String {
+    format!("expiry_notice:{}", slug)
+}
+
+/// Channels configured in `config`, fails on settings only checked when the channel is created
+fn notifiers(config: &ExpiryNoticeConfig) -> Result<Vec<Arc<dyn ExpiryNotifier>>, String> {
+    let mut notifiers: Vec<Arc<dyn ExpiryNotifier>> = Vec::new();
+    if let Some(url) = &config.webhook_url {
+        notifiers.push(Arc::new(Webhook::new(url.clone())));
+    }
+    if let Some(email) = &config.email {
+        notifiers.push(Arc::new(Email::new(email)?));
+    }
+    Ok(notifiers)
+}
+
+/// Scans for expiring links every `interval`, `None` when neither a webhook nor email is configured
+pub fn spawn_expiry_notifier(
+    state: Data<AppState>,
+    config: &ExpiryNoticeConfig,
+) -> Result<Option<JoinHandle<()>>, String> {
+    let notifiers = notifiers(config)?;
+    if notifiers.is_empty() {
+        return Ok(None);
+    }
+    let (window, every) = (config.window, config.interval);
+    Ok(Some(tokio::spawn(async move {
+        let mut ticker = interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            match notify_expiring(&state, &notifiers, window).await {
+                Ok(sent) => log::info!("Expiry scan done, {} expiring links announced", sent),
+                Err(err) => log::error!("Expiry scan failed: {}", err),
+            }
+        }
+    })))
+}
+
+/// Notifies the owners of every link expiring within `window`, returns the number of notices sent
+async fn notify_expiring(
+    state: &AppState,
+    notifiers: &[Arc<dyn ExpiryNotifier>],
+    window: Duration,
+) -> Result<usize, ApiError> {
+    let mut sent = 0;
+    let mut cursor = 0;
+    loop {
+        let (next, owners) = ownership::owners_page(state, cursor).await?;
+        for owner in &owners {
+            sent += notify_owner(state, notifiers, owner, Utc::now() + window).await?;
+        }
+        if next == 0 {
+            return Ok(sent);
+        }
+        cursor = next;
+    }
+}
+
+/// Notifies the owner of their links expiring by `deadline` that weren't announced yet
+async fn notify_owner(
+    state: &AppState,
+    notifiers: &[Arc<dyn ExpiryNotifier>],
+    owner: &str,
+    deadline: DateTime<Utc>,
+) -> Result<usize, ApiError> {
+    let links = ownership::expiring_links(state, owner, deadline).await?;
+    if links.is_empty() {
+        return Ok(0);
+    }
+    let email = users::user_email(state.store.as_ref(), owner).await?;
+    let mut sent = 0;
+    for OwnedLink {
+        slug,
+        short_url,
+        url,
+        expires_at,
+        ..
+    } in links
+    {
+        let Some(expires_at) = expires_at else {
+            continue;
+        };
+        // Claimed before sending, so several instances scanning at once don't notify twice
+        let claim = notice_key(&slug);
+        let ttl = (expires_at - Utc::now()).num_seconds().max(1) as usize;
+        if !state.store.set(&claim, "1", Some(ttl)).await? {
+            continue;
+        }
+        let notice = ExpiryNotice {
+            owner: owner.to_string(),
+            email: email.clone(),
+            slug,
+            short_url,
+            url,
+            expires_at,
+        };
+        if deliver(notifiers, &notice).await {
+            sent += 1;
+        } else if let Err(err) = state.store.delete(&claim).await {
+            log::warn!(
+                "Failed to release the expiry notice of {}: {}",
+                notice.slug,
+                err
+            );
+        }
+    }
+    Ok(sent)
+}
+
+/// Whether any of the channels took the notice, a notice no channel took is tried again on the next scan
+async fn deliver(notifiers: &[Arc<dyn ExpiryNotifier>], notice: &ExpiryNotice) -> bool {
+    let mut delivered = false;
+    for notifier in notifiers {
+        match notifier.notify(notice).await {
+            Ok(()) => delivered = true,
+            Err(err) => log::warn!(
+                "Failed to send the expiry notice of {}: {}",
+                notice.slug,
+                err
+            ),
+        }
+    }
+    delivered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::{create_link, AppConfig, Creator, UrlShortenOptions, UrlShortener};
+    use async_graphql::MaybeUndefined;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct RecordingNotifier {
+        notices: Mutex<Vec<ExpiryNotice>>,
+        failing: bool,
+    }
+
+    #[async_trait]
+    impl ExpiryNotifier for RecordingNotifier {
+        async fn notify(&self, notice: &ExpiryNotice) -> Result<(), String> {
+            if self.failing {
+                return Err("unreachable".to_string());
+            }
+            self.notices.lock().unwrap().push(notice.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_owners_are_notified_once_per_expiry() {
+        let shortener =
+            UrlShortener::with_store(&AppConfig::default(), Arc::new(MemoryStore::new()))
+                .await
+                .unwrap();
+        let state = &shortener.state;
+        let owner = Creator {
+            owner: Some("u_1".to_string()),
+            ..Default::default()
+        };
+        let mut created = Vec::new();
+        for hours in [1, 24 * 7] {
+            let options = UrlShortenOptions {
+                url: format!("https://example.com/{}", hours),
+                expires_in_seconds: MaybeUndefined::Value(hours * 60 * 60),
+                ..Default::default()
+            };
+            created.push(create_link(state, options, &owner).await.unwrap());
+        }
+        let deadline = Utc::now() + Duration::days(1);
+
+        let failing: Vec<Arc<dyn ExpiryNotifier>> = vec![Arc::new(RecordingNotifier {
+            failing: true,
+            ..Default::default()
+        })];
+        assert_eq!(
+            notify_owner(state, &failing, "u_1", deadline)
+                .await
+                .unwrap(),
+            0
+        );
+
+        let recorder = Arc::new(RecordingNotifier::default());
+        let notifiers: Vec<Arc<dyn ExpiryNotifier>> = vec![recorder.clone()];
+        assert_eq!(
+            notify_owner(state, &notifiers, "u_1", deadline)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            notify_owner(state, &notifiers, "u_1", deadline)
+                .await
+                .unwrap(),
+            0
+        );
+        let notices = recorder.notices.lock().unwrap();
+        assert_eq!(notices[0].short_url, created[0].short_url);
+        assert_eq!(notices[0].url, "https://example.com/1");
+        assert_eq!(notices[0].email, None);
+        assert!(
+            (notices[0].expires_at - created[0].expires_at.unwrap())
+                .num_seconds()
+                .abs()
+                <= 1
+        );
+    }
+}
@@ -0,0 +1,993 @@
+use actix_web::http::header;
+use actix_web::web::{self, Data};
+use actix_web::{delete, get, patch, HttpRequest, HttpResponse};
+use async_graphql::{InputObject, SimpleObject};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{self, AuditAction, AuditContext};
+use crate::auth::admin_key;
+use crate::body::JsonBody;
+use crate::breakdown;
+use crate::clicks;
+use crate::deadlinks;
+use crate::dedup;
+use crate::error::ApiError;
+use crate::events::LinkEvent;
+use crate::expiration::{compute_ttl, parse_range};
+use crate::expiry_events;
+use crate::link::{Link, Schedule};
+use crate::metadata::{self, LinkMetadata};
+use crate::notifications;
+use crate::split;
+use crate::storage::StorageError;
+use crate::tags;
+use crate::tenants;
+use crate::threats;
+use crate::timeseries;
+use crate::users::{owned_links_key, CurrentUser, MaybeUser};
+use crate::validation::validate_and_normalize;
+use crate::visitors;
+use crate::AppState;
+
+/// Times an update is re-applied to a freshly loaded link when another update got in between
+const MAX_UPDATE_ATTEMPTS: u32 = 3;
+/// Keys looked at per `SCAN` page when visiting every user's links
+const SCAN_PAGE_SIZE: usize = 500;
+/// Most links on one page of `GET /api/me/links`
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Serialize, SimpleObject)]
+#[graphql(name = "Link")]
+pub struct OwnedLink {
+    pub slug: String,
+    pub short_url: String,
+    pub url: String,
+    pub enabled: bool,
+    pub suspended: bool,
+    /// Set for reserved slugs still waiting for their destination
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub reserved: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Redirects counted so far, missing for links created before clicks were counted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clicks: Option<u64>,
+    /// Missing for links stored without an expiry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Missing for links created before creation times were kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Title, description and icon of the destination, missing until they were fetched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<LinkMetadata>,
+}
+
+impl OwnedLink {
+    pub fn new(state: &AppState, slug: String, link: Link) -> Self {
+        OwnedLink {
+            short_url: state.short_url(&slug),
+            slug,
+            url: link.url,
+            enabled: !link.disabled,
+            suspended: link.suspension.is_some(),
+            reserved: link.reserved,
+            tags: link.tags,
+            clicks: None,
+            expires_at: None,
+            created_at: None,
+            metadata: None,
+        }
+    }
+}
+
+/// Fills in the click counts of `links`
+pub async fn count_clicks(state: &AppState, links: &mut [OwnedLink]) -> Result<(), ApiError> {
+    let slugs: Vec<String> = links.iter().map(|link| link.slug.clone()).collect();
+    let totals = clicks::total_clicks(state.store.as_ref(), &slugs).await?;
+    for (link, total) in links.iter_mut().zip(totals) {
+        link.clicks = total;
+    }
+    Ok(())
+}
+
+/// Index of the user's links scored by when they were created, in microseconds. Links created before the index
+/// existed score 0.
+pub fn links_by_created_key(user_id: &str) -> String {
+    format!("links_by_created:{}", user_id)
+}
+
+/// Index of the user's links scored by their redirects
+pub fn links_by_clicks_key(user_id: &str) -> String {
+    format!("links_by_clicks:{}", user_id)
+}
+
+/// Entries of a link created by `owner` at `created_at` in the owner's indexes as `(key, member, score)`
+pub fn index_entries(
+    owner: &str,
+    slug: &str,
+    created_at: DateTime<Utc>,
+) -> [(String, String, i64); 2] {
+    [
+        (
+            links_by_created_key(owner),
+            slug.to_string(),
+            created_at.timestamp_micros(),
+        ),
+        (links_by_clicks_key(owner), slug.to_string(), 0),
+    ]
+}
+
+/// Fills in when the links of the user were created, going by their scores in the user's index
+pub async fn load_created(
+    state: &AppState,
+    user_id: &str,
+    links: &mut [OwnedLink],
+) -> Result<(), ApiError> {
+    let slugs: Vec<String> = links.iter().map(|link| link.slug.clone()).collect();
+    let scores = state
+        .store
+        .scores(&links_by_created_key(user_id), &slugs)
+        .await?;
+    for (link, score) in links.iter_mut().zip(scores) {
+        link.created_at = score.and_then(created_at);
+    }
+    Ok(())
+}
+
+/// Creation time of a score in the index by creation time, `None` for links from before the index
+pub fn created_at(score: i64) -> Option<DateTime<Utc>> {
+    (score > 0)
+        .then(|| DateTime::from_timestamp_micros(score))
+        .flatten()
+}
+
+/// Counters stored next to a new link under `slug` as `(key, value, ttl)`, they expire together with it
+pub fn counter_entries(
+    slug: &str,
+    max_clicks: Option<u64>,
+    variants: usize,
+    ttl: Option<usize>,
+) -> Vec<(String, String, Option<usize>)> {
+    std::iter::once(clicks::total_entry(slug, ttl))
+        .chain(max_clicks.map(|max_clicks| clicks::counter_entry(slug, max_clicks, ttl)))
+        .chain(split::counter_entries(slug, variants, ttl))
+        .collect()
+}
+
+/// Fills in the destination metadata of `links`
+pub async fn load_metadata(state: &AppState, links: &mut [OwnedLink]) -> Result<(), ApiError> {
+    let slugs: Vec<String> = links.iter().map(|link| link.slug.clone()).collect();
+    let found = metadata::load(state.store.as_ref(), &slugs).await?;
+    for (link, metadata) in links.iter_mut().zip(found) {
+        link.metadata = metadata;
+    }
+    Ok(())
+}
+
+/// Fills in when `links` expire, going by the TTL of their records
+pub async fn load_expiry(state: &AppState, links: &mut [OwnedLink]) -> Result<(), ApiError> {
+    let slugs: Vec<String> = links.iter().map(|link| link.slug.clone()).collect();
+    let ttls = state.store.ttl_many(&slugs).await?;
+    let now = Utc::now();
+    for (link, ttl) in links.iter_mut().zip(ttls) {
+        link.expires_at = ttl.map(|ttl| now + Duration::seconds(ttl as i64));
+    }
+    Ok(())
+}
+
+/// Rejects redirects and previews of links their owner disabled or an admin suspended, of reserved slugs
+/// without a destination yet and of scheduled links outside their window. Links past `active_until` answer
+/// like expired ones.
+pub fn ensure_enabled(slug: &str, link: &Link) -> Result<(), ApiError> {
+    if link.reserved {
+        return Err(ApiError::not_found(slug));
+    }
+    if link.disabled || link.suspension.is_some() {
+        return Err(ApiError::Gone {
+            message: format!("The link '{}' is disabled.", slug),
+        });
+    }
+    match (link.schedule(Utc::now()), link.active_from) {
+        (Schedule::Pending, Some(active_from)) => Err(ApiError::NotYetActive {
+            slug: slug.to_string(),
+            active_from,
+        }),
+        (Schedule::Ended, _) => Err(ApiError::not_found(slug)),
+        _ => Ok(()),
+    }
+}
+
+/// Records `slugs` as created by `owner` just now, failures only affect the listing so they are logged
+pub async fn record_owned_links(state: &AppState, owner: &str, slugs: &[String]) {
+    let now = Utc::now();
+    let links: Vec<(String, DateTime<Utc>)> =
+        slugs.iter().map(|slug| (slug.clone(), now)).collect();
+    index_owned_links(state, owner, &links).await;
+}
+
+/// Adds `links` to the set and indexes of `owner` as `(slug, created_at)`, failures are logged
+pub async fn index_owned_links(state: &AppState, owner: &str, links: &[(String, DateTime<Utc>)]) {
+    let slugs: Vec<String> = links.iter().map(|(slug, _)| slug.clone()).collect();
+    let (mut created, mut clicks) = (Vec::new(), Vec::new());
+    for (slug, created_at) in links {
+        let [(_, _, created_score), (_, _, clicks_score)] = index_entries(owner, slug, *created_at);
+        created.push((slug.clone(), created_score));
+        clicks.push((slug.clone(), clicks_score));
+    }
+    let recorded = async {
+        state
+            .store
+            .add_to_set(&owned_links_key(owner), &slugs)
+            .await?;
+        state
+            .store
+            .add_scored(&links_by_created_key(owner), &created)
+            .await?;
+        state
+            .store
+            .add_scored(&links_by_clicks_key(owner), &clicks)
+            .await
+    };
+    if let Err(err) = recorded.await {
+        log::warn!("Failed to record links owned by {}: {}", owner, err);
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum LinkSort {
+    #[default]
+    CreatedAt,
+    Clicks,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+#[derive(Deserialize)]
+struct MyLinksQuery {
+    /// Only links expiring within this range from now, like `24h` or `7d`
+    expiring_within: Option<String>,
+    #[serde(default)]
+    sort: LinkSort,
+    #[serde(default)]
+    order: SortOrder,
+    /// All links when left out
+    page_size: Option<usize>,
+    /// Where the previous page ended, taken from its `Link` header
+    cursor: Option<String>,
+}
+
+/// Position of a link in the owner's index: its score, then its slug to break ties
+#[derive(Clone, Debug, PartialEq)]
+struct ListingCursor {
+    value: i64,
+    slug: String,
+}
+
+impl ListingCursor {
+    fn parse(cursor: &str) -> Option<Self> {
+        let (value, slug) = cursor.split_once('.')?;
+        Some(ListingCursor {
+            value: value.parse().ok()?,
+            slug: slug.to_string(),
+        })
+        .filter(|cursor| !cursor.slug.is_empty())
+    }
+
+    fn encode(&self) -> String {
+        format!("{}.{}", self.value, self.slug)
+    }
+}
+
+/// The user's links, newest first unless `sort` and `order` say otherwise, read from the owner's index of that
+/// sort. With `page_size` the listing is cut into pages, the `Link` header of a page points at the next one.
+/// Pages start behind the position of the last link, so links created or deleted meanwhile don't shift them.
+/// Click counts keep going up while paging, a link sorted by clicks can move past the cursor and show up
+/// twice or not at all.
+#[get("/api/me/links")]
+async fn my_links(
+    req: HttpRequest,
+    query: web::Query<MyLinksQuery>,
+    user: CurrentUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let expiring_within = query
+        .expiring_within
+        .as_deref()
+        .map(|range| {
+            parse_range(range).ok_or_else(|| {
+                ApiError::validation(
+                    "invalid_range",
+                    "expiring_within must be a number of hours or days like '24h' or '7d'",
+                )
+            })
+        })
+        .transpose()?;
+    let page_size = query
+        .page_size
+        .map(|size| match size {
+            1..=MAX_PAGE_SIZE => Ok(size),
+            _ => Err(ApiError::validation(
+                "invalid_page_size",
+                format!("page_size must be between 1 and {}", MAX_PAGE_SIZE),
+            )),
+        })
+        .transpose()?;
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|cursor| {
+            ListingCursor::parse(cursor).ok_or_else(|| {
+                ApiError::validation(
+                    "invalid_cursor",
+                    "cursor must be taken from the Link header of the previous page",
+                )
+            })
+        })
+        .transpose()?;
+
+    let index = match query.sort {
+        LinkSort::CreatedAt => links_by_created_key(&user.id),
+        LinkSort::Clicks => links_by_clicks_key(&user.id),
+    };
+    let descending = query.order == SortOrder::Desc;
+    let wanted = page_size.unwrap_or(usize::MAX);
+    let deadline = expiring_within.map(|within| Utc::now() + within);
+    let (mut positions, mut page, mut stale) = (Vec::new(), Vec::new(), Vec::new());
+    let mut after = cursor;
+    let mut more = false;
+    // The index is read a batch at a time since expired and deleted links are skipped, reading one link more
+    // than the page needs tells whether there is a next page
+    'batches: loop {
+        let need = (wanted.saturating_sub(page.len()).saturating_add(1)).min(MAX_PAGE_SIZE + 1);
+        let batch = state
+            .store
+            .scored_page(
+                &index,
+                after
+                    .as_ref()
+                    .map(|after| (after.value, after.slug.as_str())),
+                descending,
+                need,
+            )
+            .await?;
+        let exhausted = batch.len() < need;
+        let Some((slug, score)) = batch.last() else {
+            break;
+        };
+        after = Some(ListingCursor {
+            value: *score,
+            slug: slug.clone(),
+        });
+        let (batch_positions, mut links) =
+            live_members(&state, &user.id, batch, &mut stale).await?;
+        let mut batch_positions = batch_positions.into_iter();
+        if let Some(deadline) = deadline {
+            load_expiry(&state, &mut links).await?;
+            let (kept_positions, kept): (Vec<_>, Vec<_>) = batch_positions
+                .zip(links)
+                .filter(|(_, link)| expires_by(link, deadline))
+                .unzip();
+            batch_positions = kept_positions.into_iter();
+            links = kept;
+        }
+        for (position, link) in batch_positions.zip(links) {
+            if page.len() == wanted {
+                more = true;
+                break 'batches;
+            }
+            positions.push(position);
+            page.push(link);
+        }
+        if exhausted {
+            break;
+        }
+    }
+    forget_stale(&state, &user.id, stale).await;
+    let next = positions.last().filter(|_| more).map(ListingCursor::encode);
+
+    count_clicks(&state, &mut page).await?;
+    load_created(&state, &user.id, &mut page).await?;
+    if deadline.is_none() {
+        load_expiry(&state, &mut page).await?;
+    }
+    load_metadata(&state, &mut page).await?;
+
+    let mut response = HttpResponse::Ok();
+    if let Some(next) = next {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(
+                url::form_urlencoded::parse(req.query_string().as_bytes())
+                    .filter(|(name, _)| name != "cursor"),
+            )
+            .append_pair("cursor", &next)
+            .finish();
+        response.insert_header((
+            header::LINK,
+            format!("<{}?{}>; rel=\"next\"", req.path(), query),
+        ));
+    }
+    Ok(response.json(page))
+}
+
+/// Links created by the user that still exist, oldest slugs first
+pub async fn owned_links(state: &AppState, user_id: &str) -> Result<Vec<OwnedLink>, ApiError> {
+    let (mut links, stale) = load_owned_links(state, user_id).await?;
+    count_clicks(state, &mut links).await?;
+    load_expiry(state, &mut links).await?;
+    load_created(state, user_id, &mut links).await?;
+    load_metadata(state, &mut links).await?;
+    forget_stale(state, user_id, stale).await;
+    Ok(links)
+}
+
+/// The links among the index members `batch` that still exist and belong to the user, with their positions in
+/// the index. The slugs of the others are added to `stale`.
+async fn live_members(
+    state: &AppState,
+    user_id: &str,
+    batch: Vec<(String, i64)>,
+    stale: &mut Vec<String>,
+) -> Result<(Vec<ListingCursor>, Vec<OwnedLink>), ApiError> {
+    let slugs: Vec<String> = batch.iter().map(|(slug, _)| slug.clone()).collect();
+    let records = state.store.get_many(&slugs).await?;
+    let (mut positions, mut links) = (Vec::new(), Vec::new());
+    for ((slug, score), record) in batch.into_iter().zip(records) {
+        match record.map(|record| Link::decode(&record)) {
+            Some(link) if link.owner.as_deref() == Some(user_id) => {
+                positions.push(ListingCursor {
+                    value: score,
+                    slug: slug.clone(),
+                });
+                links.push(OwnedLink::new(state, slug, link));
+            }
+            _ => stale.push(slug),
+        }
+    }
+    Ok((positions, links))
+}
+
+/// Drops slugs from the user's set and indexes
+pub async fn forget_owned_links(
+    state: &AppState,
+    user_id: &str,
+    slugs: &[String],
+) -> Result<(), StorageError> {
+    if slugs.is_empty() {
+        return Ok(());
+    }
+    state
+        .store
+        .remove_from_set(&owned_links_key(user_id), slugs)
+        .await?;
+    state
+        .store
+        .remove_scored(&links_by_created_key(user_id), slugs)
+        .await?;
+    state
+        .store
+        .remove_scored(&links_by_clicks_key(user_id), slugs)
+        .await
+}
+
+/// Drops the stale slugs found while listing the user's links, failures only leave them for the next listing
+async fn forget_stale(state: &AppState, user_id: &str, stale: Vec<String>) {
+    match forget_owned_links(state, user_id, &stale).await {
+        Ok(()) => publish_expired(state, user_id, stale),
+        Err(err) => log::warn!("Failed to prune expired owned links: {}", err),
+    }
+}
+
+/// Drops the slugs of expired and deleted links from the user's set and indexes, returns how many were dropped.
+/// Links created before the indexes existed are added to them, without a creation time.
+async fn prune_owned_links(state: &AppState, user_id: &str) -> Result<usize, ApiError> {
+    let (mut links, stale) = load_owned_links(state, user_id).await?;
+    count_clicks(state, &mut links).await?;
+    let unknown: Vec<(String, i64)> = links.iter().map(|link| (link.slug.clone(), 0)).collect();
+    let clicks: Vec<(String, i64)> = links
+        .iter()
+        .map(|link| (link.slug.clone(), link.clicks.unwrap_or(0) as i64))
+        .collect();
+    state
+        .store
+        .add_scored(&links_by_created_key(user_id), &unknown)
+        .await?;
+    state
+        .store
+        .add_scored(&links_by_clicks_key(user_id), &clicks)
+        .await?;
+    forget_owned_links(state, user_id, &stale).await?;
+    let pruned = stale.len();
+    publish_expired(state, user_id, stale);
+    Ok(pruned)
+}
+
+/// Announces the links whose slugs were pruned from the owner's set, unless they were announced when they
+/// expired already
+fn publish_expired(state: &AppState, user_id: &str, stale: Vec<String>) {
+    if state.expiry_events {
+        return;
+    }
+    let at = Utc::now();
+    for slug in stale {
+        state.publish(LinkEvent::LinkExpired {
+            slug,
+            owner: Some(user_id.to_string()),
+            at,
+        });
+    }
+}
+
+/// Link records expire on their own, but their slugs stay in the owner's set until it is listed.
+/// Returns the number of users visited and the number of slugs dropped.
+pub async fn purge_expired(state: &AppState) -> Result<(usize, usize), ApiError> {
+    let (mut users, mut purged) = (0, 0);
+    let mut cursor = 0;
+    loop {
+        let (next, owners) = owners_page(state, cursor).await?;
+        for user_id in &owners {
+            users += 1;
+            purged += prune_owned_links(state, user_id).await?;
+        }
+        if next == 0 {
+            return Ok((users, purged));
+        }
+        cursor = next;
+    }
+}
+
+/// Ids of the users with link sets on one `scan_keys` page starting at `cursor`, along with the next cursor
+pub async fn owners_page(state: &AppState, cursor: u64) -> Result<(u64, Vec<String>), ApiError> {
+    let prefix = owned_links_key("");
+    let (next, keys) = state.store.scan_keys(cursor, SCAN_PAGE_SIZE).await?;
+    let owners = keys
+        .iter()
+        .filter_map(|key| key.strip_prefix(&prefix))
+        .map(String::from)
+        .collect();
+    Ok((next, owners))
+}
+
+/// Existing links of the user expiring by `deadline`, with their expiry filled in
+pub async fn expiring_links(
+    state: &AppState,
+    user_id: &str,
+    deadline: DateTime<Utc>,
+) -> Result<Vec<OwnedLink>, ApiError> {
+    let (mut links, _) = load_owned_links(state, user_id).await?;
+    load_expiry(state, &mut links).await?;
+    links.retain(|link| expires_by(link, deadline));
+    Ok(links)
+}
+
+fn expires_by(link: &OwnedLink, deadline: DateTime<Utc>) -> bool {
+    link.expires_at
+        .is_some_and(|expires_at| expires_at <= deadline)
+}
+
+/// Existing links of the user along with the stale slugs left in their set,
+/// expired links leave their slug behind since sets don't expire with them
+async fn load_owned_links(
+    state: &AppState,
+    user_id: &str,
+) -> Result<(Vec<OwnedLink>, Vec<String>), ApiError> {
+    let mut slugs = state.store.set_members(&owned_links_key(user_id)).await?;
+    slugs.sort();
+    let records = state.store.get_many(&slugs).await?;
+
+    let mut links = Vec::new();
+    let mut stale = Vec::new();
+    for (slug, record) in slugs.into_iter().zip(records) {
+        match record.map(|record| Link::decode(&record)) {
+            Some(link) if link.owner.as_deref() == Some(user_id) => {
+                links.push(OwnedLink::new(state, slug, link))
+            }
+            _ => stale.push(slug),
+        }
+    }
+    Ok((links, stale))
+}
+
+/// Loads the link stored under `slug`, returns its raw record and the decoded link
+pub async fn load_link(state: &AppState, slug: &str) -> Result<(String, Link), ApiError> {
+    let record = if slug.contains(':') {
+        None
+    } else {
+        state.store.get(slug).await?
+    };
+    let record = record.ok_or_else(|| ApiError::not_found(slug))?;
+    let link = Link::decode(&record);
+    Ok((record, link))
+}
+
+/// Suspended links stay as they are until an admin lifts the suspension, so owners can't hide the evidence
+fn ensure_owner(link: &Link, user_id: &str) -> Result<(), ApiError> {
+    if link.owner.as_deref() != Some(user_id) {
+        return Err(ApiError::Forbidden {
+            message: "Only the owner can change this link.".to_string(),
+        });
+    }
+    if link.suspension.is_some() {
+        return Err(ApiError::Forbidden {
+            message: "The link was suspended by an admin and can't be changed.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Applies `change` to the link stored under `slug` with a compare-and-set against the record it was loaded
+/// from, a change that raced with another write is re-applied on top of it instead of overwriting it.
+/// Returns the previous record and the updated link.
+pub async fn modify_link(
+    state: &AppState,
+    slug: &str,
+    ttl: Option<usize>,
+    change: impl Fn(&mut Link) -> Result<(), ApiError>,
+) -> Result<(String, Link), ApiError> {
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+        let (record, mut link) = load_link(state, slug).await?;
+        change(&mut link)?;
+        if state
+            .store
+            .compare_and_set(slug, &record, &link.encode(), ttl)
+            .await?
+        {
+            state.link_cache.invalidate(slug);
+            return Ok((record, link));
+        }
+        log::info!("Link {} changed while updating it, retrying", slug);
+    }
+    Err(ApiError::Conflict {
+        code: "update_conflict",
+        message: format!(
+            "The link '{}' kept changing while updating it, try again.",
+            slug
+        ),
+    })
+}
+
+/// Counters, analytics and notices kept next to the link under `slug`, besides its entries in the sets of its
+/// owner and tags. Without the link, e.g. once it expired, the counters of every possible variant are included.
+pub fn leftover_keys(slug: &str, link: Option<&Link>, now: DateTime<Utc>) -> Vec<String> {
+    let counters = match link {
+        Some(link) => counter_keys(slug, link),
+        None => [
+            clicks::total_key(slug),
+            metadata::metadata_key(slug),
+            clicks::counter_key(slug),
+        ]
+        .into_iter()
+        .chain((0..split::MAX_VARIANTS).map(|index| split::served_key(slug, index)))
+        .collect(),
+    };
+    counters
+        .into_iter()
+        .chain(timeseries::bucket_keys(slug, now))
+        .chain(visitors::visitor_keys(slug, now))
+        .chain(breakdown::breakdown_keys(slug))
+        .chain([
+            notifications::notice_key(slug),
+            deadlinks::failures_key(slug),
+            expiry_events::indexes_key(slug),
+        ])
+        .collect()
+}
+
+/// Keys of the records stored next to the link under `slug`: its counters and its metadata
+pub fn counter_keys(slug: &str, link: &Link) -> Vec<String> {
+    [clicks::total_key(slug), metadata::metadata_key(slug)]
+        .into_iter()
+        .chain(link.max_clicks.map(|_| clicks::counter_key(slug)))
+        .chain((0..link.variants.len()).map(|index| split::served_key(slug, index)))
+        .collect()
+}
+
+fn missing_token() -> ApiError {
+    ApiError::Unauthorized {
+        code: "missing_token",
+        message: "Log in and pass the token in an 'Authorization: Bearer <token>' header."
+            .to_string(),
+    }
+}
+
+/// Caller of a link management endpoint, a logged in user, an admin API key or both
+pub struct Manager {
+    pub user_id: Option<String>,
+    pub admin: bool,
+    /// Tenant of the admin API key, such keys only manage the links of their tenant
+    pub tenant: Option<String>,
+    /// Recorded with every change the manager makes
+    pub audit: AuditContext,
+}
+
+impl Manager {
+    /// The caller of the request, anonymous when it carries neither a session token nor an admin API key
+    pub async fn from_request(req: &HttpRequest, user: MaybeUser) -> Result<Self, ApiError> {
+        let admin = admin_key(req).await?;
+        let user_id = user.0.map(|user| user.id);
+        Ok(Manager {
+            audit: AuditContext::from_request(req, user_id.as_deref()),
+            user_id,
+            admin: admin.is_some(),
+            tenant: admin.and_then(|admin| admin.key.tenant),
+        })
+    }
+
+    /// Like `from_request`, but rejects anonymous callers
+    pub async fn identify(req: &HttpRequest, user: MaybeUser) -> Result<Self, ApiError> {
+        let manager = Self::from_request(req, user).await?;
+        manager.ensure_identified()?;
+        Ok(manager)
+    }
+
+    pub fn ensure_identified(&self) -> Result<(), ApiError> {
+        if !self.admin && self.user_id.is_none() {
+            return Err(missing_token());
+        }
+        Ok(())
+    }
+
+    /// Id of the logged in user, admin API keys don't own links
+    pub fn user(&self) -> Result<&str, ApiError> {
+        self.user_id.as_deref().ok_or_else(missing_token)
+    }
+
+    /// Admins can change any link of their tenant, users only their own links that are not suspended
+    pub fn ensure_can_change(&self, link: &Link) -> Result<(), ApiError> {
+        match &self.user_id {
+            _ if self.admin => tenants::ensure_same_tenant(self.tenant.as_deref(), link),
+            Some(user_id) => ensure_owner(link, user_id),
+            None => Err(ApiError::Forbidden {
+                message: "Only the owner can change this link.".to_string(),
+            }),
+        }
+    }
+
+    /// Admins can see any link of their tenant, users only their own
+    pub fn ensure_can_view(&self, link: &Link) -> Result<(), ApiError> {
+        if self.admin {
+            return tenants::ensure_same_tenant(self.tenant.as_deref(), link);
+        }
+        if link.owner.is_some() && link.owner == self.user_id {
+            return Ok(());
+        }
+        Err(ApiError::Forbidden {
+            message: "Only the owner can see this link.".to_string(),
+        })
+    }
+}
+
+/// Fields of a link that can be changed, omitted fields are left as they are
+#[derive(Deserialize, InputObject)]
+#[graphql(name = "UpdateLinkInput")]
+pub struct UpdateLinkRequest {
+    url: Option<String>,
+    /// New lifetime counted from now, like `expires_in_seconds` when shortening
+    expires_in_seconds: Option<u64>,
+    expires_at: Option<DateTime<Utc>>,
+    enabled: Option<bool>,
+}
+
+impl UpdateLinkRequest {
+    fn apply(&self, link: &mut Link, url: Option<&String>) {
+        if let Some(url) = url {
+            link.url = url.clone();
+        }
+        if let Some(enabled) = self.enabled {
+            link.disabled = !enabled;
+        }
+    }
+}
+
+/// Changes the destination, expiry or enabled state of a link, allowed for its owner and admin API keys
+#[patch("/api/links/{slug}")]
+async fn update_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    user: MaybeUser,
+    body: JsonBody<UpdateLinkRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let manager = Manager::identify(&req, user).await?;
+    let updated = change_link(
+        &state,
+        state.slug(path.into_inner()),
+        body.into_inner(),
+        &manager,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(updated))
+}
+
+/// Applies an update of the owner or an admin to the link, shared by the REST and GraphQL APIs
+pub async fn change_link(
+    state: &AppState,
+    slug: String,
+    body: UpdateLinkRequest,
+    manager: &Manager,
+) -> Result<OwnedLink, ApiError> {
+    if body.url.is_none()
+        && body.expires_in_seconds.is_none()
+        && body.expires_at.is_none()
+        && body.enabled.is_none()
+    {
+        return Err(ApiError::validation(
+            "invalid_update",
+            "Provide at least one of url, expires_in_seconds, expires_at or enabled.",
+        ));
+    }
+
+    let url = body
+        .url
+        .as_deref()
+        .map(|url| validate_and_normalize(url, state.max_url_length))
+        .transpose()
+        .map_err(|err| ApiError::validation("invalid_url", err.to_string()))?;
+    if let Some(url) = &url {
+        state.short_domains.ensure_not_short_link(url)?;
+        state.domains.check_url(url)?;
+        threats::check_destination(state, url).await?;
+    }
+    let now = Utc::now();
+    let ttl = if body.expires_in_seconds.is_some() || body.expires_at.is_some() {
+        let ttl = compute_ttl(
+            body.expires_in_seconds,
+            body.expires_at,
+            now,
+            state.default_ttl_seconds,
+            state.ttl_bounds,
+        )
+        .map_err(|err| ApiError::validation("invalid_expiration", err.to_string()))?;
+        Some(ttl)
+    } else {
+        None
+    };
+
+    let (record, link) = modify_link(state, &slug, ttl, |link| {
+        manager.ensure_can_change(link)?;
+        if link.reserved {
+            return Err(ApiError::validation(
+                "invalid_update",
+                "Attach a destination to the reserved slug with POST /api/slugs/{slug}/attach first.",
+            ));
+        }
+        if url.is_some() && !link.variants.is_empty() {
+            return Err(ApiError::validation(
+                "invalid_update",
+                "The destinations of a split test can't be changed.",
+            ));
+        }
+        body.apply(link, url.as_ref());
+        Ok(())
+    })
+    .await?;
+    audit::record(
+        state,
+        &manager.audit,
+        AuditAction::LinkUpdated,
+        &slug,
+        link.tenant.as_deref(),
+    )
+    .await;
+    if let Some(ttl) = ttl {
+        // Counters have to live as long as the link, the dedup entry would report the old expiry
+        let indexes = (
+            expiry_events::indexes_key(&slug),
+            expiry_events::indexes_ttl(ttl),
+        );
+        for (key, ttl) in counter_keys(&slug, &link)
+            .into_iter()
+            .chain([deadlinks::failures_key(&slug)])
+            .map(|key| (key, ttl))
+            .chain([indexes])
+        {
+            if let Err(err) = state.store.expire(&key, ttl).await {
+                log::warn!("Failed to move the expiry of {}: {}", key, err);
+            }
+        }
+        if let Err(err) = state.store.delete(&dedup::index_key(&record)).await {
+            log::warn!("Failed to drop the dedup entry of {}: {}", slug, err);
+        }
+    }
+    // Other changes alter the record, so an outdated dedup entry no longer matches and is ignored
+    let mut updated = OwnedLink::new(state, slug, link);
+    count_clicks(state, std::slice::from_mut(&mut updated)).await?;
+    load_expiry(state, std::slice::from_mut(&mut updated)).await?;
+    if let Some(url) = &url {
+        // The metadata described the previous destination
+        state
+            .store
+            .delete(&metadata::metadata_key(&updated.slug))
+            .await?;
+        let ttl = updated
+            .expires_at
+            .map(|at| (at - Utc::now()).num_seconds().max(1) as usize);
+        state.metadata.fetch_later(&updated.slug, url, ttl);
+    }
+    load_metadata(state, std::slice::from_mut(&mut updated)).await?;
+    Ok(updated)
+}
+
+#[delete("/api/links/{slug}")]
+async fn delete_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    user: CurrentUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let audit = AuditContext::from_request(&req, Some(&user.id));
+    remove_link(&state, &state.slug(path.into_inner()), &user.id, &audit).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Deletes a link of the user along with the records kept next to it
+pub async fn remove_link(
+    state: &AppState,
+    slug: &str,
+    user_id: &str,
+    audit: &AuditContext,
+) -> Result<(), ApiError> {
+    let (record, link) = load_link(state, slug).await?;
+    ensure_owner(&link, user_id)?;
+
+    state.store.delete(slug).await?;
+    state.link_cache.invalidate(slug);
+    audit::record(
+        state,
+        audit,
+        AuditAction::LinkDeleted,
+        slug,
+        link.tenant.as_deref(),
+    )
+    .await;
+    // Leftovers only cost space, the link itself is gone
+    let mut cleanup = vec![
+        forget_owned_links(state, user_id, &[slug.to_string()]).await,
+        state
+            .store
+            .delete(&dedup::index_key(&record))
+            .await
+            .map(|_| ()),
+    ];
+    for key in leftover_keys(slug, Some(&link), Utc::now()) {
+        cleanup.push(state.store.delete(&key).await.map(|_| ()));
+    }
+    for err in cleanup.into_iter().filter_map(Result::err) {
+        log::warn!("Failed to clean up after deleting link {}: {}", slug, err);
+    }
+    tags::unindex_link(state, slug, &link).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_only_touches_given_fields() {
+        let update: UpdateLinkRequest = serde_json::from_str(r#"{"enabled": false}"#).unwrap();
+        let mut link = Link {
+            owner: Some("u_1".to_string()),
+            ..Link::new("https://example.com".to_string())
+        };
+        update.apply(&mut link, None);
+
+        assert!(link.disabled);
+        assert_eq!(link.url, "https://example.com");
+        assert_eq!(link.owner.as_deref(), Some("u_1"));
+        assert!(ensure_enabled("abc", &link).is_err());
+
+        let update: UpdateLinkRequest = serde_json::from_str(r#"{"enabled": true}"#).unwrap();
+        update.apply(&mut link, Some(&"https://example.org/".to_string()));
+        assert!(ensure_enabled("abc", &link).is_ok());
+        assert_eq!(link.url, "https://example.org/");
+    }
+}
@@ -0,0 +1,274 @@
+use actix_web::http::header::{self, Accept, Header};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use askama::Template;
+use chrono::{DateTime, Utc};
+
+use crate::error::ApiError;
+use crate::link::Link;
+use crate::metadata::LinkMetadata;
+use crate::ownership::OwnedLink;
+use crate::short_domains::split_key;
+use crate::AppState;
+
+/// `404` page for unknown slugs. Expired links are removed from storage, so they end up here too.
+#[derive(Template)]
+#[template(path = "pages/not_found.html")]
+struct NotFoundPage<'a> {
+    brand: &'a str,
+    home_url: &'a str,
+    slug: &'a str,
+}
+
+/// `410` page for links that exist but are disabled or used up
+#[derive(Template)]
+#[template(path = "pages/unavailable.html")]
+struct UnavailablePage<'a> {
+    brand: &'a str,
+    home_url: &'a str,
+    #[allow(dead_code)] // not shown by the built-in template, custom ones may use it
+    slug: &'a str,
+    message: &'a str,
+}
+
+/// `403` page for scheduled links before they go live
+#[derive(Template)]
+#[template(path = "pages/not_yet_live.html")]
+struct NotYetLivePage<'a> {
+    brand: &'a str,
+    home_url: &'a str,
+    slug: &'a str,
+    /// When the link goes live, in UTC
+    active_from: &'a str,
+}
+
+/// Page naming the destination, shown instead of redirecting right away
+#[derive(Template)]
+#[template(path = "pages/interstitial.html")]
+struct InterstitialPage<'a> {
+    brand: &'a str,
+    home_url: &'a str,
+    slug: &'a str,
+    destination: &'a str,
+    destination_host: &'a str,
+    delay_seconds: u64,
+}
+
+/// Open Graph and Twitter card tags for link preview bots, without the destination
+#[derive(Template)]
+#[template(path = "pages/unfurl.html")]
+struct UnfurlPage<'a> {
+    brand: &'a str,
+    home_url: &'a str,
+    #[allow(dead_code)] // not shown by the built-in template, custom ones may use it
+    slug: &'a str,
+    short_url: &'a str,
+    title: &'a str,
+    description: Option<&'a str>,
+    image: &'a str,
+}
+
+/// Details and stats of a link, shown once for a preview token
+#[derive(Template)]
+#[template(path = "pages/preview.html")]
+struct PreviewPage<'a> {
+    brand: &'a str,
+    home_url: &'a str,
+    slug: &'a str,
+    short_url: &'a str,
+    destination: &'a str,
+    status: &'a str,
+    tags: &'a [String],
+    clicks: Option<u64>,
+    expires_at: Option<String>,
+    /// Day and clicks, oldest first
+    daily_clicks: Vec<(String, u64)>,
+    /// Referring domain and clicks, most clicks first
+    referrers: &'a [(String, u64)],
+}
+
+/// The base URL of a short domain without its scheme, and the URL of its home page
+fn branding(base_url: &str) -> (&str, String) {
+    let brand = base_url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/');
+    (brand, format!("{}/", base_url.trim_end_matches('/')))
+}
+
+fn render_failed(slug: &str, err: askama::Error) -> ApiError {
+    ApiError::Internal(format!("Failed to render page for {}: {}", slug, err))
+}
+
+/// Interstitial page for `destination`, it moves on by itself after `INTERSTITIAL_DELAY_SECONDS`
+pub fn interstitial_page(
+    state: &AppState,
+    key: &str,
+    destination: &str,
+) -> Result<String, ApiError> {
+    let (slug, host) = split_key(key);
+    let base_url = state.short_domains.base_url(host);
+    let (brand, home_url) = branding(&base_url);
+    let destination_host = url::Url::parse(destination)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    InterstitialPage {
+        brand,
+        home_url: &home_url,
+        slug,
+        destination,
+        destination_host: &destination_host,
+        delay_seconds: state.interstitial_delay_seconds,
+    }
+    .render()
+    .map_err(|err| render_failed(slug, err))
+}
+
+/// Preview page of the link under `key`, titled by the destination's `metadata` or by the short URL without it
+pub fn unfurl_page(
+    state: &AppState,
+    key: &str,
+    link: &Link,
+    metadata: Option<&LinkMetadata>,
+) -> Result<String, ApiError> {
+    let (slug, host) = split_key(key);
+    let base_url = state.short_domains.base_url(host);
+    let (brand, home_url) = branding(&base_url);
+    let short_url = state.short_url(key);
+    let fallback_title = if link.password_hash.is_some() {
+        format!("Password-protected link on {}", brand)
+    } else {
+        format!("{}/{}", brand, slug)
+    };
+    UnfurlPage {
+        brand,
+        home_url: &home_url,
+        slug,
+        short_url: &short_url,
+        title: metadata
+            .and_then(|metadata| metadata.title.as_deref())
+            .unwrap_or(&fallback_title),
+        description: metadata.and_then(|metadata| metadata.description.as_deref()),
+        image: &format!("{}/card.png", short_url),
+    }
+    .render()
+    .map_err(|err| render_failed(slug, err))
+}
+
+/// Preview page of `link`, with its `status` for people and the clicks of the last days
+pub fn preview_page(
+    state: &AppState,
+    link: &OwnedLink,
+    status: &str,
+    daily_clicks: &[(DateTime<Utc>, u64)],
+    referrers: &[(String, u64)],
+) -> Result<String, ApiError> {
+    let (slug, host) = split_key(&link.slug);
+    let base_url = state.short_domains.base_url(host);
+    let (brand, home_url) = branding(&base_url);
+    PreviewPage {
+        brand,
+        home_url: &home_url,
+        slug,
+        short_url: &link.short_url,
+        destination: &link.url,
+        status,
+        tags: &link.tags,
+        clicks: link.clicks,
+        expires_at: link
+            .expires_at
+            .map(|at| at.format("%Y-%m-%d %H:%M UTC").to_string()),
+        daily_clicks: daily_clicks
+            .iter()
+            .map(|(day, clicks)| (day.format("%Y-%m-%d").to_string(), *clicks))
+            .collect(),
+        referrers,
+    }
+    .render()
+    .map_err(|err| render_failed(slug, err))
+}
+
+/// Sends the visitor of an unknown slug to `FALLBACK_URL`, with the slug in place of `{slug}`.
+/// Not cached, the slug may be taken any time.
+pub fn fallback_redirect(fallback_url: &str, slug: &str) -> HttpResponse {
+    let slug: String = url::form_urlencoded::byte_serialize(slug.as_bytes()).collect();
+    HttpResponse::Found()
+        .append_header((header::LOCATION, fallback_url.replace("{slug}", &slug)))
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .finish()
+}
+
+/// Whether the request comes from a browser, API clients keep getting JSON error bodies
+pub fn wants_html(req: &HttpRequest) -> bool {
+    Accept::parse(req).is_ok_and(|accept| {
+        accept
+            .iter()
+            .any(|item| item.item.essence_str() == "text/html")
+    })
+}
+
+/// Shows browsers a page for the link errors visitors run into, other errors and API clients get the usual
+/// JSON body
+pub fn error_page(
+    req: &HttpRequest,
+    state: &AppState,
+    slug: &str,
+    err: ApiError,
+) -> Result<HttpResponse, ApiError> {
+    if !wants_html(req) {
+        return Err(err);
+    }
+    let host = state
+        .short_domains
+        .request_host(req.connection_info().host());
+    let base_url = state.short_domains.base_url(host);
+    let (brand, home_url) = branding(&base_url);
+    let page = match &err {
+        ApiError::NotFound { .. } => NotFoundPage {
+            brand,
+            home_url: &home_url,
+            slug,
+        }
+        .render(),
+        ApiError::Gone { message } => UnavailablePage {
+            brand,
+            home_url: &home_url,
+            slug,
+            message,
+        }
+        .render(),
+        ApiError::NotYetActive { active_from, .. } => NotYetLivePage {
+            brand,
+            home_url: &home_url,
+            slug,
+            active_from: &active_from.format("%Y-%m-%d %H:%M UTC").to_string(),
+        }
+        .render(),
+        _ => return Err(err),
+    }
+    .map_err(|render_err| render_failed(slug, render_err))?;
+    Ok(HttpResponse::build(err.status_code())
+        .content_type("text/html; charset=utf-8")
+        // The slug may be taken or re-enabled any time
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .body(page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_found_page_escapes_the_slug() {
+        let page = NotFoundPage {
+            brand: "short.me",
+            home_url: "https://short.me/",
+            slug: "<b>",
+        }
+        .render()
+        .unwrap();
+
+        assert!(page.contains("&#60;b&#62;"));
+        assert!(page.contains(r#"<a href="https://short.me/">Go to short.me</a>"#));
+    }
+}
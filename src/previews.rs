@@ -0,0 +1,140 @@
+use actix_web::http::header;
+use actix_web::web::{self, Data};
+use actix_web::{post, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use serde::Serialize;
+
+use crate::audit::{self, AuditAction};
+use crate::breakdown;
+use crate::error::ApiError;
+use crate::link::Link;
+use crate::ownership::{count_clicks, load_expiry, load_link, Manager, OwnedLink};
+use crate::pages;
+use crate::timeseries;
+use crate::users::MaybeUser;
+use crate::AppState;
+
+/// Query parameter carrying the token on the short URL
+pub const PREVIEW_TOKEN_PARAM: &str = "preview_token";
+
+/// Days of clicks and referrers shown on the page
+const PREVIEW_DAYS: i64 = 7;
+const PREVIEW_REFERRERS: usize = 5;
+
+/// Token record, holding the storage key of the link it previews
+fn token_key(token: &str) -> String {
+    format!("preview:{}", token)
+}
+
+fn generate_token() -> String {
+    rand::rng()
+        .sample_iter(rand::distr::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Serialize)]
+struct PreviewToken {
+    preview_url: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Creates a URL showing the details and stats of the link to whoever opens it first, without signing in.
+/// For the owner of the link and admin API keys.
+#[post("/api/links/{slug}/preview-token")]
+async fn create_preview_token(
+    req: HttpRequest,
+    path: web::Path<String>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let manager = Manager::identify(&req, user).await?;
+    let (_, link) = load_link(&state, &slug).await?;
+    manager.ensure_can_view(&link)?;
+
+    let token = generate_token();
+    let ttl = state.preview_token_ttl_seconds;
+    state
+        .store
+        .set(&token_key(&token), &slug, Some(ttl as usize))
+        .await?;
+    audit::record(
+        &state,
+        &manager.audit,
+        AuditAction::PreviewTokenCreated,
+        &slug,
+        link.tenant.as_deref(),
+    )
+    .await;
+    let preview_url = url::Url::parse_with_params(
+        &state.short_url(&slug),
+        [(PREVIEW_TOKEN_PARAM, token.as_str())],
+    )
+    .map_err(|err| ApiError::Internal(format!("Invalid short URL of {}: {}", slug, err)))?;
+    Ok(HttpResponse::Created().json(PreviewToken {
+        preview_url: preview_url.into(),
+        expires_at: Utc::now() + Duration::seconds(ttl as i64),
+    }))
+}
+
+/// The preview token a request to a short URL carries, if any
+pub fn token(req: &HttpRequest) -> Option<String> {
+    url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(name, _)| name == PREVIEW_TOKEN_PARAM)
+        .map(|(_, token)| token.into_owned())
+}
+
+/// Uses up `token` if it was created for the link under `key`. Only the request that deletes the token
+/// gets through, so it works once even when opened twice at the same time.
+async fn redeem(state: &AppState, key: &str, token: &str) -> Result<bool, ApiError> {
+    let token_key = token_key(token);
+    if state.store.get(&token_key).await?.as_deref() != Some(key) {
+        return Ok(false);
+    }
+    Ok(state.store.delete(&token_key).await?)
+}
+
+/// Page with the details and stats of the link under `key` for a valid preview token. Disabled and suspended
+/// links are shown too, the visit isn't counted as a click.
+pub async fn show(
+    state: &AppState,
+    key: &str,
+    link: Link,
+    token: &str,
+) -> Result<HttpResponse, ApiError> {
+    if !redeem(state, key, token).await? {
+        return Err(ApiError::Gone {
+            message: "This preview link was already used or has expired.".to_string(),
+        });
+    }
+    let status = if link.reserved {
+        "reserved"
+    } else if link.suspension.is_some() {
+        "suspended"
+    } else if link.disabled {
+        "disabled"
+    } else if link.password_hash.is_some() {
+        "password protected"
+    } else {
+        "active"
+    };
+    let mut details = OwnedLink::new(state, key.to_string(), link);
+    count_clicks(state, std::slice::from_mut(&mut details)).await?;
+    load_expiry(state, std::slice::from_mut(&mut details)).await?;
+    let daily_clicks =
+        timeseries::daily_clicks(state.store.as_ref(), key, PREVIEW_DAYS, Utc::now()).await?;
+    let referrers = state
+        .store
+        .top_scores(&breakdown::referrers_key(key), PREVIEW_REFERRERS)
+        .await?;
+    let page = pages::preview_page(state, &details, status, &daily_clicks, &referrers)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .append_header((header::CACHE_CONTROL, "private, no-store"))
+        // The URL carries the token, it isn't handed on to sites linked from the page
+        .append_header(("Referrer-Policy", "no-referrer"))
+        .body(page))
+}
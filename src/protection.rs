@@ -0,0 +1,218 @@
+use actix_web::http::header;
+use actix_web::web::{self, Data, Form};
+use actix_web::{post, HttpRequest, HttpResponse, ResponseError};
+use serde::Deserialize;
+
+use crate::clicks;
+use crate::error::ApiError;
+use crate::link::Link;
+use crate::moderation;
+use crate::ownership;
+use crate::pages;
+use crate::proxy;
+use crate::ratelimit::{check_rate_limit, ip_bucket, RateLimitDecision};
+use crate::short_domains::split_key;
+use crate::users::verify_password;
+use crate::{redirect_to, AppState};
+
+/// Query parameter carrying the password of a protected link, never passed through to the destination
+pub const PASSWORD_PARAM: &str = "password";
+/// Header alternative to `?password=` for API clients
+pub const PASSWORD_HEADER: &str = "X-Link-Password";
+
+/// Splits the password off the request query
+fn take_password(request_query: &str) -> (String, Option<String>) {
+    let mut password = None;
+    let mut rest = url::form_urlencoded::Serializer::new(String::new());
+    for (key, value) in url::form_urlencoded::parse(request_query.as_bytes()) {
+        if key == PASSWORD_PARAM {
+            password = Some(value.into_owned());
+        } else {
+            rest.append_pair(&key, &value);
+        }
+    }
+    (rest.finish(), password)
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `401` page asking for the password, the form posts back to the same URL so the query string is kept
+fn password_form(key: &str, wrong_password: bool) -> HttpResponse {
+    let (slug, _) = split_key(key);
+    let error = if wrong_password {
+        r#"<p class="error">Wrong password, try again.</p>"#
+    } else {
+        ""
+    };
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>Password required</title>
+<style>
+body {{ font-family: sans-serif; max-width: 24rem; margin: 4rem auto; padding: 0 1rem; }}
+input, button {{ font-size: 1rem; padding: .5rem; width: 100%; box-sizing: border-box; margin-top: .5rem; }}
+.error {{ color: #b00020; }}
+</style>
+</head>
+<body>
+<h1>Password required</h1>
+<p>The link <strong>{slug}</strong> is password protected.</p>
+{error}
+<form method="post">
+<input type="password" name="{param}" placeholder="Password" autofocus required>
+<button type="submit">Continue</button>
+</form>
+</body>
+</html>
+"#,
+        slug = escape_html(slug),
+        error = error,
+        param = PASSWORD_PARAM,
+    );
+    HttpResponse::Unauthorized()
+        .content_type("text/html; charset=utf-8")
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .body(body)
+}
+
+/// Lets requests for unprotected links through untouched. For protected ones the password is taken from
+/// the submitted form, the `X-Link-Password` header or `?password=` and verified. On success the request
+/// query without the password is returned so it can be passed through to the destination.
+pub async fn unlock(
+    req: &HttpRequest,
+    state: &AppState,
+    slug: &str,
+    link: &Link,
+    password: Option<String>,
+) -> Result<String, HttpResponse> {
+    let Some(password_hash) = link.password_hash.clone() else {
+        return Ok(req.query_string().to_string());
+    };
+    let (query, query_password) = take_password(req.query_string());
+    let header_password = req
+        .headers()
+        .get(PASSWORD_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let Some(password) = password.or(header_password).or(query_password) else {
+        return Err(password_form(slug, false));
+    };
+
+    // Every guess costs an Argon2 verification, so guesses count against the client's rate limit
+    let rate_limit = state.settings.get().rate_limit;
+    if rate_limit.requests > 0 {
+        let bucket = ip_bucket("unlock", proxy::client_ip(req));
+        match check_rate_limit(
+            state.store.as_ref(),
+            &bucket,
+            rate_limit.requests,
+            rate_limit.window_seconds,
+        )
+        .await
+        {
+            Ok(RateLimitDecision::Allowed { .. }) => {}
+            Ok(RateLimitDecision::Limited {
+                retry_after_seconds,
+            }) => {
+                return Err(ApiError::RateLimited {
+                    retry_after_seconds,
+                }
+                .error_response())
+            }
+            Err(err) => log::error!("Rate limiter failed, letting the request through: {}", err),
+        }
+    }
+
+    let valid = web::block(move || verify_password(&password, &password_hash))
+        .await
+        .unwrap_or(false);
+    if valid {
+        Ok(query)
+    } else {
+        Err(password_form(slug, true))
+    }
+}
+
+#[derive(Deserialize)]
+struct UnlockForm {
+    password: String,
+}
+
+/// Target of the password form
+#[post("/{slug}")]
+async fn unlock_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    form: Form<UnlockForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = path.into_inner();
+    let unlocked = match state.visited_key(&req, &slug) {
+        Ok(key) => unlock_with_form(&req, &state, &key, form.into_inner().password).await,
+        Err(err) => Err(err),
+    };
+    unlocked.or_else(|err| pages::error_page(&req, &state, &slug, err))
+}
+
+async fn unlock_with_form(
+    req: &HttpRequest,
+    state: &AppState,
+    slug: &str,
+    password: String,
+) -> Result<HttpResponse, ApiError> {
+    let record = if slug.contains(':') {
+        None
+    } else {
+        state.store.get(slug).await?
+    };
+    let link = Link::decode(&record.ok_or_else(|| ApiError::not_found(slug))?);
+    if link.suspension.is_some() {
+        return Ok(moderation::suspended_page(slug));
+    }
+    ownership::ensure_enabled(slug, &link)?;
+    let query = match unlock(req, state, slug, &link, Some(password)).await {
+        Ok(query) => query,
+        Err(response) => return Ok(response),
+    };
+    clicks::consume_click(state, slug, &link).await?;
+    clicks::record_click(state, req, slug, &link);
+    // See other, so the browser follows with a GET instead of re-posting the password
+    let mut response = HttpResponse::SeeOther();
+    redirect_to(&mut response, req, state, slug, &link, &query).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_password_strips_the_parameter() {
+        assert_eq!(
+            take_password("ref=tw&password=s3cret&x=1"),
+            ("ref=tw&x=1".to_string(), Some("s3cret".to_string()))
+        );
+        assert_eq!(take_password("ref=tw"), ("ref=tw".to_string(), None));
+    }
+
+    #[tokio::test]
+    async fn test_password_form_escapes_the_slug() {
+        let response = password_form("<b>", true);
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("&lt;b&gt;"));
+        assert!(body.contains("Wrong password"));
+    }
+}
@@ -0,0 +1,161 @@
+use actix_web::http::header;
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::AppState;
+
+/// Reverse proxies in front of the service, from `TRUSTED_PROXIES`. Only their forwarding headers are believed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// Parses a comma separated list of addresses and CIDR networks
+    pub fn parse(value: &str) -> Result<Self, String> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(|network| {
+                network
+                    .parse::<IpNet>()
+                    .map(|net| net.trunc())
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| {
+                        format!("'{}' is neither an IP address nor a CIDR network", network)
+                    })
+            })
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Address of the client that sent `req`. Requests from a trusted proxy are followed back through the
+    /// `Forwarded` or `X-Forwarded-For` chain, from the right, to the first hop that isn't a trusted proxy.
+    /// Everything left of it was sent by the client and could be made up.
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let mut client = req.peer_addr()?.ip().to_canonical();
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+        for hop in forwarded_chain(req).into_iter().rev() {
+            // Obfuscated or garbled hops can't be followed, the proxy that recorded them is as far as we know
+            let Some(hop) = hop else {
+                break;
+            };
+            client = hop.to_canonical();
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+/// Addresses of the hops in `Forwarded`, or `X-Forwarded-For` without it, the client first. Hops that aren't
+/// an IP address are `None`.
+fn forwarded_chain(req: &HttpRequest) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        req.headers()
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(header::FORWARDED);
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect();
+    }
+    values(header::X_FORWARDED_FOR)
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+/// Parses `192.0.2.60`, `192.0.2.60:4711`, `2001:db8::17` or `"[2001:db8::17]:4711"`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+/// Address of the client that sent `req`, see `TrustedProxies::client_ip`. Without the app state, e.g. in
+/// services embedding the shortener without its routes, only the socket address is used.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    match req.app_data::<Data<AppState>>() {
+        Some(state) => state.trusted_proxies.client_ip(req),
+        None => req.peer_addr().map(|addr| addr.ip().to_canonical()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_forwarding_headers_of_trusted_proxies() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8, 192.0.2.1").unwrap();
+        let request = |peer: &str, header: (&str, &str)| {
+            TestRequest::default()
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(header)
+                .to_http_request()
+        };
+
+        // Hops left of the first untrusted one are made up by the client
+        let req = request(
+            "10.0.0.2:4000",
+            ("X-Forwarded-For", "198.51.100.1, 203.0.113.9, 10.0.0.3"),
+        );
+        assert_eq!(proxies.client_ip(&req), ip("203.0.113.9"));
+        let req = request(
+            "192.0.2.1:4000",
+            (
+                "Forwarded",
+                r#"for=198.51.100.1, for="[2001:db8:cafe::17]:4711";proto=https"#,
+            ),
+        );
+        assert_eq!(proxies.client_ip(&req), ip("2001:db8:cafe::17"));
+        let req = request("10.0.0.2:4000", ("Forwarded", "for=_hidden, for=10.0.0.3"));
+        assert_eq!(proxies.client_ip(&req), ip("10.0.0.3"));
+
+        // Anyone else can't pick their address
+        let req = request("203.0.113.9:4000", ("X-Forwarded-For", "10.0.0.1"));
+        assert_eq!(proxies.client_ip(&req), ip("203.0.113.9"));
+        assert_eq!(
+            TrustedProxies::default().client_ip(&request(
+                "10.0.0.2:4000",
+                ("X-Forwarded-For", "203.0.113.9")
+            )),
+            ip("10.0.0.2")
+        );
+
+        assert!(TrustedProxies::parse("10.0.0.0/8, lb.internal").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, HttpMessage, ResponseError};
+use serde::Serialize;
+use std::net::IpAddr;
+
+use crate::auth::AuthenticatedKey;
+use crate::error::ApiError;
+use crate::metrics::Counter;
+use crate::proxy;
+use crate::storage::{StorageError, UrlStore};
+use crate::AppState;
+
+/// Requests allowed per client in every window, a limit of 0 turns limiting off for that kind of client
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct RateLimitConfig {
+    /// Limit for anonymous clients, keyed by IP
+    pub requests: u64,
+    /// Limit for clients authenticated with an API key, keyed by the key
+    pub api_key_requests: u64,
+    pub window_seconds: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RateLimitDecision {
+    Allowed { remaining: u64 },
+    Limited { retry_after_seconds: usize },
+}
+
+/// Counts the request against `bucket` using a fixed window counter (Redis `INCR` + `EXPIRE`)
+pub async fn check_rate_limit(
+    store: &dyn UrlStore,
+    bucket: &str,
+    limit: u64,
+    window_seconds: usize,
+) -> Result<RateLimitDecision, StorageError> {
+    let (count, resets_in) = store.incr_window(bucket, window_seconds).await?;
+    if count > limit {
+        Ok(RateLimitDecision::Limited {
+            retry_after_seconds: resets_in.max(1),
+        })
+    } else {
+        Ok(RateLimitDecision::Allowed {
+            remaining: limit - count,
+        })
+    }
+}
+
+/// Picks the bucket and limit for a request, API keys get their own budget instead of sharing one with their IP
+fn bucket_for(req: &ServiceRequest, config: &RateLimitConfig) -> (String, u64) {
+    // Routes get separate budgets, so wrapping another route doesn't eat into this one
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| req.path().to_string());
+    match req.extensions().get::<AuthenticatedKey>() {
+        Some(key) => (
+            format!("ratelimit:{}:key:{}", route, key.id),
+            config.api_key_requests,
+        ),
+        None => (
+            ip_bucket(&route, proxy::client_ip(req.request())),
+            config.requests,
+        ),
+    }
+}
+
+/// Bucket for `scope` keyed by the client IP, see `proxy::client_ip`
+pub fn ip_bucket(scope: &str, ip: Option<IpAddr>) -> String {
+    let ip = ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    format!("ratelimit:{}:ip:{}", scope, ip)
+}
+
+/// Rejects clients over their limit with `429 Too Many Requests`, wrap any route with
+/// `from_fn(ratelimit::rate_limit)`. Storage failures let the request through.
+/// Routes that also take API keys list it before `require_api_key`: the last `wrap` runs first,
+/// so the limiter already sees the authenticated key.
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(state) = req.app_data::<Data<AppState>>().cloned() else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    let rate_limit = state.settings.get().rate_limit;
+    let (bucket, limit) = bucket_for(&req, &rate_limit);
+    if limit == 0 {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+
+    match check_rate_limit(
+        state.store.as_ref(),
+        &bucket,
+        limit,
+        rate_limit.window_seconds,
+    )
+    .await
+    {
+        Ok(RateLimitDecision::Allowed { .. }) => {}
+        Ok(RateLimitDecision::Limited {
+            retry_after_seconds,
+        }) => {
+            state.metrics.incr(Counter::RateLimited);
+            let response = ApiError::RateLimited {
+                retry_after_seconds,
+            }
+            .error_response();
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+        Err(err) => {
+            state.metrics.incr(Counter::StorageErrors);
+            log::error!("Rate limiter failed, letting the request through: {}", err);
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    #[tokio::test]
+    async fn test_requests_over_the_limit_are_rejected() {
+        let store = MemoryStore::new();
+
+        assert_eq!(
+            check_rate_limit(&store, "bucket", 2, 60).await.unwrap(),
+            RateLimitDecision::Allowed { remaining: 1 }
+        );
+        assert_eq!(
+            check_rate_limit(&store, "bucket", 2, 60).await.unwrap(),
+            RateLimitDecision::Allowed { remaining: 0 }
+        );
+        assert_eq!(
+            check_rate_limit(&store, "bucket", 2, 60).await.unwrap(),
+            RateLimitDecision::Limited {
+                retry_after_seconds: 60
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buckets_are_independent() {
+        let store = MemoryStore::new();
+
+        check_rate_limit(&store, "a", 1, 60).await.unwrap();
+        assert!(matches!(
+            check_rate_limit(&store, "a", 1, 60).await.unwrap(),
+            RateLimitDecision::Limited { .. }
+        ));
+        assert_eq!(
+            check_rate_limit(&store, "b", 1, 60).await.unwrap(),
+            RateLimitDecision::Allowed { remaining: 0 }
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use reqwest::{Method, StatusCode};
+use std::time::Duration;
+use url::Url;
+
+use crate::metadata::{external_redirects, is_internal};
+
+/// Redirects followed to the final page, like the metadata fetcher
+const MAX_REDIRECTS: usize = 3;
+
+/// Checks destinations of links created with `verify`, so typos show up while the link is created.
+/// The check only warns, the link is created either way.
+#[derive(Clone)]
+pub struct ReachabilityCheck {
+    http: reqwest::Client,
+}
+
+impl ReachabilityCheck {
+    pub fn new(timeout: Duration) -> Self {
+        ReachabilityCheck {
+            http: reqwest::Client::builder()
+                .timeout(timeout)
+                // Redirects to internal addresses are not followed either
+                .redirect(external_redirects(MAX_REDIRECTS))
+                .user_agent(concat!("url-shortener/", env!("CARGO_PKG_VERSION")))
+                .build()
+                .expect("HTTP client settings are valid"),
+        }
+    }
+
+    /// Sends a `HEAD` request to `url`, error statuses are confirmed with a `GET` since plenty of servers
+    /// answer `HEAD` with `404` or `405`.
+    /// `None` when it answered with a success, a warning for error statuses and unreachable hosts.
+    pub async fn check(&self, url: &str) -> Option<String> {
+        let Ok(url) = Url::parse(url) else {
+            return None;
+        };
+        if is_internal(&url) {
+            return Some("The destination is an internal address and wasn't checked.".to_string());
+        }
+        self.probe(&url).await
+    }
+
+    pub(crate) async fn probe(&self, url: &Url) -> Option<String> {
+        let mut status = self.status(Method::HEAD, url).await;
+        if status
+            .as_ref()
+            .is_ok_and(|status| status.is_client_error() || status.is_server_error())
+        {
+            status = self.status(Method::GET, url).await;
+        }
+        match status {
+            Ok(status) if status.is_client_error() || status.is_server_error() => {
+                Some(format!("The destination answered with {}.", status))
+            }
+            Ok(_) => None,
+            Err(err) => Some(format!("The destination could not be reached: {}", err)),
+        }
+    }
+
+    async fn status(&self, method: Method, url: &Url) -> Result<StatusCode, String> {
+        self.http
+            .request(method, url.clone())
+            .send()
+            .await
+            .map(|response| response.status())
+            .map_err(|err| match err {
+                err if err.is_timeout() => "timed out".to_string(),
+                err if err.is_redirect() => "a redirect was not followed".to_string(),
+                err if err.is_connect() => "connection failed".to_string(),
+                err => err.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    #[actix_web::test]
+    async fn test_probe() {
+        // Answers `HEAD` to the GET-only route with `404`, like many servers do
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/page", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/broken",
+                    web::route().to(HttpResponse::InternalServerError),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        let check = ReachabilityCheck::new(Duration::from_secs(2));
+        let probe = |path: &str| {
+            let url = Url::parse(&format!("{}{}", base, path)).unwrap();
+            let check = check.clone();
+            async move { check.probe(&url).await }
+        };
+
+        assert_eq!(probe("/page").await, None);
+        assert_eq!(
+            probe("/missing").await.unwrap(),
+            "The destination answered with 404 Not Found."
+        );
+        assert_eq!(
+            probe("/broken").await.unwrap(),
+            "The destination answered with 500 Internal Server Error."
+        );
+        assert!(check
+            .check(&format!("{}/page", base))
+            .await
+            .unwrap()
+            .contains("internal address"));
+    }
+}
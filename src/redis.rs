@@ -1,105 +1,1116 @@
-use redis::{aio::ConnectionManager, Client, RedisError};
-use std::sync::Arc;
-use tokio::time::{sleep, Duration};
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt};
+use opentelemetry::trace::SpanKind;
+use opentelemetry::KeyValue;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::{Client, FromRedisValue, RedisError};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::storage::{CountBatch, LinkWrites, StorageError, UrlStore};
+use crate::telemetry::traced;
+
+/// Adds ARGV[1] to the counter, returns nil instead of creating it when it doesn't exist
+const ADD_IF_EXISTS: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return redis.call('INCRBY', KEYS[1], ARGV[1])
+end
+return false
+"#;
+
+/// Sets ARGV[2] if the key still holds ARGV[1], with ARGV[3] as the new TTL or keeping the current one when empty
+const COMPARE_AND_SET: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+if ARGV[3] == '' then
+    redis.call('SET', KEYS[1], ARGV[2], 'KEEPTTL')
+else
+    redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+end
+return 1
+"#;
+
+/// Like `SET_FIRST` for the first ARGV[3] of KEYS, the candidates, and stores the records of the candidate that
+/// was free along with it. The records of each candidate follow in KEYS, entries first, then sets and then indexes,
+/// and in ARGV after the number of its entries, set members and index members: a value and TTL per entry, set like
+/// the link, a member per set and a member and score per index.
+const SET_FIRST_WITH: &str = r#"
+local candidates = tonumber(ARGV[3])
+local key = candidates + 1
+local arg = 4
+for index = 1, candidates do
+    local entries, members, scored = tonumber(ARGV[arg]), tonumber(ARGV[arg + 1]), tonumber(ARGV[arg + 2])
+    arg = arg + 3
+    local stored
+    if ARGV[2] == '' then
+        stored = redis.call('SET', KEYS[index], ARGV[1], 'NX')
+    else
+        stored = redis.call('SET', KEYS[index], ARGV[1], 'NX', 'EX', ARGV[2])
+    end
+    if stored then
+        for _ = 1, entries do
+            if ARGV[arg + 1] == '' then
+                redis.call('SET', KEYS[key], ARGV[arg], 'NX')
+            else
+                redis.call('SET', KEYS[key], ARGV[arg], 'NX', 'EX', ARGV[arg + 1])
+            end
+            key, arg = key + 1, arg + 2
+        end
+        for _ = 1, members do
+            redis.call('SADD', KEYS[key], ARGV[arg])
+            key, arg = key + 1, arg + 1
+        end
+        for _ = 1, scored do
+            redis.call('ZADD', KEYS[key], 'NX', ARGV[arg + 1], ARGV[arg])
+            key, arg = key + 1, arg + 2
+        end
+        return index
+    end
+    key = key + entries + members + scored
+    arg = arg + 2 * entries + members + 2 * scored
+end
+return 0
+"#;
+
+/// Up to ARGV[2] members of the sorted set with their scores, highest first when ARGV[1] is '1'. With a score in
+/// ARGV[3] and a member in ARGV[4] the page starts behind the member's rank while it still has that score, and
+/// behind the score otherwise.
+const SCORED_PAGE: &str = r#"
+local descending = ARGV[1] == '1'
+local limit = tonumber(ARGV[2])
+local start = 0
+if ARGV[3] ~= '' then
+    local score = redis.call('ZSCORE', KEYS[1], ARGV[4])
+    if score and tonumber(score) == tonumber(ARGV[3]) then
+        if descending then
+            start = redis.call('ZREVRANK', KEYS[1], ARGV[4]) + 1
+        else
+            start = redis.call('ZRANK', KEYS[1], ARGV[4]) + 1
+        end
+    elseif descending then
+        return redis.call('ZREVRANGEBYSCORE', KEYS[1], '(' .. ARGV[3], '-inf', 'WITHSCORES', 'LIMIT', 0, limit)
+    else
+        return redis.call('ZRANGEBYSCORE', KEYS[1], '(' .. ARGV[3], '+inf', 'WITHSCORES', 'LIMIT', 0, limit)
+    end
+end
+if descending then
+    return redis.call('ZREVRANGE', KEYS[1], start, start + limit - 1, 'WITHSCORES')
+end
+return redis.call('ZRANGE', KEYS[1], start, start + limit - 1, 'WITHSCORES')
+"#;
+
+/// Sets ARGV[1] under the first of KEYS that doesn't exist, with ARGV[2] as the TTL unless empty.
+/// Returns the 1-based index of that key, 0 when every key is taken.
+const SET_FIRST: &str = r#"
+for index, key in ipairs(KEYS) do
+    local stored
+    if ARGV[2] == '' then
+        stored = redis.call('SET', key, ARGV[1], 'NX')
+    else
+        stored = redis.call('SET', key, ARGV[1], 'NX', 'EX', ARGV[2])
+    end
+    if stored then
+        return index
+    end
+end
+return 0
+"#;
+
+/// Connection settings of the Redis backend
+#[derive(Clone, Debug, PartialEq)]
+pub struct RedisConfig {
+    pub url: String,
+    /// Number of multiplexed connections commands are spread over, so one slow reply doesn't hold up every handler
+    pub pool_size: usize,
+    pub connect_timeout: Duration,
+    /// Commands without a reply after this long fail instead of stalling the request
+    pub command_timeout: Duration,
+    /// How often a command failing with a transient error (timeout, dropped connection) is retried
+    pub command_retries: u32,
+    /// Delay before the first retry, doubled for every further one
+    pub retry_backoff: Duration,
+    /// Connection attempts at startup, Redis may still be starting next to us
+    pub connect_retries: u32,
+    pub connect_backoff: Duration,
+    /// Failures in a row that open the circuit breaker, 0 turns it off
+    pub breaker_failures: u32,
+    /// How long the open breaker fails fast before trying Redis again
+    pub breaker_cooldown: Duration,
+    /// Links kept in memory to resolve while the breaker is open
+    pub local_cache_size: usize,
+    /// Namespace put in front of every key, e.g. `us:`, so the service can share Redis with other applications
+    pub key_prefix: String,
+    /// Lets test cleanups run `FLUSHDB` when there is no key prefix, wiping the keys of every application using
+    /// the database. Only meant for a Redis of its own.
+    pub dangerously_flush_on_cleanup: bool,
+    /// Replicas of `url` that resolve links, writes and every other read stay on the primary
+    pub replica_urls: Vec<String>,
+    /// Replicas that haven't heard from the primary for longer than this stop resolving links until they catch up
+    pub replica_max_lag: Duration,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig {
+            url: "redis://localhost:6379".to_string(),
+            pool_size: 4,
+            connect_timeout: Duration::from_secs(2),
+            command_timeout: Duration::from_secs(1),
+            command_retries: 2,
+            retry_backoff: Duration::from_millis(50),
+            connect_retries: 30,
+            connect_backoff: Duration::from_millis(500),
+            breaker_failures: 5,
+            breaker_cooldown: Duration::from_secs(5),
+            local_cache_size: 10_000,
+            key_prefix: String::new(),
+            dangerously_flush_on_cleanup: false,
+            replica_urls: Vec::new(),
+            // Primaries ping their replicas every 10 seconds when idle
+            replica_max_lag: Duration::from_secs(15),
+        }
+    }
+}
+
+/// How often a replica's replication state is looked at again
+const REPLICA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether a failed command may be sent again
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Retry {
+    /// Repeating the command doesn't change its outcome, retry on any transient error
+    Idempotent,
+    /// The command may have been applied before a timeout, only retry if it never reached Redis
+    IfNotSent,
+}
+
+fn is_retryable(err: &RedisError, retry: Retry) -> bool {
+    match retry {
+        Retry::Idempotent => {
+            err.is_timeout()
+                || err.is_connection_dropped()
+                || err.is_connection_refusal()
+                || err.is_io_error()
+        }
+        Retry::IfNotSent => err.is_connection_refusal(),
+    }
+}
+
+/// `SCAN` pattern matching the keys under `prefix`, and only those
+fn scan_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    pattern
+}
+
+/// Whether `info`, the `INFO replication` reply of a replica, shows it in sync with its primary
+fn replica_in_sync(info: &str, max_lag: Duration) -> bool {
+    let field = |name: &str| {
+        info.lines()
+            .find_map(|line| line.trim().strip_prefix(name)?.strip_prefix(':'))
+    };
+    field("master_link_status") == Some("up")
+        && field("master_last_io_seconds_ago")
+            .and_then(|seconds| seconds.parse::<u64>().ok())
+            .is_some_and(|seconds| Duration::from_secs(seconds) <= max_lag)
+}
+
+async fn connect_pool(
+    client: Client,
+    config: &RedisConfig,
+) -> Result<Arc<[ConnectionManager]>, RedisError> {
+    let manager_config = ConnectionManagerConfig::new()
+        .set_connection_timeout(config.connect_timeout)
+        .set_response_timeout(config.command_timeout);
+    let mut connections = Vec::with_capacity(config.pool_size);
+    for _ in 0..config.pool_size {
+        connections.push(
+            ConnectionManager::new_with_config(client.clone(), manager_config.clone()).await?,
+        );
+    }
+    Ok(connections.into())
+}
+
+/// Hands out `connections` round robin
+fn next_of(connections: &[ConnectionManager], next: &AtomicUsize) -> ConnectionManager {
+    let index = next.fetch_add(1, Ordering::Relaxed) % connections.len();
+    connections[index].clone()
+}
+
+/// A read replica resolving links, left out while it lags behind the primary
+struct Replica {
+    url: String,
+    connections: Arc<[ConnectionManager]>,
+    next_connection: AtomicUsize,
+    in_sync: AtomicBool,
+    checked_at: Mutex<Option<Instant>>,
+}
+
+impl Replica {
+    /// Whether the replica is in sync, looking at its replication state again once `REPLICA_CHECK_INTERVAL`
+    /// is over. Only one caller checks at a time, the others go by the last check.
+    async fn in_sync(&self, max_lag: Duration) -> bool {
+        let due = {
+            let mut checked_at = self.checked_at.lock().unwrap();
+            let due = checked_at.is_none_or(|at| at.elapsed() >= REPLICA_CHECK_INTERVAL);
+            if due {
+                *checked_at = Some(Instant::now());
+            }
+            due
+        };
+        if due {
+            let mut conn = next_of(&self.connections, &self.next_connection);
+            let in_sync = match redis::cmd("INFO")
+                .arg("replication")
+                .query_async::<String>(&mut conn)
+                .await
+            {
+                Ok(info) => replica_in_sync(&info, max_lag),
+                Err(err) => {
+                    log::warn!("Failed to check replica {}: {}", self.url, err);
+                    false
+                }
+            };
+            if self.in_sync.swap(in_sync, Ordering::Relaxed) != in_sync {
+                match in_sync {
+                    true => log::info!("Replica {} caught up, resolving links", self.url),
+                    false => log::warn!("Replica {} lags behind, leaving it out", self.url),
+                }
+            }
+        }
+        self.in_sync.load(Ordering::Relaxed)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, RedisError> {
+        let attributes = vec![
+            KeyValue::new("db.system.name", "redis"),
+            KeyValue::new("db.operation.name", "GET"),
+            KeyValue::new("server.address", self.url.clone()),
+        ];
+        let mut conn = next_of(&self.connections, &self.next_connection);
+        traced("GET".to_string(), SpanKind::Client, attributes, async {
+            redis::cmd("GET").arg(key).query_async(&mut conn).await
+        })
+        .await
+    }
+}
 
 #[derive(Clone)]
 pub struct RedisService {
-    connection_manager: Arc<ConnectionManager>,
+    connections: Arc<[ConnectionManager]>,
+    next_connection: Arc<AtomicUsize>,
+    replicas: Arc<[Replica]>,
+    next_replica: Arc<AtomicUsize>,
+    replica_max_lag: Duration,
+    command_retries: u32,
+    retry_backoff: Duration,
+    key_prefix: Arc<str>,
+    /// Lets `cleanup` run `FLUSHDB` when there is no key prefix to scope it to, see `RedisConfig`
+    #[cfg(test)]
+    flush_on_cleanup: bool,
 }
 
 impl RedisService {
-    pub async fn new(redis_url: &str) -> Result<Self, RedisError> {
-        let client = Client::open(redis_url)?;
-        let connection_manager = ConnectionManager::new(client).await?;
+    pub async fn new(config: &RedisConfig) -> Result<Self, RedisError> {
+        let connections = connect_pool(Client::open(config.url.as_str())?, config).await?;
+        let mut replicas = Vec::with_capacity(config.replica_urls.len());
+        for url in &config.replica_urls {
+            replicas.push(Replica {
+                url: url.clone(),
+                connections: connect_pool(Client::open(url.as_str())?, config).await?,
+                next_connection: AtomicUsize::new(0),
+                in_sync: AtomicBool::new(false),
+                checked_at: Mutex::new(None),
+            });
+        }
 
         Ok(RedisService {
-            connection_manager: Arc::new(connection_manager),
+            connections,
+            next_connection: Arc::new(AtomicUsize::new(0)),
+            replicas: replicas.into(),
+            next_replica: Arc::new(AtomicUsize::new(0)),
+            replica_max_lag: config.replica_max_lag,
+            command_retries: config.command_retries,
+            retry_backoff: config.retry_backoff,
+            key_prefix: config.key_prefix.as_str().into(),
+            #[cfg(test)]
+            flush_on_cleanup: config.dangerously_flush_on_cleanup,
         })
     }
 
-    pub async fn get(&self, key: &str) -> Result<Option<String>, RedisError> {
-        let mut conn = (*self.connection_manager).clone();
-        redis::cmd("GET").arg(key).query_async(&mut conn).await
+    /// The Redis key of `key`, in the configured namespace
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
     }
 
-    pub async fn set(
+    fn keys(&self, keys: &[String]) -> Vec<String> {
+        keys.iter().map(|key| self.key(key)).collect()
+    }
+
+    /// One `SCAN` page of the keys in the namespace, prefix included
+    async fn scan_namespace(
         &self,
-        key: &str,
-        value: &str,
-        ttl: Option<usize>,
-    ) -> Result<bool, RedisError> {
-        let mut conn = (*self.connection_manager).clone();
-
-        let result: Option<String> = if let Some(ttl_seconds) = ttl {
-            redis::cmd("SET")
-                .arg(key)
-                .arg(value)
-                .arg("EX")
-                .arg(ttl_seconds)
-                .arg("NX")
-                .query_async(&mut conn)
-                .await?
-        } else {
-            redis::cmd("SET")
-                .arg(key)
-                .arg(value)
-                .arg("NX")
-                .query_async(&mut conn)
-                .await?
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<String>), RedisError> {
+        self.query(
+            Retry::Idempotent,
+            redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(scan_pattern(&self.key_prefix))
+                .arg("COUNT")
+                .arg(count),
+        )
+        .await
+    }
+
+    /// Hands out the pooled connections round robin
+    fn connection(&self) -> ConnectionManager {
+        next_of(&self.connections, &self.next_connection)
+    }
+
+    /// The next replica in sync with the primary, round robin
+    async fn replica(&self) -> Option<&Replica> {
+        let start = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.replicas.len() {
+            let replica = &self.replicas[(start + offset) % self.replicas.len()];
+            if replica.in_sync(self.replica_max_lag).await {
+                return Some(replica);
+            }
+        }
+        None
+    }
+
+    /// Runs `command` on the next pooled connection, retrying transient failures with exponential backoff.
+    /// Each command gets one client span named after `operation`, retries included.
+    async fn run<T, F, Fut>(
+        &self,
+        operation: String,
+        retry: Retry,
+        mut command: F,
+    ) -> Result<T, RedisError>
+    where
+        F: FnMut(ConnectionManager) -> Fut,
+        Fut: Future<Output = Result<T, RedisError>>,
+    {
+        let attributes = vec![
+            KeyValue::new("db.system.name", "redis"),
+            KeyValue::new("db.operation.name", operation.clone()),
+        ];
+        traced(operation, SpanKind::Client, attributes, async {
+            let mut attempt = 0;
+            loop {
+                match command(self.connection()).await {
+                    Err(err) if attempt < self.command_retries && is_retryable(&err, retry) => {
+                        let delay = self.retry_backoff * 2u32.pow(attempt);
+                        attempt += 1;
+                        log::warn!(
+                            "Redis command failed, retrying in {:?} (attempt {}/{}): {}",
+                            delay,
+                            attempt,
+                            self.command_retries,
+                            err
+                        );
+                        sleep(delay).await;
+                    }
+                    result => return result,
+                }
+            }
+        })
+        .await
+    }
+
+    async fn query<T: FromRedisValue>(
+        &self,
+        retry: Retry,
+        command: &redis::Cmd,
+    ) -> Result<T, RedisError> {
+        // Only the command name is recorded, keys and values may hold user data
+        let operation = match command.args_iter().next() {
+            Some(redis::Arg::Simple(name)) => String::from_utf8_lossy(name).to_uppercase(),
+            _ => "UNKNOWN".to_string(),
         };
+        self.run(operation, retry, |mut conn| async move {
+            command.query_async(&mut conn).await
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl UrlStore for RedisService {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        Ok(self
+            .query(Retry::Idempotent, redis::cmd("GET").arg(self.key(key)))
+            .await?)
+    }
+
+    async fn get_for_resolve(&self, key: &str) -> Result<Option<String>, StorageError> {
+        if let Some(replica) = self.replica().await {
+            match replica.get(&self.key(key)).await {
+                Ok(Some(value)) => return Ok(Some(value)),
+                // Links created moments ago may not have reached the replica yet
+                Ok(None) => {}
+                Err(err) => log::warn!(
+                    "Replica {} failed to resolve, reading from the primary: {}",
+                    replica.url,
+                    err
+                ),
+            }
+        }
+        self.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<usize>) -> Result<bool, StorageError> {
+        let mut command = redis::cmd("SET");
+        command.arg(self.key(key)).arg(value).arg("NX");
+        if let Some(ttl_seconds) = ttl {
+            command.arg("EX").arg(ttl_seconds);
+        }
+        // A retried NX write that went through the first time would look like a collision
+        let result: Option<String> = self.query(Retry::IfNotSent, &command).await?;
 
         // NX returns "OK" if set was successful, nil if key already exists
         Ok(result.is_some())
     }
 
-    /// Cleans up all data in the current Redis database (use with caution in tests)
-    pub async fn cleanup(&self) -> Result<(), RedisError> {
-        let mut conn = (*self.connection_manager).clone();
-        redis::cmd("FLUSHDB").query_async(&mut conn).await
+    async fn set_first(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<Option<usize>, StorageError> {
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let ttl = ttl.map(|seconds| seconds.to_string()).unwrap_or_default();
+        // Like `set`, a retried script that went through the first time would find its own key taken
+        let stored: usize = self
+            .query(
+                Retry::IfNotSent,
+                redis::cmd("EVAL")
+                    .arg(SET_FIRST)
+                    .arg(keys.len())
+                    .arg(self.keys(keys))
+                    .arg(value)
+                    .arg(ttl),
+            )
+            .await?;
+        Ok(stored.checked_sub(1))
+    }
+
+    async fn set_first_with(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+        writes: &[LinkWrites],
+    ) -> Result<Option<usize>, StorageError> {
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let ttl_arg =
+            |ttl: &Option<usize>| ttl.map(|seconds| seconds.to_string()).unwrap_or_default();
+        let mut script_keys = self.keys(keys);
+        let mut args = vec![value.to_string(), ttl_arg(&ttl), keys.len().to_string()];
+        for writes in writes {
+            args.push(writes.entries.len().to_string());
+            args.push(writes.set_members.len().to_string());
+            args.push(writes.scored_members.len().to_string());
+            for (key, value, ttl) in &writes.entries {
+                script_keys.push(self.key(key));
+                args.extend([value.clone(), ttl_arg(ttl)]);
+            }
+            for (key, member) in &writes.set_members {
+                script_keys.push(self.key(key));
+                args.push(member.clone());
+            }
+            for (key, member, score) in &writes.scored_members {
+                script_keys.push(self.key(key));
+                args.extend([member.clone(), score.to_string()]);
+            }
+        }
+        // Like `set_first`, a retried script that went through the first time would find its own key taken
+        let stored: usize = self
+            .query(
+                Retry::IfNotSent,
+                redis::cmd("EVAL")
+                    .arg(SET_FIRST_WITH)
+                    .arg(script_keys.len())
+                    .arg(script_keys)
+                    .arg(args),
+            )
+            .await?;
+        Ok(stored.checked_sub(1))
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .query(Retry::Idempotent, redis::cmd("MGET").arg(self.keys(keys)))
+            .await?)
     }
-}
 
-pub async fn get_redis_service() -> Result<RedisService, RedisError> {
-    let redis_url =
-        std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_string());
-    let max_attempts: u32 = std::env::var("REDIS_CONNECT_RETRIES")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(30);
-    let backoff_ms: u64 = std::env::var("REDIS_CONNECT_BACKOFF_MS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(500);
+    async fn ttl_many(&self, keys: &[String]) -> Result<Vec<Option<usize>>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.cmd("TTL").arg(self.key(key));
+        }
+        let pipe = &pipe;
+        let ttls: Vec<i64> = self
+            .run(
+                "PIPELINE".to_string(),
+                Retry::Idempotent,
+                |mut conn| async move { pipe.query_async(&mut conn).await },
+            )
+            .await?;
+        // -2 for missing keys, -1 for keys without a TTL
+        Ok(ttls
+            .into_iter()
+            .map(|ttl| usize::try_from(ttl).ok())
+            .collect())
+    }
+
+    async fn set_many(
+        &self,
+        entries: &[(String, String, Option<usize>)],
+    ) -> Result<Vec<bool>, StorageError> {
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for (key, value, ttl) in entries {
+            pipe.cmd("SET").arg(self.key(key)).arg(value).arg("NX");
+            if let Some(ttl_seconds) = ttl {
+                pipe.arg("EX").arg(ttl_seconds);
+            }
+        }
+        let pipe = &pipe;
+        let results: Vec<Option<String>> = self
+            .run(
+                "PIPELINE".to_string(),
+                Retry::IfNotSent,
+                |mut conn| async move { pipe.query_async(&mut conn).await },
+            )
+            .await?;
+        Ok(results.iter().map(Option::is_some).collect())
+    }
 
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<bool, StorageError> {
+        let ttl = ttl.map(|seconds| seconds.to_string()).unwrap_or_default();
+        Ok(self
+            .query(
+                Retry::IfNotSent,
+                redis::cmd("EVAL")
+                    .arg(COMPARE_AND_SET)
+                    .arg(1)
+                    .arg(self.key(key))
+                    .arg(expected)
+                    .arg(value)
+                    .arg(ttl),
+            )
+            .await?)
+    }
+
+    async fn expire(&self, key: &str, ttl: usize) -> Result<bool, StorageError> {
+        Ok(self
+            .query(
+                Retry::Idempotent,
+                redis::cmd("EXPIRE").arg(self.key(key)).arg(ttl),
+            )
+            .await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, StorageError> {
+        // A retry after a timed out delete would report the key as missing
+        let removed: u32 = self
+            .query(Retry::IfNotSent, redis::cmd("DEL").arg(self.key(key)))
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn add_to_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let _: u32 = self
+            .query(
+                Retry::Idempotent,
+                redis::cmd("SADD").arg(self.key(key)).arg(members),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_from_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let _: u32 = self
+            .query(
+                Retry::Idempotent,
+                redis::cmd("SREM").arg(self.key(key)).arg(members),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .query(Retry::Idempotent, redis::cmd("SMEMBERS").arg(self.key(key)))
+            .await?)
+    }
+
+    async fn incr_window(
+        &self,
+        key: &str,
+        window_seconds: usize,
+    ) -> Result<(u64, usize), StorageError> {
+        let count: u64 = self
+            .query(Retry::IfNotSent, redis::cmd("INCR").arg(self.key(key)))
+            .await?;
+        let expire = redis::cmd("EXPIRE")
+            .arg(self.key(key))
+            .arg(window_seconds)
+            .clone();
+        if count == 1 {
+            let _: () = self.query(Retry::Idempotent, &expire).await?;
+            return Ok((count, window_seconds));
+        }
+
+        let ttl: i64 = self
+            .query(Retry::Idempotent, redis::cmd("TTL").arg(self.key(key)))
+            .await?;
+        if ttl == -1 {
+            // The process died between INCR and EXPIRE, don't let the counter live forever
+            let _: () = self.query(Retry::Idempotent, &expire).await?;
+        }
+        Ok((count, usize::try_from(ttl).unwrap_or(window_seconds)))
+    }
+
+    async fn scan_keys(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<String>), StorageError> {
+        let (next, keys) = self.scan_namespace(cursor, count).await?;
+        let keys = keys
+            .into_iter()
+            .filter_map(|key| Some(key.strip_prefix(&*self.key_prefix)?.to_string()))
+            .collect();
+        Ok((next, keys))
+    }
+
+    async fn increment(&self, key: &str, amount: u64) -> Result<u64, StorageError> {
+        Ok(self
+            .query(
+                Retry::IfNotSent,
+                redis::cmd("INCRBY").arg(self.key(key)).arg(amount),
+            )
+            .await?)
+    }
+
+    async fn increment_existing(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        // Plain INCR would recreate an expired or deleted counter without a TTL
+        Ok(self
+            .query(
+                Retry::IfNotSent,
+                redis::cmd("EVAL")
+                    .arg(ADD_IF_EXISTS)
+                    .arg(1)
+                    .arg(self.key(key))
+                    .arg(1),
+            )
+            .await?)
+    }
+
+    async fn decrement(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        Ok(self
+            .query(
+                Retry::IfNotSent,
+                redis::cmd("EVAL")
+                    .arg(ADD_IF_EXISTS)
+                    .arg(1)
+                    .arg(self.key(key))
+                    .arg(-1),
+            )
+            .await?)
+    }
+
+    async fn hash_fields(&self, key: &str) -> Result<HashMap<String, String>, StorageError> {
+        Ok(self
+            .query(Retry::Idempotent, redis::cmd("HGETALL").arg(self.key(key)))
+            .await?)
+    }
+
+    async fn count_estimate(&self, keys: &[String]) -> Result<u64, StorageError> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        Ok(self
+            .query(
+                Retry::Idempotent,
+                redis::cmd("PFCOUNT").arg(self.keys(keys)),
+            )
+            .await?)
+    }
+
+    async fn write_counts(&self, counts: &CountBatch) -> Result<(), StorageError> {
+        if counts.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for (key, by) in &counts.existing {
+            pipe.cmd("EVAL")
+                .arg(ADD_IF_EXISTS)
+                .arg(1)
+                .arg(self.key(key))
+                .arg(by)
+                .ignore();
+        }
+        for (key, (fields, ttl)) in &counts.fields {
+            for (field, by) in fields {
+                pipe.cmd("HINCRBY")
+                    .arg(self.key(key))
+                    .arg(field)
+                    .arg(by)
+                    .ignore();
+            }
+            pipe.cmd("EXPIRE").arg(self.key(key)).arg(ttl).ignore();
+        }
+        for (key, (members, ttl)) in &counts.estimates {
+            pipe.cmd("PFADD").arg(self.key(key)).arg(members).ignore();
+            pipe.cmd("EXPIRE").arg(self.key(key)).arg(ttl).ignore();
+        }
+        for (key, (scores, ttl)) in &counts.scores {
+            for (member, by) in scores {
+                pipe.cmd("ZINCRBY")
+                    .arg(self.key(key))
+                    .arg(by)
+                    .arg(member)
+                    .ignore();
+            }
+            pipe.cmd("EXPIRE").arg(self.key(key)).arg(ttl).ignore();
+        }
+        for (key, scores) in &counts.index_scores {
+            for (member, by) in scores {
+                // XX leaves out members that were removed, INCR adds to the score
+                pipe.cmd("ZADD")
+                    .arg(self.key(key))
+                    .arg("XX")
+                    .arg("INCR")
+                    .arg(by)
+                    .arg(member)
+                    .ignore();
+            }
+        }
+        let pipe = &pipe;
+        let (): () = self
+            .run(
+                "PIPELINE".to_string(),
+                Retry::IfNotSent,
+                |mut conn| async move { pipe.query_async(&mut conn).await },
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn add_scored(&self, key: &str, members: &[(String, i64)]) -> Result<(), StorageError> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let mut command = redis::cmd("ZADD");
+        command.arg(self.key(key)).arg("NX");
+        for (member, score) in members {
+            command.arg(score).arg(member);
+        }
+        let _: usize = self.query(Retry::Idempotent, &command).await?;
+        Ok(())
+    }
+
+    async fn remove_scored(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        if members.is_empty() {
+            return Ok(());
+        }
+        let _: usize = self
+            .query(
+                Retry::Idempotent,
+                redis::cmd("ZREM").arg(self.key(key)).arg(members),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn scores(
+        &self,
+        key: &str,
+        members: &[String],
+    ) -> Result<Vec<Option<i64>>, StorageError> {
+        if members.is_empty() {
+            return Ok(Vec::new());
+        }
+        let scores: Vec<Option<f64>> = self
+            .query(
+                Retry::Idempotent,
+                redis::cmd("ZMSCORE").arg(self.key(key)).arg(members),
+            )
+            .await?;
+        Ok(scores
+            .into_iter()
+            .map(|score| score.map(|score| score as i64))
+            .collect())
+    }
+
+    async fn scored_page(
+        &self,
+        key: &str,
+        after: Option<(i64, &str)>,
+        descending: bool,
+        limit: usize,
+    ) -> Result<Vec<(String, i64)>, StorageError> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let (score, member) = after
+            .map(|(score, member)| (score.to_string(), member))
+            .unwrap_or_default();
+        let page: Vec<(String, f64)> = self
+            .query(
+                Retry::Idempotent,
+                redis::cmd("EVAL")
+                    .arg(SCORED_PAGE)
+                    .arg(1)
+                    .arg(self.key(key))
+                    .arg(if descending { "1" } else { "0" })
+                    .arg(limit.min(i64::MAX as usize))
+                    .arg(score)
+                    .arg(member),
+            )
+            .await?;
+        Ok(page
+            .into_iter()
+            .map(|(member, score)| (member, score as i64))
+            .collect())
+    }
+
+    async fn top_scores(
+        &self,
+        key: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, u64)>, StorageError> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let scores: Vec<(String, f64)> = self
+            .query(
+                Retry::Idempotent,
+                redis::cmd("ZREVRANGE")
+                    .arg(self.key(key))
+                    .arg(0)
+                    .arg(limit - 1)
+                    .arg("WITHSCORES"),
+            )
+            .await?;
+        Ok(scores
+            .into_iter()
+            .map(|(member, score)| (member, score as u64))
+            .collect())
+    }
+
+    async fn push_capped(
+        &self,
+        key: &str,
+        entry: &str,
+        max_len: usize,
+    ) -> Result<(), StorageError> {
+        let mut pipe = redis::pipe();
+        pipe.cmd("LPUSH").arg(self.key(key)).arg(entry).ignore();
+        pipe.cmd("LTRIM")
+            .arg(self.key(key))
+            .arg(0)
+            .arg(max_len.max(1) - 1)
+            .ignore();
+        let pipe = &pipe;
+        let (): () = self
+            .run(
+                "PIPELINE".to_string(),
+                Retry::IfNotSent,
+                |mut conn| async move { pipe.query_async(&mut conn).await },
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_range(
+        &self,
+        key: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, StorageError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .query(
+                Retry::Idempotent,
+                redis::cmd("LRANGE")
+                    .arg(self.key(key))
+                    .arg(offset)
+                    .arg(offset + count - 1),
+            )
+            .await?)
+    }
+
+    /// Deletes every key of the namespace, keys of other applications sharing the database are left alone.
+    /// Without a key prefix that would be every key, so it is refused unless `dangerously_flush_on_cleanup`
+    /// is set.
+    #[cfg(test)]
+    async fn cleanup(&self) -> Result<(), StorageError> {
+        if self.key_prefix.is_empty() {
+            if !self.flush_on_cleanup {
+                return Err(RedisError::from((
+                    redis::ErrorKind::ClientError,
+                    "refusing to delete every key without a key prefix",
+                ))
+                .into());
+            }
+            return Ok(self
+                .query(Retry::Idempotent, &redis::cmd("FLUSHDB"))
+                .await?);
+        }
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = self.scan_namespace(cursor, 1000).await?;
+            if !keys.is_empty() {
+                let _: u64 = self
+                    .query(Retry::Idempotent, redis::cmd("DEL").arg(&keys))
+                    .await?;
+            }
+            if next == 0 {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+}
+
+pub async fn get_redis_service(config: &RedisConfig) -> Result<RedisService, RedisError> {
     let mut attempt: u32 = 0;
     loop {
-        match RedisService::new(&redis_url).await {
+        match RedisService::new(config).await {
             Ok(service) => return Ok(service),
             Err(err) => {
                 attempt += 1;
                 log::warn!(
                     "Failed to connect to Redis (attempt {}/{}): {}",
                     attempt,
-                    max_attempts,
+                    config.connect_retries,
                     err
                 );
-                if attempt >= max_attempts {
+                if attempt >= config.connect_retries {
                     return Err(err);
                 }
-                sleep(Duration::from_millis(backoff_ms)).await;
+                sleep(config.connect_backoff).await;
             }
         }
     }
 }
 
+/// Whether `flags`, the `notify-keyspace-events` setting, has Redis announce expired keys
+fn announces_expiry(flags: &str) -> bool {
+    flags.contains('E') && (flags.contains('x') || flags.contains('A'))
+}
+
+/// Switches on the keyspace notifications of expired keys, keeping the ones other applications asked for
+async fn enable_expiry_notifications(client: &Client) -> Result<(), RedisError> {
+    let mut connection = client.get_multiplexed_async_connection().await?;
+    let settings: HashMap<String, String> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query_async(&mut connection)
+        .await?;
+    let flags = settings
+        .get("notify-keyspace-events")
+        .map(String::as_str)
+        .unwrap_or_default();
+    if announces_expiry(flags) {
+        return Ok(());
+    }
+    redis::cmd("CONFIG")
+        .arg("SET")
+        .arg("notify-keyspace-events")
+        .arg(format!("{}Ex", flags))
+        .query_async(&mut connection)
+        .await
+}
+
+/// Keys of the namespace that Redis expired, without the prefix, as announced by keyspace notifications.
+/// Keys expiring while nobody is subscribed are never announced.
+pub async fn expired_keys(config: &RedisConfig) -> Result<impl Stream<Item = String>, RedisError> {
+    let client = Client::open(config.url.as_str())?;
+    // Managed servers may refuse `CONFIG`, they need `notify-keyspace-events Ex` set by their operator
+    if let Err(err) = enable_expiry_notifications(&client).await {
+        log::warn!(
+            "Failed to switch on keyspace notifications of expired keys, make sure notify-keyspace-events includes Ex: {}",
+            err
+        );
+    }
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub
+        .subscribe(format!(
+            "__keyevent@{}__:expired",
+            client.get_connection_info().redis.db
+        ))
+        .await?;
+    let prefix = config.key_prefix.clone();
+    Ok(pubsub.into_on_message().filter_map(move |message| {
+        let key = message
+            .get_payload::<String>()
+            .ok()
+            .and_then(|key| Some(key.strip_prefix(prefix.as_str())?.to_string()));
+        std::future::ready(key)
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::UrlStore;
+
+    /// Keeps the tests to a namespace of their own, in case they point at a Redis shared with something else
+    fn test_config() -> RedisConfig {
+        RedisConfig {
+            key_prefix: "url-shortener-test:".to_string(),
+            ..RedisConfig::default()
+        }
+    }
 
     #[tokio::test]
     async fn test_redis_service_set_then_get() {
         // Create a fresh Redis service for testing
-        let redis_service = RedisService::new("redis://localhost:6379")
+        let redis_service = RedisService::new(&test_config())
             .await
             .expect("Failed to connect to Redis");
 
@@ -138,10 +1149,178 @@ mod tests {
             .expect("Failed to cleanup Redis");
     }
 
+    #[tokio::test]
+    async fn test_redis_service_set_first() {
+        let redis_service = RedisService::new(&test_config())
+            .await
+            .expect("Failed to connect to Redis");
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+
+        redis_service.set("taken", "old", None).await.unwrap();
+        let keys = vec!["taken".to_string(), "free".to_string()];
+        assert_eq!(
+            redis_service
+                .set_first(&keys, "new", Some(60))
+                .await
+                .unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            redis_service.get("taken").await.unwrap().as_deref(),
+            Some("old")
+        );
+        assert_eq!(
+            redis_service.get("free").await.unwrap().as_deref(),
+            Some("new")
+        );
+        assert_eq!(
+            redis_service.set_first(&keys, "new", None).await.unwrap(),
+            None
+        );
+
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+    }
+
+    #[tokio::test]
+    async fn test_redis_service_index_pages() {
+        let redis_service = RedisService::new(&test_config())
+            .await
+            .expect("Failed to connect to Redis");
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+
+        redis_service
+            .add_scored(
+                "index",
+                &[
+                    ("a".to_string(), 1),
+                    ("b".to_string(), 2),
+                    ("c".to_string(), 2),
+                    ("d".to_string(), 3),
+                ],
+            )
+            .await
+            .unwrap();
+        // Members already in the index keep their score
+        redis_service
+            .add_scored("index", &[("a".to_string(), 9)])
+            .await
+            .unwrap();
+        let page = |after, descending| redis_service.scored_page("index", after, descending, 2);
+        let members = |page: Vec<(String, i64)>| -> Vec<String> {
+            page.into_iter().map(|(member, _)| member).collect()
+        };
+        assert_eq!(members(page(None, true).await.unwrap()), ["d", "c"]);
+        assert_eq!(
+            members(page(Some((2, "c")), true).await.unwrap()),
+            ["b", "a"]
+        );
+        assert_eq!(
+            members(page(Some((2, "b")), false).await.unwrap()),
+            ["c", "d"]
+        );
+        assert_eq!(
+            redis_service
+                .scored_page("index", None, false, 1)
+                .await
+                .unwrap(),
+            vec![("a".to_string(), 1)]
+        );
+
+        let mut counts = CountBatch::default();
+        counts.increment_index_score("index", "a");
+        counts.increment_index_score("index", "gone");
+        redis_service.write_counts(&counts).await.unwrap();
+        redis_service
+            .remove_scored("index", &["d".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            redis_service
+                .scores(
+                    "index",
+                    &["a".to_string(), "d".to_string(), "gone".to_string()]
+                )
+                .await
+                .unwrap(),
+            vec![Some(2), None, None]
+        );
+
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+    }
+
+    #[tokio::test]
+    async fn test_redis_service_set_first_with() {
+        let redis_service = RedisService::new(&test_config())
+            .await
+            .expect("Failed to connect to Redis");
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+
+        redis_service.set("taken", "old", None).await.unwrap();
+        let keys = vec!["taken".to_string(), "free".to_string()];
+        let writes: Vec<LinkWrites> = keys
+            .iter()
+            .map(|key| LinkWrites {
+                entries: vec![(format!("clicks:{}", key), "0".to_string(), Some(60))],
+                set_members: vec![("links:alice".to_string(), key.clone())],
+                scored_members: vec![("by_created:alice".to_string(), key.clone(), 7)],
+            })
+            .collect();
+        assert_eq!(
+            redis_service
+                .set_first_with(&keys, "new", Some(60), &writes)
+                .await
+                .unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            redis_service.get("clicks:free").await.unwrap().as_deref(),
+            Some("0")
+        );
+        assert_eq!(redis_service.get("clicks:taken").await.unwrap(), None);
+        assert_eq!(
+            redis_service.set_members("links:alice").await.unwrap(),
+            vec!["free".to_string()]
+        );
+        assert_eq!(
+            redis_service
+                .scores("by_created:alice", &keys)
+                .await
+                .unwrap(),
+            vec![None, Some(7)]
+        );
+        assert_eq!(
+            redis_service
+                .set_first_with(&keys, "new", None, &writes)
+                .await
+                .unwrap(),
+            None
+        );
+
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+    }
+
     #[tokio::test]
     async fn test_redis_service_get_nonexistent_key() {
         // Create a fresh Redis service for testing
-        let redis_service = RedisService::new("redis://localhost:6379")
+        let redis_service = RedisService::new(&test_config())
             .await
             .expect("Failed to connect to Redis");
 
@@ -174,7 +1353,7 @@ mod tests {
     #[tokio::test]
     async fn test_redis_service_set_nx_prevents_overwrite() {
         // Create a fresh Redis service for testing
-        let redis_service = RedisService::new("redis://localhost:6379")
+        let redis_service = RedisService::new(&test_config())
             .await
             .expect("Failed to connect to Redis");
 
@@ -245,7 +1424,7 @@ mod tests {
     #[tokio::test]
     async fn test_redis_service_ttl_functionality() {
         // Create a fresh Redis service for testing
-        let redis_service = RedisService::new("redis://localhost:6379")
+        let redis_service = RedisService::new(&test_config())
             .await
             .expect("Failed to connect to Redis");
 
@@ -274,6 +1453,21 @@ mod tests {
             "Failed to get key immediately after setting"
         );
         assert_eq!(immediate_get.unwrap(), Some(test_value.to_string()));
+        redis_service
+            .set("test_key_long_ttl", test_value, Some(60))
+            .await
+            .unwrap();
+        redis_service
+            .set("test_key_no_ttl", test_value, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            redis_service
+                .ttl_many(&["test_key_long_ttl", "test_key_no_ttl", "missing"].map(String::from))
+                .await
+                .unwrap(),
+            vec![Some(60), None, None]
+        );
 
         // Wait for TTL to expire
         tokio::time::sleep(tokio::time::Duration::from_secs((ttl_seconds + 1) as u64)).await;
@@ -289,4 +1483,288 @@ mod tests {
             .await
             .expect("Failed to cleanup Redis");
     }
+
+    #[tokio::test]
+    async fn test_redis_service_set_many_and_get_many() {
+        let redis_service = RedisService::new(&test_config())
+            .await
+            .expect("Failed to connect to Redis");
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+
+        assert!(redis_service.set("taken", "old", None).await.unwrap());
+
+        let results = redis_service
+            .set_many(&[
+                ("fresh".to_string(), "a".to_string(), Some(60)),
+                ("taken".to_string(), "b".to_string(), Some(60)),
+                ("no_ttl".to_string(), "c".to_string(), None),
+            ])
+            .await
+            .expect("Pipelined set should succeed");
+
+        assert_eq!(results, vec![true, false, true]);
+        assert_eq!(
+            redis_service.get("taken").await.unwrap(),
+            Some("old".to_string())
+        );
+        assert_eq!(
+            redis_service.get("no_ttl").await.unwrap(),
+            Some("c".to_string())
+        );
+        assert_eq!(
+            redis_service
+                .get_many(&["taken".to_string(), "missing".to_string()])
+                .await
+                .unwrap(),
+            vec![Some("old".to_string()), None]
+        );
+
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+    }
+
+    #[tokio::test]
+    async fn test_redis_service_compare_and_set_keeps_ttl_and_sets() {
+        let redis_service = RedisService::new(&test_config())
+            .await
+            .expect("Failed to connect to Redis");
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+
+        assert!(!redis_service
+            .compare_and_set("missing", "first", "value", None)
+            .await
+            .unwrap());
+        assert!(redis_service.set("key", "first", Some(1)).await.unwrap());
+        assert!(!redis_service
+            .compare_and_set("key", "stale", "second", None)
+            .await
+            .unwrap());
+        assert!(redis_service
+            .compare_and_set("key", "first", "second", None)
+            .await
+            .unwrap());
+        assert_eq!(
+            redis_service.get("key").await.unwrap(),
+            Some("second".to_string())
+        );
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        assert_eq!(
+            redis_service.get("key").await.unwrap(),
+            None,
+            "Replace should keep the original TTL"
+        );
+
+        let members = ["a".to_string(), "b".to_string()];
+        redis_service.add_to_set("set", &members).await.unwrap();
+        redis_service
+            .remove_from_set("set", &members[..1])
+            .await
+            .unwrap();
+        assert_eq!(
+            redis_service.set_members("set").await.unwrap(),
+            vec!["b".to_string()]
+        );
+
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+    }
+
+    #[tokio::test]
+    async fn test_redis_service_counts_only_existing_keys() {
+        let redis_service = RedisService::new(&test_config())
+            .await
+            .expect("Failed to connect to Redis");
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+
+        assert_eq!(redis_service.decrement("missing").await.unwrap(), None);
+        assert_eq!(redis_service.get("missing").await.unwrap(), None);
+        assert!(redis_service.set("counter", "1", Some(60)).await.unwrap());
+        assert_eq!(redis_service.decrement("counter").await.unwrap(), Some(0));
+        assert_eq!(redis_service.decrement("counter").await.unwrap(), Some(-1));
+        assert_eq!(
+            redis_service.increment_existing("counter").await.unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            redis_service.increment_existing("missing").await.unwrap(),
+            None
+        );
+
+        let mut counts = CountBatch::default();
+        for _ in 0..2 {
+            counts.increment_existing("counter");
+            counts.increment_existing("missing");
+            counts.increment_field("hash", "a", 60);
+        }
+        for member in ["a", "b", "a"] {
+            counts.add_to_estimate("estimate:1", member, 60);
+        }
+        counts.add_to_estimate("estimate:2", "c", 60);
+        for member in ["a", "b", "b", "c", "b", "c"] {
+            counts.increment_score("scores", member, 60);
+        }
+        redis_service.write_counts(&counts).await.unwrap();
+
+        assert_eq!(
+            redis_service.get("counter").await.unwrap().as_deref(),
+            Some("2")
+        );
+        assert_eq!(redis_service.get("missing").await.unwrap(), None);
+        assert_eq!(
+            redis_service.hash_fields("hash").await.unwrap(),
+            HashMap::from([("a".to_string(), "2".to_string())])
+        );
+        assert!(redis_service
+            .hash_fields("missing")
+            .await
+            .unwrap()
+            .is_empty());
+        let keys = ["estimate:1", "estimate:2", "missing"].map(String::from);
+        assert_eq!(redis_service.count_estimate(&keys).await.unwrap(), 3);
+        assert_eq!(
+            redis_service.top_scores("scores", 2).await.unwrap(),
+            vec![("b".to_string(), 3), ("c".to_string(), 2)]
+        );
+
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+    }
+
+    #[test]
+    fn test_only_idempotent_commands_retry_after_timeouts() {
+        let timeout = RedisError::from(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        let refused = RedisError::from(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+
+        assert!(is_retryable(&timeout, Retry::Idempotent));
+        assert!(!is_retryable(&timeout, Retry::IfNotSent));
+        assert!(is_retryable(&refused, Retry::IfNotSent));
+    }
+
+    #[test]
+    fn test_replicas_in_sync_only_while_they_hear_from_the_primary() {
+        let info = |status: &str, seconds: &str| {
+            format!(
+                "# Replication\r\nrole:slave\r\nmaster_host:primary\r\nmaster_link_status:{}\r\nmaster_last_io_seconds_ago:{}\r\n",
+                status, seconds
+            )
+        };
+        let max_lag = Duration::from_secs(15);
+        assert!(replica_in_sync(&info("up", "3"), max_lag));
+        assert!(replica_in_sync(&info("up", "15"), max_lag));
+        assert!(!replica_in_sync(&info("up", "16"), max_lag));
+        assert!(!replica_in_sync(&info("down", "3"), max_lag));
+        // Replicas that never reached the primary report -1
+        assert!(!replica_in_sync(&info("down", "-1"), max_lag));
+        // A primary listed as a replica isn't a replica
+        assert!(!replica_in_sync(
+            "# Replication\r\nrole:master\r\n",
+            max_lag
+        ));
+    }
+
+    #[test]
+    fn test_expiry_notification_flags() {
+        assert!(announces_expiry("Ex"));
+        assert!(announces_expiry("KEA"));
+        assert!(!announces_expiry(""));
+        assert!(!announces_expiry("Kx"));
+        assert!(!announces_expiry("Eg"));
+    }
+
+    #[test]
+    fn test_scan_pattern_only_matches_the_namespace() {
+        assert_eq!(scan_pattern(""), "*");
+        assert_eq!(scan_pattern("us:"), "us:*");
+        assert_eq!(scan_pattern("a*[b]?\\:"), "a\\*\\[b\\]\\?\\\\:*");
+    }
+
+    #[tokio::test]
+    async fn test_redis_service_keeps_to_its_namespace() {
+        let shared = RedisService::new(&test_config())
+            .await
+            .expect("Failed to connect to Redis");
+        let redis_service = RedisService::new(&RedisConfig {
+            key_prefix: "url-shortener-test:us:".to_string(),
+            ..test_config()
+        })
+        .await
+        .expect("Failed to connect to Redis");
+        shared.cleanup().await.expect("Failed to cleanup Redis");
+
+        shared.set("other-app", "value", None).await.unwrap();
+        redis_service.set("launch", "record", None).await.unwrap();
+        redis_service.increment("clicks:launch", 1).await.unwrap();
+        assert_eq!(
+            shared.get("us:launch").await.unwrap(),
+            Some("record".to_string())
+        );
+        assert_eq!(redis_service.get("other-app").await.unwrap(), None);
+
+        let (_, mut keys) = redis_service.scan_keys(0, 1000).await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["clicks:launch", "launch"]);
+
+        redis_service.cleanup().await.unwrap();
+        assert_eq!(redis_service.get("launch").await.unwrap(), None);
+        assert_eq!(
+            shared.get("other-app").await.unwrap(),
+            Some("value".to_string())
+        );
+        shared.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_redis_service_refuses_to_clean_up_without_a_prefix() {
+        let redis_service = RedisService::new(&RedisConfig::default())
+            .await
+            .expect("Failed to connect to Redis");
+        // Flushing is not tried here, the database may be shared
+        assert!(redis_service.cleanup().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redis_service_spreads_commands_over_the_pool() {
+        let redis_service = RedisService::new(&RedisConfig {
+            pool_size: 3,
+            ..test_config()
+        })
+        .await
+        .expect("Failed to connect to Redis");
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+
+        assert_eq!(redis_service.connections.len(), 3);
+        assert!(redis_service
+            .set("pooled", "value", Some(60))
+            .await
+            .unwrap());
+        for _ in 0..3 {
+            assert_eq!(
+                redis_service.get("pooled").await.unwrap(),
+                Some("value".to_string())
+            );
+        }
+
+        redis_service
+            .cleanup()
+            .await
+            .expect("Failed to cleanup Redis");
+    }
 }
@@ -0,0 +1,256 @@
+use actix_web::web::{self, Data};
+use actix_web::{get, post, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{self, AuditAction, AuditContext};
+use crate::auth::AuthenticatedKey;
+use crate::body::JsonBody;
+use crate::error::ApiError;
+use crate::moderation::{self, LinkModeration, MAX_REASON_LENGTH};
+use crate::ownership::load_link;
+use crate::proxy;
+use crate::ratelimit::{check_rate_limit, ip_bucket, RateLimitDecision};
+use crate::tenants;
+use crate::AppState;
+
+/// Slugs with reports nobody looked at yet
+const OPEN_REPORTS_KEY: &str = "reports:open";
+/// Reports kept per link, a link flagged more often than that is in the queue either way
+const MAX_REPORTS_PER_LINK: usize = 100;
+/// Reports a client may send per window, see `REPORTS_PER_HOUR`
+const REPORT_WINDOW_SECONDS: usize = 60 * 60;
+
+fn reports_key(slug: &str) -> String {
+    format!("report:{}", slug)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportCategory {
+    Spam,
+    Phishing,
+    Malware,
+    Other,
+}
+
+#[derive(Deserialize)]
+struct ReportRequest {
+    category: ReportCategory,
+    reason: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Report {
+    category: ReportCategory,
+    #[serde(default)]
+    reason: Option<String>,
+    reported_at: DateTime<Utc>,
+}
+
+/// Flags a link as harmful, anyone can report a link but only `REPORTS_PER_HOUR` times per IP
+#[post("/api/report/{slug}")]
+async fn report_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: JsonBody<ReportRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let ReportRequest { category, reason } = body.into_inner();
+    let reason = reason
+        .map(|reason| reason.trim().to_string())
+        .filter(|reason| !reason.is_empty());
+    if reason
+        .as_ref()
+        .is_some_and(|reason| reason.chars().count() > MAX_REASON_LENGTH)
+    {
+        return Err(ApiError::validation(
+            "invalid_reason",
+            format!(
+                "The reason can be at most {} characters long.",
+                MAX_REASON_LENGTH
+            ),
+        ));
+    }
+    load_link(&state, &slug).await?;
+
+    if state.report_rate_limit > 0 {
+        match check_rate_limit(
+            state.store.as_ref(),
+            &ip_bucket("report", proxy::client_ip(&req)),
+            state.report_rate_limit,
+            REPORT_WINDOW_SECONDS,
+        )
+        .await
+        {
+            Ok(RateLimitDecision::Allowed { .. }) => {}
+            Ok(RateLimitDecision::Limited {
+                retry_after_seconds,
+            }) => {
+                return Ok(ApiError::RateLimited {
+                    retry_after_seconds,
+                }
+                .error_response())
+            }
+            Err(err) => log::error!("Rate limiter failed, letting the report through: {}", err),
+        }
+    }
+
+    let report = Report {
+        category,
+        reason,
+        reported_at: Utc::now(),
+    };
+    let json = serde_json::to_string(&report).expect("reports are always serializable");
+    state
+        .store
+        .push_capped(&reports_key(&slug), &json, MAX_REPORTS_PER_LINK)
+        .await?;
+    state
+        .store
+        .add_to_set(OPEN_REPORTS_KEY, std::slice::from_ref(&slug))
+        .await?;
+    log::info!("Link {} reported as {:?}", slug, category);
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Serialize)]
+struct ReportedLink {
+    slug: String,
+    url: String,
+    tenant: Option<String>,
+    suspended: bool,
+    /// The newest first
+    reports: Vec<Report>,
+}
+
+/// Links with open reports, the most reported first. Admin keys of a tenant only see the links of their tenant.
+#[get(
+    "/api/admin/reports",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn report_queue(req: HttpRequest, state: Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let tenant = admin_tenant(&req);
+    let mut queue = Vec::new();
+    let mut stale = Vec::new();
+    for slug in state.store.set_members(OPEN_REPORTS_KEY).await? {
+        let link = match load_link(&state, &slug).await {
+            Ok((_, link)) => link,
+            Err(ApiError::NotFound { .. }) => {
+                stale.push(slug);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if tenants::ensure_same_tenant(tenant.as_deref(), &link).is_err() {
+            continue;
+        }
+        let reports = state
+            .store
+            .list_range(&reports_key(&slug), 0, MAX_REPORTS_PER_LINK)
+            .await?
+            .iter()
+            .filter_map(|raw| serde_json::from_str(raw).ok())
+            .collect();
+        queue.push(ReportedLink {
+            slug,
+            url: link.url,
+            tenant: link.tenant,
+            suspended: link.suspension.is_some(),
+            reports,
+        });
+    }
+    // Expired and deleted links leave the queue on their own
+    if !stale.is_empty() {
+        for slug in &stale {
+            state.store.delete(&reports_key(slug)).await?;
+        }
+        state
+            .store
+            .remove_from_set(OPEN_REPORTS_KEY, &stale)
+            .await?;
+    }
+    queue.sort_by(|a, b| {
+        b.reports
+            .len()
+            .cmp(&a.reports.len())
+            .then_with(|| a.slug.cmp(&b.slug))
+    });
+    Ok(HttpResponse::Ok().json(queue))
+}
+
+/// Suspends a reported link and closes its reports, the reason lists the reported categories
+#[post(
+    "/api/admin/reports/{slug}/disable",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn disable_reported_link(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let mut categories: Vec<ReportCategory> = Vec::new();
+    for raw in state
+        .store
+        .list_range(&reports_key(&slug), 0, MAX_REPORTS_PER_LINK)
+        .await?
+    {
+        if let Ok(report) = serde_json::from_str::<Report>(&raw) {
+            if !categories.contains(&report.category) {
+                categories.push(report.category);
+            }
+        }
+    }
+    let reason = (!categories.is_empty()).then(|| {
+        let categories: Vec<String> = categories
+            .iter()
+            .map(|category| format!("{:?}", category).to_lowercase())
+            .collect();
+        format!("Reported as {}", categories.join(", "))
+    });
+    let link = moderation::suspend(&req, &state, &slug, reason).await?;
+    close_reports(&state, &slug).await?;
+    Ok(HttpResponse::Ok().json(LinkModeration::new(slug, link)))
+}
+
+/// Closes the reports of a link without suspending it
+#[post(
+    "/api/admin/reports/{slug}/dismiss",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn dismiss_reports(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let (_, link) = load_link(&state, &slug).await?;
+    tenants::ensure_same_tenant(admin_tenant(&req).as_deref(), &link)?;
+    close_reports(&state, &slug).await?;
+    audit::record(
+        &state,
+        &AuditContext::from_request(&req, None),
+        AuditAction::ReportsDismissed,
+        &slug,
+        link.tenant.as_deref(),
+    )
+    .await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn admin_tenant(req: &HttpRequest) -> Option<String> {
+    req.extensions()
+        .get::<AuthenticatedKey>()
+        .and_then(|key| key.key.tenant.clone())
+}
+
+async fn close_reports(state: &AppState, slug: &str) -> Result<(), ApiError> {
+    state.store.delete(&reports_key(slug)).await?;
+    state
+        .store
+        .remove_from_set(OPEN_REPORTS_KEY, &[slug.to_string()])
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,266 @@
+use actix_web::web::{self, Data};
+use actix_web::{get, post, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{self, AuditAction};
+use crate::auth;
+use crate::body::JsonBody;
+use crate::error::ApiError;
+use crate::expiration::compute_ttl;
+use crate::expiry_events;
+use crate::link::Link;
+use crate::ownership::{load_link, record_owned_links};
+use crate::short_domains::link_key;
+use crate::tags;
+use crate::url_shortener::{validate_alias, AliasError};
+use crate::usage;
+use crate::users::MaybeUser;
+use crate::{
+    audit_created, link_host, prepare_link, start_counters, AppState, Creator, UrlShortenData,
+    UrlShortenOptions,
+};
+
+#[derive(Deserialize)]
+struct ReserveRequest {
+    slug: String,
+    /// Domain to reserve the slug under, like `domain` when shortening
+    domain: Option<String>,
+    /// Lifetime of the reservation, mutually exclusive with `expires_at`, defaults to `DEFAULT_TTL_SECONDS`
+    expires_in_seconds: Option<u64>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct AvailabilityQuery {
+    /// Domain the alias would be used under, like `domain` when shortening
+    domain: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AliasStatus {
+    Available,
+    /// A route of the service or one of `RESERVED_SLUGS`, it can never be used
+    Reserved,
+    /// A link or a slug reservation holds it
+    Taken,
+}
+
+#[derive(Serialize)]
+struct AliasAvailability {
+    alias: String,
+    short_url: String,
+    available: bool,
+    status: AliasStatus,
+}
+
+/// Creators of reservations are remembered on them, only they can attach the destination
+fn ensure_identified(creator: &Creator) -> Result<(), ApiError> {
+    if creator.owner.is_none() && creator.api_key.is_none() {
+        return Err(auth::missing_key());
+    }
+    Ok(())
+}
+
+/// A reservation belongs to the user who made it, or to the API key for reservations made without a user
+fn ensure_reserved_by(reservation: &Link, creator: &Creator) -> Result<(), ApiError> {
+    let reserved_by = match &reservation.owner {
+        Some(owner) => creator.owner.as_ref() == Some(owner),
+        None => reservation.api_key.is_some() && reservation.api_key == creator.api_key,
+    };
+    if !reserved_by {
+        return Err(ApiError::Forbidden {
+            message: "Only whoever reserved the slug can attach a destination to it.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Locks in a custom slug before its destination is known, e.g. `short.me/launch` ahead of a product launch.
+/// The slug answers `404` and can't be taken by anyone else until the reservation expires.
+#[post(
+    "/api/slugs/reserve",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_api_key)"
+)]
+async fn reserve_slug(
+    req: HttpRequest,
+    body: JsonBody<ReserveRequest>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let creator = Creator::from_request(&req, user.0.map(|user| user.id));
+    ensure_identified(&creator)?;
+    let ReserveRequest {
+        slug,
+        domain,
+        expires_in_seconds,
+        expires_at,
+    } = body.into_inner();
+    let slug = state.slug(slug);
+    if let Err(err) = validate_alias(&slug, &state.settings.get().reserved_slugs) {
+        return Err(ApiError::InvalidAlias {
+            message: err.to_string(),
+            alias: slug,
+        });
+    }
+    let host = link_host(&state, domain.as_deref(), &creator)?;
+    let now = Utc::now();
+    let ttl = compute_ttl(
+        expires_in_seconds,
+        expires_at,
+        now,
+        state.default_ttl_seconds,
+        state.ttl_bounds,
+    )
+    .map_err(|err| ApiError::validation("invalid_expiration", err.to_string()))?;
+
+    let reservation = Link {
+        domain: host.clone(),
+        tenant: creator.tenant.clone(),
+        api_key: creator.api_key.clone(),
+        owner: creator.owner.clone(),
+        reserved: true,
+        ..Default::default()
+    };
+    let key = link_key(&slug, host.as_deref());
+    if !state
+        .store
+        .set(&key, &reservation.encode(), Some(ttl))
+        .await?
+    {
+        return Err(ApiError::AliasTaken { alias: slug });
+    }
+    if let Some(context) = &creator.audit {
+        audit::record(
+            &state,
+            context,
+            AuditAction::SlugReserved,
+            &key,
+            creator.tenant.as_deref(),
+        )
+        .await;
+    }
+    // Owners find their reservations among their links, and can delete them to let the slug go
+    if let Some(owner) = &creator.owner {
+        record_owned_links(&state, owner, std::slice::from_ref(&key)).await;
+        if let Some((indexes_key, indexes, ttl)) =
+            expiry_events::indexes_entry(&key, Some(owner), &[], Some(ttl))
+        {
+            if let Err(err) = state.store.set(&indexes_key, &indexes, ttl).await {
+                log::warn!("Failed to store the owner of reservation {}: {}", key, err);
+            }
+        }
+    }
+    Ok(HttpResponse::Created().json(UrlShortenData {
+        short_url: state.short_url(&key),
+        expires_at: Some(now + Duration::seconds(ttl as i64)),
+        collisions: None,
+        warning: None,
+    }))
+}
+
+/// Whether a custom alias can still be had, for forms validating vanity slugs as they are typed. Aliases
+/// that can't be used at all fail like they do when shortening. Each check is a request against the rate
+/// limit of this route, so the keyspace can't be walked quickly.
+#[get(
+    "/api/slugs/{alias}/available",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_api_key)"
+)]
+async fn alias_available(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<AvailabilityQuery>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let alias = state.slug(path.into_inner());
+    let host = link_host(
+        &state,
+        query.domain.as_deref(),
+        &Creator::from_request(&req, None),
+    )?;
+    let key = link_key(&alias, host.as_deref());
+    let status = match validate_alias(&alias, &state.settings.get().reserved_slugs) {
+        Err(AliasError::Reserved) => AliasStatus::Reserved,
+        Err(err) => {
+            return Err(ApiError::InvalidAlias {
+                message: err.to_string(),
+                alias,
+            })
+        }
+        Ok(()) if state.store.get(&key).await?.is_some() => AliasStatus::Taken,
+        Ok(()) => AliasStatus::Available,
+    };
+    Ok(HttpResponse::Ok().json(AliasAvailability {
+        short_url: state.short_url(&key),
+        alias,
+        available: status == AliasStatus::Available,
+        status,
+    }))
+}
+
+/// Turns a reservation into a link to the destination, the body takes the options of a shorten request.
+/// The slug comes from the path, `alias` is ignored.
+#[post(
+    "/api/slugs/{slug}/attach",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_api_key)"
+)]
+async fn attach_url(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: JsonBody<UrlShortenOptions>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let creator = Creator::from_request(&req, user.0.map(|user| user.id));
+    ensure_identified(&creator)?;
+    let slug = state.slug(path.into_inner());
+    let mut options = body.into_inner();
+    options.alias = None;
+    let prepared = prepare_link(options, &creator, &state, Utc::now()).await?;
+    let key = prepared.key(&slug);
+
+    let (record, reservation) = load_link(&state, &key).await?;
+    if !reservation.reserved {
+        return Err(ApiError::AliasTaken { alias: slug });
+    }
+    ensure_reserved_by(&reservation, &creator)?;
+    usage::claim_link(&state, &creator).await?;
+    let attached = state
+        .store
+        .compare_and_set(&key, &record, &prepared.link, prepared.ttl)
+        .await;
+    if !attached.as_ref().is_ok_and(|attached| *attached) {
+        usage::release_link(&state, &creator).await;
+        attached?;
+        return Err(ApiError::Conflict {
+            code: "reservation_changed",
+            message: format!(
+                "The reservation of '{}' expired or changed while attaching the URL.",
+                slug
+            ),
+        });
+    }
+    state.link_cache.invalidate(&key);
+    // The owner recorded for the reservation expires with it, the link may live longer and carry tags
+    state
+        .store
+        .delete(&expiry_events::indexes_key(&key))
+        .await?;
+    start_counters(&state, &key, &prepared).await?;
+    state.publish(prepared.created_event(&key));
+    state
+        .metadata
+        .fetch_later(&key, &prepared.url, prepared.ttl);
+    audit_created(&state, &creator, &key).await;
+    tags::index_links(&state, [(key.as_str(), prepared.tags.as_slice())]).await;
+    Ok(HttpResponse::Ok().json(UrlShortenData {
+        short_url: state.short_url(&key),
+        expires_at: prepared.expires_at,
+        collisions: None,
+        warning: None,
+    }))
+}
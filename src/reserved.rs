@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+
+/// Paths served by the application itself, a slug with one of these names would never resolve
+pub const BUILTIN_RESERVED_SLUGS: [&str; 6] = [
+    "shorten-url",
+    "healthz",
+    "metrics",
+    "api",
+    "graphql",
+    "static",
+];
+
+/// Slugs that can't be handed out, neither generated nor as custom aliases. Matching is case-insensitive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReservedSlugs {
+    words: HashSet<String>,
+}
+
+impl ReservedSlugs {
+    /// Builtin application routes plus `extra` entries, e.g. routes of a reverse proxy in front of the service
+    pub fn new<I, S>(extra: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let words = BUILTIN_RESERVED_SLUGS
+            .iter()
+            .map(|word| word.to_string())
+            .chain(
+                extra
+                    .into_iter()
+                    .map(|word| word.as_ref().trim().to_ascii_lowercase())
+                    .filter(|word| !word.is_empty()),
+            )
+            .collect();
+        ReservedSlugs { words }
+    }
+
+    pub fn is_reserved(&self, slug: &str) -> bool {
+        self.words.contains(&slug.to_ascii_lowercase())
+    }
+
+    /// The reserved slugs in alphabetical order, builtin routes included
+    pub fn words(&self) -> Vec<String> {
+        let mut words: Vec<String> = self.words.iter().cloned().collect();
+        words.sort();
+        words
+    }
+}
+
+impl Default for ReservedSlugs {
+    fn default() -> Self {
+        Self::new(std::iter::empty::<&str>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_routes_are_reserved() {
+        let reserved = ReservedSlugs::default();
+
+        assert!(reserved.is_reserved("shorten-url"));
+        assert!(reserved.is_reserved("Metrics"));
+        assert!(reserved.is_reserved("graphql"));
+        assert!(!reserved.is_reserved("my-launch"));
+    }
+
+    #[test]
+    fn test_extra_entries_are_normalized() {
+        let reserved = ReservedSlugs::new([" Admin ", "", "login"]);
+
+        assert!(reserved.is_reserved("admin"));
+        assert!(reserved.is_reserved("LOGIN"));
+        assert!(reserved.is_reserved("healthz"));
+        assert!(!reserved.is_reserved(""));
+    }
+}
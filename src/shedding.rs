@@ -0,0 +1,161 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::Error;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::config::ServerTuning;
+use crate::error::ApiError;
+use crate::metrics::Counter;
+use crate::reserved::BUILTIN_RESERVED_SLUGS;
+use crate::AppState;
+
+/// Seconds shed clients are asked to wait, a worker over its limit frees up within moments
+const RETRY_AFTER_SECONDS: usize = 1;
+
+/// Routes never shed, load balancers taking an instance out for one busy second would only move its load
+const EXEMPT_ROUTES: [&str; 2] = ["healthz", "metrics"];
+
+/// In-flight request limits of one worker, redirects and the rest of the API counted apart so a burst of
+/// API calls doesn't starve redirects and the other way round
+pub struct LoadShedder {
+    redirects: Option<Arc<Semaphore>>,
+    api: Option<Arc<Semaphore>>,
+}
+
+impl LoadShedder {
+    pub fn new(server: &ServerTuning) -> Self {
+        let limit = |permits: usize| (permits > 0).then(|| Arc::new(Semaphore::new(permits)));
+        LoadShedder {
+            redirects: limit(server.max_inflight_redirects),
+            api: limit(server.max_inflight_api_requests),
+        }
+    }
+
+    /// The limit requests to `path` count against, links and their pages are redirects
+    fn limit_of(&self, path: &str) -> Option<&Arc<Semaphore>> {
+        let route = path.trim_start_matches('/').split('/').next().unwrap_or("");
+        if EXEMPT_ROUTES.contains(&route) {
+            None
+        } else if route.is_empty() || BUILTIN_RESERVED_SLUGS.contains(&route) {
+            self.api.as_ref()
+        } else {
+            self.redirects.as_ref()
+        }
+    }
+}
+
+/// Answers `503 Service Unavailable` with a `Retry-After` right away once the worker handles as many
+/// requests as it may, instead of queueing them until everything is slow. Requests count until their
+/// response starts, bodies streamed after that don't hold a slot.
+pub async fn shed_load(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(shedder) = req.app_data::<Data<LoadShedder>>().cloned() else {
+        return next.call(req).await;
+    };
+    let Some(limit) = shedder.limit_of(req.path()) else {
+        return next.call(req).await;
+    };
+    let Ok(_permit) = limit.try_acquire() else {
+        if let Some(state) = req.app_data::<Data<AppState>>() {
+            state.metrics.incr(Counter::RequestsShed);
+        }
+        return Err(ApiError::Overloaded {
+            retry_after_seconds: RETRY_AFTER_SECONDS,
+        }
+        .into());
+    };
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::{AppConfig, UrlShortener};
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, web, App, HttpResponse};
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    #[actix_web::test]
+    async fn test_requests_over_the_limit_are_shed() {
+        let shortener =
+            UrlShortener::with_store(&AppConfig::default(), Arc::new(MemoryStore::new()))
+                .await
+                .unwrap();
+        let server = ServerTuning {
+            max_inflight_redirects: 1,
+            max_inflight_api_requests: 1,
+            ..AppConfig::default().server
+        };
+        let release = Arc::new(Notify::new());
+        let held = release.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(shortener.state.clone())
+                .app_data(Data::new(LoadShedder::new(&server)))
+                .wrap(actix_web::middleware::from_fn(shed_load))
+                .route(
+                    "/api/slow",
+                    web::get().to(move || {
+                        let held = held.clone();
+                        async move {
+                            held.notified().await;
+                            HttpResponse::Ok().finish()
+                        }
+                    }),
+                )
+                .route("/healthz", web::get().to(HttpResponse::Ok))
+                .route("/{slug}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let slow = test::call_service(&app, test::TestRequest::get().uri("/api/slow").to_request());
+        let others = async {
+            let shed = match test::try_call_service(
+                &app,
+                test::TestRequest::get().uri("/api/links").to_request(),
+            )
+            .await
+            {
+                Ok(_) => panic!("the request over the limit got through"),
+                Err(err) => err.error_response(),
+            };
+            // Redirects and health checks don't wait for the API
+            let redirect =
+                test::call_service(&app, test::TestRequest::get().uri("/launch").to_request())
+                    .await;
+            let health =
+                test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request())
+                    .await;
+            release.notify_one();
+            (shed, redirect, health)
+        };
+        let (slow, (shed, redirect, health)) = tokio::time::timeout(
+            Duration::from_secs(5),
+            futures_util::future::join(slow, others),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(slow.status(), StatusCode::OK);
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shed.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        assert_eq!(redirect.status(), StatusCode::OK);
+        assert_eq!(health.status(), StatusCode::OK);
+        assert_eq!(shortener.state.metrics.get(Counter::RequestsShed), 1);
+
+        // The slot is free again once the response started
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/api/links").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,212 @@
+use url::Url;
+
+use crate::error::ApiError;
+
+/// Domains short links are minted under. Links of the primary `SHORTENER_DOMAIN` are stored under their
+/// slug, links of the other domains under `slug@host`, so every domain has its own slugs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShortDomains {
+    primary: String,
+    /// `(host, base URL)` of the domains from `SHORTENER_DOMAINS`
+    others: Vec<(String, String)>,
+}
+
+/// Lowercased host of a base URL like `https://go.corp.com`, or of a bare host like `go.corp.com`
+pub fn host_of(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('/');
+    if domain.contains("://") {
+        Url::parse(domain).ok()?.host_str().map(str::to_lowercase)
+    } else if domain.is_empty() || domain.contains(['/', '@', ':']) {
+        None
+    } else {
+        Some(domain.to_lowercase())
+    }
+}
+
+/// Storage key of `slug` on the domain `host`, `None` for the primary domain
+pub fn link_key(slug: &str, host: Option<&str>) -> String {
+    match host {
+        Some(host) => format!("{}@{}", slug, host),
+        None => slug.to_string(),
+    }
+}
+
+/// The slug visitors type and the host of its domain, `None` for the primary domain
+pub fn split_key(key: &str) -> (&str, Option<&str>) {
+    match key.split_once('@') {
+        Some((slug, host)) => (slug, Some(host)),
+        None => (key, None),
+    }
+}
+
+impl ShortDomains {
+    /// Base URLs without a trailing slash, as validated by the configuration
+    pub fn new(primary: String, others: &[String]) -> Self {
+        let others = others
+            .iter()
+            .filter_map(|base| Some((host_of(base)?, base.clone())))
+            .collect();
+        ShortDomains { primary, others }
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    /// Host of a configured domain given as host or base URL, `None` for the primary domain
+    pub fn lookup(&self, domain: &str) -> Result<Option<String>, ApiError> {
+        let host = host_of(domain);
+        if host.is_some() && host == host_of(&self.primary) {
+            return Ok(None);
+        }
+        match self
+            .others
+            .iter()
+            .find(|(other, _)| Some(other) == host.as_ref())
+        {
+            Some((host, _)) => Ok(Some(host.clone())),
+            None => Err(ApiError::validation(
+                "invalid_domain",
+                format!("'{}' is not one of the domains of this service", domain),
+            )),
+        }
+    }
+
+    /// Base URL of the domain `host`, the primary one for `None`. Domains removed from the configuration
+    /// are assumed to be served over HTTPS.
+    pub fn base_url(&self, host: Option<&str>) -> String {
+        let Some(host) = host else {
+            return self.primary.clone();
+        };
+        self.others
+            .iter()
+            .find(|(other, _)| other == host)
+            .map(|(_, base)| base.clone())
+            .unwrap_or_else(|| format!("https://{}", host))
+    }
+
+    /// Short URL of the link stored under `key`
+    pub fn short_url(&self, key: &str) -> String {
+        let (slug, host) = split_key(key);
+        format!("{}/{}", self.base_url(host), slug)
+    }
+
+    /// Rejects destinations that are short links of this service themselves, e.g. `short.me/a -> short.me/a`.
+    /// Chains of short links could loop forever, pages of the service like its home page are fine.
+    pub fn ensure_not_short_link(&self, url: &str) -> Result<(), ApiError> {
+        let Ok(url) = Url::parse(url) else {
+            return Ok(());
+        };
+        let Some(host) = url.host_str().map(str::to_lowercase) else {
+            return Ok(());
+        };
+        let own = host_of(&self.primary).as_ref() == Some(&host)
+            || self.others.iter().any(|(other, _)| *other == host);
+        let slug = url.path().trim_matches('/');
+        if own && !slug.is_empty() && !slug.contains('/') {
+            return Err(ApiError::DomainRejected {
+                code: "short_link_destination",
+                message: format!(
+                    "'{}' is a short link of this service, shorten its destination instead.",
+                    url
+                ),
+                domain: host,
+            });
+        }
+        Ok(())
+    }
+
+    /// Host of the domain a request came in on, `None` for the primary domain and unknown hosts
+    pub fn request_host(&self, host: &str) -> Option<&str> {
+        let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+        self.others
+            .iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(host))
+            .map(|(other, _)| other.as_str())
+    }
+
+    /// Storage key behind a short URL minted by this service, `None` for other URLs
+    pub fn key_of_short_url(&self, short_url: &str) -> Option<String> {
+        if let Some(slug) = short_url.strip_prefix(&format!("{}/", self.primary)) {
+            return Some(slug.to_string());
+        }
+        self.others.iter().find_map(|(host, base)| {
+            let slug = short_url.strip_prefix(&format!("{}/", base))?;
+            Some(link_key(slug, Some(host)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domains() -> ShortDomains {
+        ShortDomains::new(
+            "https://short.me".to_string(),
+            &["https://go.corp.com".to_string()],
+        )
+    }
+
+    #[test]
+    fn test_lookup_and_keys() {
+        let domains = domains();
+
+        assert_eq!(domains.lookup("short.me").unwrap(), None);
+        assert_eq!(
+            domains.lookup("https://Go.corp.com/").unwrap().as_deref(),
+            Some("go.corp.com")
+        );
+        assert_eq!(
+            domains.lookup("evil.com").unwrap_err().code(),
+            "invalid_domain"
+        );
+
+        let key = link_key("launch", Some("go.corp.com"));
+        assert_eq!(key, "launch@go.corp.com");
+        assert_eq!(split_key(&key), ("launch", Some("go.corp.com")));
+        assert_eq!(domains.short_url(&key), "https://go.corp.com/launch");
+        assert_eq!(domains.short_url("launch"), "https://short.me/launch");
+        assert_eq!(
+            domains.key_of_short_url("https://go.corp.com/launch"),
+            Some(key)
+        );
+        assert_eq!(domains.key_of_short_url("https://other.com/launch"), None);
+    }
+
+    #[test]
+    fn test_request_host() {
+        let domains = domains();
+
+        assert_eq!(domains.request_host("go.corp.com"), Some("go.corp.com"));
+        assert_eq!(
+            domains.request_host("GO.corp.com:8080"),
+            Some("go.corp.com")
+        );
+        assert_eq!(domains.request_host("short.me"), None);
+        assert_eq!(domains.request_host("localhost:8080"), None);
+    }
+
+    #[test]
+    fn test_short_link_destinations() {
+        let domains = domains();
+
+        for url in [
+            "https://short.me/a",
+            "http://SHORT.me/a/",
+            "https://go.corp.com/launch?x=1",
+        ] {
+            assert_eq!(
+                domains.ensure_not_short_link(url).unwrap_err().code(),
+                "short_link_destination"
+            );
+        }
+        for url in [
+            "https://short.me/",
+            "https://short.me/launch/card.png",
+            "https://example.com/a",
+        ] {
+            assert!(domains.ensure_not_short_link(url).is_ok());
+        }
+    }
+}
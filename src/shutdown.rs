@@ -0,0 +1,21 @@
+use std::io;
+use tokio::signal;
+
+/// Resolves with the name of the first SIGINT or SIGTERM received.
+/// The handlers are installed when this is called, so call it before the server starts serving.
+pub fn shutdown_signal() -> io::Result<impl std::future::Future<Output = &'static str>> {
+    #[cfg(unix)]
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+
+    Ok(async move {
+        #[cfg(unix)]
+        let terminated = terminate.recv();
+        #[cfg(not(unix))]
+        let terminated = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = terminated => "SIGTERM",
+            _ = signal::ctrl_c() => "SIGINT",
+        }
+    })
+}
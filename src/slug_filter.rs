@@ -0,0 +1,137 @@
+use std::path::PathBuf;
+
+/// A list of slug filter entries, comma separated or `file:<path>` with one entry per line
+#[derive(Clone, Debug, PartialEq)]
+pub enum SlugFilterList {
+    Inline(Vec<String>),
+    /// `#` starts a comment, blank lines are skipped
+    File(PathBuf),
+}
+
+impl std::str::FromStr for SlugFilterList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().strip_prefix("file:") {
+            Some("") => Err("expected 'file:<path>' or comma separated entries".to_string()),
+            Some(path) => Ok(SlugFilterList::File(path.into())),
+            None => Ok(SlugFilterList::Inline(parse_entries(s.split(',')))),
+        }
+    }
+}
+
+fn parse_entries<'a>(entries: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    entries
+        .into_iter()
+        .map(|entry| entry.split('#').next().unwrap_or_default().trim())
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl SlugFilterList {
+    fn read(&self) -> Result<Vec<String>, String> {
+        match self {
+            SlugFilterList::Inline(entries) => Ok(entries.clone()),
+            SlugFilterList::File(path) => std::fs::read_to_string(path)
+                .map(|text| parse_entries(text.lines()))
+                .map_err(|err| format!("failed to read {}: {}", path.display(), err)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SlugFilterConfig {
+    /// Words generated slugs must not contain, from `SLUG_BLOCKED_WORDS`
+    pub blocked_words: Option<SlugFilterList>,
+    /// Groups of characters readers mix up, e.g. `0O` and `1lI`, from `SLUG_CONFUSABLES`
+    pub confusables: Option<SlugFilterList>,
+}
+
+/// Rejects generated slugs that spell a blocked word or mix characters readers can't tell apart, they are
+/// regenerated like collisions. Aliases are picked by people and aren't filtered.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SlugFilter {
+    /// Lowercased, matched anywhere in the slug regardless of its casing
+    words: Vec<String>,
+    confusables: Vec<Vec<char>>,
+}
+
+impl SlugFilter {
+    /// Reads the configured lists, failing here keeps the service from starting without its filter
+    pub fn load(config: &SlugFilterConfig) -> Result<Self, String> {
+        let read = |list: &Option<SlugFilterList>| {
+            list.as_ref().map_or(Ok(Vec::new()), |list| list.read())
+        };
+        let words = read(&config.blocked_words)?
+            .into_iter()
+            .map(|word| word.to_lowercase())
+            .collect();
+        let confusables = read(&config.confusables)?
+            .into_iter()
+            .map(|group| group.chars().collect::<Vec<_>>())
+            .filter(|group| group.len() > 1)
+            .collect();
+        Ok(SlugFilter { words, confusables })
+    }
+
+    /// Whether `slug` contains a blocked word, or two different characters of one confusable group, e.g.
+    /// both `0` and `O`. A single one of them reads fine once the reader knows there is no look-alike.
+    pub fn rejects(&self, slug: &str) -> bool {
+        let lowercase = slug.to_lowercase();
+        self.words
+            .iter()
+            .any(|word| lowercase.contains(word.as_str()))
+            || self.confusables.iter().any(|group| {
+                group
+                    .iter()
+                    .filter(|confusable| slug.contains(**confusable))
+                    .nth(1)
+                    .is_some()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocked_words_and_confusables() {
+        let filter = SlugFilter::load(&SlugFilterConfig {
+            blocked_words: Some("darn, Heck".parse().unwrap()),
+            confusables: Some("0O,1lI".parse().unwrap()),
+        })
+        .unwrap();
+
+        assert!(filter.rejects("xDaRn7"));
+        assert!(filter.rejects("aheck"));
+        assert!(filter.rejects("a0bOc"));
+        assert!(filter.rejects("1xxxI"));
+        assert!(!filter.rejects("a0b1c"));
+        assert!(!filter.rejects("OOlll"));
+        assert!(!SlugFilter::default().rejects("darn0O"));
+    }
+
+    #[test]
+    fn test_lists_from_files() {
+        let path = std::env::temp_dir().join(format!("slug-words-{}.txt", std::process::id()));
+        std::fs::write(&path, "# offensive\ndarn\n\n  heck # mild\n").unwrap();
+        let list: SlugFilterList = format!("file:{}", path.display()).parse().unwrap();
+        let filter = SlugFilter::load(&SlugFilterConfig {
+            blocked_words: Some(list.clone()),
+            confusables: None,
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(filter.rejects("heckle"));
+        assert!(!filter.rejects("mild"));
+        assert!(SlugFilter::load(&SlugFilterConfig {
+            blocked_words: Some(list),
+            confusables: None,
+        })
+        .is_err());
+        assert!("file:".parse::<SlugFilterList>().is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::web::{self, Data};
+use actix_web::{get, HttpRequest, HttpResponse};
+use async_graphql::SimpleObject;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::clicks;
+use crate::error::ApiError;
+use crate::link::{Link, Variant};
+use crate::ownership::{load_link, Manager};
+use crate::short_domains::split_key;
+use crate::users::MaybeUser;
+use crate::AppState;
+
+pub const MAX_VARIANTS: usize = 10;
+pub const MAX_WEIGHT: u32 = 10_000;
+/// Holds the index of the variant served to the visitor, scoped to the path of the short link
+const STICKY_COOKIE: &str = "variant";
+const STICKY_COOKIE_DAYS: i64 = 30;
+
+/// Counter of the redirects to one variant of a split test link
+pub fn served_key(slug: &str, index: usize) -> String {
+    format!("variant:{}:{}", slug, index)
+}
+
+/// Served counters for a newly stored link as `(key, value, ttl)`, they expire together with the link
+pub fn counter_entries(
+    slug: &str,
+    variants: usize,
+    ttl: Option<usize>,
+) -> Vec<(String, String, Option<usize>)> {
+    (0..variants)
+        .map(|index| (served_key(slug, index), "0".to_string(), ttl))
+        .collect()
+}
+
+/// Checks the number of variants and their weights
+pub fn validate_variants(variants: &[Variant]) -> Result<(), String> {
+    if !(2..=MAX_VARIANTS).contains(&variants.len()) {
+        return Err(format!(
+            "A split test needs between 2 and {} variants",
+            MAX_VARIANTS
+        ));
+    }
+    if variants
+        .iter()
+        .any(|variant| !(1..=MAX_WEIGHT).contains(&variant.weight))
+    {
+        return Err(format!(
+            "Variant weights must be between 1 and {}",
+            MAX_WEIGHT
+        ));
+    }
+    Ok(())
+}
+
+/// Index of the variant `roll` falls on, `roll` is in `0..` the sum of the weights
+fn pick(variants: &[Variant], mut roll: u64) -> usize {
+    for (index, variant) in variants.iter().enumerate() {
+        if roll < variant.weight as u64 {
+            return index;
+        }
+        roll -= variant.weight as u64;
+    }
+    variants.len() - 1
+}
+
+/// Variant to serve for a split test link, along with the cookie remembering it for sticky links.
+/// `None` for links without variants.
+pub fn choose(
+    req: &HttpRequest,
+    slug: &str,
+    link: &Link,
+) -> Option<(usize, Option<Cookie<'static>>)> {
+    if link.variants.is_empty() {
+        return None;
+    }
+    if link.sticky_variants {
+        let remembered = req
+            .cookie(STICKY_COOKIE)
+            .and_then(|cookie| cookie.value().parse::<usize>().ok())
+            .filter(|index| *index < link.variants.len());
+        if let Some(index) = remembered {
+            return Some((index, None));
+        }
+    }
+
+    let total: u64 = link
+        .variants
+        .iter()
+        .map(|variant| variant.weight as u64)
+        .sum();
+    let index = pick(&link.variants, rand::rng().random_range(0..total.max(1)));
+    let cookie = link.sticky_variants.then(|| {
+        Cookie::build(STICKY_COOKIE, index.to_string())
+            .path(format!("/{}", split_key(slug).0))
+            .max_age(time::Duration::days(STICKY_COOKIE_DAYS))
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish()
+    });
+    Some((index, cookie))
+}
+
+/// Counts a redirect to the variant, failures only affect the stats so they are logged
+pub async fn record_served(state: &AppState, slug: &str, index: usize) {
+    log::debug!("Serving variant {} of {}", index, slug);
+    if let Err(err) = state
+        .store
+        .increment_existing(&served_key(slug, index))
+        .await
+    {
+        log::warn!("Failed to count variant {} of {}: {}", index, slug, err);
+    }
+}
+
+#[derive(Serialize, SimpleObject)]
+pub struct VariantStats {
+    url: String,
+    weight: u32,
+    served: u64,
+}
+
+#[derive(Serialize)]
+struct SplitTestStats {
+    slug: String,
+    sticky: bool,
+    /// Redirects of the link as a whole
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clicks: Option<u64>,
+    variants: Vec<VariantStats>,
+}
+
+/// How often each variant of a split test link was served, for its owner and admin API keys
+#[get("/api/links/{slug}/variants")]
+async fn variant_stats(
+    req: HttpRequest,
+    path: web::Path<String>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let manager = Manager::identify(&req, user).await?;
+    let (_, link) = load_link(&state, &slug).await?;
+    manager.ensure_can_view(&link)?;
+    if link.variants.is_empty() {
+        return Err(ApiError::NotFound {
+            message: format!("The link '{}' is not a split test.", slug),
+        });
+    }
+
+    let sticky = link.sticky_variants;
+    let clicks = clicks::total_clicks(state.store.as_ref(), std::slice::from_ref(&slug))
+        .await?
+        .pop()
+        .flatten();
+    let variants = variant_stats_of(&state, &slug, link).await?;
+    Ok(HttpResponse::Ok().json(SplitTestStats {
+        slug,
+        sticky,
+        clicks,
+        variants,
+    }))
+}
+
+/// Served counts of the variants of a split test link, empty for other links
+pub async fn variant_stats_of(
+    state: &AppState,
+    slug: &str,
+    link: Link,
+) -> Result<Vec<VariantStats>, ApiError> {
+    let keys: Vec<String> = (0..link.variants.len())
+        .map(|index| served_key(slug, index))
+        .collect();
+    let served = state.store.get_many(&keys).await?;
+    Ok(link
+        .variants
+        .into_iter()
+        .zip(served)
+        .map(|(variant, served)| VariantStats {
+            url: variant.url,
+            weight: variant.weight,
+            served: served.and_then(|count| count.parse().ok()).unwrap_or(0),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variants(weights: &[u32]) -> Vec<Variant> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(index, weight)| Variant {
+                url: format!("https://example.com/{}", index),
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pick_follows_the_weights() {
+        let variants = variants(&[3, 1]);
+        let picks: Vec<usize> = (0..4).map(|roll| pick(&variants, roll)).collect();
+
+        assert_eq!(picks, vec![0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_validate_variants() {
+        assert!(validate_variants(&variants(&[50, 50])).is_ok());
+        assert!(validate_variants(&variants(&[100])).is_err());
+        assert!(validate_variants(&variants(&[1; MAX_VARIANTS + 1])).is_err());
+        assert!(validate_variants(&variants(&[1, 0])).is_err());
+        assert!(validate_variants(&variants(&[1, MAX_WEIGHT + 1])).is_err());
+    }
+}
@@ -0,0 +1,1064 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::storage::{CountBatch, LinkWrites, StorageError, UrlStore};
+
+/// Rows of a key are live while this holds, `?2` being the current time
+const LIVE: &str = "(expires_at IS NULL OR expires_at > ?2)";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL,
+        expires_at INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS entries_expires_at ON entries (expires_at);
+    CREATE TABLE IF NOT EXISTS sets (
+        key TEXT NOT NULL,
+        member TEXT NOT NULL,
+        PRIMARY KEY (key, member)
+    );
+    CREATE TABLE IF NOT EXISTS hashes (
+        key TEXT NOT NULL,
+        field TEXT NOT NULL,
+        value INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        PRIMARY KEY (key, field)
+    );
+    CREATE TABLE IF NOT EXISTS estimates (
+        key TEXT NOT NULL,
+        member TEXT NOT NULL,
+        expires_at INTEGER NOT NULL,
+        PRIMARY KEY (key, member)
+    );
+    CREATE TABLE IF NOT EXISTS scores (
+        key TEXT NOT NULL,
+        member TEXT NOT NULL,
+        score INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        PRIMARY KEY (key, member)
+    );
+    CREATE TABLE IF NOT EXISTS indexes (
+        key TEXT NOT NULL,
+        member TEXT NOT NULL,
+        score INTEGER NOT NULL,
+        PRIMARY KEY (key, member)
+    );
+    CREATE INDEX IF NOT EXISTS indexes_score ON indexes (key, score, member);
+    CREATE TABLE IF NOT EXISTS lists (
+        key TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        entry TEXT NOT NULL,
+        PRIMARY KEY (key, position)
+    );
+";
+
+/// Tables whose rows expire, swept by the cleanup job
+const EXPIRING_TABLES: [&str; 4] = ["entries", "hashes", "estimates", "scores"];
+
+/// Settings of the SQLite backend
+#[derive(Clone, Debug, PartialEq)]
+pub struct SqliteConfig {
+    /// Database file, created on first start
+    pub path: PathBuf,
+    /// How often expired rows are deleted, they are ignored by every read until then
+    pub cleanup_interval: Duration,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            path: "url-shortener.db".into(),
+            cleanup_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Milliseconds since the epoch, expiries are stored as such
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock is after the epoch")
+        .as_millis() as i64
+}
+
+fn expiry(now: i64, ttl: usize) -> i64 {
+    now + ttl as i64 * 1000
+}
+
+/// Store in a single SQLite file for single-node deployments. Statements run one at a time on a blocking
+/// thread, WAL mode keeps readers of other processes (backups, the CLI) from blocking them.
+#[derive(Clone)]
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens or creates the database at `path` and its tables, `:memory:` opens a private in-memory database
+    pub fn open(path: &std::path::Path) -> Result<Self, StorageError> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteStore {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Deletes expired rows every `every`, until the store is dropped
+    pub fn spawn_cleanup(&self, every: Duration) -> JoinHandle<()> {
+        let connection = Arc::downgrade(&self.connection);
+        tokio::spawn(async move {
+            let mut ticker = interval(every);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match Self::delete_expired(&connection).await {
+                    Some(Ok(deleted)) => log::debug!("Deleted {} expired rows", deleted),
+                    Some(Err(err)) => log::error!("Failed to delete expired rows: {}", err),
+                    None => return,
+                }
+            }
+        })
+    }
+
+    /// `None` once the store is gone
+    async fn delete_expired(
+        connection: &Weak<Mutex<Connection>>,
+    ) -> Option<Result<usize, StorageError>> {
+        let store = SqliteStore {
+            connection: connection.upgrade()?,
+        };
+        Some(
+            store
+                .transaction(|tx, now| {
+                    let mut deleted = 0;
+                    for table in EXPIRING_TABLES {
+                        deleted += tx.execute(
+                            &format!("DELETE FROM {} WHERE expires_at <= ?1", table),
+                            params![now],
+                        )?;
+                    }
+                    Ok(deleted)
+                })
+                .await,
+        )
+    }
+
+    /// Runs `query` with the connection and the current time on a blocking thread
+    async fn run<T, F>(&self, query: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection, i64) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+            query(&mut connection, now_millis())
+        })
+        .await
+        .expect("SQLite statements don't panic")
+        .map_err(StorageError::from)
+    }
+
+    /// Like `run`, in a transaction committed when `query` succeeds
+    async fn transaction<T, F>(&self, query: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Transaction, i64) -> rusqlite::Result<T> + Send + 'static,
+    {
+        self.run(move |connection, now| {
+            let tx = connection.transaction()?;
+            let result = query(&tx, now)?;
+            tx.commit()?;
+            Ok(result)
+        })
+        .await
+    }
+}
+
+fn set_entry(
+    connection: &Connection,
+    now: i64,
+    key: &str,
+    value: &str,
+    ttl: Option<usize>,
+) -> rusqlite::Result<bool> {
+    // Like `SET NX`, an expired entry counts as missing
+    let changed = connection.execute(
+        "INSERT INTO entries (key, value, expires_at) VALUES (?1, ?3, ?4)
+         ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at
+         WHERE entries.expires_at IS NOT NULL AND entries.expires_at <= ?2",
+        params![key, now, value, ttl.map(|ttl| expiry(now, ttl))],
+    )?;
+    Ok(changed == 1)
+}
+
+fn get_entry(connection: &Connection, now: i64, key: &str) -> rusqlite::Result<Option<String>> {
+    connection
+        .query_row(
+            &format!("SELECT value FROM entries WHERE key = ?1 AND {}", LIVE),
+            params![key, now],
+            |row| row.get(0),
+        )
+        .optional()
+}
+
+/// Adds `delta` to an existing counter, `None` when it doesn't exist
+fn add_if_exists(
+    connection: &Connection,
+    now: i64,
+    key: &str,
+    delta: i64,
+) -> rusqlite::Result<Option<i64>> {
+    connection
+        .query_row(
+            &format!(
+                "UPDATE entries SET value = CAST(value AS INTEGER) + ?3 WHERE key = ?1 AND {}
+                 RETURNING CAST(value AS INTEGER)",
+                LIVE
+            ),
+            params![key, now, delta],
+            |row| row.get(0),
+        )
+        .optional()
+}
+
+/// Empties the collection under `key` in `table` when it expired, then gives it a new TTL
+fn renew(tx: &Transaction, table: &str, key: &str, now: i64, ttl: usize) -> rusqlite::Result<()> {
+    tx.execute(
+        &format!("DELETE FROM {} WHERE key = ?1 AND expires_at <= ?2", table),
+        params![key, now],
+    )?;
+    tx.execute(
+        &format!("UPDATE {} SET expires_at = ?2 WHERE key = ?1", table),
+        params![key, expiry(now, ttl)],
+    )?;
+    Ok(())
+}
+
+#[async_trait]
+impl UrlStore for SqliteStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let key = key.to_string();
+        self.run(move |connection, now| get_entry(connection, now, &key))
+            .await
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<usize>) -> Result<bool, StorageError> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.run(move |connection, now| set_entry(connection, now, &key, &value, ttl))
+            .await
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<bool, StorageError> {
+        let (key, expected, value) = (key.to_string(), expected.to_string(), value.to_string());
+        self.run(move |connection, now| {
+            let changed = connection.execute(
+                &format!(
+                    "UPDATE entries SET value = ?4, expires_at = COALESCE(?5, expires_at)
+                     WHERE key = ?1 AND {} AND value = ?3",
+                    LIVE
+                ),
+                params![key, now, expected, value, ttl.map(|ttl| expiry(now, ttl))],
+            )?;
+            Ok(changed == 1)
+        })
+        .await
+    }
+
+    async fn expire(&self, key: &str, ttl: usize) -> Result<bool, StorageError> {
+        let key = key.to_string();
+        self.run(move |connection, now| {
+            let changed = connection.execute(
+                &format!(
+                    "UPDATE entries SET expires_at = ?3 WHERE key = ?1 AND {}",
+                    LIVE
+                ),
+                params![key, now, expiry(now, ttl)],
+            )?;
+            Ok(changed == 1)
+        })
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, StorageError> {
+        let key = key.to_string();
+        self.transaction(move |tx, now| {
+            let existed: bool = tx.query_row(
+                &format!(
+                    "SELECT EXISTS (SELECT 1 FROM entries WHERE key = ?1 AND {live})
+                     OR EXISTS (SELECT 1 FROM sets WHERE key = ?1)
+                     OR EXISTS (SELECT 1 FROM hashes WHERE key = ?1 AND {live})
+                     OR EXISTS (SELECT 1 FROM estimates WHERE key = ?1 AND {live})
+                     OR EXISTS (SELECT 1 FROM scores WHERE key = ?1 AND {live})
+                     OR EXISTS (SELECT 1 FROM indexes WHERE key = ?1)
+                     OR EXISTS (SELECT 1 FROM lists WHERE key = ?1)",
+                    live = LIVE
+                ),
+                params![key, now],
+                |row| row.get(0),
+            )?;
+            for table in [
+                "entries",
+                "sets",
+                "hashes",
+                "estimates",
+                "scores",
+                "indexes",
+                "lists",
+            ] {
+                tx.execute(
+                    &format!("DELETE FROM {} WHERE key = ?1", table),
+                    params![key],
+                )?;
+            }
+            Ok(existed)
+        })
+        .await
+    }
+
+    async fn add_to_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        let (key, members) = (key.to_string(), members.to_vec());
+        self.transaction(move |tx, _| {
+            for member in &members {
+                tx.execute(
+                    "INSERT OR IGNORE INTO sets (key, member) VALUES (?1, ?2)",
+                    params![key, member],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_from_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        let (key, members) = (key.to_string(), members.to_vec());
+        self.transaction(move |tx, _| {
+            for member in &members {
+                tx.execute(
+                    "DELETE FROM sets WHERE key = ?1 AND member = ?2",
+                    params![key, member],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError> {
+        let key = key.to_string();
+        self.run(move |connection, _| {
+            connection
+                .prepare_cached("SELECT member FROM sets WHERE key = ?1")?
+                .query_map(params![key], |row| row.get(0))?
+                .collect()
+        })
+        .await
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+        let keys = keys.to_vec();
+        self.run(move |connection, now| {
+            keys.iter()
+                .map(|key| get_entry(connection, now, key))
+                .collect()
+        })
+        .await
+    }
+
+    async fn ttl_many(&self, keys: &[String]) -> Result<Vec<Option<usize>>, StorageError> {
+        let keys = keys.to_vec();
+        self.run(move |connection, now| {
+            let mut ttls = Vec::with_capacity(keys.len());
+            for key in &keys {
+                let expires_at: Option<Option<i64>> = connection
+                    .query_row(
+                        &format!("SELECT expires_at FROM entries WHERE key = ?1 AND {}", LIVE),
+                        params![key, now],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                // Rounded like Redis `TTL`
+                ttls.push(
+                    expires_at
+                        .flatten()
+                        .map(|expires_at| ((expires_at - now) as f64 / 1000.0).round() as usize),
+                );
+            }
+            Ok(ttls)
+        })
+        .await
+    }
+
+    async fn set_first_with(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+        writes: &[LinkWrites],
+    ) -> Result<Option<usize>, StorageError> {
+        let (keys, value, writes) = (keys.to_vec(), value.to_string(), writes.to_vec());
+        self.transaction(move |tx, now| {
+            for (index, key) in keys.iter().enumerate() {
+                if !set_entry(tx, now, key, &value, ttl)? {
+                    continue;
+                }
+                for (key, value, ttl) in &writes[index].entries {
+                    set_entry(tx, now, key, value, *ttl)?;
+                }
+                for (key, member) in &writes[index].set_members {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO sets (key, member) VALUES (?1, ?2)",
+                        params![key, member],
+                    )?;
+                }
+                for (key, member, score) in &writes[index].scored_members {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO indexes (key, member, score) VALUES (?1, ?2, ?3)",
+                        params![key, member, score],
+                    )?;
+                }
+                return Ok(Some(index));
+            }
+            Ok(None)
+        })
+        .await
+    }
+
+    async fn set_many(
+        &self,
+        entries: &[(String, String, Option<usize>)],
+    ) -> Result<Vec<bool>, StorageError> {
+        let entries = entries.to_vec();
+        self.transaction(move |tx, now| {
+            entries
+                .iter()
+                .map(|(key, value, ttl)| set_entry(tx, now, key, value, *ttl))
+                .collect()
+        })
+        .await
+    }
+
+    async fn incr_window(
+        &self,
+        key: &str,
+        window_seconds: usize,
+    ) -> Result<(u64, usize), StorageError> {
+        let key = key.to_string();
+        self.transaction(move |tx, now| {
+            tx.execute(
+                "DELETE FROM entries WHERE key = ?1 AND expires_at <= ?2",
+                params![key, now],
+            )?;
+            let (count, expires_at): (i64, Option<i64>) = tx.query_row(
+                "INSERT INTO entries (key, value, expires_at) VALUES (?1, 1, ?2)
+                 ON CONFLICT (key) DO UPDATE SET value = CAST(value AS INTEGER) + 1
+                 RETURNING CAST(value AS INTEGER), expires_at",
+                params![key, expiry(now, window_seconds)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            let remaining = expires_at.map_or(window_seconds as f64, |expires_at| {
+                (expires_at - now).max(0) as f64 / 1000.0
+            });
+            Ok((count as u64, remaining.ceil() as usize))
+        })
+        .await
+    }
+
+    async fn scan_keys(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<String>), StorageError> {
+        // Ordered by key so the cursor is a stable position, like `MemoryStore`
+        let count = count.max(1);
+        self.run(move |connection, now| {
+            let mut keys: Vec<String> = connection
+                .prepare_cached(&format!(
+                    "SELECT key FROM entries WHERE {} ORDER BY key LIMIT ?3 OFFSET ?1",
+                    LIVE
+                ))?
+                .query_map(params![cursor as i64, now, count as i64 + 1], |row| {
+                    row.get(0)
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            if keys.len() <= count {
+                return Ok((0, keys));
+            }
+            keys.truncate(count);
+            Ok((cursor + count as u64, keys))
+        })
+        .await
+    }
+
+    async fn increment(&self, key: &str, amount: u64) -> Result<u64, StorageError> {
+        let key = key.to_string();
+        self.transaction(move |tx, now| {
+            tx.execute(
+                "DELETE FROM entries WHERE key = ?1 AND expires_at <= ?2",
+                params![key, now],
+            )?;
+            let value: i64 = tx.query_row(
+                "INSERT INTO entries (key, value, expires_at) VALUES (?1, ?2, NULL)
+                 ON CONFLICT (key) DO UPDATE SET value = CAST(value AS INTEGER) + ?2
+                 RETURNING CAST(value AS INTEGER)",
+                params![key, amount as i64],
+                |row| row.get(0),
+            )?;
+            Ok(value as u64)
+        })
+        .await
+    }
+
+    async fn increment_existing(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        let key = key.to_string();
+        self.run(move |connection, now| add_if_exists(connection, now, &key, 1))
+            .await
+    }
+
+    async fn decrement(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        let key = key.to_string();
+        self.run(move |connection, now| add_if_exists(connection, now, &key, -1))
+            .await
+    }
+
+    async fn hash_fields(&self, key: &str) -> Result<HashMap<String, String>, StorageError> {
+        let key = key.to_string();
+        self.run(move |connection, now| {
+            connection
+                .prepare_cached(&format!(
+                    "SELECT field, value FROM hashes WHERE key = ?1 AND {}",
+                    LIVE
+                ))?
+                .query_map(params![key, now], |row| {
+                    Ok((row.get(0)?, row.get::<_, i64>(1)?.to_string()))
+                })?
+                .collect()
+        })
+        .await
+    }
+
+    async fn count_estimate(&self, keys: &[String]) -> Result<u64, StorageError> {
+        let keys = keys.to_vec();
+        self.run(move |connection, now| {
+            let mut statement = connection.prepare_cached(&format!(
+                "SELECT member FROM estimates WHERE key = ?1 AND {}",
+                LIVE
+            ))?;
+            let mut members = HashSet::new();
+            for key in &keys {
+                for member in
+                    statement.query_map(params![key, now], |row| row.get::<_, String>(0))?
+                {
+                    members.insert(member?);
+                }
+            }
+            Ok(members.len() as u64)
+        })
+        .await
+    }
+
+    async fn write_counts(&self, counts: &CountBatch) -> Result<(), StorageError> {
+        let existing: Vec<(String, i64)> = counts
+            .existing
+            .iter()
+            .map(|(key, by)| (key.clone(), *by))
+            .collect();
+        let fields = counts.fields.clone();
+        let estimates = counts.estimates.clone();
+        let scores = counts.scores.clone();
+        let index_scores = counts.index_scores.clone();
+        self.transaction(move |tx, now| {
+            for (key, by) in &existing {
+                add_if_exists(tx, now, key, *by)?;
+            }
+            for (key, (fields, ttl)) in &fields {
+                renew(tx, "hashes", key, now, *ttl)?;
+                for (field, by) in fields {
+                    tx.execute(
+                        "INSERT INTO hashes (key, field, value, expires_at) VALUES (?1, ?2, ?3, ?4)
+                         ON CONFLICT (key, field) DO UPDATE SET value = value + excluded.value",
+                        params![key, field, by, expiry(now, *ttl)],
+                    )?;
+                }
+            }
+            for (key, (members, ttl)) in &estimates {
+                renew(tx, "estimates", key, now, *ttl)?;
+                for member in members {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO estimates (key, member, expires_at) VALUES (?1, ?2, ?3)",
+                        params![key, member, expiry(now, *ttl)],
+                    )?;
+                }
+            }
+            for (key, (scores, ttl)) in &scores {
+                renew(tx, "scores", key, now, *ttl)?;
+                for (member, by) in scores {
+                    tx.execute(
+                        "INSERT INTO scores (key, member, score, expires_at) VALUES (?1, ?2, ?3, ?4)
+                         ON CONFLICT (key, member) DO UPDATE SET score = score + excluded.score",
+                        params![key, member, by, expiry(now, *ttl)],
+                    )?;
+                }
+            }
+            for (key, scores) in &index_scores {
+                for (member, by) in scores {
+                    tx.execute(
+                        "UPDATE indexes SET score = score + ?3 WHERE key = ?1 AND member = ?2",
+                        params![key, member, by],
+                    )?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn add_scored(&self, key: &str, members: &[(String, i64)]) -> Result<(), StorageError> {
+        let (key, members) = (key.to_string(), members.to_vec());
+        self.transaction(move |tx, _| {
+            for (member, score) in &members {
+                tx.execute(
+                    "INSERT OR IGNORE INTO indexes (key, member, score) VALUES (?1, ?2, ?3)",
+                    params![key, member, score],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_scored(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        let (key, members) = (key.to_string(), members.to_vec());
+        self.transaction(move |tx, _| {
+            for member in &members {
+                tx.execute(
+                    "DELETE FROM indexes WHERE key = ?1 AND member = ?2",
+                    params![key, member],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn scores(
+        &self,
+        key: &str,
+        members: &[String],
+    ) -> Result<Vec<Option<i64>>, StorageError> {
+        let (key, members) = (key.to_string(), members.to_vec());
+        self.run(move |connection, _| {
+            let mut statement = connection
+                .prepare_cached("SELECT score FROM indexes WHERE key = ?1 AND member = ?2")?;
+            members
+                .iter()
+                .map(|member| {
+                    statement
+                        .query_row(params![key, member], |row| row.get(0))
+                        .optional()
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn scored_page(
+        &self,
+        key: &str,
+        after: Option<(i64, &str)>,
+        descending: bool,
+        limit: usize,
+    ) -> Result<Vec<(String, i64)>, StorageError> {
+        let key = key.to_string();
+        let after = after.map(|(score, member)| (score, member.to_string()));
+        let (direction, before) = match descending {
+            true => ("DESC", "<"),
+            false => ("ASC", ">"),
+        };
+        self.run(move |connection, _| {
+            let limit = limit.min(i64::MAX as usize) as i64;
+            let order = format!("ORDER BY score {0}, member {0} LIMIT ?2", direction);
+            let mut statement = match &after {
+                None => connection.prepare_cached(&format!(
+                    "SELECT member, score FROM indexes WHERE key = ?1 {}",
+                    order
+                ))?,
+                Some(_) => connection.prepare_cached(&format!(
+                    "SELECT member, score FROM indexes WHERE key = ?1 AND (score, member) {} (?3, ?4) {}",
+                    before, order
+                ))?,
+            };
+            let read = |row: &rusqlite::Row| Ok((row.get(0)?, row.get(1)?));
+            match &after {
+                None => statement.query_map(params![key, limit], read)?.collect(),
+                Some((score, member)) => statement
+                    .query_map(params![key, limit, score, member], read)?
+                    .collect(),
+            }
+        })
+        .await
+    }
+
+    async fn top_scores(
+        &self,
+        key: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, u64)>, StorageError> {
+        let key = key.to_string();
+        self.run(move |connection, now| {
+            // Highest first, ties in reverse member order like ZREVRANGE
+            connection
+                .prepare_cached(&format!(
+                    "SELECT member, score FROM scores WHERE key = ?1 AND {}
+                     ORDER BY score DESC, member DESC LIMIT ?3",
+                    LIVE
+                ))?
+                .query_map(params![key, now, limit as i64], |row| {
+                    Ok((row.get(0)?, row.get::<_, i64>(1)?.max(0) as u64))
+                })?
+                .collect()
+        })
+        .await
+    }
+
+    async fn push_capped(
+        &self,
+        key: &str,
+        entry: &str,
+        max_len: usize,
+    ) -> Result<(), StorageError> {
+        let (key, entry) = (key.to_string(), entry.to_string());
+        self.transaction(move |tx, _| {
+            let position: i64 = tx.query_row(
+                "INSERT INTO lists (key, position, entry)
+                 SELECT ?1, COALESCE(MAX(position), 0) + 1, ?2 FROM lists WHERE key = ?1
+                 RETURNING position",
+                params![key, entry],
+                |row| row.get(0),
+            )?;
+            tx.execute(
+                "DELETE FROM lists WHERE key = ?1 AND position <= ?2",
+                params![key, position - max_len.max(1) as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_range(
+        &self,
+        key: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, StorageError> {
+        let key = key.to_string();
+        self.run(move |connection, _| {
+            connection
+                .prepare_cached(
+                    "SELECT entry FROM lists WHERE key = ?1 ORDER BY position DESC LIMIT ?2 OFFSET ?3",
+                )?
+                .query_map(params![key, count as i64, offset as i64], |row| row.get(0))?
+                .collect()
+        })
+        .await
+    }
+
+    #[cfg(test)]
+    async fn cleanup(&self) -> Result<(), StorageError> {
+        self.transaction(|tx, _| {
+            for table in [
+                "entries",
+                "sets",
+                "hashes",
+                "estimates",
+                "scores",
+                "indexes",
+                "lists",
+            ] {
+                tx.execute(&format!("DELETE FROM {}", table), [])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> SqliteStore {
+        SqliteStore::open(std::path::Path::new(":memory:")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_set_first_with_stores_the_records_of_the_free_key() {
+        let store = store();
+
+        store.set("taken", "old", None).await.unwrap();
+        let keys = vec!["taken".to_string(), "free".to_string()];
+        let writes: Vec<LinkWrites> = keys
+            .iter()
+            .map(|key| LinkWrites {
+                entries: vec![(format!("clicks:{}", key), "0".to_string(), Some(60))],
+                set_members: vec![("links:alice".to_string(), key.clone())],
+                scored_members: vec![("by_created:alice".to_string(), key.clone(), 7)],
+            })
+            .collect();
+        assert_eq!(
+            store
+                .set_first_with(&keys, "new", Some(60), &writes)
+                .await
+                .unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            store.get("clicks:free").await.unwrap().as_deref(),
+            Some("0")
+        );
+        assert_eq!(store.get("clicks:taken").await.unwrap(), None);
+        assert_eq!(
+            store.set_members("links:alice").await.unwrap(),
+            vec!["free".to_string()]
+        );
+        assert_eq!(
+            store.scores("by_created:alice", &keys).await.unwrap(),
+            vec![None, Some(7)]
+        );
+        assert_eq!(
+            store
+                .set_first_with(&keys, "new", None, &writes)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_index_pages() {
+        let store = store();
+
+        store
+            .add_scored(
+                "index",
+                &[
+                    ("a".to_string(), 1),
+                    ("b".to_string(), 2),
+                    ("c".to_string(), 2),
+                    ("d".to_string(), 3),
+                ],
+            )
+            .await
+            .unwrap();
+        // Members already in the index keep their score
+        store
+            .add_scored("index", &[("a".to_string(), 9)])
+            .await
+            .unwrap();
+        let page = |after, descending| store.scored_page("index", after, descending, 2);
+        let members = |page: Vec<(String, i64)>| -> Vec<String> {
+            page.into_iter().map(|(member, _)| member).collect()
+        };
+        assert_eq!(members(page(None, true).await.unwrap()), ["d", "c"]);
+        assert_eq!(
+            members(page(Some((2, "c")), true).await.unwrap()),
+            ["b", "a"]
+        );
+        assert_eq!(
+            members(page(Some((2, "b")), false).await.unwrap()),
+            ["c", "d"]
+        );
+        assert_eq!(
+            store.scored_page("index", None, false, 1).await.unwrap(),
+            vec![("a".to_string(), 1)]
+        );
+
+        let mut counts = CountBatch::default();
+        counts.increment_index_score("index", "a");
+        counts.increment_index_score("index", "gone");
+        store.write_counts(&counts).await.unwrap();
+        store
+            .remove_scored("index", &["d".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .scores(
+                    "index",
+                    &["a".to_string(), "d".to_string(), "gone".to_string()]
+                )
+                .await
+                .unwrap(),
+            vec![Some(2), None, None]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_set_nx_and_ttl() {
+        let store = store();
+
+        assert!(store.set("key", "first", Some(1)).await.unwrap());
+        assert!(!store.set("key", "second", None).await.unwrap());
+        assert_eq!(store.get("key").await.unwrap(), Some("first".to_string()));
+        store.set("forever", "value", None).await.unwrap();
+        assert_eq!(
+            store
+                .ttl_many(&["key", "forever", "missing"].map(String::from))
+                .await
+                .unwrap(),
+            vec![Some(1), None, None]
+        );
+        let keys = vec!["key".to_string(), "other".to_string()];
+        assert_eq!(
+            store.set_first(&keys, "third", None).await.unwrap(),
+            Some(1)
+        );
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        assert_eq!(store.get("key").await.unwrap(), None);
+        assert_eq!(
+            store.scan_keys(0, 10).await.unwrap(),
+            (0, vec!["forever".to_string(), "other".to_string()])
+        );
+        assert_eq!(
+            store.scan_keys(0, 1).await.unwrap(),
+            (1, vec!["forever".to_string()])
+        );
+        assert!(store.set("key", "new value", Some(60)).await.unwrap());
+        assert_eq!(
+            store.get_many(&keys).await.unwrap(),
+            vec![Some("new value".to_string()), Some("third".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_compare_and_set_and_sets() {
+        let store = store();
+
+        assert!(store.set("key", "first", Some(60)).await.unwrap());
+        assert!(!store
+            .compare_and_set("key", "stale", "second", None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_set("key", "first", "second", None)
+            .await
+            .unwrap());
+        assert_eq!(
+            store.ttl_many(&["key".to_string()]).await.unwrap(),
+            vec![Some(60)]
+        );
+        assert!(store.expire("key", 120).await.unwrap());
+        assert!(!store.expire("missing", 60).await.unwrap());
+
+        let members = ["a".to_string(), "b".to_string()];
+        store.add_to_set("set", &members).await.unwrap();
+        store.remove_from_set("set", &members[..1]).await.unwrap();
+        assert_eq!(
+            store.set_members("set").await.unwrap(),
+            vec!["b".to_string()]
+        );
+        assert!(store.delete("set").await.unwrap());
+        assert!(store.delete("key").await.unwrap());
+        assert!(!store.delete("key").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_counters_and_collections() {
+        let store = store();
+
+        assert_eq!(store.increment("total", 2).await.unwrap(), 2);
+        assert_eq!(store.increment("total", 3).await.unwrap(), 5);
+        assert_eq!(store.increment_existing("clicks").await.unwrap(), None);
+        store.set("clicks", "0", Some(60)).await.unwrap();
+        assert_eq!(store.increment_existing("clicks").await.unwrap(), Some(1));
+        assert_eq!(store.decrement("clicks").await.unwrap(), Some(0));
+        assert_eq!(store.incr_window("window", 60).await.unwrap(), (1, 60));
+        assert_eq!(store.incr_window("window", 60).await.unwrap().0, 2);
+
+        let mut counts = CountBatch::default();
+        counts.increment_existing("clicks");
+        counts.increment_field("days", "2026-10-16", 60);
+        counts.increment_field("days", "2026-10-16", 60);
+        counts.add_to_estimate("visitors:a", "1.2.3.4", 60);
+        counts.add_to_estimate("visitors:b", "1.2.3.4", 60);
+        counts.add_to_estimate("visitors:b", "5.6.7.8", 60);
+        counts.increment_score("referrers", "news.example", 60);
+        counts.increment_score("referrers", "blog.example", 60);
+        counts.increment_score("referrers", "blog.example", 60);
+        store.write_counts(&counts).await.unwrap();
+        store.write_counts(&counts).await.unwrap();
+
+        assert_eq!(store.get("clicks").await.unwrap(), Some("2".to_string()));
+        assert_eq!(
+            store.hash_fields("days").await.unwrap(),
+            HashMap::from([("2026-10-16".to_string(), "4".to_string())])
+        );
+        assert_eq!(
+            store
+                .count_estimate(&["visitors:a", "visitors:b"].map(String::from))
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            store.top_scores("referrers", 1).await.unwrap(),
+            vec![("blog.example".to_string(), 4)]
+        );
+
+        for entry in ["first", "second", "third"] {
+            store.push_capped("log", entry, 2).await.unwrap();
+        }
+        assert_eq!(
+            store.list_range("log", 0, 10).await.unwrap(),
+            vec!["third".to_string(), "second".to_string()]
+        );
+        assert_eq!(
+            store.list_range("log", 1, 10).await.unwrap(),
+            vec!["second".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_persists_and_deletes_expired_rows() {
+        let path = std::env::temp_dir().join(format!("url-shortener-{}.db", std::process::id()));
+        let store = SqliteStore::open(&path).unwrap();
+        store.set("kept", "value", None).await.unwrap();
+        store.set("expiring", "value", Some(1)).await.unwrap();
+        drop(store);
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.get("kept").await.unwrap(), Some("value".to_string()));
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let deleted = SqliteStore::delete_expired(&Arc::downgrade(&store.connection))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(deleted, 1);
+        assert_eq!(
+            store.scan_keys(0, 10).await.unwrap(),
+            (0, vec!["kept".to_string()])
+        );
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
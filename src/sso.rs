@@ -0,0 +1,189 @@
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+/// How long fetching the key set may take
+const JWKS_TIMEOUT: Duration = Duration::from_secs(10);
+/// Tokens signed with an unknown key refetch the key set at most this often
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Identity provider whose tokens are accepted next to the session tokens, from `JWT_ISSUER` and `JWT_JWKS_URL`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SsoConfig {
+    /// Expected `iss` claim
+    pub issuer: String,
+    /// Where the provider publishes its signing keys as a JSON Web Key Set
+    pub jwks_url: String,
+    /// Expected `aud` claim, `None` accepts tokens for any audience
+    pub audience: Option<String>,
+}
+
+/// Owner id of the links created with a token for the provider's user `subject`, kept apart from
+/// the ids of local accounts
+pub fn sso_user_id(subject: &str) -> String {
+    format!("sso:{}", subject)
+}
+
+#[derive(Deserialize)]
+struct SsoClaims {
+    sub: String,
+}
+
+struct KeyCache {
+    keys: JwkSet,
+    fetched_at: Option<Instant>,
+}
+
+/// Verifies RS256 and ES256 tokens of the provider, its key set is fetched on first use and again
+/// whenever a token names a key that isn't known yet
+pub struct SsoTokens {
+    config: SsoConfig,
+    http: reqwest::Client,
+    cache: RwLock<KeyCache>,
+}
+
+impl SsoTokens {
+    pub fn new(config: SsoConfig) -> Self {
+        SsoTokens {
+            config,
+            http: reqwest::Client::builder()
+                .timeout(JWKS_TIMEOUT)
+                .build()
+                .expect("HTTP client settings are valid"),
+            cache: RwLock::new(KeyCache {
+                keys: JwkSet { keys: Vec::new() },
+                fetched_at: None,
+            }),
+        }
+    }
+
+    /// Whether the token was signed with an algorithm of the provider rather than as a session token
+    pub fn is_sso_token(token: &str) -> bool {
+        jsonwebtoken::decode_header(token)
+            .is_ok_and(|header| matches!(header.alg, Algorithm::RS256 | Algorithm::ES256))
+    }
+
+    /// Returns the subject of a valid, unexpired token issued by the provider
+    pub async fn verify(&self, token: &str) -> Option<String> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        if !matches!(header.alg, Algorithm::RS256 | Algorithm::ES256) {
+            return None;
+        }
+        let kid = header.kid.unwrap_or_default();
+        let key = match self.decoding_key(&kid).await {
+            Some(key) => key,
+            None => {
+                self.refresh().await;
+                self.decoding_key(&kid).await?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        jsonwebtoken::decode::<SsoClaims>(token, &key, &validation)
+            .map_err(|err| log::debug!("Rejected SSO token: {}", err))
+            .ok()
+            .map(|data| data.claims.sub)
+    }
+
+    /// Key `kid` of the cached set, tokens without a `kid` can only use a set holding a single key
+    async fn decoding_key(&self, kid: &str) -> Option<DecodingKey> {
+        let cache = self.cache.read().await;
+        let jwk = match kid {
+            "" if cache.keys.keys.len() == 1 => cache.keys.keys.first(),
+            "" => None,
+            kid => cache.keys.find(kid),
+        }?;
+        DecodingKey::from_jwk(jwk).ok()
+    }
+
+    /// Refetches the key set, unless it was fetched within `MIN_REFRESH_INTERVAL`
+    async fn refresh(&self) {
+        let mut cache = self.cache.write().await;
+        if cache
+            .fetched_at
+            .is_some_and(|fetched_at| fetched_at.elapsed() < MIN_REFRESH_INTERVAL)
+        {
+            return;
+        }
+        // Also recorded on failure, so an unreachable provider isn't asked on every request
+        cache.fetched_at = Some(Instant::now());
+        match self.fetch().await {
+            Ok(keys) => cache.keys = keys,
+            Err(err) => log::warn!(
+                "Failed to fetch the SSO key set from {}: {}",
+                self.config.jwks_url,
+                err
+            ),
+        }
+    }
+
+    async fn fetch(&self) -> Result<JwkSet, reqwest::Error> {
+        self.http
+            .get(&self.config.jwks_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    fn tokens(audience: Option<&str>) -> SsoTokens {
+        let tokens = SsoTokens::new(SsoConfig {
+            issuer: "https://login.corp.com".to_string(),
+            // Never fetched, the key set is already loaded
+            jwks_url: "http://127.0.0.1:9/jwks.json".to_string(),
+            audience: audience.map(String::from),
+        });
+        tokens.cache.try_write().unwrap().keys =
+            serde_json::from_str(include_str!("../tests/fixtures/sso_jwks.json")).unwrap();
+        tokens
+    }
+
+    fn sign(claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test-key".to_string());
+        let key =
+            EncodingKey::from_rsa_pem(include_bytes!("../tests/fixtures/sso_key.pem")).unwrap();
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify_checks_signature_issuer_and_audience() {
+        let exp = Utc::now().timestamp() + 60;
+        let token = sign(
+            json!({ "sub": "alice", "iss": "https://login.corp.com", "aud": "shortener", "exp": exp }),
+        );
+        assert!(SsoTokens::is_sso_token(&token));
+        assert_eq!(tokens(None).verify(&token).await.as_deref(), Some("alice"));
+        assert_eq!(
+            tokens(Some("shortener")).verify(&token).await.as_deref(),
+            Some("alice")
+        );
+        assert_eq!(tokens(Some("other")).verify(&token).await, None);
+
+        let foreign = sign(json!({ "sub": "alice", "iss": "https://evil.com", "exp": exp }));
+        assert_eq!(tokens(None).verify(&foreign).await, None);
+        let expired =
+            sign(json!({ "sub": "alice", "iss": "https://login.corp.com", "exp": exp - 3600 }));
+        assert_eq!(tokens(None).verify(&expired).await, None);
+
+        let mut tampered = token.clone();
+        tampered.insert(tampered.rfind('.').unwrap() + 1, 'x');
+        assert_eq!(tokens(None).verify(&tampered).await, None);
+    }
+}
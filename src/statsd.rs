@@ -0,0 +1,229 @@
+use std::io;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration};
+
+use crate::metrics::{Counter, Metrics};
+
+#[derive(Clone, Debug)]
+pub struct StatsdConfig {
+    pub host: String,
+    pub port: u16,
+    pub prefix: String,
+    /// DogStatsD tags (`key:value`), omitted from the payload when empty so plain StatsD keeps working
+    pub tags: Vec<String>,
+    pub flush_interval: Duration,
+}
+
+impl StatsdConfig {
+    /// Reads the exporter settings from the environment, the exporter is disabled unless `STATSD_HOST` is set
+    pub fn from_env() -> Option<Self> {
+        let host = std::env::var("STATSD_HOST").ok()?;
+        let port = std::env::var("STATSD_PORT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(8125);
+        let prefix = std::env::var("STATSD_PREFIX").unwrap_or_else(|_| "url_shortener".to_string());
+        let tags = std::env::var("STATSD_TAGS")
+            .map(|v| parse_tags(&v))
+            .unwrap_or_default();
+        let flush_interval_ms: u64 = std::env::var("STATSD_FLUSH_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10_000);
+
+        Some(StatsdConfig {
+            host,
+            port,
+            prefix,
+            tags,
+            flush_interval: Duration::from_millis(flush_interval_ms),
+        })
+    }
+}
+
+fn parse_tags(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Formats a single counter line, e.g. `url_shortener.resolve_hits:3|c|#env:prod`
+fn format_counter(prefix: &str, name: &str, value: u64, tags: &[String]) -> String {
+    let mut line = if prefix.is_empty() {
+        format!("{}:{}|c", name, value)
+    } else {
+        format!("{}.{}:{}|c", prefix, name, value)
+    };
+    if !tags.is_empty() {
+        line.push_str("|#");
+        line.push_str(&tags.join(","));
+    }
+    line
+}
+
+/// Pushes counter deltas over UDP, StatsD counters are additive so we only send what changed since the last flush
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    config: StatsdConfig,
+    last_sent: [u64; Counter::ALL.len()],
+}
+
+impl StatsdExporter {
+    pub async fn new(config: StatsdConfig) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect((config.host.as_str(), config.port)).await?;
+        Ok(StatsdExporter {
+            socket,
+            config,
+            last_sent: [0; Counter::ALL.len()],
+        })
+    }
+
+    pub async fn flush(&mut self, metrics: &Metrics) -> io::Result<()> {
+        let mut lines = Vec::new();
+        let mut current = [0; Counter::ALL.len()];
+        for (i, counter) in Counter::ALL.into_iter().enumerate() {
+            current[i] = metrics.get(counter);
+            let delta = current[i] - self.last_sent[i];
+            if delta > 0 {
+                lines.push(format_counter(
+                    &self.config.prefix,
+                    counter.name(),
+                    delta,
+                    &self.config.tags,
+                ));
+            }
+        }
+
+        if lines.is_empty() {
+            return Ok(());
+        }
+        // Both StatsD and DogStatsD accept several newline separated metrics in one datagram
+        self.socket.send(lines.join("\n").as_bytes()).await?;
+        // Deltas that failed to send go out with the next flush
+        self.last_sent = current;
+        Ok(())
+    }
+}
+
+/// Running push loop, see `spawn_statsd_exporter`
+pub struct StatsdHandle {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl StatsdHandle {
+    /// Stops the push loop after a final flush, so counters from the last interval aren't lost
+    pub async fn shutdown(self) {
+        let _ = self.stop.send(());
+        if let Err(err) = self.task.await {
+            log::warn!("StatsD exporter stopped abnormally: {}", err);
+        }
+    }
+}
+
+/// Starts the background push loop, failures are logged and retried on the next tick
+pub async fn spawn_statsd_exporter(
+    config: StatsdConfig,
+    metrics: Arc<Metrics>,
+) -> io::Result<StatsdHandle> {
+    let flush_interval = config.flush_interval;
+    log::info!(
+        "Pushing metrics to StatsD at {}:{} every {:?}",
+        config.host,
+        config.port,
+        flush_interval
+    );
+    let mut exporter = StatsdExporter::new(config).await?;
+
+    let (stop, mut stopped) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let mut ticker = interval(flush_interval);
+        loop {
+            let stopping = tokio::select! {
+                _ = ticker.tick() => false,
+                _ = &mut stopped => true,
+            };
+            if let Err(err) = exporter.flush(&metrics).await {
+                log::warn!("Failed to push metrics to StatsD: {}", err);
+            }
+            if stopping {
+                break;
+            }
+        }
+    });
+    Ok(StatsdHandle { stop, task })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_counter_without_tags() {
+        let line = format_counter("url_shortener", "resolve_hits", 3, &[]);
+        assert_eq!(line, "url_shortener.resolve_hits:3|c");
+    }
+
+    #[test]
+    fn test_format_counter_with_tags() {
+        let tags = parse_tags("env:prod, region:eu ,");
+        let line = format_counter("us", "resolve_hits", 1, &tags);
+        assert_eq!(line, "us.resolve_hits:1|c|#env:prod,region:eu");
+    }
+
+    #[tokio::test]
+    async fn test_exporter_sends_only_deltas() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = StatsdConfig {
+            host: "127.0.0.1".to_string(),
+            port: receiver.local_addr().unwrap().port(),
+            prefix: "us".to_string(),
+            tags: vec![],
+            flush_interval: Duration::from_secs(10),
+        };
+        let metrics = Metrics::default();
+        let mut exporter = StatsdExporter::new(config).await.unwrap();
+        let mut buf = [0u8; 1024];
+
+        metrics.incr(Counter::ResolveHits);
+        metrics.incr(Counter::ResolveHits);
+        exporter.flush(&metrics).await.unwrap();
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"us.resolve_hits:2|c");
+
+        metrics.incr(Counter::ResolveHits);
+        metrics.incr(Counter::ResolveMisses);
+        exporter.flush(&metrics).await.unwrap();
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"us.resolve_hits:1|c\nus.resolve_misses:1|c");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes_pending_counters() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = StatsdConfig {
+            host: "127.0.0.1".to_string(),
+            port: receiver.local_addr().unwrap().port(),
+            prefix: "us".to_string(),
+            tags: vec![],
+            flush_interval: Duration::from_secs(3600),
+        };
+        let metrics = Arc::new(Metrics::default());
+        let handle = spawn_statsd_exporter(config, metrics.clone())
+            .await
+            .unwrap();
+
+        metrics.incr(Counter::ShortenRequests);
+        handle.shutdown().await;
+
+        let mut buf = [0u8; 1024];
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"us.shorten_requests:1|c");
+    }
+}
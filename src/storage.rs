@@ -0,0 +1,368 @@
+use async_trait::async_trait;
+use redis::RedisError;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use crate::breaker::CircuitBreaker;
+use crate::config::AppConfig;
+use crate::memory::MemoryStore;
+use crate::redis::get_redis_service;
+use crate::sqlite::SqliteStore;
+use crate::tiered::TieredStore;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Redis(RedisError),
+    Sqlite(rusqlite::Error),
+    /// The circuit breaker is open after repeated failures, the backend isn't tried before the cooldown is over
+    Unavailable {
+        retry_after_seconds: usize,
+    },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Redis(err) => write!(f, "{}", err),
+            StorageError::Sqlite(err) => write!(f, "{}", err),
+            StorageError::Unavailable {
+                retry_after_seconds,
+            } => write!(
+                f,
+                "circuit breaker open, retrying the backend in {} seconds",
+                retry_after_seconds
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<RedisError> for StorageError {
+    fn from(err: RedisError) -> Self {
+        StorageError::Redis(err)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        StorageError::Sqlite(err)
+    }
+}
+
+/// Records stored along with a new link by `UrlStore::set_first_with`, written for the key the link ends up under
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkWrites {
+    /// `(key, value, ttl)` entries with the semantics of `set`, e.g. counters and the dedup entry
+    pub entries: Vec<(String, String, Option<usize>)>,
+    /// `(key, member)` pairs added to sets, e.g. the owner's links and tag listings
+    pub set_members: Vec<(String, String)>,
+    /// `(key, member, score)` entries added to indexes, see `UrlStore::add_scored`
+    pub scored_members: Vec<(String, String, i64)>,
+}
+
+/// Counter updates collected from many clicks, written together by `UrlStore::write_counts`.
+/// Updates of the same counter are added up, collections get the TTL of their last update.
+#[derive(Debug, Default, PartialEq)]
+pub struct CountBatch {
+    /// Counters that are only incremented while they exist
+    pub existing: HashMap<String, i64>,
+    /// Hash fields to increment, per hash
+    pub fields: HashMap<String, (HashMap<String, i64>, usize)>,
+    /// Members to add to HyperLogLogs
+    pub estimates: HashMap<String, (HashSet<String>, usize)>,
+    /// Sorted set scores to increment, per sorted set
+    pub scores: HashMap<String, (HashMap<String, i64>, usize)>,
+    /// Index scores to increment, per index, only for members the index still holds
+    pub index_scores: HashMap<String, HashMap<String, i64>>,
+}
+
+impl CountBatch {
+    pub fn is_empty(&self) -> bool {
+        self.existing.is_empty()
+            && self.fields.is_empty()
+            && self.estimates.is_empty()
+            && self.scores.is_empty()
+            && self.index_scores.is_empty()
+    }
+
+    /// Increments an existing counter, see `UrlStore::increment_existing`
+    pub fn increment_existing(&mut self, key: &str) {
+        *self.existing.entry(key.to_string()).or_insert(0) += 1;
+    }
+
+    /// Increments `field` of the hash under `key`, creating the hash if needed
+    pub fn increment_field(&mut self, key: &str, field: &str, ttl: usize) {
+        let (fields, hash_ttl) = self.fields.entry(key.to_string()).or_default();
+        *fields.entry(field.to_string()).or_insert(0) += 1;
+        *hash_ttl = ttl;
+    }
+
+    /// Adds `member` to the HyperLogLog under `key`, creating it if needed
+    pub fn add_to_estimate(&mut self, key: &str, member: &str, ttl: usize) {
+        let (members, estimate_ttl) = self.estimates.entry(key.to_string()).or_default();
+        members.insert(member.to_string());
+        *estimate_ttl = ttl;
+    }
+
+    /// Increments the score of `member` in the sorted set under `key`, creating it if needed
+    pub fn increment_score(&mut self, key: &str, member: &str, ttl: usize) {
+        let (scores, set_ttl) = self.scores.entry(key.to_string()).or_default();
+        *scores.entry(member.to_string()).or_insert(0) += 1;
+        *set_ttl = ttl;
+    }
+
+    /// Increments the score of `member` in the index under `key`, members it doesn't hold are left out
+    pub fn increment_index_score(&mut self, key: &str, member: &str) {
+        let scores = self.index_scores.entry(key.to_string()).or_default();
+        *scores.entry(member.to_string()).or_insert(0) += 1;
+    }
+}
+
+/// Key-value storage for short links, implemented by Redis for production, by SQLite for single-node deployments and
+/// by an in-memory map for local runs and tests
+#[async_trait]
+pub trait UrlStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError>;
+
+    /// Reads the record of a link to redirect to it. Backends with read replicas may answer from one, a little
+    /// behind the latest writes, the others read it with `get`.
+    async fn get_for_resolve(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.get(key).await
+    }
+
+    /// Stores the value only if the key doesn't exist yet, returns `false` on collision
+    async fn set(&self, key: &str, value: &str, ttl: Option<usize>) -> Result<bool, StorageError>;
+
+    /// Overwrites the value only if it still equals `expected`, returns `false` if it changed or doesn't exist.
+    /// A `ttl` resets the expiry, without one the key keeps its TTL.
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<bool, StorageError>;
+
+    /// Sets a new TTL on an existing key, returns `false` if it doesn't exist
+    async fn expire(&self, key: &str, ttl: usize) -> Result<bool, StorageError>;
+
+    /// Removes the key, returns `false` if it didn't exist
+    async fn delete(&self, key: &str) -> Result<bool, StorageError>;
+
+    /// Adds members to the set stored under `key`
+    async fn add_to_set(&self, key: &str, members: &[String]) -> Result<(), StorageError>;
+
+    async fn remove_from_set(&self, key: &str, members: &[String]) -> Result<(), StorageError>;
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError>;
+
+    /// Reads several keys at once, results are in the order of `keys`
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+        let mut results = Vec::with_capacity(keys.len());
+        for key in keys {
+            results.push(self.get(key).await?);
+        }
+        Ok(results)
+    }
+
+    /// Seconds until each of `keys` expires, in the order of `keys`. `None` for missing keys and keys without a TTL.
+    async fn ttl_many(&self, keys: &[String]) -> Result<Vec<Option<usize>>, StorageError>;
+
+    /// Stores several `(key, value, ttl)` entries with the same semantics as `set`,
+    /// backends override it to save round trips
+    async fn set_many(
+        &self,
+        entries: &[(String, String, Option<usize>)],
+    ) -> Result<Vec<bool>, StorageError> {
+        let mut results = Vec::with_capacity(entries.len());
+        for (key, value, ttl) in entries {
+            results.push(self.set(key, value, *ttl).await?);
+        }
+        Ok(results)
+    }
+
+    /// Stores the value under the first of `keys` that doesn't exist yet, with the semantics of `set`.
+    /// Returns the index of that key, `None` when every key is taken. Backends override it to try
+    /// every key in one round trip.
+    async fn set_first(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<Option<usize>, StorageError> {
+        for (index, key) in keys.iter().enumerate() {
+            if self.set(key, value, ttl).await? {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Like `set_first`, and stores `writes[index]` along with the value when it ends up under `keys[index]`,
+    /// `writes` holds the records of each of `keys` in the same order.
+    /// Backends override it to store everything atomically in one round trip, by default the writes follow
+    /// one by one.
+    async fn set_first_with(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+        writes: &[LinkWrites],
+    ) -> Result<Option<usize>, StorageError> {
+        let Some(index) = self.set_first(keys, value, ttl).await? else {
+            return Ok(None);
+        };
+        let writes = &writes[index];
+        self.set_many(&writes.entries).await?;
+        for (key, member) in &writes.set_members {
+            self.add_to_set(key, std::slice::from_ref(member)).await?;
+        }
+        Ok(Some(index))
+    }
+
+    /// Increments a counter that expires `window_seconds` after its first increment,
+    /// returns the new count and the seconds left until the counter resets
+    async fn incr_window(
+        &self,
+        key: &str,
+        window_seconds: usize,
+    ) -> Result<(u64, usize), StorageError>;
+
+    /// One page of keys starting at `cursor`, roughly `count` long. Returns the cursor of the next page,
+    /// which is 0 once every key was visited. Like Redis `SCAN`, keys changed during the scan may be missed.
+    async fn scan_keys(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<String>), StorageError>;
+
+    /// Atomically adds `amount` to a counter that never expires, starting at 0, and returns the new value
+    async fn increment(&self, key: &str, amount: u64) -> Result<u64, StorageError>;
+
+    /// Atomically increments an existing counter and returns the new value,
+    /// returns `None` without creating the key if it doesn't exist
+    async fn increment_existing(&self, key: &str) -> Result<Option<i64>, StorageError>;
+
+    /// Atomically decrements an existing counter and returns the new value,
+    /// returns `None` without creating the key if it doesn't exist
+    async fn decrement(&self, key: &str) -> Result<Option<i64>, StorageError>;
+
+    /// Fields of the hash under `key`, empty when it doesn't exist
+    async fn hash_fields(&self, key: &str) -> Result<HashMap<String, String>, StorageError>;
+
+    /// Approximate number of distinct members added to any of `keys`, 0 when none of them exist
+    async fn count_estimate(&self, keys: &[String]) -> Result<u64, StorageError>;
+
+    /// Applies every update of the batch and sets the TTL of each hash, HyperLogLog and sorted set it touches.
+    /// Not atomic, a failure can leave part of the batch written.
+    async fn write_counts(&self, counts: &CountBatch) -> Result<(), StorageError>;
+
+    /// Up to `limit` members of the sorted set under `key` with their scores, highest first
+    async fn top_scores(&self, key: &str, limit: usize)
+        -> Result<Vec<(String, u64)>, StorageError>;
+
+    /// Adds members with their scores to the index under `key`, a sorted set that never expires. Members
+    /// already in it keep their score.
+    async fn add_scored(&self, key: &str, members: &[(String, i64)]) -> Result<(), StorageError>;
+
+    /// Removes members from the index under `key`
+    async fn remove_scored(&self, key: &str, members: &[String]) -> Result<(), StorageError>;
+
+    /// Scores of `members` in the index under `key`, `None` for members it doesn't hold
+    async fn scores(&self, key: &str, members: &[String])
+        -> Result<Vec<Option<i64>>, StorageError>;
+
+    /// Up to `limit` members of the index under `key` with their scores, ordered by score and then by member,
+    /// highest first when `descending`. With `after` as `(score, member)` the page starts behind that
+    /// position. Redis resumes behind the member's rank while it still has that score, otherwise behind the
+    /// score, skipping the members tied with it.
+    async fn scored_page(
+        &self,
+        key: &str,
+        after: Option<(i64, &str)>,
+        descending: bool,
+        limit: usize,
+    ) -> Result<Vec<(String, i64)>, StorageError>;
+
+    /// Adds `entry` in front of the list under `key`, only the newest `max_len` entries are kept
+    async fn push_capped(&self, key: &str, entry: &str, max_len: usize)
+        -> Result<(), StorageError>;
+
+    /// Up to `count` entries of the list under `key` starting at `offset`, newest first
+    async fn list_range(
+        &self,
+        key: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, StorageError>;
+
+    /// Removes all stored data (use with caution in tests)
+    #[cfg(test)]
+    async fn cleanup(&self) -> Result<(), StorageError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    Redis,
+    Sqlite,
+    Memory,
+}
+
+impl std::str::FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redis" => Ok(StorageBackend::Redis),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err("expected 'redis', 'sqlite' or 'memory'".to_string()),
+        }
+    }
+}
+
+/// Redis behind the circuit breaker, unless it is turned off
+async fn redis_store(config: &AppConfig) -> Result<Arc<dyn UrlStore>, StorageError> {
+    let redis = Arc::new(get_redis_service(&config.redis).await?);
+    if config.redis.breaker_failures == 0 {
+        return Ok(redis);
+    }
+    Ok(Arc::new(CircuitBreaker::new(
+        redis,
+        config.redis.breaker_failures,
+        config.redis.breaker_cooldown,
+        config.redis.local_cache_size,
+    )))
+}
+
+/// Creates the store selected with `STORAGE_BACKEND`, with Redis in front of it when `REDIS_CACHE` is on
+pub async fn get_store(config: &AppConfig) -> Result<Arc<dyn UrlStore>, StorageError> {
+    let store = backend_store(config).await?;
+    if !config.redis_cache {
+        return Ok(store);
+    }
+    let cache = redis_store(config).await?;
+    Ok(Arc::new(TieredStore::new(
+        cache,
+        store,
+        config.redis_cache_ttl_seconds,
+    )))
+}
+
+async fn backend_store(config: &AppConfig) -> Result<Arc<dyn UrlStore>, StorageError> {
+    match config.storage_backend {
+        StorageBackend::Redis => redis_store(config).await,
+        StorageBackend::Sqlite => {
+            let store = SqliteStore::open(&config.sqlite.path)?;
+            // Ends once the store is dropped
+            store.spawn_cleanup(config.sqlite.cleanup_interval);
+            Ok(Arc::new(store))
+        }
+        StorageBackend::Memory => {
+            log::warn!("Using in-memory storage, links will be lost on restart");
+            Ok(Arc::new(MemoryStore::new()))
+        }
+    }
+}
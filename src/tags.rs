@@ -0,0 +1,152 @@
+use actix_web::web::{self, Data};
+use actix_web::{get, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::error::ApiError;
+use crate::link::Link;
+use crate::ownership::{count_clicks, load_metadata, Manager, OwnedLink};
+use crate::users::MaybeUser;
+use crate::AppState;
+
+pub const MAX_TAGS: usize = 10;
+pub const MAX_TAG_LENGTH: usize = 32;
+
+/// Set of the slugs carrying a tag
+pub fn tag_key(tag: &str) -> String {
+    format!("tag:{}", tag)
+}
+
+/// Trims and lowercases the tags and drops duplicates. Tags are 1-32 letters, digits, `-` or `_`.
+pub fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty()
+            || tag.len() > MAX_TAG_LENGTH
+            || !tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "Tag '{}' must be 1-{} letters, digits, '-' or '_'",
+                tag, MAX_TAG_LENGTH
+            ));
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(format!("A link can have at most {} tags", MAX_TAGS));
+    }
+    Ok(normalized)
+}
+
+/// Adds new links to the sets of their tags, failures only affect tag listings so they are logged
+pub async fn index_links<'a>(
+    state: &AppState,
+    links: impl IntoIterator<Item = (&'a str, &'a [String])>,
+) {
+    let mut by_tag: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for (slug, tags) in links {
+        for tag in tags {
+            by_tag.entry(tag).or_default().push(slug.to_string());
+        }
+    }
+    for (tag, slugs) in by_tag {
+        if let Err(err) = state.store.add_to_set(&tag_key(tag), &slugs).await {
+            log::warn!("Failed to index links tagged {}: {}", tag, err);
+        }
+    }
+}
+
+/// Drops a deleted link from the sets of its tags
+pub async fn unindex_link(state: &AppState, slug: &str, link: &Link) {
+    unindex_slug(state, slug, &link.tags).await;
+}
+
+/// Drops `slug` from the sets of `tags`
+pub async fn unindex_slug(state: &AppState, slug: &str, tags: &[String]) {
+    for tag in tags {
+        if let Err(err) = state
+            .store
+            .remove_from_set(&tag_key(tag), &[slug.to_string()])
+            .await
+        {
+            log::warn!("Failed to drop {} from tag {}: {}", slug, tag, err);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TagQuery {
+    tag: String,
+}
+
+/// Links carrying a tag, only the caller's own links unless it is an admin API key
+#[get("/api/links")]
+async fn links_by_tag(
+    req: HttpRequest,
+    query: web::Query<TagQuery>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let manager = Manager::identify(&req, user).await?;
+    let tag = query.into_inner().tag.trim().to_lowercase();
+    Ok(HttpResponse::Ok().json(tagged_links(&state, &tag, &manager).await?))
+}
+
+/// Existing links with `tag` the manager can see, oldest slugs first. Slugs of expired links stay in the
+/// set since sets don't expire with them, they are dropped here.
+pub async fn tagged_links(
+    state: &AppState,
+    tag: &str,
+    manager: &Manager,
+) -> Result<Vec<OwnedLink>, ApiError> {
+    let key = tag_key(tag);
+    let mut slugs = state.store.set_members(&key).await?;
+    slugs.sort();
+    let records = state.store.get_many(&slugs).await?;
+
+    let mut links = Vec::new();
+    let mut stale = Vec::new();
+    for (slug, record) in slugs.into_iter().zip(records) {
+        match record.map(|record| Link::decode(&record)) {
+            // The slug may have been taken by another link since
+            Some(link) if link.tags.iter().any(|t| t == tag) => {
+                if manager.ensure_can_view(&link).is_ok() {
+                    links.push(OwnedLink::new(state, slug, link));
+                }
+            }
+            _ => stale.push(slug),
+        }
+    }
+    if let Err(err) = state.store.remove_from_set(&key, &stale).await {
+        log::warn!("Failed to prune expired links tagged {}: {}", tag, err);
+    }
+    count_clicks(state, &mut links).await?;
+    load_metadata(state, &mut links).await?;
+    Ok(links)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_tags() {
+        assert_eq!(
+            normalize_tags(vec![
+                " Campaign-Q3 ".to_string(),
+                "email".to_string(),
+                "campaign-q3".to_string(),
+            ]),
+            Ok(vec!["campaign-q3".to_string(), "email".to_string()])
+        );
+        assert!(normalize_tags(vec!["".to_string()]).is_err());
+        assert!(normalize_tags(vec!["spring sale".to_string()]).is_err());
+        assert!(normalize_tags(vec!["a".repeat(MAX_TAG_LENGTH + 1)]).is_err());
+        assert!(normalize_tags((0..=MAX_TAGS).map(|i| i.to_string()).collect()).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::Next;
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::future::Future;
+
+const SERVICE_NAME: &str = "url-shortener";
+
+fn tracer() -> BoxedTracer {
+    global::tracer(SERVICE_NAME)
+}
+
+/// Sets up OTLP trace export when `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set.
+/// Without it spans go to the no-op default provider and cost next to nothing. The returned provider has to
+/// be shut down to export the last batch.
+pub fn init_tracer_from_env() -> Result<Option<SdkTracerProvider>, String> {
+    let configured = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|var| std::env::var(var).is_ok_and(|value| !value.is_empty()));
+    if !configured {
+        return Ok(None);
+    }
+
+    // The endpoint, headers and timeout are read from the standard OTEL_EXPORTER_OTLP_* variables
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|err| format!("failed to create OTLP exporter: {}", err))?;
+    let mut resource = Resource::builder();
+    if std::env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.with_service_name(SERVICE_NAME);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    Ok(Some(provider))
+}
+
+struct RequestHeaders<'a>(&'a HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Server span named after the matched route around every request, continuing the trace of the W3C
+/// `traceparent` header when the caller sent one
+pub async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&RequestHeaders(req.headers()))
+    });
+    let method = req.method().to_string();
+    let tracer = tracer();
+    let span = tracer
+        .span_builder(method.clone())
+        .with_kind(SpanKind::Server)
+        .with_attributes([KeyValue::new("http.request.method", method.clone())])
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+
+    let result = next.call(req).with_context(cx.clone()).await;
+    let span = cx.span();
+    match &result {
+        Ok(res) => {
+            // The route is only known once the router picked the handler
+            if let Some(route) = res.request().match_pattern() {
+                span.update_name(format!("{} {}", method, route));
+                span.set_attribute(KeyValue::new("http.route", route));
+            }
+            let status = res.status();
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                i64::from(status.as_u16()),
+            ));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+        }
+        Err(err) => span.set_status(Status::error(err.to_string())),
+    }
+    span.end();
+    result
+}
+
+/// Runs `future` in a child span of the current one, the span is marked failed when it returns an error
+pub async fn traced<T, E: Display>(
+    name: impl Into<Cow<'static, str>>,
+    kind: SpanKind,
+    attributes: Vec<KeyValue>,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let tracer = tracer();
+    let span = tracer
+        .span_builder(name)
+        .with_kind(kind)
+        .with_attributes(attributes)
+        .start(&tracer);
+    let cx = Context::current_with_span(span);
+
+    let result = future.with_context(cx.clone()).await;
+    if let Err(err) = &result {
+        cx.span().set_status(Status::error(err.to_string()));
+    }
+    cx.span().end();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use opentelemetry::propagation::TextMapPropagator;
+
+    #[test]
+    fn test_traceparent_header_is_continued() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("traceparent"),
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+
+        let cx = TraceContextPropagator::new().extract(&RequestHeaders(&headers));
+        let parent = cx.span().span_context().clone();
+
+        assert!(parent.is_remote());
+        assert!(parent.is_sampled());
+        assert_eq!(
+            parent.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(parent.span_id().to_string(), "00f067aa0ba902b7");
+    }
+}
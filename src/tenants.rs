@@ -0,0 +1,296 @@
+use actix_web::web::{self, Data};
+use actix_web::{get, put, HttpMessage, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{self, AuditAction, AuditContext};
+use crate::auth::AuthenticatedKey;
+use crate::body::JsonBody;
+use crate::error::ApiError;
+use crate::link::Link;
+use crate::storage::UrlStore;
+use crate::{AppState, Creator};
+
+const MAX_TENANT_LENGTH: usize = 64;
+
+/// Tenant ids are lowercase letters, digits and dashes, so they can't break out of the `tenant:<id>:` prefix
+pub fn validate_tenant(tenant: &str) -> Result<(), ApiError> {
+    if tenant.is_empty()
+        || tenant.len() > MAX_TENANT_LENGTH
+        || !tenant
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(ApiError::validation(
+            "invalid_tenant",
+            format!(
+                "A tenant must be 1 to {} lowercase letters, digits or dashes.",
+                MAX_TENANT_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Key of a record kept for `tenant`, every one of them lives under the `tenant:<id>:` prefix
+fn tenant_key(tenant: &str, name: &str) -> String {
+    format!("tenant:{}:{}", tenant, name)
+}
+
+/// Counter of the links created by the keys of `tenant`, their shared quota is checked against it
+pub fn links_created_key(tenant: &str) -> String {
+    tenant_key(tenant, "links_created")
+}
+
+/// Counter of the redirects of every link of `tenant`
+pub fn clicks_key(tenant: &str) -> String {
+    tenant_key(tenant, "clicks")
+}
+
+/// Number of links the keys of `tenant` may create together, missing for unlimited
+fn quota_key(tenant: &str) -> String {
+    tenant_key(tenant, "quota")
+}
+
+async fn tenant_quota(store: &dyn UrlStore, tenant: &str) -> Result<Option<u64>, ApiError> {
+    Ok(store
+        .get(&quota_key(tenant))
+        .await?
+        .and_then(|quota| quota.parse().ok()))
+}
+
+/// Quota the links of `creator` count against, the one of the tenant for keys that belong to one
+pub async fn quota(state: &AppState, creator: &Creator) -> Result<Option<u64>, ApiError> {
+    match &creator.tenant {
+        Some(tenant) => tenant_quota(state.store.as_ref(), tenant).await,
+        None => Ok(creator.quota),
+    }
+}
+
+/// Starts the click counter of a tenant, the analytics writer only increments existing counters
+pub async fn start_counters(store: &dyn UrlStore, tenant: &str) -> Result<(), ApiError> {
+    // Only stored if missing, so provisioning another key of the tenant keeps its count
+    store.set(&clicks_key(tenant), "0", None).await?;
+    Ok(())
+}
+
+/// Counter the links of `creator` are counted on, `None` when neither a tenant nor a quota needs them counted
+pub fn links_counter(creator: &Creator) -> Option<String> {
+    match (&creator.tenant, &creator.api_key) {
+        (Some(tenant), _) => Some(links_created_key(tenant)),
+        (None, Some(id)) if creator.quota.is_some() => Some(format!("apikey:{}:links_created", id)),
+        _ => None,
+    }
+}
+
+/// Counts a link about to be stored, fails once the tenant or key used up its quota
+pub async fn claim_link(state: &AppState, creator: &Creator) -> Result<(), ApiError> {
+    let Some(counter) = links_counter(creator) else {
+        return Ok(());
+    };
+    let created = state.store.increment(&counter, 1).await?;
+    if let Some(quota) = quota(state, creator)
+        .await?
+        .filter(|quota| created > *quota)
+    {
+        release_link(state, creator).await;
+        return Err(ApiError::QuotaExceeded { quota });
+    }
+    Ok(())
+}
+
+/// Gives back a claimed link that ended up not being stored
+pub async fn release_link(state: &AppState, creator: &Creator) {
+    let Some(counter) = links_counter(creator) else {
+        return;
+    };
+    if let Err(err) = state.store.decrement(&counter).await {
+        log::warn!("Failed to release a link counted on {}: {}", counter, err);
+    }
+}
+
+/// Admin keys bound to a tenant only reach the links of their tenant, `None` reaches every link
+pub fn ensure_same_tenant(tenant: Option<&str>, link: &Link) -> Result<(), ApiError> {
+    match tenant {
+        Some(tenant) if link.tenant.as_deref() != Some(tenant) => Err(ApiError::Forbidden {
+            message: "This link belongs to another tenant.".to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct TenantUsage {
+    tenant: String,
+    /// Links the keys of the tenant may create together, `None` for unlimited
+    quota: Option<u64>,
+    links_created: u64,
+    clicks: u64,
+}
+
+/// Links created and redirects counted for a tenant, admin keys of another tenant are refused
+#[get(
+    "/api/admin/tenants/{tenant}/usage",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn tenant_usage(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let tenant = path.into_inner();
+    validate_tenant(&tenant)?;
+    let own = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .and_then(|key| key.key.tenant.clone());
+    if own.as_ref().is_some_and(|own| *own != tenant) {
+        return Err(ApiError::Forbidden {
+            message: "This API key can only see the usage of its own tenant.".to_string(),
+        });
+    }
+    Ok(HttpResponse::Ok().json(usage(state.store.as_ref(), tenant).await?))
+}
+
+#[derive(Deserialize)]
+struct TenantQuotaRequest {
+    /// `null` lifts the quota
+    quota: Option<u64>,
+}
+
+/// Sets the number of links the keys of a tenant may create together. Only admin keys without a tenant can,
+/// otherwise a tenant could lift its own quota.
+#[put(
+    "/api/admin/tenants/{tenant}/quota",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_admin)"
+)]
+async fn set_tenant_quota(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: JsonBody<TenantQuotaRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let tenant = path.into_inner();
+    validate_tenant(&tenant)?;
+    let own = req
+        .extensions()
+        .get::<AuthenticatedKey>()
+        .and_then(|key| key.key.tenant.clone());
+    if own.is_some() {
+        return Err(ApiError::Forbidden {
+            message: "Only admin keys without a tenant can change the quota of a tenant."
+                .to_string(),
+        });
+    }
+    let store = state.store.as_ref();
+    match body.into_inner().quota {
+        Some(quota) => replace_quota(store, &tenant, quota).await?,
+        None => {
+            store.delete(&quota_key(&tenant)).await?;
+        }
+    }
+    audit::record(
+        &state,
+        &AuditContext::from_request(&req, None),
+        AuditAction::TenantQuotaChanged,
+        &tenant,
+        Some(&tenant),
+    )
+    .await;
+    Ok(HttpResponse::Ok().json(usage(store, tenant).await?))
+}
+
+/// Overwrites the quota of `tenant` without a moment in which it has none, retrying when another admin
+/// changed it in between
+async fn replace_quota(store: &dyn UrlStore, tenant: &str, quota: u64) -> Result<(), ApiError> {
+    let key = quota_key(tenant);
+    let value = quota.to_string();
+    loop {
+        let replaced = match store.get(&key).await? {
+            Some(current) => store.compare_and_set(&key, &current, &value, None).await?,
+            None => store.set(&key, &value, None).await?,
+        };
+        if replaced {
+            return Ok(());
+        }
+    }
+}
+
+async fn usage(store: &dyn UrlStore, tenant: String) -> Result<TenantUsage, ApiError> {
+    let counts = store
+        .get_many(&[
+            links_created_key(&tenant),
+            clicks_key(&tenant),
+            quota_key(&tenant),
+        ])
+        .await?;
+    let count = |value: &Option<String>| {
+        value
+            .as_deref()
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(0)
+            .max(0) as u64
+    };
+    Ok(TenantUsage {
+        quota: counts[2].as_deref().and_then(|quota| quota.parse().ok()),
+        links_created: count(&counts[0]),
+        clicks: count(&counts[1]),
+        tenant,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::{AppConfig, UrlShortener};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_validate_tenant() {
+        assert!(validate_tenant("acme-corp").is_ok());
+        assert!(validate_tenant("team42").is_ok());
+        for tenant in ["", "Acme", "acme:links", "acme corp", &"a".repeat(65)] {
+            assert_eq!(
+                validate_tenant(tenant).unwrap_err().code(),
+                "invalid_tenant"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quota_is_shared_by_the_keys_of_a_tenant() {
+        let shortener =
+            UrlShortener::with_store(&AppConfig::default(), Arc::new(MemoryStore::new()))
+                .await
+                .unwrap();
+        let state = &shortener.state;
+        state
+            .store
+            .set(&quota_key("acme"), "2", None)
+            .await
+            .unwrap();
+        // A quota left on a key of the tenant doesn't override the one of the tenant
+        let key = |id: &str, quota| Creator {
+            tenant: Some("acme".to_string()),
+            api_key: Some(id.to_string()),
+            quota: Some(quota),
+            ..Default::default()
+        };
+
+        claim_link(state, &key("a", 2)).await.unwrap();
+        claim_link(state, &key("b", 5)).await.unwrap();
+        assert_eq!(
+            claim_link(state, &key("b", 5)).await.unwrap_err().code(),
+            "quota_exceeded"
+        );
+        // The tenant's own counter is untouched by the refused claim
+        let used = usage(state.store.as_ref(), "acme".to_string())
+            .await
+            .unwrap();
+        assert_eq!(used.links_created, 2);
+        assert_eq!(used.quota, Some(2));
+
+        release_link(state, &key("b", 5)).await;
+        claim_link(state, &key("a", 2)).await.unwrap();
+    }
+}
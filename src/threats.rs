@@ -0,0 +1,359 @@
+use actix_web::web::Data;
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::error::ApiError;
+use crate::link::{Link, Suspension};
+use crate::metrics::Counter;
+use crate::ownership::modify_link;
+use crate::AppState;
+
+pub const SAFE_BROWSING_URL: &str = "https://safebrowsing.googleapis.com/v4/threatMatches:find";
+/// Largest number of URLs the Safe Browsing Lookup API accepts in one request
+const MAX_URLS_PER_CHECK: usize = 500;
+/// Recorded as `suspended_by` on links taken down by the rescan
+const SCANNER_NAME: &str = "threat scanner";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreatConfig {
+    /// Enables Safe Browsing checks, without a key no checks are made
+    pub safe_browsing_api_key: Option<String>,
+    pub safe_browsing_url: String,
+    /// Checks taking longer let the link through, the rescan catches it later
+    pub timeout: Duration,
+    /// How often existing links are checked again, `None` disables the rescan
+    pub rescan_interval: Option<Duration>,
+}
+
+impl Default for ThreatConfig {
+    fn default() -> Self {
+        ThreatConfig {
+            safe_browsing_api_key: None,
+            safe_browsing_url: SAFE_BROWSING_URL.to_string(),
+            timeout: Duration::from_secs(2),
+            rescan_interval: Some(Duration::from_secs(60 * 60)),
+        }
+    }
+}
+
+/// Threat intelligence source destinations are checked against
+#[async_trait]
+pub trait ThreatChecker: Send + Sync {
+    /// Threat type of each URL (e.g. `MALWARE`) or `None` when it is not flagged, in the order of `urls`
+    async fn check(&self, urls: &[String]) -> Result<Vec<Option<String>>, String>;
+}
+
+/// Google Safe Browsing Lookup API (v4)
+pub struct SafeBrowsing {
+    http: reqwest::Client,
+    endpoint: String,
+    api_key: String,
+}
+
+impl SafeBrowsing {
+    pub fn new(endpoint: String, api_key: String, timeout: Duration) -> Self {
+        SafeBrowsing {
+            http: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("HTTP client settings are valid"),
+            endpoint,
+            api_key,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LookupRequest<'a> {
+    client: LookupClient,
+    threat_info: ThreatInfo<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LookupClient {
+    client_id: &'static str,
+    client_version: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatInfo<'a> {
+    threat_types: [&'static str; 4],
+    platform_types: [&'static str; 1],
+    threat_entry_types: [&'static str; 1],
+    threat_entries: Vec<ThreatEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct ThreatEntry<'a> {
+    url: &'a str,
+}
+
+#[derive(Deserialize)]
+struct MatchedEntry {
+    url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatMatch {
+    threat_type: String,
+    threat: MatchedEntry,
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    /// Left out entirely when nothing matched
+    #[serde(default)]
+    matches: Vec<ThreatMatch>,
+}
+
+fn lookup_request(urls: &[String]) -> LookupRequest<'_> {
+    LookupRequest {
+        client: LookupClient {
+            client_id: env!("CARGO_PKG_NAME"),
+            client_version: env!("CARGO_PKG_VERSION"),
+        },
+        threat_info: ThreatInfo {
+            threat_types: [
+                "MALWARE",
+                "SOCIAL_ENGINEERING",
+                "UNWANTED_SOFTWARE",
+                "POTENTIALLY_HARMFUL_APPLICATION",
+            ],
+            platform_types: ["ANY_PLATFORM"],
+            threat_entry_types: ["URL"],
+            threat_entries: urls.iter().map(|url| ThreatEntry { url }).collect(),
+        },
+    }
+}
+
+/// Maps the matches back onto the checked URLs
+fn verdicts(urls: &[String], response: LookupResponse) -> Vec<Option<String>> {
+    urls.iter()
+        .map(|url| {
+            response
+                .matches
+                .iter()
+                .find(|found| &found.threat.url == url)
+                .map(|found| found.threat_type.clone())
+        })
+        .collect()
+}
+
+#[async_trait]
+impl ThreatChecker for SafeBrowsing {
+    async fn check(&self, urls: &[String]) -> Result<Vec<Option<String>>, String> {
+        let response: LookupResponse = self
+            .http
+            .post(&self.endpoint)
+            .query(&[("key", &self.api_key)])
+            .json(&lookup_request(urls))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            // The URL carries the API key
+            .map_err(|err| format!("Safe Browsing lookup failed: {}", err.without_url()))?
+            .json()
+            .await
+            .map_err(|err| format!("Unexpected Safe Browsing response: {}", err.without_url()))?;
+        Ok(verdicts(urls, response))
+    }
+}
+
+/// Creates the checker for the configuration, `None` when threat checks are disabled
+pub fn threat_checker(config: &ThreatConfig) -> Option<Arc<dyn ThreatChecker>> {
+    let api_key = config.safe_browsing_api_key.clone()?;
+    Some(Arc::new(SafeBrowsing::new(
+        config.safe_browsing_url.clone(),
+        api_key,
+        config.timeout,
+    )))
+}
+
+/// Rejects flagged destinations of new or changed links. When the check itself fails the link is let
+/// through, so an outage of the threat intelligence source doesn't take shortening down with it.
+pub async fn check_destination(state: &AppState, url: &str) -> Result<(), ApiError> {
+    let Some(checker) = &state.threats else {
+        return Ok(());
+    };
+    match checker.check(&[url.to_string()]).await {
+        Ok(verdicts) => match verdicts.into_iter().next().flatten() {
+            Some(threat_type) => {
+                state.metrics.incr(Counter::ThreatsDetected);
+                log::warn!("Rejected {} flagged as {}", url, threat_type);
+                Err(ApiError::UnsafeDestination { threat_type })
+            }
+            None => Ok(()),
+        },
+        Err(err) => {
+            log::warn!(
+                "Threat check of {} failed, letting it through: {}",
+                url,
+                err
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Suspends a link flagged by the rescan, unless the flagged destination was changed since it was checked
+async fn suspend_flagged(
+    state: &AppState,
+    slug: &str,
+    url: &str,
+    threat_type: &str,
+) -> Result<(), ApiError> {
+    modify_link(state, slug, None, |link| {
+        if link.suspension.is_none() && link.destinations().any(|destination| destination == url) {
+            link.suspension = Some(Suspension {
+                reason: Some(format!("Flagged as {}", threat_type)),
+                suspended_by: SCANNER_NAME.to_string(),
+                suspended_at: Utc::now(),
+            });
+        }
+        Ok(())
+    })
+    .await
+    .map(|_| ())
+}
+
+/// Checks every active link once, returns the number of links that were suspended
+async fn rescan(state: &AppState, checker: &dyn ThreatChecker) -> Result<usize, String> {
+    let mut suspended = 0;
+    let mut cursor = 0;
+    loop {
+        let (next, keys) = state
+            .store
+            .scan_keys(cursor, MAX_URLS_PER_CHECK)
+            .await
+            .map_err(|err| err.to_string())?;
+        // Internal records share the keyspace, slugs never contain ':'
+        let slugs: Vec<String> = keys.into_iter().filter(|key| !key.contains(':')).collect();
+        let records = state
+            .store
+            .get_many(&slugs)
+            .await
+            .map_err(|err| err.to_string())?;
+        let links: Vec<(String, Link)> = slugs
+            .into_iter()
+            .zip(records)
+            .filter_map(|(slug, record)| Some((slug, Link::decode(&record?))))
+            .filter(|(_, link)| link.suspension.is_none() && !link.reserved)
+            .collect();
+
+        // Device specific destinations are checked along with the main URL
+        let destinations: Vec<(&str, String)> = links
+            .iter()
+            .flat_map(|(slug, link)| {
+                link.destinations()
+                    .map(move |url| (slug.as_str(), url.to_string()))
+            })
+            .collect();
+        let mut last_suspended = None;
+        for chunk in destinations.chunks(MAX_URLS_PER_CHECK) {
+            let urls: Vec<String> = chunk.iter().map(|(_, url)| url.clone()).collect();
+            let verdicts = checker.check(&urls).await?;
+            for ((slug, url), verdict) in chunk.iter().zip(verdicts) {
+                let Some(threat_type) = verdict else {
+                    continue;
+                };
+                state.metrics.incr(Counter::ThreatsDetected);
+                // Destinations of a link are next to each other, one suspension per link is enough
+                if last_suspended == Some(*slug) {
+                    continue;
+                }
+                log::warn!(
+                    "Link {} to {} flagged as {}, suspending it",
+                    slug,
+                    url,
+                    threat_type
+                );
+                match suspend_flagged(state, slug, url, &threat_type).await {
+                    Ok(()) => {
+                        suspended += 1;
+                        last_suspended = Some(*slug);
+                    }
+                    Err(err) => log::error!("Failed to suspend flagged link {}: {}", slug, err),
+                }
+            }
+        }
+
+        if next == 0 {
+            return Ok(suspended);
+        }
+        cursor = next;
+    }
+}
+
+/// Periodically checks existing links again, destinations can turn malicious after they were shortened
+pub fn spawn_threat_rescanner(
+    state: Data<AppState>,
+    config: &ThreatConfig,
+) -> Option<JoinHandle<()>> {
+    let checker = state.threats.clone()?;
+    let rescan_interval = config.rescan_interval?;
+    Some(tokio::spawn(async move {
+        let mut ticker = interval(rescan_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Links were checked when they were created, no need to rescan right at startup
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match rescan(&state, checker.as_ref()).await {
+                Ok(suspended) => log::info!("Threat rescan done, {} links suspended", suspended),
+                Err(err) => log::error!("Threat rescan failed: {}", err),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_request_format() {
+        let urls = vec!["https://example.com/".to_string()];
+        let request = serde_json::to_value(lookup_request(&urls)).unwrap();
+
+        assert_eq!(request["client"]["clientId"], "url-shortener");
+        assert_eq!(request["threatInfo"]["threatEntryTypes"][0], "URL");
+        assert_eq!(
+            request["threatInfo"]["threatEntries"][0]["url"],
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn test_verdicts_follow_the_checked_urls() {
+        let urls = vec![
+            "https://fine.example/".to_string(),
+            "https://phish.example/login".to_string(),
+        ];
+        let response: LookupResponse = serde_json::from_str(
+            r#"{"matches": [{
+                "threatType": "SOCIAL_ENGINEERING",
+                "platformType": "ANY_PLATFORM",
+                "threat": {"url": "https://phish.example/login"},
+                "cacheDuration": "300s",
+                "threatEntryType": "URL"
+            }]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            verdicts(&urls, response),
+            vec![None, Some("SOCIAL_ENGINEERING".to_string())]
+        );
+        let empty: LookupResponse = serde_json::from_str("{}").unwrap();
+        assert_eq!(verdicts(&urls, empty), vec![None, None]);
+    }
+}
@@ -0,0 +1,430 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::breaker::is_link_key;
+use crate::storage::{CountBatch, LinkWrites, StorageError, UrlStore};
+
+/// A cache in front of a durable store, e.g. Redis over SQLite. Link records are written through to both on
+/// create and read through on a cache miss, so redirects are served by the cache while links survive it being
+/// flushed, evicting them or restarting. Everything else only lives in the durable store.
+///
+/// The cache is best effort: when it fails, reads go to the durable store and writes only log. A record cached
+/// just before another instance changed the link may be served for up to `cache_ttl` seconds.
+pub struct TieredStore {
+    cache: Arc<dyn UrlStore>,
+    durable: Arc<dyn UrlStore>,
+    /// Longest a record is kept in the cache, shorter for links expiring sooner
+    cache_ttl: usize,
+}
+
+impl TieredStore {
+    pub fn new(cache: Arc<dyn UrlStore>, durable: Arc<dyn UrlStore>, cache_ttl: usize) -> Self {
+        TieredStore {
+            cache,
+            durable,
+            cache_ttl,
+        }
+    }
+
+    fn cache_ttl(&self, ttl: Option<usize>) -> usize {
+        ttl.map_or(self.cache_ttl, |ttl| ttl.min(self.cache_ttl))
+    }
+
+    async fn fill(&self, key: &str, value: &str, ttl: Option<usize>) {
+        if let Err(err) = self.cache.set(key, value, Some(self.cache_ttl(ttl))).await {
+            log::warn!("Failed to cache {}: {}", key, err);
+        }
+    }
+
+    /// Drops the cached records of keys changed in the durable store, they are read through again
+    async fn invalidate(&self, key: &str) {
+        if !is_link_key(key) {
+            return;
+        }
+        if let Err(err) = self.cache.delete(key).await {
+            log::warn!("Failed to drop the cached record of {}: {}", key, err);
+        }
+    }
+
+    /// Reads the link records under `keys` from the durable store and caches the ones found
+    async fn read_through(&self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+        let values = self.durable.get_many(keys).await?;
+        let found: Vec<(String, String)> = keys
+            .iter()
+            .zip(&values)
+            .filter_map(|(key, value)| Some((key.clone(), value.clone()?)))
+            .collect();
+        if found.is_empty() {
+            return Ok(values);
+        }
+        let found_keys: Vec<String> = found.iter().map(|(key, _)| key.clone()).collect();
+        let ttls = self.durable.ttl_many(&found_keys).await?;
+        let entries: Vec<(String, String, Option<usize>)> = found
+            .into_iter()
+            .zip(ttls)
+            .map(|((key, value), ttl)| (key, value, Some(self.cache_ttl(ttl))))
+            .collect();
+        if let Err(err) = self.cache.set_many(&entries).await {
+            log::warn!("Failed to cache {} link records: {}", entries.len(), err);
+        }
+        Ok(values)
+    }
+
+    /// The link record under `key`, as `cached` or read through when the cache missed or failed
+    async fn read_link(
+        &self,
+        key: &str,
+        cached: Result<Option<String>, StorageError>,
+    ) -> Result<Option<String>, StorageError> {
+        match cached {
+            Ok(Some(value)) => return Ok(Some(value)),
+            Ok(None) => {}
+            Err(err) => log::warn!("Cache read of {} failed, reading through: {}", key, err),
+        }
+        let value = self.read_through(&[key.to_string()]).await?;
+        Ok(value.into_iter().next().flatten())
+    }
+}
+
+#[async_trait]
+impl UrlStore for TieredStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        if !is_link_key(key) {
+            return self.durable.get(key).await;
+        }
+        let cached = self.cache.get(key).await;
+        self.read_link(key, cached).await
+    }
+
+    async fn get_for_resolve(&self, key: &str) -> Result<Option<String>, StorageError> {
+        if !is_link_key(key) {
+            return self.durable.get_for_resolve(key).await;
+        }
+        let cached = self.cache.get_for_resolve(key).await;
+        self.read_link(key, cached).await
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<usize>) -> Result<bool, StorageError> {
+        let stored = self.durable.set(key, value, ttl).await?;
+        if stored && is_link_key(key) {
+            self.fill(key, value, ttl).await;
+        }
+        Ok(stored)
+    }
+
+    async fn set_first(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<Option<usize>, StorageError> {
+        let stored = self.durable.set_first(keys, value, ttl).await?;
+        if let Some(key) = stored.map(|index| &keys[index]) {
+            if is_link_key(key) {
+                self.fill(key, value, ttl).await;
+            }
+        }
+        Ok(stored)
+    }
+
+    async fn set_first_with(
+        &self,
+        keys: &[String],
+        value: &str,
+        ttl: Option<usize>,
+        writes: &[LinkWrites],
+    ) -> Result<Option<usize>, StorageError> {
+        let stored = self
+            .durable
+            .set_first_with(keys, value, ttl, writes)
+            .await?;
+        if let Some(key) = stored.map(|index| &keys[index]) {
+            if is_link_key(key) {
+                self.fill(key, value, ttl).await;
+            }
+        }
+        Ok(stored)
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        value: &str,
+        ttl: Option<usize>,
+    ) -> Result<bool, StorageError> {
+        let swapped = self
+            .durable
+            .compare_and_set(key, expected, value, ttl)
+            .await?;
+        if swapped {
+            self.invalidate(key).await;
+        }
+        Ok(swapped)
+    }
+
+    async fn expire(&self, key: &str, ttl: usize) -> Result<bool, StorageError> {
+        let expired = self.durable.expire(key, ttl).await?;
+        self.invalidate(key).await;
+        Ok(expired)
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, StorageError> {
+        let deleted = self.durable.delete(key).await?;
+        self.invalidate(key).await;
+        Ok(deleted)
+    }
+
+    async fn add_to_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        self.durable.add_to_set(key, members).await
+    }
+
+    async fn remove_from_set(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        self.durable.remove_from_set(key, members).await
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError> {
+        self.durable.set_members(key).await
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>, StorageError> {
+        if !keys.iter().all(|key| is_link_key(key)) {
+            return self.durable.get_many(keys).await;
+        }
+        let mut values = match self.cache.get_many(keys).await {
+            Ok(values) => values,
+            Err(err) => {
+                log::warn!(
+                    "Cache read of {} keys failed, reading through: {}",
+                    keys.len(),
+                    err
+                );
+                vec![None; keys.len()]
+            }
+        };
+        let missed: Vec<usize> = (0..keys.len()).filter(|&i| values[i].is_none()).collect();
+        if missed.is_empty() {
+            return Ok(values);
+        }
+        let missed_keys: Vec<String> = missed.iter().map(|&i| keys[i].clone()).collect();
+        for (i, value) in missed
+            .into_iter()
+            .zip(self.read_through(&missed_keys).await?)
+        {
+            values[i] = value;
+        }
+        Ok(values)
+    }
+
+    async fn ttl_many(&self, keys: &[String]) -> Result<Vec<Option<usize>>, StorageError> {
+        self.durable.ttl_many(keys).await
+    }
+
+    async fn set_many(
+        &self,
+        entries: &[(String, String, Option<usize>)],
+    ) -> Result<Vec<bool>, StorageError> {
+        let stored = self.durable.set_many(entries).await?;
+        let cached: Vec<(String, String, Option<usize>)> = entries
+            .iter()
+            .zip(&stored)
+            .filter(|((key, _, _), stored)| **stored && is_link_key(key))
+            .map(|((key, value, ttl), _)| (key.clone(), value.clone(), Some(self.cache_ttl(*ttl))))
+            .collect();
+        if !cached.is_empty() {
+            if let Err(err) = self.cache.set_many(&cached).await {
+                log::warn!("Failed to cache {} link records: {}", cached.len(), err);
+            }
+        }
+        Ok(stored)
+    }
+
+    async fn incr_window(
+        &self,
+        key: &str,
+        window_seconds: usize,
+    ) -> Result<(u64, usize), StorageError> {
+        self.durable.incr_window(key, window_seconds).await
+    }
+
+    async fn scan_keys(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> Result<(u64, Vec<String>), StorageError> {
+        self.durable.scan_keys(cursor, count).await
+    }
+
+    async fn increment(&self, key: &str, amount: u64) -> Result<u64, StorageError> {
+        self.durable.increment(key, amount).await
+    }
+
+    async fn increment_existing(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        self.durable.increment_existing(key).await
+    }
+
+    async fn decrement(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        self.durable.decrement(key).await
+    }
+
+    async fn hash_fields(&self, key: &str) -> Result<HashMap<String, String>, StorageError> {
+        self.durable.hash_fields(key).await
+    }
+
+    async fn count_estimate(&self, keys: &[String]) -> Result<u64, StorageError> {
+        self.durable.count_estimate(keys).await
+    }
+
+    async fn write_counts(&self, counts: &CountBatch) -> Result<(), StorageError> {
+        self.durable.write_counts(counts).await
+    }
+
+    async fn top_scores(
+        &self,
+        key: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, u64)>, StorageError> {
+        self.durable.top_scores(key, limit).await
+    }
+
+    async fn add_scored(&self, key: &str, members: &[(String, i64)]) -> Result<(), StorageError> {
+        self.durable.add_scored(key, members).await
+    }
+
+    async fn remove_scored(&self, key: &str, members: &[String]) -> Result<(), StorageError> {
+        self.durable.remove_scored(key, members).await
+    }
+
+    async fn scores(
+        &self,
+        key: &str,
+        members: &[String],
+    ) -> Result<Vec<Option<i64>>, StorageError> {
+        self.durable.scores(key, members).await
+    }
+
+    async fn scored_page(
+        &self,
+        key: &str,
+        after: Option<(i64, &str)>,
+        descending: bool,
+        limit: usize,
+    ) -> Result<Vec<(String, i64)>, StorageError> {
+        self.durable
+            .scored_page(key, after, descending, limit)
+            .await
+    }
+
+    async fn push_capped(
+        &self,
+        key: &str,
+        entry: &str,
+        max_len: usize,
+    ) -> Result<(), StorageError> {
+        self.durable.push_capped(key, entry, max_len).await
+    }
+
+    async fn list_range(
+        &self,
+        key: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, StorageError> {
+        self.durable.list_range(key, offset, count).await
+    }
+
+    #[cfg(test)]
+    async fn cleanup(&self) -> Result<(), StorageError> {
+        self.cache.cleanup().await?;
+        self.durable.cleanup().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    fn stores() -> (Arc<MemoryStore>, Arc<MemoryStore>, TieredStore) {
+        let (cache, durable) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+        let store = TieredStore::new(cache.clone(), durable.clone(), 60);
+        (cache, durable, store)
+    }
+
+    #[tokio::test]
+    async fn test_links_are_written_and_read_through() {
+        let (cache, durable, store) = stores();
+
+        assert!(store.set("launch", "record", Some(3600)).await.unwrap());
+        assert!(!store.set("launch", "other", None).await.unwrap());
+        assert_eq!(
+            cache.get("launch").await.unwrap(),
+            Some("record".to_string())
+        );
+        assert_eq!(
+            cache.ttl_many(&["launch".to_string()]).await.unwrap(),
+            vec![Some(60)]
+        );
+        assert_eq!(
+            durable.get("launch").await.unwrap(),
+            Some("record".to_string())
+        );
+
+        // Lost from the cache, e.g. evicted or restarted
+        cache.cleanup().await.unwrap();
+        durable.set("docs", "docs record", None).await.unwrap();
+        assert_eq!(
+            store
+                .get_many(&["launch", "docs", "missing"].map(String::from))
+                .await
+                .unwrap(),
+            vec![
+                Some("record".to_string()),
+                Some("docs record".to_string()),
+                None
+            ]
+        );
+        assert_eq!(
+            cache.get("launch").await.unwrap(),
+            Some("record".to_string())
+        );
+        assert_eq!(
+            cache.get("docs").await.unwrap(),
+            Some("docs record".to_string())
+        );
+
+        // Non-link keys only live in the durable store
+        store.increment("clicks:launch", 1).await.unwrap();
+        assert_eq!(cache.get("clicks:launch").await.unwrap(), None);
+        assert_eq!(
+            store.get("clicks:launch").await.unwrap(),
+            Some("1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_changes_drop_cached_records() {
+        let (cache, durable, store) = stores();
+
+        store.set("launch", "record", None).await.unwrap();
+        assert!(store
+            .compare_and_set("launch", "record", "changed", None)
+            .await
+            .unwrap());
+        assert_eq!(cache.get("launch").await.unwrap(), None);
+        assert_eq!(
+            store.get("launch").await.unwrap(),
+            Some("changed".to_string())
+        );
+        assert_eq!(
+            cache.get("launch").await.unwrap(),
+            Some("changed".to_string())
+        );
+
+        assert!(store.delete("launch").await.unwrap());
+        assert_eq!(cache.get("launch").await.unwrap(), None);
+        assert_eq!(durable.get("launch").await.unwrap(), None);
+        assert_eq!(store.get("launch").await.unwrap(), None);
+    }
+}
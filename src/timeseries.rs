@@ -0,0 +1,228 @@
+use actix_web::web::{self, Data};
+use actix_web::{get, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::ApiError;
+use crate::expiration::parse_range;
+use crate::ownership::{load_link, Manager};
+use crate::storage::{CountBatch, StorageError, UrlStore};
+use crate::users::MaybeUser;
+use crate::visitors;
+use crate::AppState;
+
+/// Hourly buckets are kept a day longer than the longest range they can be asked for
+const MAX_HOURLY_RANGE_DAYS: i64 = 7;
+const HOURLY_RETENTION_SECONDS: usize = (MAX_HOURLY_RANGE_DAYS as usize + 1) * 24 * 60 * 60;
+/// Daily buckets are kept a month longer than the longest range they can be asked for
+const MAX_DAILY_RANGE_DAYS: i64 = 365;
+const DAILY_RETENTION_SECONDS: usize = (MAX_DAILY_RANGE_DAYS as usize + 31) * 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Granularity {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    fn step(self) -> Duration {
+        match self {
+            Granularity::Hour => Duration::hours(1),
+            Granularity::Day => Duration::days(1),
+        }
+    }
+
+    /// Hash holding the bucket of `at` and the field of the bucket in it: one hash per day with a field
+    /// per hour, or one hash per month with a field per day
+    fn bucket(self, slug: &str, at: DateTime<Utc>) -> (String, String) {
+        match self {
+            Granularity::Hour => (
+                format!("clicks:hourly:{}:{}", slug, at.format("%Y-%m-%d")),
+                at.format("%H").to_string(),
+            ),
+            Granularity::Day => (
+                format!("clicks:daily:{}:{}", slug, at.format("%Y-%m")),
+                at.format("%d").to_string(),
+            ),
+        }
+    }
+}
+
+/// Counts a click in the hourly and daily buckets of `at`. Every click pushes the expiry of its buckets out
+/// again, buckets without clicks expire after the retention.
+pub fn count_click(counts: &mut CountBatch, slug: &str, at: DateTime<Utc>) {
+    for (granularity, retention) in [
+        (Granularity::Hour, HOURLY_RETENTION_SECONDS),
+        (Granularity::Day, DAILY_RETENTION_SECONDS),
+    ] {
+        let (key, field) = granularity.bucket(slug, at);
+        counts.increment_field(&key, &field, retention);
+    }
+}
+
+/// Every bucket hash that may still exist for the link, so deleting it leaves no history behind for
+/// the next link with the same slug
+pub fn bucket_keys(slug: &str, now: DateTime<Utc>) -> Vec<String> {
+    let hourly = (0..=MAX_HOURLY_RANGE_DAYS + 1)
+        .map(|days| Granularity::Hour.bucket(slug, now - Duration::days(days)).0);
+    let mut keys: Vec<String> = (0..=MAX_DAILY_RANGE_DAYS + 31)
+        .map(|days| Granularity::Day.bucket(slug, now - Duration::days(days)).0)
+        .collect();
+    keys.dedup();
+    hourly.chain(keys).collect()
+}
+
+#[derive(Deserialize)]
+struct TimeseriesQuery {
+    #[serde(default)]
+    granularity: Granularity,
+    range: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Timeseries {
+    slug: String,
+    granularity: Granularity,
+    /// Start of every bucket, oldest first. The last one is the current, still running bucket.
+    timestamps: Vec<DateTime<Utc>>,
+    clicks: Vec<u64>,
+    /// Estimated distinct visitors over the UTC days the buckets touch
+    unique_visitors: u64,
+}
+
+/// Clicks of each of the last `days` UTC days up to `now`, oldest first
+pub async fn daily_clicks(
+    store: &dyn UrlStore,
+    slug: &str,
+    days: i64,
+    now: DateTime<Utc>,
+) -> Result<Vec<(DateTime<Utc>, u64)>, StorageError> {
+    let timeseries =
+        load_timeseries(store, slug, Granularity::Day, Duration::days(days), now).await?;
+    Ok(timeseries
+        .timestamps
+        .into_iter()
+        .zip(timeseries.clicks)
+        .collect())
+}
+
+/// Buckets covering the `range` up to `now`
+async fn load_timeseries(
+    store: &dyn UrlStore,
+    slug: &str,
+    granularity: Granularity,
+    range: Duration,
+    now: DateTime<Utc>,
+) -> Result<Timeseries, StorageError> {
+    let step = granularity.step();
+    let last = now.duration_trunc(step).expect("bucket sizes divide a day");
+    let buckets = (range.num_seconds() + step.num_seconds() - 1) / step.num_seconds();
+    let timestamps: Vec<DateTime<Utc>> = (0..buckets)
+        .rev()
+        .map(|back| last - step * back as i32)
+        .collect();
+
+    let mut hashes: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut clicks = Vec::with_capacity(timestamps.len());
+    for at in &timestamps {
+        let (key, field) = granularity.bucket(slug, *at);
+        if !hashes.contains_key(&key) {
+            let fields = store.hash_fields(&key).await?;
+            hashes.insert(key.clone(), fields);
+        }
+        clicks.push(
+            hashes[&key]
+                .get(&field)
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(0),
+        );
+    }
+    let unique_visitors = visitors::unique_visitors(store, slug, timestamps[0], now).await?;
+    Ok(Timeseries {
+        slug: slug.to_string(),
+        granularity,
+        timestamps,
+        clicks,
+        unique_visitors,
+    })
+}
+
+/// Clicks per hour or day over the last `range`, for the owner of the link and admin API keys
+#[get("/api/links/{slug}/stats/timeseries")]
+async fn click_timeseries(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<TimeseriesQuery>,
+    user: MaybeUser,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slug = state.slug(path.into_inner());
+    let TimeseriesQuery { granularity, range } = query.into_inner();
+    let max_days = match granularity {
+        Granularity::Hour => MAX_HOURLY_RANGE_DAYS,
+        Granularity::Day => MAX_DAILY_RANGE_DAYS,
+    };
+    let range = match range {
+        Some(range) => parse_range(&range)
+            .filter(|range| *range <= Duration::days(max_days))
+            .ok_or_else(|| {
+                ApiError::validation(
+                    "invalid_range",
+                    format!(
+                        "range must be a number of hours or days like '24h' or '7d', at most {}d for {} buckets",
+                        max_days,
+                        match granularity {
+                            Granularity::Hour => "hourly",
+                            Granularity::Day => "daily",
+                        }
+                    ),
+                )
+            })?,
+        None => Duration::days(MAX_HOURLY_RANGE_DAYS),
+    };
+
+    let manager = Manager::identify(&req, user).await?;
+    let (_, link) = load_link(&state, &slug).await?;
+    manager.ensure_can_view(&link)?;
+    let timeseries =
+        load_timeseries(state.store.as_ref(), &slug, granularity, range, Utc::now()).await?;
+    Ok(HttpResponse::Ok().json(timeseries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use chrono::TimeZone;
+
+    #[tokio::test]
+    async fn test_timeseries_spans_bucket_hashes() {
+        let store = MemoryStore::new();
+        let midnight = Utc.with_ymd_and_hms(2026, 10, 1, 0, 0, 0).unwrap();
+        let mut counts = CountBatch::default();
+        count_click(&mut counts, "abc", midnight - Duration::minutes(30));
+        for _ in 0..2 {
+            count_click(&mut counts, "abc", midnight + Duration::minutes(5));
+        }
+        store.write_counts(&counts).await.unwrap();
+
+        let now = midnight + Duration::minutes(45);
+        let hourly = load_timeseries(&store, "abc", Granularity::Hour, Duration::hours(3), now)
+            .await
+            .unwrap();
+        assert_eq!(hourly.timestamps[0], midnight - Duration::hours(2));
+        assert_eq!(hourly.clicks, vec![0, 1, 2]);
+
+        let daily = load_timeseries(&store, "abc", Granularity::Day, Duration::days(2), now)
+            .await
+            .unwrap();
+        assert_eq!(
+            daily.timestamps,
+            vec![midnight - Duration::days(1), midnight]
+        );
+        assert_eq!(daily.clicks, vec![1, 2]);
+    }
+}
@@ -0,0 +1,127 @@
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::task::JoinHandle;
+
+/// PEM files of the certificate chain and private key served over HTTPS
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Reads the certificate chain and key, checking that the key belongs to the certificate
+fn load_certified_key(
+    config: &TlsConfig,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("failed to read {}: {}", config.cert_path.display(), err))?;
+    if certs.is_empty() {
+        return Err(format!(
+            "no certificate found in {}",
+            config.cert_path.display()
+        ));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .map_err(|err| format!("failed to read {}: {}", config.key_path.display(), err))?;
+    CertifiedKey::from_der(certs, key, provider)
+        .map_err(|err| format!("invalid certificate or key: {}", err))
+}
+
+/// Hands every handshake the current certificate, `reload` swaps it without restarting the server
+#[derive(Debug)]
+pub struct ReloadableCert {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCert {
+    /// Loads the certificate, failing here keeps the service from starting without it
+    pub fn load(config: TlsConfig) -> Result<Arc<Self>, String> {
+        let provider = Arc::new(ring::default_provider());
+        let current = load_certified_key(&config, &provider)?;
+        Ok(Arc::new(ReloadableCert {
+            config,
+            provider,
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+
+    /// Reads the files again, the previous certificate stays in use if they are broken
+    pub fn reload(&self) -> Result<(), String> {
+        let reloaded = load_certified_key(&self.config, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(reloaded);
+        Ok(())
+    }
+
+    /// rustls server settings resolving certificates through `self`
+    pub fn server_config(self: &Arc<Self>) -> Result<ServerConfig, String> {
+        Ok(ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone()))
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Reloads the certificate on every SIGHUP, e.g. after a renewal replaced the files
+#[cfg(unix)]
+pub fn spawn_cert_reloader(cert: Arc<ReloadableCert>) -> std::io::Result<JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    Ok(tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match cert.reload() {
+                Ok(()) => log::info!("Reloaded TLS certificate on SIGHUP"),
+                Err(err) => log::error!(
+                    "Failed to reload TLS certificate, keeping the previous one: {}",
+                    err
+                ),
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+pub fn spawn_cert_reloader(_cert: Arc<ReloadableCert>) -> std::io::Result<JoinHandle<()>> {
+    Ok(tokio::spawn(std::future::pending()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_reports_unreadable_files() {
+        let dir = std::env::temp_dir().join(format!("url-shortener-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        std::fs::write(&cert_path, "not a certificate").unwrap();
+        let config = TlsConfig {
+            cert_path: cert_path.clone(),
+            key_path: dir.join("missing.pem"),
+        };
+
+        let err = ReloadableCert::load(config.clone()).unwrap_err();
+        assert!(err.contains("no certificate found"), "{}", err);
+
+        std::fs::remove_file(&cert_path).unwrap();
+        let err = ReloadableCert::load(config).unwrap_err();
+        assert!(err.contains("cert.pem"), "{}", err);
+    }
+}
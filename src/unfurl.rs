@@ -0,0 +1,79 @@
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+
+use crate::error::ApiError;
+use crate::link::Link;
+use crate::metadata;
+use crate::pages;
+use crate::AppState;
+
+/// `User-Agent` tokens of the bots chat apps and social networks send to build link previews
+const UNFURLERS: [&str; 12] = [
+    "Slackbot",
+    "Twitterbot",
+    "facebookexternalhit",
+    "Facebot",
+    "LinkedInBot",
+    "Discordbot",
+    "TelegramBot",
+    "WhatsApp",
+    "SkypeUriPreview",
+    "redditbot",
+    "Embedly",
+    "Mastodon",
+];
+
+/// Whether a `User-Agent` header belongs to a bot previewing the link rather than a visitor
+pub fn is_unfurler(user_agent: &str) -> bool {
+    UNFURLERS.iter().any(|token| user_agent.contains(token))
+}
+
+/// Whether the request should get the preview page instead of being redirected, with `UNFURL_CRAWLERS` on
+pub fn wants_preview(req: &HttpRequest, state: &AppState) -> bool {
+    state.unfurl_crawlers
+        && req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(is_unfurler)
+}
+
+/// Open Graph and Twitter card tags for the link under `key`, filled from the stored metadata of its destination.
+/// The destination itself isn't named, the preview isn't a click and password-protected links show no metadata.
+pub async fn preview(state: &AppState, key: &str, link: &Link) -> Result<HttpResponse, ApiError> {
+    let metadata = if link.password_hash.is_some() {
+        None
+    } else {
+        metadata::load(state.store.as_ref(), &[key.to_string()])
+            .await?
+            .pop()
+            .flatten()
+    };
+    let page = pages::unfurl_page(state, key, link, metadata.as_ref())?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        // Visitors of the same URL are redirected, caches must keep the preview for bots
+        .append_header((header::VARY, "User-Agent"))
+        .append_header((header::CACHE_CONTROL, "private, no-store"))
+        .body(page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unfurlers() {
+        assert!(is_unfurler(
+            "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)"
+        ));
+        assert!(is_unfurler("Twitterbot/1.0"));
+        assert!(is_unfurler(
+            "facebookexternalhit/1.1 (+http://www.facebook.com/externalhit_uatext.php)"
+        ));
+        assert!(!is_unfurler(
+            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0"
+        ));
+        assert!(!is_unfurler("curl/8.5.0"));
+    }
+}
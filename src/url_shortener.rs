@@ -1,52 +1,696 @@
-use rand::rngs::SmallRng;
+use async_trait::async_trait;
+use opentelemetry::trace::SpanKind;
+use opentelemetry::KeyValue;
 use rand::Rng;
+use sha2::{Digest, Sha256};
+use siphasher::sip128::{Hasher128, SipHasher13};
+use std::hash::Hasher;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
-/// Generates a shortened URL by combining a checksum of the original URL with a random part
-/// Hashing takes care of most of the collisions, but we still need to generate a random part to avoid collisions since CRC32 is not a secure hash function
-/// We accept that if the url is the same, the shortened url will be different because of the random part. We trade it for sake of analytics
-pub async fn get_url_slug(url: String, random_part: Option<String>) -> String {
-    let checksum = crc32fast::hash(url.as_bytes());
-    let encoded = base62::encode(checksum);
-    // We store old url and the order integer in redis using HSET command
-    // We can use the TTL feature for expiration date
-    format!("{}{}", encoded, random_part.unwrap_or("".to_string()))
+use crate::reserved::ReservedSlugs;
+use crate::storage::{StorageError, UrlStore};
+use crate::telemetry::traced;
+
+const BASE62_ALPHABET: &[u8; 62] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+/// Crockford's base32 in lowercase, without `i`, `l`, `o` and `u` that get mistaken for `1`, `0` and `v`
+const BASE32_ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+pub const DEFAULT_SLUG_LENGTH: usize = 7;
+/// Shortest and longest `SLUG_LENGTH`, 62^21 still fits into the 128 bits slugs are drawn from
+pub const MIN_SLUG_LENGTH: usize = 4;
+pub const MAX_SLUG_LENGTH: usize = 21;
+/// Bits of a snowflake id taken by the worker id and by the sequence within a millisecond
+const WORKER_ID_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+pub const MAX_WORKER_ID: u16 = (1 << WORKER_ID_BITS) - 1;
+/// Snowflake timestamps count milliseconds from 2024-01-01, which leaves 41 bits for about 70 years
+const SNOWFLAKE_EPOCH_MS: u64 = 1_704_067_200_000;
+
+/// Counter behind `counter` and `sequential` slugs, the ':' keeps it out of the slug namespace
+const SLUG_COUNTER_KEY: &str = "slugs:counter";
+
+/// Characters generated slugs are made of
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlugAlphabet {
+    #[default]
+    Base62,
+    /// Lowercase only, used when `CASE_INSENSITIVE_SLUGS` is set so every generated slug survives lowercasing
+    Base32,
 }
 
-/// Generates a random code using base62 encoding
-pub fn generate_random_code(rng: &mut SmallRng) -> String {
-    let random_number: u32 = rng.random();
-    base62::encode(random_number)
+impl SlugAlphabet {
+    fn symbols(self) -> &'static [u8] {
+        match self {
+            SlugAlphabet::Base62 => BASE62_ALPHABET,
+            SlugAlphabet::Base32 => BASE32_ALPHABET,
+        }
+    }
+
+    /// `length` digits of `number`
+    fn digits(self, mut number: u128, length: usize) -> String {
+        let symbols = self.symbols();
+        let base = symbols.len() as u128;
+        (0..length)
+            .map(|_| {
+                let digit = (number % base) as usize;
+                number /= base;
+                symbols[digit] as char
+            })
+            .collect()
+    }
+
+    /// `number` in as few digits as possible, most significant first
+    fn encode(self, number: u64) -> String {
+        match self {
+            SlugAlphabet::Base62 => base62::encode(number),
+            SlugAlphabet::Base32 => {
+                let mut digits = self.digits(u128::from(number), 13).into_bytes();
+                digits.reverse();
+                let start = digits.iter().position(|&d| d != b'0').unwrap_or(12);
+                String::from_utf8(digits[start..].to_vec()).expect("the alphabet is ASCII")
+            }
+        }
+    }
+
+    /// `number` like `encode`, left padded with zeros to at least `length` digits
+    fn encode_padded(self, number: u64, length: usize) -> String {
+        let digits = self.encode(number);
+        let zero = self.symbols()[0] as char;
+        let padding: String =
+            std::iter::repeat_n(zero, length.saturating_sub(digits.len())).collect();
+        padding + &digits
+    }
+}
+
+/// Slug generation scheme, selected with `SLUG_STRATEGY`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlugStrategyKind {
+    Random,
+    Counter,
+    Hash,
+    Sequential,
+    Snowflake,
+    Deterministic,
+}
+
+impl std::str::FromStr for SlugStrategyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(SlugStrategyKind::Random),
+            "counter" => Ok(SlugStrategyKind::Counter),
+            "hash" => Ok(SlugStrategyKind::Hash),
+            "sequential" => Ok(SlugStrategyKind::Sequential),
+            "snowflake" => Ok(SlugStrategyKind::Snowflake),
+            "deterministic" => Ok(SlugStrategyKind::Deterministic),
+            _ => Err(
+                "expected 'random', 'counter', 'hash', 'sequential', 'snowflake' or 'deterministic'"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// What happens when a generated slug is taken, selected with `COLLISION_POLICY`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Ask the strategy for another candidate of the same length, up to `MAX_COLLISION_ATTEMPTS` times
+    Retry,
+    /// Like `Retry`, but after every `after` collisions the candidates get one more random character
+    Grow { after: u32 },
+    /// Give up after the first candidate
+    FailFast,
+}
+
+impl CollisionPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            CollisionPolicy::Retry => "retry",
+            CollisionPolicy::Grow { .. } => "grow",
+            CollisionPolicy::FailFast => "fail_fast",
+        }
+    }
+
+    /// Candidates tried before giving up
+    pub fn attempts(self, max_attempts: u32) -> u32 {
+        match self {
+            CollisionPolicy::FailFast => 1,
+            _ => max_attempts,
+        }
+    }
+
+    /// The strategy's candidate for `attempt`, extended with random characters once `Grow` kicks in
+    pub fn candidate(self, slug: String, attempt: u32, alphabet: SlugAlphabet) -> String {
+        let CollisionPolicy::Grow { after } = self else {
+            return slug;
+        };
+        let extra = ((attempt - 1) / after) as usize;
+        if extra == 0 {
+            return slug;
+        }
+        slug + &alphabet.digits(rand::rng().random(), extra)
+    }
+}
+
+/// Produces candidate slugs for generated short links
+#[async_trait]
+pub trait SlugStrategy: Send + Sync {
+    /// Candidate slug for `url`, `attempt` starts at 1 and grows with every collision
+    async fn next_slug(&self, url: &str, attempt: u32) -> Result<String, StorageError>;
+
+    /// Whether every attempt's candidate can be generated up front and tried in one round trip.
+    /// Strategies whose candidates cost something, like numbers of a counter, take them one at a time.
+    fn batches_candidates(&self) -> bool {
+        true
+    }
+
+    /// Whether a link identical to the one already stored under its candidate gets that slug back instead
+    /// of a new one
+    fn shares_slugs(&self) -> bool {
+        false
+    }
+
+    /// Whether candidates can be generated for a dry run, strategies taking numbers of a counter would skip
+    /// them for good
+    fn previews_slugs(&self) -> bool {
+        true
+    }
+}
+
+/// Uniformly random slugs of a fixed length, they reveal nothing about the URL
+pub struct RandomSlugs {
+    pub length: usize,
+    pub alphabet: SlugAlphabet,
+}
+
+#[async_trait]
+impl SlugStrategy for RandomSlugs {
+    async fn next_slug(&self, _url: &str, _attempt: u32) -> Result<String, StorageError> {
+        Ok(self.alphabet.digits(rand::rng().random(), self.length))
+    }
+}
+
+/// Sequential slugs from a counter in the store, as short as possible but easy to enumerate
+pub struct CounterSlugs {
+    pub store: Arc<dyn UrlStore>,
+    pub alphabet: SlugAlphabet,
+}
+
+#[async_trait]
+impl SlugStrategy for CounterSlugs {
+    async fn next_slug(&self, _url: &str, _attempt: u32) -> Result<String, StorageError> {
+        // Every attempt takes a fresh number, numbers lost to aliases or failed writes are skipped
+        let number = self.store.increment(SLUG_COUNTER_KEY, 1).await?;
+        Ok(self.alphabet.encode(number))
+    }
+
+    /// Counter slugs only collide with aliases, numbers taken ahead would be skipped for good
+    fn batches_candidates(&self) -> bool {
+        false
+    }
+
+    fn previews_slugs(&self) -> bool {
+        false
+    }
+}
+
+/// Numbers of the slug counter reserved in blocks, so most slugs cost no storage round trip. Numbers of a
+/// block left unused when the instance stops are skipped, the counter only moves forward so they are never
+/// handed out twice. Slugs are padded to `length`, they only get longer once the numbers outgrow it.
+pub struct SequentialSlugs {
+    store: Arc<dyn UrlStore>,
+    alphabet: SlugAlphabet,
+    length: usize,
+    block_size: u64,
+    /// Next number to hand out and the end of the reserved block, exclusive
+    block: Mutex<(u64, u64)>,
+}
+
+impl SequentialSlugs {
+    pub fn new(
+        store: Arc<dyn UrlStore>,
+        alphabet: SlugAlphabet,
+        length: usize,
+        block_size: u64,
+    ) -> Self {
+        SequentialSlugs {
+            store,
+            alphabet,
+            length,
+            block_size,
+            block: Mutex::new((0, 0)),
+        }
+    }
+}
+
+#[async_trait]
+impl SlugStrategy for SequentialSlugs {
+    async fn next_slug(&self, _url: &str, _attempt: u32) -> Result<String, StorageError> {
+        let mut block = self.block.lock().await;
+        if block.0 == block.1 {
+            let end = self
+                .store
+                .increment(SLUG_COUNTER_KEY, self.block_size)
+                .await?;
+            *block = (end - self.block_size + 1, end + 1);
+        }
+        let number = block.0;
+        block.0 += 1;
+        Ok(self.alphabet.encode_padded(number, self.length))
+    }
+
+    /// Collisions only happen with aliases, numbers taken ahead would be skipped for good
+    fn batches_candidates(&self) -> bool {
+        false
+    }
+
+    fn previews_slugs(&self) -> bool {
+        false
+    }
+}
+
+/// Ids made of the milliseconds since `SNOWFLAKE_EPOCH_MS`, the worker id and a sequence within the
+/// millisecond, unique across instances with distinct worker ids without asking the store. The slug is the
+/// lowest `length` digits of the id, shorter slugs repeat once the timestamp digits they keep wrap around and
+/// rely on collision resolution from then on.
+pub struct SnowflakeSlugs {
+    alphabet: SlugAlphabet,
+    length: usize,
+    worker_id: u16,
+    /// Millisecond and sequence of the last id
+    last: std::sync::Mutex<(u64, u64)>,
+}
+
+impl SnowflakeSlugs {
+    pub fn new(alphabet: SlugAlphabet, length: usize, worker_id: u16) -> Self {
+        SnowflakeSlugs {
+            alphabet,
+            length,
+            worker_id: worker_id & MAX_WORKER_ID,
+            last: std::sync::Mutex::new((0, 0)),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        let mut last = self.last.lock().unwrap();
+        loop {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default()
+                .saturating_sub(SNOWFLAKE_EPOCH_MS);
+            // A clock stepping back keeps counting on the last millisecond instead of repeating ids
+            let millis = now.max(last.0);
+            let sequence = if millis == last.0 { last.1 + 1 } else { 0 };
+            if sequence < 1 << SEQUENCE_BITS {
+                *last = (millis, sequence);
+                return (millis << (WORKER_ID_BITS + SEQUENCE_BITS))
+                    | (u64::from(self.worker_id) << SEQUENCE_BITS)
+                    | sequence;
+            }
+            // Every id of this millisecond is taken, wait for the next one
+            std::thread::yield_now();
+        }
+    }
+}
+
+#[async_trait]
+impl SlugStrategy for SnowflakeSlugs {
+    async fn next_slug(&self, _url: &str, _attempt: u32) -> Result<String, StorageError> {
+        Ok(self
+            .alphabet
+            .digits(u128::from(self.next_id()), self.length))
+    }
+}
+
+/// Slugs derived from a keyed SipHash of the normalized URL, the same URL gets the same slug on every instance
+/// sharing `SLUG_HASH_KEY`, and without the key nobody can tell which slug a URL will get. A candidate taken by
+/// a different link is a genuine collision, later attempts take one more digit of the same hash.
+pub struct DeterministicSlugs {
+    key: [u8; 16],
+    length: usize,
+    alphabet: SlugAlphabet,
+}
+
+impl DeterministicSlugs {
+    pub fn new(secret: &str, length: usize, alphabet: SlugAlphabet) -> Self {
+        let digest = Sha256::digest(secret.as_bytes());
+        DeterministicSlugs {
+            key: digest[..16].try_into().expect("SHA-256 has 32 bytes"),
+            length,
+            alphabet,
+        }
+    }
+}
+
+#[async_trait]
+impl SlugStrategy for DeterministicSlugs {
+    async fn next_slug(&self, url: &str, attempt: u32) -> Result<String, StorageError> {
+        let mut hasher = SipHasher13::new_with_key(&self.key);
+        hasher.write(url.as_bytes());
+        let number = hasher.finish128().as_u128();
+        let length = (self.length + attempt as usize - 1).min(MAX_SLUG_LENGTH);
+        Ok(self.alphabet.digits(number, length))
+    }
+
+    /// Candidates are only worth trying once the previous one turned out to hold a different link
+    fn batches_candidates(&self) -> bool {
+        false
+    }
+
+    fn shares_slugs(&self) -> bool {
+        true
+    }
+}
+
+/// Slugs derived from a SHA-256 of the URL, the same URL always gets the same first candidate.
+/// Later attempts hash the URL together with the attempt number.
+pub struct HashSlugs {
+    pub length: usize,
+    pub alphabet: SlugAlphabet,
+}
+
+#[async_trait]
+impl SlugStrategy for HashSlugs {
+    async fn next_slug(&self, url: &str, attempt: u32) -> Result<String, StorageError> {
+        let mut hasher = Sha256::new();
+        hasher.update(url.as_bytes());
+        if attempt > 1 {
+            hasher.update(attempt.to_be_bytes());
+        }
+        let digest = hasher.finalize();
+        let number = u128::from_be_bytes(digest[..16].try_into().expect("SHA-256 has 32 bytes"));
+        Ok(self.alphabet.digits(number, self.length))
+    }
+}
+
+/// Records a span for every candidate slug, tying slug generation time to the request that asked for it
+struct TracedSlugs {
+    kind: SlugStrategyKind,
+    inner: Arc<dyn SlugStrategy>,
+}
+
+#[async_trait]
+impl SlugStrategy for TracedSlugs {
+    async fn next_slug(&self, url: &str, attempt: u32) -> Result<String, StorageError> {
+        let strategy = match self.kind {
+            SlugStrategyKind::Random => "random",
+            SlugStrategyKind::Counter => "counter",
+            SlugStrategyKind::Hash => "hash",
+            SlugStrategyKind::Sequential => "sequential",
+            SlugStrategyKind::Snowflake => "snowflake",
+            SlugStrategyKind::Deterministic => "deterministic",
+        };
+        let attributes = vec![
+            KeyValue::new("slug.strategy", strategy),
+            KeyValue::new("slug.attempt", i64::from(attempt)),
+        ];
+        traced(
+            "generate slug",
+            SpanKind::Internal,
+            attributes,
+            self.inner.next_slug(url, attempt),
+        )
+        .await
+    }
+
+    fn batches_candidates(&self) -> bool {
+        self.inner.batches_candidates()
+    }
+
+    fn shares_slugs(&self) -> bool {
+        self.inner.shares_slugs()
+    }
+
+    fn previews_slugs(&self) -> bool {
+        self.inner.previews_slugs()
+    }
+}
+
+/// Creates the strategy configured at startup
+pub fn slug_strategy(
+    kind: SlugStrategyKind,
+    length: usize,
+    alphabet: SlugAlphabet,
+    block_size: u64,
+    worker_id: u16,
+    hash_key: &str,
+    store: Arc<dyn UrlStore>,
+) -> Arc<dyn SlugStrategy> {
+    let inner: Arc<dyn SlugStrategy> = match kind {
+        SlugStrategyKind::Random => Arc::new(RandomSlugs { length, alphabet }),
+        SlugStrategyKind::Counter => Arc::new(CounterSlugs { store, alphabet }),
+        SlugStrategyKind::Hash => Arc::new(HashSlugs { length, alphabet }),
+        SlugStrategyKind::Sequential => {
+            Arc::new(SequentialSlugs::new(store, alphabet, length, block_size))
+        }
+        SlugStrategyKind::Snowflake => Arc::new(SnowflakeSlugs::new(alphabet, length, worker_id)),
+        SlugStrategyKind::Deterministic => {
+            Arc::new(DeterministicSlugs::new(hash_key, length, alphabet))
+        }
+    };
+    Arc::new(TracedSlugs { kind, inner })
+}
+
+pub const MIN_ALIAS_LENGTH: usize = 3;
+pub const MAX_ALIAS_LENGTH: usize = 64;
+
+#[derive(Debug, PartialEq)]
+pub enum AliasError {
+    InvalidLength,
+    InvalidCharacters,
+    Reserved,
+}
+
+impl std::fmt::Display for AliasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AliasError::InvalidLength => write!(
+                f,
+                "Alias must be between {} and {} characters long",
+                MIN_ALIAS_LENGTH, MAX_ALIAS_LENGTH
+            ),
+            AliasError::InvalidCharacters => {
+                write!(f, "Alias may only contain letters, digits, '-' and '_'")
+            }
+            AliasError::Reserved => write!(f, "Alias is reserved"),
+        }
+    }
+}
+
+/// Validates a user supplied alias, aliases use the base62 alphabet of generated slugs plus `-` and `_` as word separators
+pub fn validate_alias(alias: &str, reserved: &ReservedSlugs) -> Result<(), AliasError> {
+    if alias.len() < MIN_ALIAS_LENGTH || alias.len() > MAX_ALIAS_LENGTH {
+        return Err(AliasError::InvalidLength);
+    }
+    if !alias
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(AliasError::InvalidCharacters);
+    }
+    if reserved.is_reserved(alias) {
+        return Err(AliasError::Reserved);
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand::SeedableRng;
+    use crate::memory::MemoryStore;
 
-    #[test]
-    fn test_get_shortened_url_happy_path() {
-        // Arrange
-        let test_url = "https://example.com/very/long/url/that/needs/shortening".to_string();
-        let test_random_part = "abc123".to_string();
+    #[tokio::test]
+    async fn test_random_slugs() {
+        let slugs = RandomSlugs {
+            length: 7,
+            alphabet: SlugAlphabet::Base62,
+        };
+        let first = slugs.next_slug("https://example.com", 1).await.unwrap();
+        let second = slugs.next_slug("https://example.com", 1).await.unwrap();
+
+        assert_eq!(first.len(), 7);
+        assert!(first.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(first, second);
+    }
 
-        // Act - use tokio::runtime to run the async function
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        let result = rt.block_on(get_url_slug(test_url.clone(), Some(test_random_part.clone())));
+    #[tokio::test]
+    async fn test_counter_slugs_are_sequential() {
+        let slugs = CounterSlugs {
+            store: Arc::new(MemoryStore::new()),
+            alphabet: SlugAlphabet::Base62,
+        };
 
-        // Assert
-        assert!(result.contains(&test_random_part));
+        assert_eq!(slugs.next_slug("https://a.com", 1).await.unwrap(), "1");
+        assert_eq!(slugs.next_slug("https://b.com", 1).await.unwrap(), "2");
+        assert_eq!(slugs.next_slug("https://b.com", 2).await.unwrap(), "3");
+    }
+
+    #[tokio::test]
+    async fn test_sequential_slugs_reserve_blocks() {
+        let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+        let slugs = SequentialSlugs::new(store.clone(), SlugAlphabet::Base62, 4, 3);
+        for expected in ["0001", "0002", "0003", "0004"] {
+            assert_eq!(slugs.next_slug("https://a.com", 1).await.unwrap(), expected);
+        }
+        // Two blocks taken, the next instance starts after them
+        assert_eq!(
+            store.get(SLUG_COUNTER_KEY).await.unwrap().as_deref(),
+            Some("6")
+        );
+        let restarted = SequentialSlugs::new(store, SlugAlphabet::Base62, 4, 3);
+        assert_eq!(
+            restarted.next_slug("https://a.com", 1).await.unwrap(),
+            "0007"
+        );
+        assert_eq!(SlugAlphabet::Base62.encode_padded(62u64.pow(4), 4), "10000");
+    }
+
+    #[tokio::test]
+    async fn test_snowflake_slugs_are_unique_per_worker() {
+        let first = SnowflakeSlugs::new(SlugAlphabet::Base62, 11, 1);
+        let second = SnowflakeSlugs::new(SlugAlphabet::Base62, 11, 2);
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..5000 {
+            for slugs in [&first, &second] {
+                let slug = slugs.next_slug("https://a.com", 1).await.unwrap();
+                assert_eq!(slug.len(), 11);
+                assert!(seen.insert(slug));
+            }
+        }
+
+        let id = first.next_id();
+        assert_eq!((id >> SEQUENCE_BITS) & u64::from(MAX_WORKER_ID), 1);
+        assert!(first.next_id() > id);
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_slugs_depend_on_the_key() {
+        let slugs = DeterministicSlugs::new("secret", 7, SlugAlphabet::Base62);
+        let first = slugs.next_slug("https://example.com", 1).await.unwrap();
+        assert_eq!(first.len(), 7);
+        assert_eq!(
+            DeterministicSlugs::new("secret", 7, SlugAlphabet::Base62)
+                .next_slug("https://example.com", 1)
+                .await
+                .unwrap(),
+            first
+        );
+        // Later attempts lengthen the same hash
+        let second = slugs.next_slug("https://example.com", 2).await.unwrap();
+        assert_eq!(second.len(), 8);
+        assert!(second.starts_with(&first));
+        assert_ne!(
+            DeterministicSlugs::new("other", 7, SlugAlphabet::Base62)
+                .next_slug("https://example.com", 1)
+                .await
+                .unwrap(),
+            first
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_slugs_are_stable_per_attempt() {
+        let slugs = HashSlugs {
+            length: 8,
+            alphabet: SlugAlphabet::Base62,
+        };
+        let first = slugs.next_slug("https://example.com", 1).await.unwrap();
+
+        assert_eq!(first.len(), 8);
+        assert_eq!(
+            slugs.next_slug("https://example.com", 1).await.unwrap(),
+            first
+        );
+        assert_ne!(
+            slugs.next_slug("https://example.com", 2).await.unwrap(),
+            first
+        );
+        assert_ne!(
+            slugs.next_slug("https://example.org", 1).await.unwrap(),
+            first
+        );
+    }
+
+    #[tokio::test]
+    async fn test_base32_slugs_are_lowercase() {
+        let slugs = RandomSlugs {
+            length: 7,
+            alphabet: SlugAlphabet::Base32,
+        };
+        let slug = slugs.next_slug("https://example.com", 1).await.unwrap();
+        assert_eq!(slug.len(), 7);
+        assert!(slug.bytes().all(|c| BASE32_ALPHABET.contains(&c)));
+
+        let counter = CounterSlugs {
+            store: Arc::new(MemoryStore::new()),
+            alphabet: SlugAlphabet::Base32,
+        };
+        for expected in ["1", "2"] {
+            assert_eq!(
+                counter.next_slug("https://a.com", 1).await.unwrap(),
+                expected
+            );
+        }
+        assert_eq!(SlugAlphabet::Base32.encode(0), "0");
+        assert_eq!(SlugAlphabet::Base32.encode(31), "z");
+        assert_eq!(SlugAlphabet::Base32.encode(32), "10");
+        assert_eq!(SlugAlphabet::Base32.encode(u64::MAX).len(), 13);
+    }
+
+    #[test]
+    fn test_grow_policy_lengthens_later_candidates() {
+        let grow = CollisionPolicy::Grow { after: 2 };
+        let candidate = |attempt| grow.candidate("abcd".to_string(), attempt, SlugAlphabet::Base62);
+        assert_eq!(candidate(1), "abcd");
+        assert_eq!(candidate(2), "abcd");
+        assert_eq!(candidate(3).len(), 5);
+        assert!(candidate(5).starts_with("abcd"));
+        assert_eq!(candidate(5).len(), 6);
 
-        assert!(!result.is_empty());
-        assert!(result.chars().all(|c| c.is_alphanumeric()));
-        assert!(result.ends_with(&test_random_part));
+        assert_eq!(
+            CollisionPolicy::Retry.candidate("abcd".to_string(), 9, SlugAlphabet::Base62),
+            "abcd"
+        );
+        assert_eq!(CollisionPolicy::FailFast.attempts(5), 1);
+        assert_eq!(grow.attempts(5), 5);
     }
 
     #[test]
-    fn test_generate_random_code() {
-        let mut rng = SmallRng::from_os_rng();
-        let code = generate_random_code(&mut rng);
+    fn test_validate_alias() {
+        let reserved = ReservedSlugs::new(["admin"]);
 
-        assert!(!code.is_empty());
-        assert!(code.chars().all(|c| c.is_alphanumeric()));
+        assert_eq!(validate_alias("my-launch", &reserved), Ok(()));
+        assert_eq!(validate_alias("Launch_2025", &reserved), Ok(()));
+        assert_eq!(
+            validate_alias("ab", &reserved),
+            Err(AliasError::InvalidLength)
+        );
+        assert_eq!(
+            validate_alias(&"a".repeat(MAX_ALIAS_LENGTH + 1), &reserved),
+            Err(AliasError::InvalidLength)
+        );
+        assert_eq!(
+            validate_alias("my/launch", &reserved),
+            Err(AliasError::InvalidCharacters)
+        );
+        assert_eq!(
+            validate_alias("zażółć", &reserved),
+            Err(AliasError::InvalidCharacters)
+        );
+        assert_eq!(
+            validate_alias("shorten-url", &reserved),
+            Err(AliasError::Reserved)
+        );
+        assert_eq!(
+            validate_alias("Metrics", &reserved),
+            Err(AliasError::Reserved)
+        );
+        assert_eq!(
+            validate_alias("admin", &reserved),
+            Err(AliasError::Reserved)
+        );
     }
 }
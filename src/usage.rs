@@ -0,0 +1,227 @@
+use actix_web::web::Data;
+use actix_web::{get, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::auth::{missing_key, AuthenticatedKey};
+use crate::error::ApiError;
+use crate::storage::CountBatch;
+use crate::tenants;
+use crate::{AppState, Creator};
+
+/// Monthly rollups are kept a little over a year, so the last 12 months can always be reported
+const RETENTION_SECONDS: usize = 400 * 24 * 60 * 60;
+const REPORTED_MONTHS: u32 = 12;
+
+/// Calendar month of `at` like `2024-05`, the rollups are keyed by it
+fn month_of(at: DateTime<Utc>) -> String {
+    at.format("%Y-%m").to_string()
+}
+
+/// Counter of the links the API key `id` created in `month`
+fn links_key(id: &str, month: &str) -> String {
+    format!("apikey:{}:links_created:{}", id, month)
+}
+
+/// Hash of the redirects served for the links of the API key `id`, with a field per month
+fn redirects_key(id: &str) -> String {
+    format!("apikey:{}:redirects", id)
+}
+
+/// Seconds until the next calendar month starts, when a used up monthly quota frees up again
+fn seconds_until_next_month(now: DateTime<Utc>) -> usize {
+    let next = NaiveDate::from_ymd_opt(now.year(), now.month(), 1)
+        .and_then(|first| first.checked_add_months(Months::new(1)))
+        .and_then(|next| next.and_hms_opt(0, 0, 0))
+        .expect("the first of next month is a valid date")
+        .and_utc();
+    (next - now).num_seconds().max(1) as usize
+}
+
+/// Counts a redirect of a link created with the API key `id`
+pub fn count_redirect(counts: &mut CountBatch, id: &str, at: DateTime<Utc>) {
+    counts.increment_field(&redirects_key(id), &month_of(at), RETENTION_SECONDS);
+}
+
+/// Counts a link about to be stored against the tenant, the API key and their quotas
+pub async fn claim_link(state: &AppState, creator: &Creator) -> Result<(), ApiError> {
+    tenants::claim_link(state, creator).await?;
+    if let Err(err) = claim_monthly(state, creator, Utc::now()).await {
+        tenants::release_link(state, creator).await;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Gives back a claimed link that ended up not being stored
+pub async fn release_link(state: &AppState, creator: &Creator) {
+    tenants::release_link(state, creator).await;
+    if let Some(id) = &creator.api_key {
+        release_monthly(state, id, Utc::now()).await;
+    }
+}
+
+async fn claim_monthly(
+    state: &AppState,
+    creator: &Creator,
+    now: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    let Some(id) = &creator.api_key else {
+        return Ok(());
+    };
+    // The month is part of the key, the window only decides how long the rollup is kept
+    let (created, _) = state
+        .store
+        .incr_window(&links_key(id, &month_of(now)), RETENTION_SECONDS)
+        .await?;
+    if let Some(quota) = creator.monthly_quota.filter(|quota| created > *quota) {
+        release_monthly(state, id, now).await;
+        return Err(ApiError::MonthlyQuotaExceeded {
+            quota,
+            retry_after_seconds: seconds_until_next_month(now),
+        });
+    }
+    Ok(())
+}
+
+async fn release_monthly(state: &AppState, id: &str, now: DateTime<Utc>) {
+    let key = links_key(id, &month_of(now));
+    if let Err(err) = state.store.decrement(&key).await {
+        log::warn!("Failed to release a link counted on {}: {}", key, err);
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct MonthlyUsage {
+    month: String,
+    links_created: u64,
+    redirects: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct KeyUsage {
+    id: String,
+    name: String,
+    /// The quota of the tenant for keys that belong to one
+    quota: Option<u64>,
+    /// Links created towards `quota`, shared by every key of the tenant for keys that belong to one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_used: Option<u64>,
+    monthly_quota: Option<u64>,
+    /// The last 12 months, the current one first
+    months: Vec<MonthlyUsage>,
+}
+
+/// Links created and redirects served per month for the API key the request is made with
+#[get(
+    "/api/me/usage",
+    wrap = "actix_web::middleware::from_fn(crate::auth::require_api_key)"
+)]
+async fn my_usage(req: HttpRequest, state: Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let Some(key) = req.extensions().get::<AuthenticatedKey>().cloned() else {
+        return Err(missing_key());
+    };
+    let creator = Creator::from_request(&req, None);
+    let quota = tenants::quota(&state, &creator).await?;
+    let quota_used = match (quota, tenants::links_counter(&creator)) {
+        (Some(_), Some(counter)) => Some(
+            state
+                .store
+                .get(&counter)
+                .await?
+                .and_then(|used| used.parse().ok())
+                .unwrap_or(0),
+        ),
+        _ => None,
+    };
+    Ok(HttpResponse::Ok().json(KeyUsage {
+        months: monthly_usage(&state, &key.id, Utc::now()).await?,
+        id: key.id,
+        name: key.key.name,
+        quota,
+        quota_used,
+        monthly_quota: creator.monthly_quota,
+    }))
+}
+
+/// Rollups of the API key `id` for the 12 months up to the one of `now`, the latest first
+async fn monthly_usage(
+    state: &AppState,
+    id: &str,
+    now: DateTime<Utc>,
+) -> Result<Vec<MonthlyUsage>, ApiError> {
+    let months: Vec<String> = (0..REPORTED_MONTHS)
+        .filter_map(|back| now.checked_sub_months(Months::new(back)))
+        .map(month_of)
+        .collect();
+    let keys: Vec<String> = months.iter().map(|month| links_key(id, month)).collect();
+    let created = state.store.get_many(&keys).await?;
+    let redirects = state.store.hash_fields(&redirects_key(id)).await?;
+    let count = |value: Option<&String>| {
+        value
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(0)
+            .max(0) as u64
+    };
+    Ok(months
+        .into_iter()
+        .zip(created)
+        .map(|(month, created)| MonthlyUsage {
+            links_created: count(created.as_ref()),
+            redirects: count(redirects.get(&month)),
+            month,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::{AppConfig, UrlShortener};
+    use chrono::TimeZone;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_monthly_quota_frees_up_next_month() {
+        let now = Utc.with_ymd_and_hms(2024, 12, 31, 23, 0, 0).unwrap();
+        assert_eq!(month_of(now), "2024-12");
+        assert_eq!(seconds_until_next_month(now), 60 * 60);
+    }
+
+    #[tokio::test]
+    async fn test_links_and_redirects_roll_up_per_month() {
+        let shortener =
+            UrlShortener::with_store(&AppConfig::default(), Arc::new(MemoryStore::new()))
+                .await
+                .unwrap();
+        let state = &shortener.state;
+        let creator = Creator {
+            api_key: Some("k1".to_string()),
+            monthly_quota: Some(2),
+            ..Default::default()
+        };
+        let now = Utc::now();
+        claim_monthly(state, &creator, now).await.unwrap();
+        claim_monthly(state, &creator, now).await.unwrap();
+        let err = claim_monthly(state, &creator, now).await.unwrap_err();
+        assert_eq!(err.code(), "monthly_quota_exceeded");
+
+        let mut counts = CountBatch::default();
+        count_redirect(&mut counts, "k1", now);
+        count_redirect(&mut counts, "k1", now);
+        state.store.write_counts(&counts).await.unwrap();
+
+        let months = monthly_usage(state, "k1", now).await.unwrap();
+        assert_eq!(months.len(), 12);
+        assert_eq!(
+            months[0],
+            MonthlyUsage {
+                month: month_of(now),
+                links_created: 2,
+                redirects: 2,
+            }
+        );
+        assert_eq!(months[1].links_created, 0);
+    }
+}
@@ -0,0 +1,391 @@
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::web::{self, Data};
+use actix_web::{post, Error, FromRequest, HttpRequest, HttpResponse};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Duration, Utc};
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+use crate::body::JsonBody;
+use crate::error::ApiError;
+use crate::sso::{sso_user_id, SsoConfig, SsoTokens};
+use crate::storage::{StorageError, UrlStore};
+use crate::AppState;
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_PASSWORD_LENGTH: usize = 1024;
+const MAX_EMAIL_LENGTH: usize = 254;
+
+#[derive(Clone, Debug)]
+pub struct UsersConfig {
+    /// HMAC secret for session tokens, a random one is generated when unset
+    pub jwt_secret: Option<String>,
+    pub session_ttl_seconds: u64,
+    /// Identity provider whose tokens are accepted as well, `None` only accepts session tokens
+    pub sso: Option<SsoConfig>,
+}
+
+/// Stored under `account:<email>`, emails are the login name
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Account {
+    id: String,
+    email: String,
+    password_hash: String,
+    created_at: DateTime<Utc>,
+}
+
+fn account_key(email: &str) -> String {
+    format!("account:{}", email)
+}
+
+/// Email of the user with the id, accounts are keyed by email
+fn user_email_key(user_id: &str) -> String {
+    format!("user_email:{}", user_id)
+}
+
+/// Email address of the user, `None` for accounts registered before it was recorded by id
+pub async fn user_email(
+    store: &dyn UrlStore,
+    user_id: &str,
+) -> Result<Option<String>, StorageError> {
+    store.get(&user_email_key(user_id)).await
+}
+
+/// Set of slugs created by the user
+pub fn owned_links_key(user_id: &str) -> String {
+    format!("links:{}", user_id)
+}
+
+fn generate_user_id() -> String {
+    let id: String = rand::rng()
+        .sample_iter(rand::distr::Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    format!("u_{}", id)
+}
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt_bytes: [u8; 16] = rand::rng().random();
+    let salt = SaltString::encode_b64(&salt_bytes)?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Hash logins for unknown emails are checked against, so they take as long as wrong passwords
+fn dummy_password_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("not a password").expect("hashing a fixed password"))
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    })
+}
+
+fn validate_credentials(email: &str, password: &str) -> Result<(), String> {
+    let valid_email = email.len() <= MAX_EMAIL_LENGTH
+        && email
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'));
+    if !valid_email {
+        return Err("A valid email address is required".to_string());
+    }
+    if password.chars().count() < MIN_PASSWORD_LENGTH || password.len() > MAX_PASSWORD_LENGTH {
+        return Err(format!(
+            "Password must be between {} and {} characters long",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    exp: i64,
+}
+
+/// Issues and verifies the HS256 JWTs handed out on login
+pub struct SessionTokens {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    ttl_seconds: u64,
+}
+
+impl SessionTokens {
+    pub fn new(secret: &[u8], ttl_seconds: u64) -> Self {
+        SessionTokens {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            ttl_seconds,
+        }
+    }
+
+    pub fn from_config(config: &UsersConfig) -> Self {
+        match &config.jwt_secret {
+            Some(secret) => Self::new(secret.as_bytes(), config.session_ttl_seconds),
+            None => {
+                log::warn!("JWT_SECRET is not set, sessions won't survive restarts or work across instances");
+                let secret: [u8; 32] = rand::rng().random();
+                Self::new(&secret, config.session_ttl_seconds)
+            }
+        }
+    }
+
+    pub fn issue(&self, user_id: &str, now: DateTime<Utc>) -> (String, DateTime<Utc>) {
+        let expires_at = now + Duration::seconds(self.ttl_seconds as i64);
+        let claims = Claims {
+            sub: user_id.to_string(),
+            exp: expires_at.timestamp(),
+        };
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &self.encoding)
+            .expect("HS256 signing can't fail");
+        (token, expires_at)
+    }
+
+    /// Returns the user id of a valid, unexpired token
+    pub fn verify(&self, token: &str) -> Option<String> {
+        jsonwebtoken::decode::<Claims>(token, &self.decoding, &Validation::default())
+            .ok()
+            .map(|data| data.claims.sub)
+    }
+}
+
+/// User authenticated with an `Authorization: Bearer <token>` header, rejects the request otherwise
+pub struct CurrentUser {
+    pub id: String,
+}
+
+/// Like `CurrentUser`, but requests without an `Authorization` header pass as anonymous
+pub struct MaybeUser(pub Option<CurrentUser>);
+
+/// User id of a session token, or of a token of the identity provider when `JWT_JWKS_URL` is set
+async fn verify_token(state: &AppState, token: &str) -> Option<String> {
+    match &state.sso {
+        Some(sso) if SsoTokens::is_sso_token(token) => {
+            sso.verify(token).await.map(|subject| sso_user_id(&subject))
+        }
+        _ => state.sessions.verify(token),
+    }
+}
+
+fn unauthorized(code: &'static str, message: &str) -> Error {
+    ApiError::Unauthorized {
+        code,
+        message: message.to_string(),
+    }
+    .into()
+}
+
+async fn bearer_user(req: &HttpRequest) -> Result<Option<CurrentUser>, Error> {
+    let Some(value) = req.headers().get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    let token = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| {
+            unauthorized(
+                "invalid_token",
+                "Expected an 'Authorization: Bearer <token>' header.",
+            )
+        })?;
+    let state = req
+        .app_data::<Data<AppState>>()
+        .expect("AppState is registered on the app");
+    match verify_token(state, token.trim()).await {
+        Some(id) => Ok(Some(CurrentUser { id })),
+        None => Err(unauthorized(
+            "invalid_token",
+            "The session token is invalid or expired, log in again.",
+        )),
+    }
+}
+
+impl FromRequest for CurrentUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            bearer_user(&req).await?.ok_or_else(|| {
+                unauthorized(
+                    "missing_token",
+                    "Log in and pass the token in an 'Authorization: Bearer <token>' header.",
+                )
+            })
+        })
+    }
+}
+
+impl FromRequest for MaybeUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { bearer_user(&req).await.map(MaybeUser) })
+    }
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    email: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct UserResponse {
+    id: String,
+    email: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[post(
+    "/api/users",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)"
+)]
+async fn register(
+    body: JsonBody<Credentials>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let Credentials { email, password } = body.into_inner();
+    let email = email.trim().to_lowercase();
+    validate_credentials(&email, &password)
+        .map_err(|message| ApiError::validation("invalid_credentials", message))?;
+
+    // Argon2 is deliberately slow, keep it off the async workers
+    let password_hash = web::block(move || hash_password(&password))
+        .await
+        .map_err(|err| ApiError::Internal(format!("Failed to hash password: {}", err)))?
+        .map_err(|err| ApiError::Internal(format!("Failed to hash password: {}", err)))?;
+    let account = Account {
+        id: generate_user_id(),
+        email,
+        password_hash,
+        created_at: Utc::now(),
+    };
+    let record = serde_json::to_string(&account).expect("account is always serializable");
+
+    if !state
+        .store
+        .set(&account_key(&account.email), &record, None)
+        .await?
+    {
+        return Err(ApiError::Conflict {
+            code: "email_taken",
+            message: format!("An account for '{}' already exists.", account.email),
+        });
+    }
+    // Only expiry emails need it, so the account stays usable without
+    if let Err(err) = state
+        .store
+        .set(&user_email_key(&account.id), &account.email, None)
+        .await
+    {
+        log::warn!("Failed to record the email of {}: {}", account.id, err);
+    }
+    Ok(HttpResponse::Created().json(UserResponse {
+        id: account.id,
+        email: account.email,
+        created_at: account.created_at,
+    }))
+}
+
+#[post(
+    "/api/users/login",
+    wrap = "actix_web::middleware::from_fn(crate::ratelimit::rate_limit)"
+)]
+async fn login(
+    body: JsonBody<Credentials>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let Credentials { email, password } = body.into_inner();
+    let account = state
+        .store
+        .get(&account_key(&email.trim().to_lowercase()))
+        .await?
+        .and_then(|record| serde_json::from_str::<Account>(&record).ok());
+
+    // Unknown emails are verified as well, answering them faster would tell which accounts exist
+    let password_hash = account
+        .as_ref()
+        .map(|account| account.password_hash.clone());
+    let valid = web::block(move || match &password_hash {
+        Some(password_hash) => verify_password(&password, password_hash),
+        None => verify_password(&password, dummy_password_hash()),
+    })
+    .await
+    .unwrap_or(false);
+    let Some(account) = account.filter(|_| valid) else {
+        return Err(invalid_login());
+    };
+
+    let (token, expires_at) = state.sessions.issue(&account.id, Utc::now());
+    Ok(HttpResponse::Ok().json(LoginResponse { token, expires_at }))
+}
+
+/// Same error for unknown emails and wrong passwords, so accounts can't be enumerated
+fn invalid_login() -> ApiError {
+    ApiError::Unauthorized {
+        code: "invalid_credentials",
+        message: "Email or password is incorrect.".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hash_roundtrip() {
+        let hash = hash_password("correct horse").unwrap();
+
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[test]
+    fn test_session_tokens() {
+        let tokens = SessionTokens::new(b"test secret", 3600);
+        let (token, expires_at) = tokens.issue("u_1", Utc::now());
+
+        assert!(expires_at > Utc::now());
+        assert_eq!(tokens.verify(&token), Some("u_1".to_string()));
+        assert_eq!(
+            SessionTokens::new(b"other secret", 3600).verify(&token),
+            None
+        );
+
+        let (expired, _) = tokens.issue("u_1", Utc::now() - Duration::hours(2));
+        assert_eq!(tokens.verify(&expired), None);
+    }
+
+    #[test]
+    fn test_validate_credentials() {
+        assert!(validate_credentials("ann@example.com", "long enough").is_ok());
+        assert!(validate_credentials("ann", "long enough").is_err());
+        assert!(validate_credentials("@example.com", "long enough").is_err());
+        assert!(validate_credentials("ann@example.com", "short").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use url::{Host, Url};
+
+/// Default `MAX_URL_LENGTH`, the longest URL browsers and crawlers reliably handle
+pub const DEFAULT_MAX_URL_LENGTH: usize = 2048;
+
+#[derive(Debug, PartialEq)]
+pub enum UrlValidationError {
+    Malformed(String),
+    UnsupportedScheme(String),
+    MissingHost,
+    PrivateTarget(String),
+    TooLong { length: usize, max: usize },
+}
+
+impl fmt::Display for UrlValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlValidationError::Malformed(err) => write!(f, "URL is malformed: {}", err),
+            UrlValidationError::UnsupportedScheme(scheme) => write!(
+                f,
+                "Scheme '{}' is not supported, only http and https URLs can be shortened",
+                scheme
+            ),
+            UrlValidationError::MissingHost => write!(f, "URL must contain a host"),
+            UrlValidationError::PrivateTarget(host) => write!(
+                f,
+                "URLs pointing at local or private addresses ({}) are not allowed",
+                host
+            ),
+            UrlValidationError::TooLong { length, max } => write!(
+                f,
+                "URL is {} characters long, at most {} are allowed",
+                length, max
+            ),
+        }
+    }
+}
+
+/// Validates a destination URL and returns its normalized form.
+/// Normalizing means `HTTPS://Example.com:443/a/` and `https://example.com/a` are stored as the same link.
+/// Hosts are only checked literally, names that resolve to private addresses are not looked up.
+/// `max_length` applies to the normalized URL, which is what visitors get redirected to.
+pub fn validate_and_normalize(raw: &str, max_length: usize) -> Result<String, UrlValidationError> {
+    let mut url =
+        Url::parse(raw.trim()).map_err(|err| UrlValidationError::Malformed(err.to_string()))?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(UrlValidationError::UnsupportedScheme(
+            url.scheme().to_string(),
+        ));
+    }
+
+    match url.host() {
+        None => return Err(UrlValidationError::MissingHost),
+        Some(Host::Domain(domain)) if is_local_domain(domain) => {
+            return Err(UrlValidationError::PrivateTarget(domain.to_string()))
+        }
+        Some(Host::Ipv4(ip)) if is_private_ipv4(ip) => {
+            return Err(UrlValidationError::PrivateTarget(ip.to_string()))
+        }
+        Some(Host::Ipv6(ip)) if is_private_ipv6(ip) => {
+            return Err(UrlValidationError::PrivateTarget(ip.to_string()))
+        }
+        Some(_) => {}
+    }
+
+    // The url crate already lowercases the scheme and host and drops default ports
+    if url.path().len() > 1 && url.path().ends_with('/') {
+        let trimmed = url.path().trim_end_matches('/').to_string();
+        url.set_path(&trimmed);
+    }
+    if url.query() == Some("") {
+        url.set_query(None);
+    }
+    if url.fragment() == Some("") {
+        url.set_fragment(None);
+    }
+
+    let url = url.to_string();
+    if url.len() > max_length {
+        return Err(UrlValidationError::TooLong {
+            length: url.len(),
+            max: max_length,
+        });
+    }
+    Ok(url)
+}
+
+fn is_local_domain(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    domain == "localhost" || domain.ends_with(".localhost")
+}
+
+pub(crate) fn is_private_ipv4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // Carrier-grade NAT, 100.64.0.0/10
+        || (octets[0] == 100 && (octets[1] & 0b1100_0000) == 64)
+}
+
+pub(crate) fn is_private_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_private_ipv4(ipv4);
+    }
+    let first_segment = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local, fc00::/7
+        || (first_segment & 0xfe00) == 0xfc00
+        // Link local, fe80::/10
+        || (first_segment & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalizes_equivalent_urls() {
+        let expected = "https://example.com/a/b?x=1";
+
+        assert_eq!(
+            validate_and_normalize("https://example.com/a/b?x=1", DEFAULT_MAX_URL_LENGTH),
+            Ok(expected.to_string())
+        );
+        assert_eq!(
+            validate_and_normalize(" HTTPS://Example.COM:443/a/b/?x=1 ", DEFAULT_MAX_URL_LENGTH),
+            Ok(expected.to_string())
+        );
+        assert_eq!(
+            validate_and_normalize("http://example.com:80/", DEFAULT_MAX_URL_LENGTH),
+            Ok("http://example.com/".to_string())
+        );
+        assert_eq!(
+            validate_and_normalize("https://example.com/path?#", DEFAULT_MAX_URL_LENGTH),
+            Ok("https://example.com/path".to_string())
+        );
+    }
+
+    #[test]
+    fn test_rejects_unsupported_urls() {
+        assert!(matches!(
+            validate_and_normalize("not a url", DEFAULT_MAX_URL_LENGTH),
+            Err(UrlValidationError::Malformed(_))
+        ));
+        assert_eq!(
+            validate_and_normalize("ftp://example.com/file", DEFAULT_MAX_URL_LENGTH),
+            Err(UrlValidationError::UnsupportedScheme("ftp".to_string()))
+        );
+        assert_eq!(
+            validate_and_normalize("javascript:alert(1)", DEFAULT_MAX_URL_LENGTH),
+            Err(UrlValidationError::UnsupportedScheme(
+                "javascript".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_rejects_private_targets() {
+        for url in [
+            "http://localhost:8080/admin",
+            "http://api.localhost/",
+            "http://127.0.0.1/",
+            "http://10.1.2.3/",
+            "http://172.16.0.1/",
+            "http://192.168.1.1/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+        ] {
+            assert!(
+                matches!(
+                    validate_and_normalize(url, DEFAULT_MAX_URL_LENGTH),
+                    Err(UrlValidationError::PrivateTarget(_))
+                ),
+                "{} should be rejected",
+                url
+            );
+        }
+
+        assert!(validate_and_normalize("http://8.8.8.8/", DEFAULT_MAX_URL_LENGTH).is_ok());
+        assert!(
+            validate_and_normalize("http://[2001:4860::8888]/", DEFAULT_MAX_URL_LENGTH).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_rejects_long_urls() {
+        let url = format!("https://example.com/{}", "a".repeat(100));
+
+        assert!(validate_and_normalize(&url, 120).is_ok());
+        assert_eq!(
+            validate_and_normalize(&url, 100),
+            Err(UrlValidationError::TooLong {
+                length: 120,
+                max: 100
+            })
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::proxy;
+use crate::storage::{CountBatch, StorageError, UrlStore};
+
+/// Days are kept as long as the longest daily click timeseries reaches back
+const RETENTION_DAYS: i64 = 366;
+
+/// HyperLogLog of the visitors of a link on one UTC day
+fn day_key(slug: &str, day: NaiveDate) -> String {
+    format!("visitors:{}:{}", slug, day.format("%Y-%m-%d"))
+}
+
+/// Anonymous identifier of the visitor sending `req`: a hash of the client IP and `User-Agent`, so neither
+/// ends up in storage. Behind `TRUSTED_PROXIES` the IP comes from the forwarding headers.
+pub fn visitor_id(req: &HttpRequest) -> String {
+    let ip = proxy::client_ip(req).map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    Sha256::digest(format!("{}\n{}", ip, user_agent).as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Adds the visitor to the link's visitors of the day of `at`
+pub fn count_visit(counts: &mut CountBatch, slug: &str, visitor: &str, at: DateTime<Utc>) {
+    let ttl = Duration::days(RETENTION_DAYS).num_seconds() as usize;
+    counts.add_to_estimate(&day_key(slug, at.date_naive()), visitor, ttl);
+}
+
+/// Approximate number of distinct visitors over the UTC days from `from` to `to`, a visitor coming back
+/// on another day is counted once
+pub async fn unique_visitors(
+    store: &dyn UrlStore,
+    slug: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<u64, StorageError> {
+    let keys: Vec<String> = from
+        .date_naive()
+        .iter_days()
+        .take_while(|day| *day <= to.date_naive())
+        .map(|day| day_key(slug, day))
+        .collect();
+    store.count_estimate(&keys).await
+}
+
+/// Every visitor key that may still exist for the link
+pub fn visitor_keys(slug: &str, now: DateTime<Utc>) -> Vec<String> {
+    (0..=RETENTION_DAYS)
+        .map(|days| day_key(slug, (now - Duration::days(days)).date_naive()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use actix_web::test::TestRequest;
+    use chrono::TimeZone;
+
+    #[tokio::test]
+    async fn test_visitors_are_counted_once_over_days() {
+        let store = MemoryStore::new();
+        let day = Utc.with_ymd_and_hms(2026, 10, 1, 12, 0, 0).unwrap();
+        let visitor = |agent: &str| {
+            visitor_id(
+                &TestRequest::default()
+                    .peer_addr("203.0.113.7:4711".parse().unwrap())
+                    .insert_header((header::USER_AGENT, agent))
+                    .to_http_request(),
+            )
+        };
+        let (firefox, curl) = (visitor("Firefox"), visitor("curl"));
+        assert_eq!(firefox, visitor("Firefox"));
+        assert_ne!(firefox, curl);
+        assert!(!firefox.contains("203.0.113.7"));
+
+        let mut counts = CountBatch::default();
+        for (visitor, at) in [
+            (&firefox, day),
+            (&firefox, day),
+            (&curl, day),
+            (&firefox, day + Duration::days(1)),
+        ] {
+            count_visit(&mut counts, "abc", visitor, at);
+        }
+        store.write_counts(&counts).await.unwrap();
+
+        let count = |from, to| unique_visitors(&store, "abc", from, to);
+        assert_eq!(count(day, day).await.unwrap(), 2);
+        assert_eq!(
+            count(day + Duration::days(1), day + Duration::days(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(count(day, day + Duration::days(1)).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,76 @@
+// Creates links through POST /shorten-url, the same endpoint API clients use
+"use strict";
+
+const API_KEY_STORAGE = "url-shortener.api-key";
+
+const form = document.getElementById("shorten");
+const apiKey = document.getElementById("api-key");
+const error = document.getElementById("error");
+const result = document.getElementById("result");
+const shortUrl = document.getElementById("short-url");
+const copy = document.getElementById("copy");
+
+apiKey.value = localStorage.getItem(API_KEY_STORAGE) || "";
+
+form.addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const submit = form.querySelector("button[type=submit]");
+  submit.disabled = true;
+  error.hidden = true;
+  try {
+    showResult(await shorten());
+  } catch (err) {
+    error.textContent = err.message;
+    error.hidden = false;
+    result.hidden = true;
+  } finally {
+    submit.disabled = false;
+  }
+});
+
+copy.addEventListener("click", async () => {
+  await navigator.clipboard.writeText(shortUrl.href);
+  copy.textContent = "Copied";
+  setTimeout(() => (copy.textContent = "Copy"), 2000);
+});
+
+async function shorten() {
+  const body = { url: form.url.value.trim() };
+  const alias = form.alias.value.trim();
+  if (alias) {
+    body.alias = alias;
+  }
+  if (form.expires.value) {
+    body.expires_in_seconds = Number(form.expires.value);
+  }
+  const headers = { "Content-Type": "application/json" };
+  const key = apiKey.value.trim();
+  if (key) {
+    headers["X-Api-Key"] = key;
+    localStorage.setItem(API_KEY_STORAGE, key);
+  } else {
+    localStorage.removeItem(API_KEY_STORAGE);
+  }
+
+  const response = await fetch("/shorten-url", {
+    method: "POST",
+    headers,
+    body: JSON.stringify(body),
+  });
+  const data = await response.json().catch(() => ({}));
+  if (!response.ok) {
+    throw new Error(data.message || `Request failed with status ${response.status}`);
+  }
+  return data;
+}
+
+function showResult(link) {
+  shortUrl.href = link.short_url;
+  shortUrl.textContent = link.short_url;
+  document.getElementById("expires-at").textContent = link.expires_at
+    ? `Expires ${new Date(link.expires_at).toLocaleString()}`
+    : "Never expires";
+  // The social card carries the QR code of the short URL
+  document.getElementById("card").src = `${link.short_url}/card.png`;
+  result.hidden = false;
+}
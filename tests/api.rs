@@ -0,0 +1,2550 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{test, App};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use url_shortener::{
+    AppConfig, CollisionPolicy, EventSink, HashSlugs, LinkCacheConfig, LinkEvent, MemoryStore,
+    SlugFilterConfig, SlugStrategy, SlugStrategyKind, SqliteStore, SsoConfig, TrustedProxies,
+    UrlShortenOptions, UrlShortener, UrlStore,
+};
+
+async fn shortener_with(config: AppConfig, store: Arc<dyn UrlStore>) -> UrlShortener {
+    UrlShortener::with_store(&config, store).await.unwrap()
+}
+
+async fn shortener() -> UrlShortener {
+    shortener_with(AppConfig::default(), Arc::new(MemoryStore::new())).await
+}
+
+fn shorten_request(body: Value) -> test::TestRequest {
+    test::TestRequest::post().uri("/shorten-url").set_json(body)
+}
+
+#[actix_web::test]
+async fn test_shorten_and_redirect_over_http() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/launch", "alias": "launch" }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let created: Value = test::read_body_json(res).await;
+    let fields: Vec<&String> = created.as_object().unwrap().keys().collect();
+    assert_eq!(fields, vec!["expires_at", "short_url"]);
+    assert_eq!(created["short_url"], "https://short.me/launch");
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/launch").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        res.headers().get(header::LOCATION).unwrap(),
+        "https://example.com/launch"
+    );
+}
+
+#[actix_web::test]
+async fn test_web_page() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("/static/app.js"));
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get().uri("/static/app.js").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let script = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(script.contains("/shorten-url"));
+
+    // Reserved, so no link can end up behind the asset path
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com", "alias": "static" })).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_configured_redirect_status() {
+    let config = AppConfig {
+        redirect_status: StatusCode::MOVED_PERMANENTLY,
+        ..AppConfig::default()
+    };
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "alias": "moved" })).to_request(),
+    )
+    .await;
+    let res = test::call_service(&app, test::TestRequest::get().uri("/moved").to_request()).await;
+    assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+
+    // Links can pick their own status
+    test::call_service(
+        &app,
+        shorten_request(
+            json!({ "url": "https://example.com/", "alias": "tracked", "redirect_status": 302 }),
+        )
+        .to_request(),
+    )
+    .await;
+    let res = test::call_service(&app, test::TestRequest::get().uri("/tracked").to_request()).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "redirect_status": 200 }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_redirect_status");
+}
+
+#[actix_web::test]
+async fn test_fallback_url() {
+    let config = AppConfig {
+        fallback_url: Some("https://corp.com/?missing={slug}".to_string()),
+        ..AppConfig::default()
+    };
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get().uri("/no%20such").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(
+        res.headers().get(header::LOCATION).unwrap(),
+        "https://corp.com/?missing=no+such"
+    );
+
+    // Links that exist but can't be followed keep their own answer
+    test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "alias": "once", "max_clicks": 1 }))
+            .to_request(),
+    )
+    .await;
+    for status in [StatusCode::TEMPORARY_REDIRECT, StatusCode::GONE] {
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri("/once").to_request()).await;
+        assert_eq!(res.status(), status);
+    }
+}
+
+#[actix_web::test]
+async fn test_case_insensitive_slugs() {
+    let config = AppConfig {
+        case_insensitive_slugs: true,
+        ..AppConfig::default()
+    };
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let req = shorten_request(json!({ "url": "https://example.com/launch", "alias": "Launch" }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["short_url"], "https://short.me/launch");
+    let req =
+        shorten_request(json!({ "url": "https://example.com/", "alias": "LAUNCH" })).to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CONFLICT
+    );
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/LaUnCh").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        res.headers().get(header::LOCATION).unwrap(),
+        "https://example.com/launch"
+    );
+
+    let req = shorten_request(json!({ "url": "https://example.com/generated" })).to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let slug = body["short_url"]
+        .as_str()
+        .unwrap()
+        .trim_start_matches("https://short.me/");
+    assert_eq!(slug, slug.to_lowercase());
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri(&format!("/{}", slug.to_uppercase()))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+}
+
+#[actix_web::test]
+async fn test_multiple_domains() {
+    let mut config = AppConfig {
+        extra_domains: vec!["https://go.corp.com".to_string()],
+        ..AppConfig::default()
+    };
+    config.auth.admin_api_key = Some("admin-secret".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    for (domain, url) in [
+        (None, "https://example.com/public"),
+        (Some("go.corp.com"), "https://example.com/internal"),
+    ] {
+        let req = shorten_request(json!({ "url": url, "alias": "launch", "domain": domain }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let base = domain.map_or("https://short.me".to_string(), |domain| {
+            format!("https://{}", domain)
+        });
+        assert_eq!(body["short_url"], format!("{}/launch", base));
+    }
+    for (host, destination) in [
+        ("short.me", "https://example.com/public"),
+        ("go.corp.com", "https://example.com/internal"),
+    ] {
+        let req = test::TestRequest::get()
+            .uri("/launch")
+            .insert_header((header::HOST, host))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::LOCATION).unwrap(), destination);
+    }
+    let req = test::TestRequest::get()
+        .uri("/launch@go.corp.com")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let req = shorten_request(json!({ "url": "https://example.com/", "domain": "evil.com" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_domain");
+
+    let req = test::TestRequest::post()
+        .uri("/api/admin/api-keys")
+        .insert_header(("X-Api-Key", "admin-secret"))
+        .set_json(json!({ "name": "intranet", "domain": "https://go.corp.com" }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["domain"], "go.corp.com");
+    let api_key = body["api_key"].as_str().unwrap().to_string();
+
+    let req = shorten_request(json!({ "url": "https://example.com/bound" }))
+        .insert_header(("X-Api-Key", api_key.as_str()))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["short_url"]
+        .as_str()
+        .unwrap()
+        .starts_with("https://go.corp.com/"));
+    let req = shorten_request(json!({ "url": "https://example.com/", "domain": "short.me" }))
+        .insert_header(("X-Api-Key", api_key.as_str()))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_web::test]
+async fn test_slug_reservations() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-secret".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    let mut api_keys = Vec::new();
+    for name in ["marketing", "other"] {
+        let req = test::TestRequest::post()
+            .uri("/api/admin/api-keys")
+            .insert_header(("X-Api-Key", "admin-secret"))
+            .set_json(json!({ "name": name }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        api_keys.push(body["api_key"].as_str().unwrap().to_string());
+    }
+    let reserve = |body: Value| {
+        test::TestRequest::post()
+            .uri("/api/slugs/reserve")
+            .set_json(body)
+    };
+    let attach = |api_key: &str, url: &str| {
+        test::TestRequest::post()
+            .uri("/api/slugs/launch/attach")
+            .insert_header(("X-Api-Key", api_key))
+            .set_json(json!({ "url": url }))
+            .to_request()
+    };
+
+    // Anonymous reservations couldn't be attached by anyone
+    let res = test::call_service(&app, reserve(json!({ "slug": "launch" })).to_request()).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let req = reserve(json!({ "slug": "launch", "expires_in_seconds": 3600 }))
+        .insert_header(("X-Api-Key", api_keys[0].as_str()))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["short_url"], "https://short.me/launch");
+
+    // The slug is taken but doesn't resolve yet
+    let res = test::call_service(&app, test::TestRequest::get().uri("/launch").to_request()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let req =
+        shorten_request(json!({ "url": "https://example.com/", "alias": "launch" })).to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CONFLICT
+    );
+
+    let res = test::call_service(&app, attach(&api_keys[1], "https://example.com/launch")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = test::call_service(&app, attach(&api_keys[0], "https://example.com/launch")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(&app, test::TestRequest::get().uri("/launch").to_request()).await;
+    assert_eq!(
+        res.headers().get(header::LOCATION).unwrap(),
+        "https://example.com/launch"
+    );
+
+    // Attaching only works once
+    let res = test::call_service(&app, attach(&api_keys[0], "https://example.com/other")).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn test_alias_availability() {
+    let mut config = AppConfig::default();
+    config.rate_limit.requests = 4;
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    let req =
+        shorten_request(json!({ "url": "https://example.com/", "alias": "launch" })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let check = |alias: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/slugs/{}/available", alias))
+            .to_request()
+    };
+
+    let body: Value = test::call_and_read_body_json(&app, check("docs")).await;
+    assert_eq!(
+        body,
+        json!({ "alias": "docs", "short_url": "https://short.me/docs", "available": true, "status": "available" })
+    );
+    let body: Value = test::call_and_read_body_json(&app, check("launch")).await;
+    assert_eq!(body["status"], "taken");
+    assert_eq!(body["available"], false);
+    let body: Value = test::call_and_read_body_json(&app, check("metrics")).await;
+    assert_eq!(body["status"], "reserved");
+    let res = test::call_service(&app, check("no%20spaces")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Every alias counts against the same budget
+    let res = test::call_service(&app, check("other")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn test_tenants() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-secret".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let create_key = |admin_key: &str, body: Value| {
+        test::TestRequest::post()
+            .uri("/api/admin/api-keys")
+            .insert_header(("X-Api-Key", admin_key))
+            .set_json(body)
+            .to_request()
+    };
+    let mut keys = Vec::new();
+    for body in [
+        json!({ "name": "acme admin", "tenant": "acme", "admin": true }),
+        json!({ "name": "acme ci", "tenant": "acme" }),
+    ] {
+        let body: Value =
+            test::call_and_read_body_json(&app, create_key("admin-secret", body)).await;
+        assert_eq!(body["tenant"], "acme");
+        keys.push(body["api_key"].as_str().unwrap().to_string());
+    }
+    let (acme_admin, acme_ci) = (keys[0].as_str(), keys[1].as_str());
+    let res = test::call_service(
+        &app,
+        create_key(acme_admin, json!({ "name": "other", "tenant": "globex" })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // Keys of another tenant and global keys can't be revoked by the tenant's admin key
+    let body: Value = test::call_and_read_body_json(
+        &app,
+        create_key(
+            "admin-secret",
+            json!({ "name": "globex ci", "tenant": "globex" }),
+        ),
+    )
+    .await;
+    let globex_ci = body["id"].as_str().unwrap().to_string();
+    let body: Value = test::call_and_read_body_json(
+        &app,
+        create_key(
+            "admin-secret",
+            json!({ "name": "global admin", "admin": true }),
+        ),
+    )
+    .await;
+    let global_admin = body["id"].as_str().unwrap().to_string();
+    for id in [&globex_ci, &global_admin] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/admin/api-keys/{}", id))
+            .insert_header(("X-Api-Key", acme_admin))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/admin/api-keys/{}", globex_ci))
+        .insert_header(("X-Api-Key", "admin-secret"))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NO_CONTENT
+    );
+
+    // The quota is set on the tenant and shared by every key of it, the tenant can't lift it itself
+    let res = test::call_service(
+        &app,
+        create_key(
+            "admin-secret",
+            json!({ "name": "acme bot", "tenant": "acme", "quota": 5 }),
+        ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let set_quota = |key: &str, quota: Value| {
+        test::TestRequest::put()
+            .uri("/api/admin/tenants/acme/quota")
+            .insert_header(("X-Api-Key", key))
+            .set_json(json!({ "quota": quota }))
+            .to_request()
+    };
+    let res = test::call_service(&app, set_quota(acme_admin, Value::Null)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    // Setting it again replaces the quota
+    for quota in [3, 2] {
+        let body: Value =
+            test::call_and_read_body_json(&app, set_quota("admin-secret", json!(quota))).await;
+        assert_eq!(body["quota"], quota);
+    }
+
+    for (key, alias, status) in [
+        (acme_admin, "acme-admin", StatusCode::OK),
+        (acme_ci, "acme-ci", StatusCode::OK),
+        (acme_ci, "acme-over", StatusCode::PAYMENT_REQUIRED),
+    ] {
+        let req = shorten_request(json!({ "url": "https://example.com/", "alias": alias }))
+            .insert_header(("X-Api-Key", key))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), status);
+        if status == StatusCode::PAYMENT_REQUIRED {
+            let body: Value = test::read_body_json(res).await;
+            assert_eq!(body["code"], "quota_exceeded");
+        }
+    }
+    test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "alias": "public" })).to_request(),
+    )
+    .await;
+    for slug in ["acme-ci", "acme-ci", "public"] {
+        test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/{}", slug))
+                .to_request(),
+        )
+        .await;
+    }
+    shortener.shutdown().await;
+
+    let usage = |key: &str, tenant: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/admin/tenants/{}/usage", tenant))
+            .insert_header(("X-Api-Key", key))
+            .to_request()
+    };
+    let body: Value = test::call_and_read_body_json(&app, usage(acme_admin, "acme")).await;
+    assert_eq!(
+        body,
+        json!({ "tenant": "acme", "quota": 2, "links_created": 2, "clicks": 2 })
+    );
+    let res = test::call_service(&app, usage(acme_admin, "globex")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // Admin keys of a tenant only see its links, other admins can filter by tenant
+    for (key, query) in [(acme_admin, ""), ("admin-secret", "&tenant=acme")] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/export/links?format=jsonl{}", query))
+            .insert_header(("X-Api-Key", key))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let mut slugs: Vec<String> = String::from_utf8(body.to_vec())
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<Value>(line).unwrap()["slug"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        slugs.sort();
+        assert_eq!(slugs, ["acme-admin", "acme-ci"]);
+    }
+    let req = test::TestRequest::post()
+        .uri("/api/admin/links/public/disable")
+        .insert_header(("X-Api-Key", acme_admin))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_web::test]
+async fn test_api_key_usage() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-secret".to_string());
+    config.auth.default_monthly_quota = Some(2);
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/admin/api-keys")
+        .insert_header(("X-Api-Key", "admin-secret"))
+        .set_json(json!({ "name": "ci" }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let api_key = body["api_key"].as_str().unwrap().to_string();
+
+    for alias in ["first", "second"] {
+        let req = shorten_request(json!({ "url": "https://example.com/", "alias": alias }))
+            .insert_header(("X-Api-Key", api_key.as_str()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+    let req = shorten_request(json!({ "url": "https://example.com/", "alias": "third" }))
+        .insert_header(("X-Api-Key", api_key.as_str()))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers().contains_key(header::RETRY_AFTER));
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "monthly_quota_exceeded");
+
+    test::call_service(&app, test::TestRequest::get().uri("/first").to_request()).await;
+    shortener.shutdown().await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/me/usage")
+        .insert_header(("X-Api-Key", api_key.as_str()))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["name"], "ci");
+    assert_eq!(body["monthly_quota"], 2);
+    assert_eq!(body["months"].as_array().unwrap().len(), 12);
+    assert_eq!(body["months"][0]["links_created"], 2);
+    assert_eq!(body["months"][0]["redirects"], 1);
+
+    let req = test::TestRequest::get().uri("/api/me/usage").to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[actix_web::test]
+async fn test_redirect_and_card_caching() {
+    let config = AppConfig {
+        redirect_cache_control: Some("public, max-age=86400".to_string()),
+        ..AppConfig::default()
+    };
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    for (alias, max_clicks, cache_control) in [
+        ("cached", None, "public, max-age=86400"),
+        ("counted", Some(5), "private, no-store"),
+    ] {
+        test::call_service(
+            &app,
+            shorten_request(
+                json!({ "url": "https://example.com/", "alias": alias, "max_clicks": max_clicks }),
+            )
+            .to_request(),
+        )
+        .await;
+        let uri = format!("/{}", alias);
+        let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            cache_control,
+            "{}",
+            alias
+        );
+    }
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/cached/card.png")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let etag = res.headers().get(header::ETAG).unwrap().clone();
+    let req = test::TestRequest::get()
+        .uri("/cached/card.png")
+        .insert_header((header::IF_NONE_MATCH, etag.clone()))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(res.headers().get(header::ETAG), Some(&etag));
+}
+
+#[actix_web::test]
+async fn test_interstitial_pages() {
+    let mut config = AppConfig::default();
+    config.domain_lists.trusted = Some("redis:domains:trusted".parse().unwrap());
+    let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+    store
+        .add_to_set("domains:trusted", &["example.com".to_string()])
+        .await
+        .unwrap();
+    let shortener = shortener_with(config, store).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    for (alias, url, interstitial) in [
+        ("trusted", "https://docs.example.com/", false),
+        ("untrusted", "https://elsewhere.test/page?a=1&b=2", false),
+        ("warned", "https://example.com/", true),
+    ] {
+        test::call_service(
+            &app,
+            shorten_request(json!({ "url": url, "alias": alias, "interstitial": interstitial }))
+                .to_request(),
+        )
+        .await;
+    }
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/trusted").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get().uri("/untrusted").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::LOCATION), None);
+    assert_eq!(
+        res.headers().get(header::CACHE_CONTROL).unwrap(),
+        "private, no-store"
+    );
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("You are leaving short.me"));
+    assert!(page.contains(r#"href="https://elsewhere.test/page?a=1&#38;b=2""#));
+    assert!(page.contains(r#"content="5;url="#));
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/warned").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_unfurl_previews() {
+    let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+    let shortener = shortener_with(AppConfig::default(), store.clone()).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    for body in [
+        json!({ "url": "https://example.com/launch", "alias": "launch", "max_clicks": 1 }),
+        json!({ "url": "https://example.com/secret", "alias": "secret", "password": "hunter2" }),
+    ] {
+        let res = test::call_service(&app, shorten_request(body).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    for slug in ["launch", "secret"] {
+        store
+            .set(
+                &format!("meta:{}", slug),
+                r#"{"title":"Launch <day>","description":"All the news"}"#,
+                None,
+            )
+            .await
+            .unwrap();
+    }
+    let preview = |slug: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/{}", slug))
+            .insert_header((
+                header::USER_AGENT,
+                "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            ))
+            .to_request()
+    };
+
+    let res = test::call_service(&app, preview("launch")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::LOCATION), None);
+    assert_eq!(res.headers().get(header::VARY).unwrap(), "User-Agent");
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains(r#"<meta property="og:title" content="Launch &#60;day&#62;">"#));
+    assert!(page.contains(r#"<meta property="og:description" content="All the news">"#));
+    assert!(
+        page.contains(r#"<meta property="og:image" content="https://short.me/launch/card.png">"#)
+    );
+    assert!(!page.contains("example.com"));
+
+    // The preview used up no click and asked for no password
+    let res = test::call_service(&app, test::TestRequest::get().uri("/launch").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    let res = test::call_service(&app, preview("secret")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains(r#"content="Password-protected link on short.me""#));
+    assert!(!page.contains("All the news"));
+}
+
+#[actix_web::test]
+async fn test_scheduled_links() {
+    let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+    let shortener = shortener_with(AppConfig::default(), store.clone()).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    let now = chrono::Utc::now();
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({
+            "url": "https://example.com/launch",
+            "alias": "embargoed",
+            "active_from": now + chrono::Duration::hours(1),
+            "active_until": now + chrono::Duration::hours(2)
+        }))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({
+            "url": "https://example.com/",
+            "alias": "live",
+            "active_until": now + chrono::Duration::hours(1)
+        }))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    // As left behind once the window closed
+    store
+        .set(
+            "ended",
+            r#"{"url":"https://example.com/","active_until":"2020-01-01T00:00:00Z"}"#,
+            None,
+        )
+        .await
+        .unwrap();
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get().uri("/embargoed").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "not_yet_active");
+    assert!(body["details"]["active_from"].is_string());
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/embargoed")
+            .insert_header((header::ACCEPT, "text/html"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("Not live yet"));
+    assert!(!page.contains("example.com"));
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/live").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        res.headers().get(header::CACHE_CONTROL).unwrap(),
+        "private, no-store"
+    );
+    let res = test::call_service(&app, test::TestRequest::get().uri("/ended").to_request()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({
+            "url": "https://example.com/",
+            "active_from": now + chrono::Duration::hours(2),
+            "active_until": now + chrono::Duration::hours(1)
+        }))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_schedule");
+}
+
+#[actix_web::test]
+async fn test_short_links_are_rejected_as_destinations() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://short.me/loop", "alias": "loop" })).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "short_link_destination");
+    assert_eq!(body["details"]["domain"], "short.me");
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({
+            "url": "https://example.com/",
+            "device_targets": { "ios": "https://short.me/app" }
+        }))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[actix_web::test]
+async fn test_verified_destinations() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    // Internal hosts are never requested, which is reported instead
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://wiki.internal/page", "verify": true }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(res).await;
+    assert!(body["short_url"].is_string());
+    assert_eq!(
+        body["warning"],
+        "The destination is an internal address and wasn't checked."
+    );
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://wiki.internal/page" })).to_request(),
+    )
+    .await;
+    let body: Value = test::read_body_json(res).await;
+    assert!(body.get("warning").is_none());
+}
+
+#[actix_web::test]
+async fn test_backup_and_restore() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-secret".to_string());
+    let shortener = shortener_with(config.clone(), Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    for alias in ["launch", "docs"] {
+        let res = test::call_service(
+            &app,
+            shorten_request(json!({ "url": "https://example.com/", "alias": alias, "expires_in_seconds": 3600, "max_clicks": 2 }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let res = test::call_service(&app, test::TestRequest::get().uri("/launch").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/admin/backup")
+            .insert_header(("X-Api-Key", "admin-secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/gzip"
+    );
+    let backup = test::read_body(res).await;
+    let mut lines = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&backup[..]), &mut lines)
+        .unwrap();
+    let mut records: Vec<Value> = lines
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    records.sort_by_key(|record| record["key"].to_string());
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1]["key"], "launch");
+    assert!(records[1]["expires_at"].is_string());
+    assert_eq!(records[1]["counters"]["clicks:launch"], "1");
+
+    // Restored into an empty store, e.g. after losing Redis
+    let restored = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| restored.configure(cfg))).await;
+    let restore = || {
+        test::TestRequest::post()
+            .uri("/api/admin/restore")
+            .insert_header(("X-Api-Key", "admin-secret"))
+            .set_payload(backup.clone())
+            .to_request()
+    };
+    let summary: Value = test::call_and_read_body_json(&app, restore()).await;
+    assert_eq!(
+        summary,
+        json!({ "restored": 2, "existing": 0, "expired": 0 })
+    );
+    // One click left on the restored counter
+    let res = test::call_service(&app, test::TestRequest::get().uri("/launch").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    let res = test::call_service(&app, test::TestRequest::get().uri("/launch").to_request()).await;
+    assert_ne!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    let summary: Value = test::call_and_read_body_json(&app, restore()).await;
+    assert_eq!(
+        summary,
+        json!({ "restored": 0, "existing": 2, "expired": 0 })
+    );
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/api/admin/restore")
+            .insert_header(("X-Api-Key", "admin-secret"))
+            .set_payload("not a backup")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    // A backup only writes links and their counters
+    for line in [
+        json!({ "key": "apikey:admin", "record": "{}" }),
+        json!({ "key": "other", "record": "{\"url\":\"https://example.com\"}", "counters": { "apikey:admin": "{}" } }),
+    ] {
+        let mut payload = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut payload, line.to_string().as_bytes()).unwrap();
+        let res = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/api/admin/restore")
+                .insert_header(("X-Api-Key", "admin-secret"))
+                .set_payload(payload.finish().unwrap())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "invalid_backup");
+    }
+}
+
+#[actix_web::test]
+async fn test_dead_links() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-secret".to_string());
+    config.dead_links.failure_threshold = 2;
+    let store = Arc::new(MemoryStore::new());
+    let shortener = shortener_with(config, store.clone()).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    for (alias, url) in [
+        ("gone", "https://no-such-host.invalid/page"),
+        ("intranet", "https://wiki.internal/page"),
+    ] {
+        let res = test::call_service(
+            &app,
+            shorten_request(json!({ "url": url, "alias": alias })).to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let dead_links = || {
+        test::TestRequest::get()
+            .uri("/api/admin/links?status=dead")
+            .insert_header(("X-Api-Key", "admin-secret"))
+            .to_request()
+    };
+
+    // Only persistently failing links count, internal destinations are never checked
+    assert_eq!(shortener.check_dead_links().await.unwrap(), 0);
+    let body: Value = test::call_and_read_body_json(&app, dead_links()).await;
+    assert_eq!(body, json!([]));
+    assert_eq!(shortener.check_dead_links().await.unwrap(), 1);
+    assert_eq!(shortener.check_dead_links().await.unwrap(), 0);
+    let body: Value = test::call_and_read_body_json(&app, dead_links()).await;
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["slug"], "gone");
+    assert_eq!(body[0]["short_url"], "https://short.me/gone");
+    assert_eq!(body[0]["url"], "https://no-such-host.invalid/page");
+    assert_eq!(body[0]["failures"], 3);
+    // The counter expires with the link
+    let ttls = store
+        .ttl_many(&["gone".to_string(), "deadlink:gone".to_string()])
+        .await
+        .unwrap();
+    assert!(ttls[1].is_some());
+    assert!(ttls[1] <= ttls[0]);
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/admin/links?status=alive")
+            .insert_header(("X-Api-Key", "admin-secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/admin/links?status=dead")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_missing_and_used_up_links() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    for uri in ["/missing", "/clicks:missing"] {
+        let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", uri);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["details"], Value::Null);
+    }
+
+    test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "alias": "once", "max_clicks": 1 }))
+            .to_request(),
+    )
+    .await;
+    let res = test::call_service(&app, test::TestRequest::get().uri("/once").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    let res = test::call_service(&app, test::TestRequest::get().uri("/once").to_request()).await;
+    assert_eq!(res.status(), StatusCode::GONE);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "gone");
+}
+
+#[actix_web::test]
+async fn test_browsers_get_error_pages() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    let browser_get = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::ACCEPT, "text/html,application/xhtml+xml,*/*;q=0.8"))
+            .to_request()
+    };
+
+    let res = test::call_service(&app, browser_get("/missing")).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        res.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("<strong>missing</strong>"));
+    assert!(page.contains(r#"href="https://short.me/""#));
+
+    test::call_service(
+        &app,
+        shorten_request(
+            json!({ "url": "https://example.com/", "alias": "single", "max_clicks": 1 }),
+        )
+        .to_request(),
+    )
+    .await;
+    test::call_service(&app, browser_get("/single")).await;
+    let res = test::call_service(&app, browser_get("/single")).await;
+    assert_eq!(res.status(), StatusCode::GONE);
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("click limit"));
+}
+
+#[actix_web::test]
+async fn test_error_responses() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "ftp://example.com" })).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_url");
+    assert_eq!(body["details"], Value::Null);
+    assert!(body["message"].as_str().unwrap().contains("ftp"));
+
+    let taken = json!({ "url": "https://example.com/", "alias": "taken" });
+    test::call_service(&app, shorten_request(taken.clone()).to_request()).await;
+    let res = test::call_service(&app, shorten_request(taken).to_request()).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "alias_taken");
+    assert_eq!(body["details"], json!({ "alias": "taken" }));
+
+    let req = test::TestRequest::post()
+        .uri("/shorten-url")
+        .insert_header((header::CONTENT_TYPE, "application/json"))
+        .set_payload("{")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_body");
+}
+
+#[actix_web::test]
+async fn test_body_validation_and_limits() {
+    let config = AppConfig {
+        max_url_length: 100,
+        max_body_bytes: 2048,
+        ..AppConfig::default()
+    };
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/shorten-batch")
+        .set_json(json!([
+            { "url": "https://example.com/" },
+            { "variants": [{ "url": "https://example.com/a", "weight": "half" }] }
+        ]))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_body");
+    assert_eq!(
+        body["details"],
+        json!({ "field": "[1].variants[0].weight" })
+    );
+
+    let long_url = format!("https://example.com/{}", "a".repeat(100));
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": long_url })).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_url");
+
+    let huge_url = format!("https://example.com/{}", "a".repeat(4096));
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": huge_url })).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "payload_too_large");
+    assert_eq!(body["details"], json!({ "limit": 2048 }));
+}
+
+#[actix_web::test]
+async fn test_collision_response() {
+    let config = AppConfig {
+        slug_strategy: SlugStrategyKind::Hash,
+        max_collision_attempts: 1,
+        ..AppConfig::default()
+    };
+    let url = "https://example.com/collides";
+    // Another record already sits on the slug the URL hashes to
+    let slug = HashSlugs {
+        length: config.slug_length,
+        alphabet: config.slug_alphabet(),
+    }
+    .next_slug(url, 1)
+    .await
+    .unwrap();
+    let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+    store.set(&slug, "taken", None).await.unwrap();
+    let shortener = shortener_with(config, store).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(&app, shorten_request(json!({ "url": url })).to_request()).await;
+    assert_eq!(res.status(), StatusCode::LOOP_DETECTED);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "collision");
+    assert_eq!(body["details"], json!({ "attempts": 1, "url": url }));
+}
+
+#[actix_web::test]
+async fn test_collision_policies() {
+    let url = "https://example.com/collides";
+    let taken = |config: &AppConfig| HashSlugs {
+        length: config.slug_length,
+        alphabet: config.slug_alphabet(),
+    };
+    for (policy, expected) in [
+        (CollisionPolicy::Retry, Some(8)),
+        (CollisionPolicy::Grow { after: 1 }, Some(9)),
+        (CollisionPolicy::FailFast, None),
+    ] {
+        let config = AppConfig {
+            slug_strategy: SlugStrategyKind::Hash,
+            slug_length: 8,
+            collision_policy: policy,
+            ..AppConfig::default()
+        };
+        // The first candidate the URL hashes to is taken
+        let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+        let slug = taken(&config).next_slug(url, 1).await.unwrap();
+        store.set(&slug, "taken", None).await.unwrap();
+        let shortener = shortener_with(config, store).await;
+        let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+        let res =
+            test::call_service(&app, shorten_request(json!({ "url": url })).to_request()).await;
+        let body: Value = test::read_body_json(res).await;
+        match expected {
+            Some(length) => {
+                let short_url = body["short_url"].as_str().unwrap();
+                assert_eq!(short_url.rsplit('/').next().unwrap().len(), length);
+                assert_eq!(
+                    body["collisions"],
+                    json!({ "policy": policy.name(), "attempts": 2 })
+                );
+            }
+            None => assert_eq!(body["code"], "collision"),
+        }
+    }
+}
+
+#[actix_web::test]
+async fn test_resolve_batch() {
+    let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+    let shortener = shortener_with(AppConfig::default(), store.clone()).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    for body in [
+        json!({ "url": "https://example.com/a", "alias": "batch-a", "utm_source": "mail" }),
+        json!({ "url": "https://example.com/b", "alias": "batch-b", "password": "hunter2" }),
+    ] {
+        let res = test::call_service(&app, shorten_request(body).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    // As stored by the metadata fetcher
+    for slug in ["batch-a", "batch-b"] {
+        store
+            .set(&format!("meta:{}", slug), r#"{"title":"Example"}"#, None)
+            .await
+            .unwrap();
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/api/resolve-batch")
+        .set_json(json!([
+            "batch-a",
+            "https://short.me/batch-b",
+            "missing",
+            "https://elsewhere.com/batch-a",
+            "apikey:admin"
+        ]))
+        .to_request();
+    let results: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        results,
+        json!([
+            {
+                "slug": "batch-a",
+                "status": "active",
+                "url": "https://example.com/a?utm_source=mail",
+                "metadata": { "title": "Example" }
+            },
+            { "slug": "https://short.me/batch-b", "status": "password_protected" },
+            { "slug": "missing", "status": "not_found" },
+            { "slug": "https://elsewhere.com/batch-a", "status": "not_found" },
+            { "slug": "apikey:admin", "status": "not_found" }
+        ])
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/api/resolve-batch")
+        .set_json(json!([]))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_web::test]
+async fn test_deterministic_slugs_are_shared_by_identical_links() {
+    let config = AppConfig {
+        slug_strategy: SlugStrategyKind::Deterministic,
+        slug_hash_key: "secret".to_string(),
+        ..AppConfig::default()
+    };
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    let shorten = |body: Value| {
+        let req = shorten_request(body).to_request();
+        let app = &app;
+        async move {
+            let body: Value = test::call_and_read_body_json(app, req).await;
+            body["short_url"].as_str().unwrap().to_string()
+        }
+    };
+
+    let url = "https://example.com/deterministic";
+    let first = shorten(json!({ "url": url })).await;
+    assert_eq!(shorten(json!({ "url": url })).await, first);
+    // The same URL with other options is a genuine collision and gets a longer slug
+    let fragment = shorten(json!({ "url": url, "fragment": "top" })).await;
+    assert_eq!(fragment.len(), first.len() + 1);
+}
+
+#[actix_web::test]
+async fn test_filtered_slugs_are_regenerated() {
+    let config = AppConfig {
+        slug_strategy: SlugStrategyKind::Counter,
+        slug_filter: SlugFilterConfig {
+            blocked_words: Some("1,3".parse().unwrap()),
+            confusables: None,
+        },
+        ..AppConfig::default()
+    };
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let mut short_urls = Vec::new();
+    for url in ["https://example.com/a", "https://example.com/b"] {
+        let req = shorten_request(json!({ "url": url })).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        short_urls.push(body["short_url"].as_str().unwrap().to_string());
+    }
+    assert_eq!(short_urls, vec!["https://short.me/2", "https://short.me/4"]);
+    // Aliases are left alone
+    let req =
+        shorten_request(json!({ "url": "https://example.com/c", "alias": "abc1" })).to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["short_url"], "https://short.me/abc1");
+}
+
+#[actix_web::test]
+async fn test_shorten_preview_writes_nothing() {
+    let config = AppConfig {
+        slug_strategy: SlugStrategyKind::Hash,
+        deduplicate: true,
+        ..AppConfig::default()
+    };
+    let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+    let shortener = shortener_with(config, store.clone()).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    let preview = |body: Value| {
+        test::TestRequest::post()
+            .uri("/api/shorten-url/preview")
+            .set_json(body)
+            .to_request()
+    };
+
+    let res = test::call_service(&app, preview(json!({ "url": "HTTPS://Example.com/docs" }))).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let previewed: Value = test::read_body_json(res).await;
+    assert_eq!(previewed["url"], "https://example.com/docs");
+    assert_eq!(previewed["existing"], false);
+    assert!(previewed["expires_at"].is_string());
+    // Only the rate limiter counted the request
+    let (_, keys) = store.scan_keys(0, 100).await.unwrap();
+    assert!(keys.iter().all(|key| key.starts_with("ratelimit:")));
+
+    // The link lands on the previewed slug, and is found by the next preview
+    let req = shorten_request(json!({ "url": "https://example.com/docs" })).to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(created["short_url"], previewed["short_url"]);
+    let again: Value =
+        test::call_and_read_body_json(&app, preview(json!({ "url": "https://example.com/docs" })))
+            .await;
+    assert_eq!(again["short_url"], previewed["short_url"]);
+    assert_eq!(again["existing"], true);
+
+    // Taken aliases and invalid destinations fail like they would when shortening
+    let req =
+        shorten_request(json!({ "url": "https://example.com/", "alias": "launch" })).to_request();
+    test::call_service(&app, req).await;
+    let res = test::call_service(
+        &app,
+        preview(json!({ "url": "https://example.com/", "alias": "launch" })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let res = test::call_service(&app, preview(json!({ "url": "ftp://example.com/" }))).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Counter slugs are only taken when a link is stored
+    let counter = shortener_with(
+        AppConfig {
+            slug_strategy: SlugStrategyKind::Counter,
+            ..AppConfig::default()
+        },
+        Arc::new(MemoryStore::new()),
+    )
+    .await;
+    let app = test::init_service(App::new().configure(|cfg| counter.configure(cfg))).await;
+    let previewed: Value =
+        test::call_and_read_body_json(&app, preview(json!({ "url": "https://example.com/" })))
+            .await;
+    assert_eq!(previewed["short_url"], Value::Null);
+    let req = shorten_request(json!({ "url": "https://example.com/" })).to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(created["short_url"], "https://short.me/1");
+}
+
+#[actix_web::test]
+async fn test_batch_results_follow_the_requests() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/shorten-batch")
+        .set_json(json!([
+            { "url": "https://example.com/a", "alias": "batch-a" },
+            { "url": "ftp://example.com" }
+        ]))
+        .to_request();
+    let results: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(results[0]["short_url"], "https://short.me/batch-a");
+    assert_eq!(results[1]["code"], "invalid_url");
+
+    let req = test::TestRequest::post()
+        .uri("/api/shorten-batch")
+        .set_json(json!([]))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_links_by_tag() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-key-0123456789".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    for (alias, tags) in [
+        ("q3-mail", json!(["Campaign-Q3", "email"])),
+        ("q3-social", json!(["campaign-q3"])),
+        ("q4-mail", json!(["campaign-q4", "email"])),
+    ] {
+        let res = test::call_service(
+            &app,
+            shorten_request(json!({ "url": "https://example.com/", "alias": alias, "tags": tags }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK, "{}", alias);
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/links?tag=campaign-q3")
+        .insert_header(("X-Api-Key", "admin-key-0123456789"))
+        .to_request();
+    let links: Value = test::call_and_read_body_json(&app, req).await;
+    let slugs: Vec<&str> = links
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|link| link["slug"].as_str().unwrap())
+        .collect();
+    assert_eq!(slugs, vec!["q3-mail", "q3-social"]);
+    assert_eq!(links[0]["tags"], json!(["campaign-q3", "email"]));
+
+    let req = test::TestRequest::get()
+        .uri("/api/links")
+        .insert_header(("X-Api-Key", "admin-key-0123456789"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_query");
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/links?tag=email")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "tags": ["spring sale"] }))
+            .to_request(),
+    )
+    .await;
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_tags");
+}
+
+#[actix_web::test]
+async fn test_export_links() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-key-0123456789".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    for body in [
+        json!({ "url": "https://example.com/a,b", "alias": "limited", "max_clicks": 3, "tags": ["q3"] }),
+        json!({ "url": "https://example.com/", "alias": "plain" }),
+    ] {
+        test::call_service(&app, shorten_request(body).to_request()).await;
+    }
+    test::call_service(&app, test::TestRequest::get().uri("/limited").to_request()).await;
+    // Writes the clicks still waiting in the analytics buffer
+    shortener.shutdown().await;
+
+    let export = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("X-Api-Key", "admin-key-0123456789"))
+            .to_request()
+    };
+    let res = test::call_service(&app, export("/api/export/links")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/csv; charset=utf-8"
+    );
+    let csv = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert_eq!(
+        csv.lines().collect::<Vec<_>>(),
+        vec![
+            "slug,short_url,url,owner,enabled,suspended,tags,max_clicks,clicks",
+            "limited,https://short.me/limited,\"https://example.com/a,b\",,true,false,q3,3,1",
+            "plain,https://short.me/plain,https://example.com/,,true,false,,,0",
+        ]
+    );
+
+    let res = test::call_service(&app, export("/api/export/links?format=jsonl&tag=q3")).await;
+    let jsonl = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    let rows: Vec<Value> = jsonl
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["slug"], "limited");
+    assert_eq!(rows[0]["clicks"], 1);
+
+    let res = test::call_service(&app, export("/api/export/links?format=xml")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_preview_tokens() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-key-0123456789".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    test::call_service(
+        &app,
+        shorten_request(
+            json!({ "url": "https://example.com/launch", "alias": "launch", "tags": ["q3"] }),
+        )
+        .to_request(),
+    )
+    .await;
+    test::call_service(&app, test::TestRequest::get().uri("/launch").to_request()).await;
+    shortener.shutdown().await;
+
+    let create = |api_key: Option<&str>| {
+        let mut req = test::TestRequest::post().uri("/api/links/launch/preview-token");
+        if let Some(api_key) = api_key {
+            req = req.insert_header(("X-Api-Key", api_key));
+        }
+        req.to_request()
+    };
+    let res = test::call_service(&app, create(None)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = test::call_service(&app, create(Some("admin-key-0123456789"))).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = test::read_body_json(res).await;
+    let preview_url = body["preview_url"].as_str().unwrap();
+    let uri = preview_url.strip_prefix("https://short.me").unwrap();
+    assert!(uri.starts_with("/launch?preview_token="));
+
+    let open = || {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::ACCEPT, "text/html"))
+            .to_request()
+    };
+    let res = test::call_service(&app, open()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::LOCATION), None);
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("https://example.com/launch"));
+    assert!(page.contains("<tr><th>Clicks</th><td>1</td></tr>"));
+    assert!(page.contains("<tr><th>Tags</th><td>q3</td></tr>"));
+
+    // Used up by the first visit
+    let res = test::call_service(&app, open()).await;
+    assert_eq!(res.status(), StatusCode::GONE);
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/launch?preview_token=guessed")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::GONE);
+}
+
+#[actix_web::test]
+async fn test_clone_links() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-key-0123456789".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({
+            "url": "https://example.com/spring",
+            "alias": "spring",
+            "password": "hunter2",
+            "utm_source": "newsletter",
+            "utm_campaign": "spring",
+            "tags": ["q3"],
+            "expires_in_seconds": 3600
+        }))
+        .to_request(),
+    )
+    .await;
+    let original: Value = test::read_body_json(res).await;
+
+    let clone = |slug: &str, body: Value| {
+        test::TestRequest::post()
+            .uri(&format!("/api/links/{}/clone", slug))
+            .insert_header(("X-Api-Key", "admin-key-0123456789"))
+            .set_json(body)
+            .to_request()
+    };
+    let res = test::call_service(
+        &app,
+        clone(
+            "spring",
+            json!({ "alias": "spring-social", "utm_source": "social", "tags": ["social"] }),
+        ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let cloned: Value = test::read_body_json(res).await;
+    assert_eq!(cloned["short_url"], "https://short.me/spring-social");
+    let expiry = |body: &Value| {
+        body["expires_at"]
+            .as_str()
+            .unwrap()
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap()
+    };
+    assert!((expiry(&cloned) - expiry(&original)).num_seconds().abs() <= 2);
+
+    // The password and the other UTM parameters carry over
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/spring-social?password=hunter2")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        res.headers().get(header::LOCATION).unwrap(),
+        "https://example.com/spring?utm_source=social&utm_campaign=spring"
+    );
+    let res = test::call_service(&app, clone("spring", json!({}))).await;
+    let generated: Value = test::read_body_json(res).await;
+    assert_ne!(generated["short_url"], original["short_url"]);
+
+    let res = test::call_service(&app, clone("missing", json!({}))).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/api/links/spring/clone")
+            .set_json(json!({}))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_click_timeseries() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-key-0123456789".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "alias": "charted" })).to_request(),
+    )
+    .await;
+    for _ in 0..3 {
+        test::call_service(&app, test::TestRequest::get().uri("/charted").to_request()).await;
+    }
+    // Writes the clicks still waiting in the analytics buffer
+    shortener.shutdown().await;
+
+    let timeseries = |query: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/links/charted/stats/timeseries{}", query))
+            .insert_header(("X-Api-Key", "admin-key-0123456789"))
+            .to_request()
+    };
+    let res = test::call_service(&app, timeseries("")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let hourly: Value = test::read_body_json(res).await;
+    assert_eq!(hourly["granularity"], "hour");
+    assert_eq!(hourly["timestamps"].as_array().unwrap().len(), 7 * 24);
+    assert_eq!(hourly["clicks"][7 * 24 - 1], 3);
+    // The test requests all come from the same address without a User-Agent
+    assert_eq!(hourly["unique_visitors"], 1);
+
+    let res = test::call_service(&app, timeseries("?granularity=day&range=30d")).await;
+    let daily: Value = test::read_body_json(res).await;
+    assert_eq!(daily["clicks"].as_array().unwrap().len(), 30);
+    assert_eq!(daily["clicks"][29], 3);
+
+    let res = test::call_service(&app, timeseries("?granularity=hour&range=30d")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_range");
+}
+
+#[actix_web::test]
+async fn test_sqlite_backend() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-key-0123456789".to_string());
+    let store = SqliteStore::open(std::path::Path::new(":memory:")).unwrap();
+    let shortener = shortener_with(config, Arc::new(store)).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "alias": "stored" })).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/other", "alias": "stored" }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    for _ in 0..2 {
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri("/stored").to_request()).await;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    }
+    shortener.shutdown().await;
+
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/links/stored/stats/timeseries?granularity=day")
+            .insert_header(("X-Api-Key", "admin-key-0123456789"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let daily: Value = test::read_body_json(res).await;
+    assert_eq!(daily["clicks"][6], 2);
+    assert_eq!(daily["unique_visitors"], 1);
+}
+
+#[actix_web::test]
+async fn test_referrer_and_device_breakdown() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-key-0123456789".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "alias": "shared" })).to_request(),
+    )
+    .await;
+    let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+    for referer in [
+        Some("https://news.ycombinator.com/item?id=1"),
+        Some("https://www.reddit.com/r/rust/"),
+        Some("https://news.ycombinator.com/"),
+        None,
+    ] {
+        let mut req = test::TestRequest::get()
+            .uri("/shared")
+            .insert_header(("User-Agent", firefox));
+        if let Some(referer) = referer {
+            req = req.insert_header(("Referer", referer));
+        }
+        test::call_service(&app, req.to_request()).await;
+    }
+    // Writes the clicks still waiting in the analytics buffer
+    shortener.shutdown().await;
+
+    let stats = |path: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/links/shared/stats/{}", path))
+            .insert_header(("X-Api-Key", "admin-key-0123456789"))
+            .to_request()
+    };
+    let res = test::call_service(&app, stats("referrers?limit=2")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(
+        body["referrers"],
+        json!([
+            { "name": "news.ycombinator.com", "clicks": 2 },
+            { "name": "reddit.com", "clicks": 1 }
+        ])
+    );
+
+    let res = test::call_service(&app, stats("devices")).await;
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["devices"], json!([{ "name": "desktop", "clicks": 4 }]));
+    assert_eq!(
+        body["browsers"],
+        json!([{ "name": "Firefox", "clicks": 4 }])
+    );
+
+    let res = test::call_service(&app, stats("referrers?limit=0")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_limit");
+}
+
+#[actix_web::test]
+async fn test_my_links_expiring_within() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let credentials = json!({ "email": "owner@example.com", "password": "correct horse" });
+    let req = test::TestRequest::post()
+        .uri("/api/users")
+        .set_json(&credentials)
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CREATED
+    );
+    let req = test::TestRequest::post()
+        .uri("/api/users/login")
+        .set_json(&credentials)
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let bearer = format!("Bearer {}", body["token"].as_str().unwrap());
+
+    for (alias, expires_in) in [("soon", 60 * 60), ("later", 60 * 60 * 24 * 30)] {
+        let req = shorten_request(
+            json!({ "url": "https://example.com/", "alias": alias, "expires_in_seconds": expires_in }),
+        )
+        .insert_header(("Authorization", bearer.as_str()))
+        .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    let my_links = |query: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/me/links{}", query))
+            .insert_header(("Authorization", bearer.as_str()))
+            .to_request()
+    };
+    let body: Value = test::call_and_read_body_json(&app, my_links("")).await;
+    assert_eq!(body.as_array().unwrap().len(), 2);
+    assert!(body[0]["expires_at"].is_string());
+
+    let body: Value = test::call_and_read_body_json(&app, my_links("?expiring_within=1d")).await;
+    let slugs: Vec<&str> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|link| link["slug"].as_str().unwrap())
+        .collect();
+    assert_eq!(slugs, ["soon"]);
+
+    let res = test::call_service(&app, my_links("?expiring_within=soon")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_range");
+}
+
+#[actix_web::test]
+async fn test_logins_are_rate_limited() {
+    let mut config = AppConfig::default();
+    config.rate_limit.requests = 2;
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let credentials = json!({ "email": "owner@example.com", "password": "correct horse" });
+    let req = test::TestRequest::post()
+        .uri("/api/users")
+        .set_json(&credentials)
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CREATED
+    );
+    let login = |email: &str| {
+        test::TestRequest::post()
+            .uri("/api/users/login")
+            .set_json(json!({ "email": email, "password": "wrong horse" }))
+            .to_request()
+    };
+    // Unknown emails fail like wrong passwords
+    for email in ["owner@example.com", "nobody@example.com"] {
+        let res = test::call_service(&app, login(email)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "invalid_credentials");
+    }
+    let res = test::call_service(&app, login("owner@example.com")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn test_my_links_pages() {
+    let shortener = shortener().await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let credentials = json!({ "email": "owner@example.com", "password": "correct horse" });
+    let req = test::TestRequest::post()
+        .uri("/api/users")
+        .set_json(&credentials)
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CREATED
+    );
+    let req = test::TestRequest::post()
+        .uri("/api/users/login")
+        .set_json(&credentials)
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let bearer = format!("Bearer {}", body["token"].as_str().unwrap());
+
+    for alias in ["first", "second", "third"] {
+        let req = shorten_request(json!({ "url": "https://example.com/", "alias": alias }))
+            .insert_header(("Authorization", bearer.as_str()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+    for _ in 0..2 {
+        let req = test::TestRequest::get().uri("/first").to_request();
+        assert!(test::call_service(&app, req)
+            .await
+            .status()
+            .is_redirection());
+    }
+    let req = test::TestRequest::get().uri("/third").to_request();
+    assert!(test::call_service(&app, req)
+        .await
+        .status()
+        .is_redirection());
+    // Writes the clicks still waiting in the analytics buffer
+    shortener.shutdown().await;
+
+    let my_links = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("Authorization", bearer.as_str()))
+            .to_request()
+    };
+    let slugs = |body: &Value| -> Vec<String> {
+        body.as_array()
+            .unwrap()
+            .iter()
+            .map(|link| link["slug"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    // Newest first by default, following the Link header until there is none
+    let mut uri = "/api/me/links?page_size=2".to_string();
+    let mut pages = Vec::new();
+    loop {
+        let res = test::call_service(&app, my_links(&uri)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let next = res
+            .headers()
+            .get(header::LINK)
+            .map(|link| link.to_str().unwrap().to_string());
+        let body: Value = test::read_body_json(res).await;
+        assert!(body[0]["created_at"].is_string());
+        pages.push(slugs(&body));
+        let Some(next) = next else { break };
+        uri = next
+            .strip_prefix('<')
+            .and_then(|next| next.strip_suffix(">; rel=\"next\""))
+            .unwrap()
+            .to_string();
+    }
+    assert_eq!(pages, [vec!["third", "second"], vec!["first"]]);
+
+    let body: Value =
+        test::call_and_read_body_json(&app, my_links("/api/me/links?sort=clicks")).await;
+    assert_eq!(slugs(&body), ["first", "third", "second"]);
+    assert_eq!(body[0]["clicks"], 2);
+    let body: Value = test::call_and_read_body_json(
+        &app,
+        my_links("/api/me/links?sort=clicks&order=asc&page_size=1"),
+    )
+    .await;
+    assert_eq!(slugs(&body), ["second"]);
+
+    for (query, code) in [
+        ("page_size=0", "invalid_page_size"),
+        ("cursor=garbage", "invalid_cursor"),
+    ] {
+        let res = test::call_service(&app, my_links(&format!("/api/me/links?{}", query))).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], code);
+    }
+    let res = test::call_service(&app, my_links("/api/me/links?sort=title")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_permanent_links() {
+    let store: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-secret".to_string());
+    let shortener = shortener_with(config, store.clone()).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    let permanent = |alias: &str| {
+        shorten_request(
+            json!({ "url": "https://example.com/", "alias": alias, "expires_in_seconds": null }),
+        )
+    };
+
+    let res = test::call_service(&app, permanent("anonymous").to_request()).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "forbidden");
+
+    let mut keys = Vec::new();
+    for permanent_links in [false, true] {
+        let req = test::TestRequest::post()
+            .uri("/api/admin/api-keys")
+            .insert_header(("X-Api-Key", "admin-secret"))
+            .set_json(json!({ "name": "ci", "permanent_links": permanent_links }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["permanent_links"], permanent_links);
+        keys.push(body["api_key"].as_str().unwrap().to_string());
+    }
+    let req = permanent("plain")
+        .insert_header(("X-Api-Key", keys[0].as_str()))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let req = permanent("forever")
+        .insert_header(("X-Api-Key", keys[1].as_str()))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["short_url"], "https://short.me/forever");
+    assert_eq!(body["expires_at"], Value::Null);
+    assert_eq!(
+        store.ttl_many(&["forever".to_string()]).await.unwrap(),
+        [None]
+    );
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "alias": "default" })).to_request(),
+    )
+    .await;
+    let body: Value = test::read_body_json(res).await;
+    assert!(body["expires_at"].is_string());
+
+    let config = AppConfig {
+        allow_permanent_links: true,
+        ..AppConfig::default()
+    };
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+    let res = test::call_service(&app, permanent("anyone").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[derive(Default)]
+struct RecordingSink {
+    published: Mutex<Vec<LinkEvent>>,
+}
+
+#[async_trait::async_trait]
+impl EventSink for RecordingSink {
+    async fn publish(&self, events: &[LinkEvent]) -> Result<(), String> {
+        self.published.lock().unwrap().extend_from_slice(events);
+        Ok(())
+    }
+}
+
+#[actix_web::test]
+async fn test_sso_tokens() {
+    // Stands in for the identity provider publishing its key set
+    let server = actix_web::HttpServer::new(|| {
+        App::new().route(
+            "/jwks.json",
+            actix_web::web::get().to(|| async {
+                actix_web::HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(include_str!("fixtures/sso_jwks.json"))
+            }),
+        )
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let jwks_url = format!("http://{}/jwks.json", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+
+    let mut config = AppConfig::default();
+    config.users.sso = Some(SsoConfig {
+        issuer: "https://login.corp.com".to_string(),
+        jwks_url,
+        audience: Some("shortener".to_string()),
+    });
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let sign = |issuer: &str| {
+        let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+        header.kid = Some("test-key".to_string());
+        let claims = json!({
+            "sub": "alice",
+            "iss": issuer,
+            "aud": "shortener",
+            "exp": chrono::Utc::now().timestamp() + 300,
+        });
+        let key = jsonwebtoken::EncodingKey::from_rsa_pem(include_bytes!("fixtures/sso_key.pem"))
+            .unwrap();
+        format!(
+            "Bearer {}",
+            jsonwebtoken::encode(&header, &claims, &key).unwrap()
+        )
+    };
+    let bearer = sign("https://login.corp.com");
+
+    let req = shorten_request(json!({ "url": "https://example.com/", "alias": "sso-link" }))
+        .insert_header(("Authorization", bearer.as_str()))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/api/me/links")
+        .insert_header(("Authorization", bearer.as_str()))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body[0]["slug"], "sso-link");
+
+    let req = test::TestRequest::get()
+        .uri("/api/me/links")
+        .insert_header(("Authorization", sign("https://evil.com").as_str()))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_token");
+}
+
+#[actix_web::test]
+async fn test_link_events() {
+    let sink = Arc::new(RecordingSink::default());
+    let shortener = UrlShortener::with_event_sink(
+        &AppConfig::default(),
+        Arc::new(MemoryStore::new()),
+        sink.clone(),
+    )
+    .await
+    .unwrap();
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/", "alias": "evented" })).to_request(),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/evented")
+        .insert_header(("Referer", "https://www.reddit.com/r/rust/"))
+        .to_request();
+    test::call_service(&app, req).await;
+    // Publishes the events still waiting in the buffer
+    shortener.shutdown().await;
+
+    let events: Vec<Value> = sink
+        .published
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|event| serde_json::from_str(&event.to_json()).unwrap())
+        .collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["type"], "link_created");
+    assert_eq!(events[0]["slug"], "evented");
+    assert_eq!(events[0]["url"], "https://example.com/");
+    assert_eq!(events[0]["owner"], Value::Null);
+    assert_eq!(events[1]["type"], "link_resolved");
+    assert_eq!(events[1]["referrer"], "reddit.com");
+    assert_eq!(events[1]["device"], "other");
+}
+
+#[actix_web::test]
+async fn test_embedded_shortening() {
+    let shortener = shortener().await;
+
+    let created = shortener
+        .shorten(UrlShortenOptions {
+            url: "https://example.com/docs".to_string(),
+            max_clicks: Some(1),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let link = shortener.resolve(&created.short_url).await.unwrap();
+    assert_eq!(link.url, "https://example.com/docs");
+    assert_eq!(link.max_clicks, Some(1));
+
+    let err = shortener
+        .shorten(UrlShortenOptions {
+            url: "ftp://example.com".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), "invalid_url");
+}
+
+#[actix_web::test]
+async fn test_audit_log() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-secret".to_string());
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let body: Value = test::call_and_read_body_json(
+        &app,
+        test::TestRequest::post()
+            .uri("/api/admin/api-keys")
+            .insert_header(("X-Api-Key", "admin-secret"))
+            .set_json(json!({ "name": "acme admin", "tenant": "acme", "admin": true }))
+            .to_request(),
+    )
+    .await;
+    let (acme_admin, acme_admin_id) = (
+        body["api_key"].as_str().unwrap().to_string(),
+        body["id"].as_str().unwrap().to_string(),
+    );
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com", "alias": "promo" }))
+            .insert_header(("X-Api-Key", acme_admin.as_str()))
+            .insert_header(("X-Request-Id", "req-promo"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/api/admin/links/promo/disable")
+            .insert_header(("X-Api-Key", "admin-secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let audit = |key: &str, query: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/api/admin/audit{}", query))
+            .insert_header(("X-Api-Key", key))
+            .to_request()
+    };
+    let entries: Value = test::call_and_read_body_json(&app, audit("admin-secret", "")).await;
+    let actions: Vec<&str> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        actions,
+        vec!["link_disabled", "link_created", "api_key_created"]
+    );
+    let created = &entries[1];
+    assert_eq!(created["target"], "promo");
+    assert_eq!(created["tenant"], "acme");
+    assert_eq!(created["actor"]["api_key"], acme_admin_id.as_str());
+    assert_eq!(created["actor"]["api_key_name"], "acme admin");
+    assert_eq!(created["request_id"], "req-promo");
+
+    let entries: Value = test::call_and_read_body_json(
+        &app,
+        audit(
+            "admin-secret",
+            &format!("?actor={}&action=link_created", acme_admin_id),
+        ),
+    )
+    .await;
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+
+    // The tenant's admin key only sees the entries of its tenant
+    let entries: Value = test::call_and_read_body_json(&app, audit(&acme_admin, "")).await;
+    assert_eq!(entries.as_array().unwrap().len(), 3);
+    let res = test::call_service(&app, audit(&acme_admin, "?tenant=globex")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = test::call_service(&app, audit("admin-secret", "?limit=0")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_abuse_reports() {
+    let mut config = AppConfig::default();
+    config.auth.admin_api_key = Some("admin-secret".to_string());
+    config.report_rate_limit = 2;
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/gift", "alias": "free-gift" }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let report = |slug: &str, body: Value| {
+        test::TestRequest::post()
+            .uri(&format!("/api/report/{}", slug))
+            .set_json(body)
+            .to_request()
+    };
+    let res = test::call_service(&app, report("missing", json!({ "category": "spam" }))).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = test::call_service(&app, report("free-gift", json!({ "category": "weird" }))).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    for body in [
+        json!({ "category": "phishing", "reason": "asks for my bank login" }),
+        json!({ "category": "spam" }),
+    ] {
+        let res = test::call_service(&app, report("free-gift", body)).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+    }
+    // Reports are limited per IP
+    let res = test::call_service(&app, report("free-gift", json!({ "category": "spam" }))).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let admin = |req: test::TestRequest| {
+        req.insert_header(("X-Api-Key", "admin-secret"))
+            .to_request()
+    };
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/admin/reports")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let queue: Value = test::call_and_read_body_json(
+        &app,
+        admin(test::TestRequest::get().uri("/api/admin/reports")),
+    )
+    .await;
+    assert_eq!(queue[0]["slug"], "free-gift");
+    assert_eq!(queue[0]["url"], "https://example.com/gift");
+    assert_eq!(queue[0]["suspended"], false);
+    let reports = queue[0]["reports"].as_array().unwrap();
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0]["category"], "spam");
+    assert_eq!(reports[1]["reason"], "asks for my bank login");
+
+    let disabled: Value = test::call_and_read_body_json(
+        &app,
+        admin(test::TestRequest::post().uri("/api/admin/reports/free-gift/disable")),
+    )
+    .await;
+    assert_eq!(
+        disabled["suspension"]["reason"],
+        "Reported as spam, phishing"
+    );
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get().uri("/free-gift").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::GONE);
+    let queue: Value = test::call_and_read_body_json(
+        &app,
+        admin(test::TestRequest::get().uri("/api/admin/reports")),
+    )
+    .await;
+    assert_eq!(queue, json!([]));
+}
+
+#[actix_web::test]
+async fn test_ip_access_rules() {
+    let config = AppConfig {
+        trusted_proxies: TrustedProxies::parse("192.0.2.1").unwrap(),
+        ..AppConfig::default()
+    };
+    let shortener = shortener_with(config, Arc::new(MemoryStore::new())).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({
+            "url": "https://wiki.corp.internal/runbook",
+            "alias": "runbook",
+            "allowed_ips": ["10.0.0.0/8"],
+            "denied_ips": ["10.0.13.0/24"]
+        }))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com", "allowed_ips": ["intranet"] }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_access_rules");
+
+    let visit = |forwarded_for: &str| {
+        test::TestRequest::get()
+            .uri("/runbook")
+            .peer_addr("192.0.2.1:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", forwarded_for))
+            .to_request()
+    };
+    let res = test::call_service(&app, visit("10.1.2.3")).await;
+    assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        res.headers().get(header::CACHE_CONTROL).unwrap(),
+        "private, no-store"
+    );
+    // Only the address appended by the proxy counts
+    let res = test::call_service(&app, visit("10.1.2.3, 203.0.113.9")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    // Forwarding headers of anyone but the proxy are ignored
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/runbook")
+            .peer_addr("203.0.113.9:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "10.1.2.3"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = test::call_service(&app, visit("10.0.13.7")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn test_link_cache() {
+    let config = AppConfig {
+        link_cache: LinkCacheConfig {
+            ttl: std::time::Duration::from_millis(100),
+            ..LinkCacheConfig::default()
+        },
+        ..AppConfig::default()
+    };
+    let store = Arc::new(MemoryStore::new());
+    let shortener = shortener_with(config, store.clone()).await;
+    let app = test::init_service(App::new().configure(|cfg| shortener.configure(cfg))).await;
+
+    let res = test::call_service(
+        &app,
+        shorten_request(json!({ "url": "https://example.com/viral", "alias": "viral" }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let resolve = || test::TestRequest::get().uri("/viral").to_request();
+    assert_eq!(
+        test::call_service(&app, resolve()).await.status(),
+        StatusCode::TEMPORARY_REDIRECT
+    );
+
+    // Deleted behind our back, e.g. through another instance: served from the cache until the TTL is over
+    store.delete("viral").await.unwrap();
+    assert_eq!(
+        test::call_service(&app, resolve()).await.status(),
+        StatusCode::TEMPORARY_REDIRECT
+    );
+    tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+    assert_eq!(
+        test::call_service(&app, resolve()).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
@@ -0,0 +1,173 @@
+//! Load test driving a running server, ignored by `cargo test`. Start the service and run
+//!
+//! ```bash
+//! LOAD_TEST_URL=http://localhost:8080 LOAD_TEST_CONCURRENCY=64 \
+//!     cargo test --release --test load -- --ignored --nocapture
+//! ```
+//!
+//! Links are shortened first, then resolved, and the latency percentiles of both are printed. Rate limits
+//! of the server count every request, turn them off or hand over a key with `LOAD_TEST_API_KEY`.
+
+use reqwest::{redirect, Client, StatusCode};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+struct LoadConfig {
+    url: String,
+    concurrency: usize,
+    requests: usize,
+    api_key: Option<String>,
+}
+
+impl LoadConfig {
+    fn from_env() -> Self {
+        let number = |var: &str, default: usize| {
+            std::env::var(var)
+                .ok()
+                .map(|value| {
+                    value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("{} must be a number", var))
+                })
+                .unwrap_or(default)
+        };
+        LoadConfig {
+            url: std::env::var("LOAD_TEST_URL")
+                .unwrap_or_else(|_| "http://localhost:8080".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            concurrency: number("LOAD_TEST_CONCURRENCY", 32).max(1),
+            requests: number("LOAD_TEST_REQUESTS", 10_000).max(1),
+            api_key: std::env::var("LOAD_TEST_API_KEY").ok(),
+        }
+    }
+}
+
+/// Latencies of one kind of request
+struct Report {
+    name: &'static str,
+    latencies: Vec<Duration>,
+    failures: usize,
+    elapsed: Duration,
+}
+
+impl Report {
+    fn percentile(&self, percent: usize) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (self.latencies.len() * percent).div_ceil(100).max(1);
+        self.latencies[rank - 1]
+    }
+
+    fn print(&self) {
+        println!(
+            "{:<8} {:>7} ok {:>5} failed {:>9.0} req/s   p50 {:>8.2?}   p99 {:>8.2?}   max {:>8.2?}",
+            self.name,
+            self.latencies.len(),
+            self.failures,
+            self.latencies.len() as f64 / self.elapsed.as_secs_f64(),
+            self.percentile(50),
+            self.percentile(99),
+            self.latencies.last().copied().unwrap_or_default(),
+        );
+    }
+}
+
+/// Sends `requests` requests, at most `concurrency` at a time. `send` returns the result of its request,
+/// which is `None` for failures.
+async fn drive<T, F, Fut>(name: &'static str, config: &LoadConfig, send: F) -> (Report, Vec<T>)
+where
+    T: Send + 'static,
+    F: Fn(usize) -> Fut,
+    Fut: std::future::Future<Output = Option<T>> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(config.concurrency));
+    let mut requests = JoinSet::new();
+    let started = Instant::now();
+    for index in 0..config.requests {
+        let permit = permits.clone().acquire_owned().await.unwrap();
+        let request = send(index);
+        requests.spawn(async move {
+            let sent = Instant::now();
+            let result = request.await;
+            drop(permit);
+            (sent.elapsed(), result)
+        });
+    }
+    let mut latencies = Vec::with_capacity(config.requests);
+    let mut results = Vec::with_capacity(config.requests);
+    let mut failures = 0;
+    while let Some(finished) = requests.join_next().await {
+        match finished.expect("Request task panicked") {
+            (latency, Some(result)) => {
+                latencies.push(latency);
+                results.push(result);
+            }
+            (_, None) => failures += 1,
+        }
+    }
+    let elapsed = started.elapsed();
+    latencies.sort();
+    let report = Report {
+        name,
+        latencies,
+        failures,
+        elapsed,
+    };
+    (report, results)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "drives a running server, see the module docs"]
+async fn load_shorten_and_resolve() {
+    let config = LoadConfig::from_env();
+    let client = Client::builder()
+        .redirect(redirect::Policy::none())
+        .pool_max_idle_per_host(config.concurrency)
+        .build()
+        .unwrap();
+    println!(
+        "{} requests of each kind against {}, {} at a time",
+        config.requests, config.url, config.concurrency
+    );
+    // Every run shortens URLs of its own, so nothing is deduplicated into an earlier run's links
+    let run = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+
+    let (shortened, slugs) = drive("shorten", &config, |index| {
+        let mut request = client
+            .post(format!("{}/shorten-url", config.url))
+            .json(&json!({ "url": format!("https://example.com/load/{}/{}", run, index) }));
+        if let Some(key) = &config.api_key {
+            request = request.header("X-Api-Key", key);
+        }
+        async move {
+            let response = request.send().await.ok()?;
+            if response.status() != StatusCode::OK {
+                return None;
+            }
+            let created: Value = response.json().await.ok()?;
+            let short_url = created["short_url"].as_str()?;
+            Some(short_url.rsplit('/').next()?.to_string())
+        }
+    })
+    .await;
+    shortened.print();
+    assert!(!slugs.is_empty(), "No link was shortened");
+
+    let (resolved, _) = drive("resolve", &config, |index| {
+        let request = client.get(format!("{}/{}", config.url, slugs[index % slugs.len()]));
+        async move {
+            let response = request.send().await.ok()?;
+            response.status().is_redirection().then_some(())
+        }
+    })
+    .await;
+    resolved.print();
+}